@@ -0,0 +1,10 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // protox compiles the proto in pure Rust, so building doesn't need protoc installed
+    let descriptors = protox::compile(["proto/admin.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+
+    println!("cargo:rerun-if-changed=proto/admin.proto");
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use crate::events::ServerEvent;
+use crate::handlers::MessageHandler;
+use crate::messages::Notification;
+use crate::server::Server;
+use crate::types::BanInfo;
+use crate::utils::current_time_ms;
+use serde::Deserialize;
+use std::sync::Arc;
+
+const MAX_ANNOUNCEMENT_MS: u64 = 600_000;
+/// The longest a ban can be, the same for admins as for a crown holder's kickban.
+pub const MAX_BAN_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Operator commands, sent over the WebSocket as
+/// `{"m": "admin message", "password": "...", "msg": {"m": "<command>", ...}}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "m", rename_all = "snake_case")]
+pub enum AdminCommand {
+    Record {
+        #[serde(rename = "_id")]
+        channel_id: String,
+        on: bool,
+    },
+    Motd {
+        messages: Vec<String>,
+    },
+    Bans {
+        #[serde(rename = "_id")]
+        channel_id: String,
+    },
+    Announce(Announcement),
+    /// Counts down with notifications and then shuts down gracefully.
+    Restart {
+        #[serde(default = "default_restart_secs")]
+        seconds: u64,
+    },
+    /// Re-reads the config file, same as SIGHUP.
+    Reload,
+    /// Writes every frame to and from these connections (participant ids) and everyone in
+    /// these channels to a file, until sent again with `on: false`.
+    Capture {
+        on: bool,
+        #[serde(default)]
+        clients: Vec<String>,
+        #[serde(default)]
+        channels: Vec<String>,
+    },
+}
+
+fn default_restart_secs() -> u64 {
+    60
+}
+
+/// A notification for everyone connected, from the `announce` command or
+/// `POST /api/admin/announce`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Announcement {
+    #[serde(default)]
+    pub title: String,
+    pub text: String,
+    #[serde(default = "default_announcement_ms")]
+    pub duration: u64,
+    /// CSS class for the notification box, e.g. "classic". Defaults to "short".
+    #[serde(default)]
+    pub class: Option<String>,
+}
+
+fn default_announcement_ms() -> u64 {
+    10_000
+}
+
+/// Sends an announcement to every connection and returns how many it reached.
+pub fn announce(server: &Server, announcement: Announcement) -> Result<usize, String> {
+    if announcement.text.trim().is_empty() {
+        return Err("Announcement text is empty".to_string());
+    }
+    if announcement.duration > MAX_ANNOUNCEMENT_MS {
+        return Err(format!("Duration can be at most {} ms", MAX_ANNOUNCEMENT_MS));
+    }
+    let class = announcement.class.unwrap_or_else(|| "short".to_string());
+    if class.is_empty() || !class.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ' ') {
+        return Err(format!("Invalid class {:?}", class));
+    }
+
+    Ok(server.announce(Notification {
+        id: Some(format!("announcement-{}", current_time_ms())),
+        title: Some(announcement.title),
+        text: announcement.text,
+        class,
+        duration: announcement.duration,
+    }))
+}
+
+/// Bans a user `_id` from a room for `duration_ms` and sends anyone of theirs already in it
+/// where a kickban would. Returns when the ban runs out. Durations past `MAX_BAN_MS` are cut
+/// down to it.
+pub async fn ban(server: &Arc<Server>, user_id: &str, channel_id: &str, duration_ms: u64, banned_by: &str) -> u64 {
+    let duration_ms = duration_ms.min(MAX_BAN_MS);
+    let expiry = current_time_ms().saturating_add(duration_ms);
+    server.add_ban(user_id, channel_id, BanInfo { expiry });
+    server.emit(ServerEvent::UserBanned {
+        channel_id: channel_id.to_string(),
+        user_id: user_id.to_string(),
+        name: String::new(),
+        banned_by: banned_by.to_string(),
+        duration_ms,
+    });
+
+    let connections: Vec<String> = server
+        .user_connections
+        .get(user_id)
+        .map(|c| c.iter().cloned().collect())
+        .unwrap_or_default();
+    let handler = MessageHandler::new(server.clone());
+    for client_id in connections {
+        let in_channel = match server.clients.get(&client_id).map(|c| c.value().clone()) {
+            Some(client) => client.read().await.channel_id.as_deref() == Some(channel_id),
+            None => false,
+        };
+        if in_channel {
+            handler
+                .join_channel(&client_id, &serde_json::json!({ "_id": "test/awkward" }))
+                .await;
+        }
+    }
+
+    expiry
+}
+
+pub async fn execute(server: &Arc<Server>, cmd: AdminCommand) -> Result<serde_json::Value, String> {
+    match cmd {
+        AdminCommand::Record { channel_id, on } => {
+            match server.set_recording(&channel_id, on).await {
+                Ok(Some(notes)) => Ok(format!("Saved recording of {} ({} notes)", channel_id, notes).into()),
+                Ok(None) if on => Ok(format!("Recording {}", channel_id).into()),
+                Ok(None) => Ok(format!("{} wasn't being recorded", channel_id).into()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        AdminCommand::Motd { messages } => {
+            if messages.is_empty() {
+                return Err("Need at least one message".to_string());
+            }
+            let count = messages.len();
+            server.set_motd(messages);
+            Ok(format!("MOTD updated ({} message{})", count, if count == 1 { "" } else { "s" }).into())
+        }
+        AdminCommand::Bans { channel_id } => {
+            let bans = server.channel_bans(&channel_id);
+            if bans.is_empty() {
+                return Ok(format!("No active bans in {}", channel_id).into());
+            }
+            let list: Vec<String> = bans
+                .iter()
+                .map(|(user_id, ban)| {
+                    let until = chrono::DateTime::<chrono::Utc>::from_timestamp((ban.expiry / 1000) as i64, 0)
+                        .map(|dt| dt.to_rfc3339())
+                        .unwrap_or_default();
+                    format!("{} until {}", user_id, until)
+                })
+                .collect();
+            Ok(format!("Bans in {}: {}", channel_id, list.join(", ")).into())
+        }
+        AdminCommand::Announce(announcement) => {
+            let sent = announce(server, announcement)?;
+            Ok(format!("Announced to {} connection(s)", sent).into())
+        }
+        AdminCommand::Restart { seconds } => {
+            if seconds == 0 || seconds > 3600 {
+                return Err("Countdown has to be 1-3600 seconds".to_string());
+            }
+            if !server.schedule_restart(seconds) {
+                return Err("A restart is already counting down".to_string());
+            }
+            Ok(format!("Restarting in {} seconds", seconds).into())
+        }
+        AdminCommand::Reload => reload(server).await.map(Into::into),
+        AdminCommand::Capture { on: true, clients, channels } => {
+            if clients.is_empty() && channels.is_empty() {
+                return Err("Need at least one client or channel to capture".to_string());
+            }
+            let path = server.capture.start(&server.config().capture_dir, clients, channels);
+            Ok(format!("Capturing to {}", path.display()).into())
+        }
+        AdminCommand::Capture { on: false, .. } => match server.capture.stop() {
+            Some(path) => Ok(format!("Capture saved to {}", path.display()).into()),
+            None => Err("No capture is running".to_string()),
+        },
+    }
+}
+
+/// Reloads the config and describes what happened, including settings that still need a restart.
+pub async fn reload(server: &Server) -> Result<String, String> {
+    match server.reload_config().await {
+        Ok(needs_restart) if needs_restart.is_empty() => Ok("Config reloaded".to_string()),
+        Ok(needs_restart) => Ok(format!(
+            "Config reloaded, restart to apply: {}",
+            needs_restart.join(", ")
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,363 @@
+use crate::admin::{self, Announcement};
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::{self, Access, Caller};
+use crate::reports::ReportQuery;
+use crate::server::Server;
+use crate::types::{ChannelStats, LeaderboardMetric, LeaderboardWindow};
+use crate::userdata;
+use crate::utils::{current_time_ms, percentile};
+use axum::{
+    extract::{rejection::JsonRejection, Path, Query, State},
+    http::{header, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+
+/// Operator HTTP API. Every route goes through `auth::require` with the access level it
+/// needs, and the whole API is off when no credentials are configured. The leaderboard is
+/// the exception, it's public.
+pub fn router(server: &Arc<Server>) -> Router<Arc<Server>> {
+    let read = middleware::from_fn_with_state((server.clone(), Access::Read), auth::require);
+    let write = middleware::from_fn_with_state((server.clone(), Access::Write), auth::require);
+
+    Router::new()
+        .route(
+            "/api/admin/users/{user_id}",
+            get(export_user)
+                .route_layer(read.clone())
+                .merge(delete(delete_user).route_layer(write.clone())),
+        )
+        .route("/api/admin/announce", post(announce).route_layer(write.clone()))
+        .route("/api/admin/audit", get(audit).route_layer(read.clone()))
+        .route("/api/admin/channels", get(list_channels).route_layer(read.clone()))
+        .route("/api/admin/channels/{channel_id}", get(channel_details).route_layer(read.clone()))
+        .route("/api/admin/channels/{channel_id}/clear", post(clear_chat).route_layer(write.clone()))
+        .route("/api/admin/channels/{channel_id}/bans", post(ban).route_layer(write.clone()))
+        .route("/api/admin/channels/{channel_id}/bans/{user_id}", delete(unban).route_layer(write.clone()))
+        .route("/api/admin/kick", post(kick).route_layer(write.clone()))
+        .route("/api/admin/reports", get(reports).route_layer(read.clone()))
+        .route("/api/admin/reports/{report_id}/resolve", post(resolve_report).route_layer(write))
+        .route("/metrics", get(metrics).route_layer(read))
+        .route("/api/leaderboard", get(leaderboard))
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    _id: String,
+    #[serde(default = "default_ban_ms")]
+    ms: u64,
+}
+
+fn default_ban_ms() -> u64 {
+    3_600_000
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    #[serde(default)]
+    by: LeaderboardMetric,
+    #[serde(default)]
+    window: LeaderboardWindow,
+    #[serde(default = "default_leaderboard_limit")]
+    limit: usize,
+}
+
+fn default_leaderboard_limit() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    /// Participant id or user `_id`.
+    id: String,
+}
+
+async fn export_user(
+    State(server): State<Arc<Server>>,
+    Path(user_id): Path<String>,
+) -> Response {
+    info!("Exporting data for user {}", user_id);
+    Json(userdata::export(&server, &user_id).await).into_response()
+}
+
+async fn delete_user(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    Path(user_id): Path<String>,
+) -> Response {
+    info!("Purging data for user {}", user_id);
+    server.audit(AuditEntry::new(caller, "purge user").target(user_id.as_str()));
+    Json(userdata::purge(&server, &user_id).await).into_response()
+}
+
+async fn announce(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    body: Result<Json<Announcement>, JsonRejection>,
+) -> Response {
+    let Json(announcement) = match body {
+        Ok(body) => body,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let details = json!({ "title": announcement.title, "text": announcement.text });
+    match admin::announce(&server, announcement) {
+        Ok(recipients) => {
+            info!("Sent an announcement to {} connection(s)", recipients);
+            server.audit(AuditEntry::new(caller, "announce").details(details));
+            Json(json!({ "recipients": recipients })).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn audit(State(server): State<Arc<Server>>, Query(query): Query<AuditQuery>) -> Response {
+    Json(server.audit_log.query(&query)).into_response()
+}
+
+async fn reports(State(server): State<Arc<Server>>, Query(query): Query<ReportQuery>) -> Response {
+    Json(server.reports.list(&query)).into_response()
+}
+
+async fn resolve_report(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    Path(report_id): Path<String>,
+) -> Response {
+    if !server.reports.resolve(&report_id, &caller) {
+        return (StatusCode::NOT_FOUND, format!("No open report {}", report_id)).into_response();
+    }
+    server.audit(AuditEntry::new(caller, "resolve report").details(json!({ "report": report_id })));
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn leaderboard(State(server): State<Arc<Server>>, Query(query): Query<LeaderboardQuery>) -> Response {
+    let entries = server.leaderboard(query.by, query.window, query.limit.clamp(1, 100)).await;
+    Json(json!({ "by": query.by, "window": query.window, "entries": entries })).into_response()
+}
+
+async fn list_channels(State(server): State<Arc<Server>>) -> Response {
+    let now = current_time_ms();
+    let mut list = server
+        .with_channels(move |_, channel| {
+            json!({
+                "_id": channel._id,
+                "count": channel.participants.len(),
+                "settings": channel.settings,
+                "crown": channel.crown,
+                "owner": channel.owner_id,
+                "stats": channel.stats(now),
+            })
+        })
+        .await;
+    list.sort_by(|a, b| a["_id"].as_str().cmp(&b["_id"].as_str()));
+    Json(list).into_response()
+}
+
+/// Per-room activity and client round trips in the Prometheus text format.
+async fn metrics(State(server): State<Arc<Server>>) -> Response {
+    let now = current_time_ms();
+    let mut rooms = server
+        .with_channels(move |_, channel| (channel._id.clone(), channel.stats(now)))
+        .await;
+    rooms.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // (metric name, type, help text, value for one room)
+    type Family = (&'static str, &'static str, &'static str, fn(&ChannelStats) -> String);
+    let families: [Family; 6] = [
+        ("mpp_channel_participants", "gauge", "Participants in the room", |s| s.participants.to_string()),
+        ("mpp_channel_peak_participants", "gauge", "Most participants at once since the room opened", |s| {
+            s.peak_participants.to_string()
+        }),
+        ("mpp_channel_notes_per_second", "gauge", "Notes relayed per second, averaged over 10 seconds", |s| {
+            s.notes_per_second.to_string()
+        }),
+        ("mpp_channel_chat_per_second", "gauge", "Chat messages per second, averaged over 10 seconds", |s| {
+            s.chat_per_second.to_string()
+        }),
+        ("mpp_channel_notes_total", "counter", "Notes relayed since the room opened", |s| s.notes_total.to_string()),
+        ("mpp_channel_chat_total", "counter", "Chat messages since the room opened", |s| s.chat_total.to_string()),
+    ];
+
+    let mut body = format!(
+        "# HELP mpp_connections Open client connections\n# TYPE mpp_connections gauge\nmpp_connections {}\n",
+        server.clients.len()
+    );
+    body.push_str(&format!(
+        "# HELP mpp_open_reports User reports nobody has handled yet\n# TYPE mpp_open_reports gauge\nmpp_open_reports {}\n",
+        server.reports.open_count()
+    ));
+    for (name, kind, help, value) in families {
+        body.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for (id, stats) in &rooms {
+            body.push_str(&format!("{name}{{channel=\"{}\"}} {}\n", escape_label(id), value(stats)));
+        }
+    }
+
+    let mut all = Vec::new();
+    let mut by_room: HashMap<String, Vec<u64>> = HashMap::new();
+    for (_, channel_id, rtt) in server.round_trips().await {
+        all.push(rtt);
+        if let Some(channel_id) = channel_id {
+            by_room.entry(channel_id).or_default().push(rtt);
+        }
+    }
+    all.sort_unstable();
+    body.push_str(
+        "# HELP mpp_round_trip_ms Round trip time of connected participants, from pings\n# TYPE mpp_round_trip_ms summary\n",
+    );
+    for q in QUANTILES {
+        if let Some(value) = percentile(&all, q) {
+            body.push_str(&format!("mpp_round_trip_ms{{quantile=\"{q}\"}} {value}\n"));
+        }
+    }
+    body.push_str(&format!("mpp_round_trip_ms_sum {}\nmpp_round_trip_ms_count {}\n", all.iter().sum::<u64>(), all.len()));
+
+    let mut by_room: Vec<_> = by_room.into_iter().collect();
+    by_room.sort_by(|a, b| a.0.cmp(&b.0));
+    body.push_str("# HELP mpp_channel_round_trip_ms Round trip time of the room's participants\n# TYPE mpp_channel_round_trip_ms gauge\n");
+    for (id, mut rtts) in by_room {
+        rtts.sort_unstable();
+        for q in QUANTILES {
+            if let Some(value) = percentile(&rtts, q) {
+                body.push_str(&format!(
+                    "mpp_channel_round_trip_ms{{channel=\"{}\",quantile=\"{q}\"}} {value}\n",
+                    escape_label(&id)
+                ));
+            }
+        }
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A room's participants (with `latencyMs`, their round trip, and `country`), chat history and
+/// active bans.
+async fn channel_details(State(server): State<Arc<Server>>, Path(channel_id): Path<String>) -> Response {
+    let room = server
+        .with_channel(&channel_id, |_, channel| {
+            let details = json!({
+                "_id": channel._id,
+                "settings": channel.settings,
+                "crown": channel.crown,
+                "owner": channel.owner_id,
+                "chat": channel.chat_history,
+                "stats": channel.stats(current_time_ms()),
+            });
+            (details, channel.participants.values().cloned().collect::<Vec<_>>())
+        })
+        .await;
+    let Some((mut details, mut ppl)) = room else {
+        return (StatusCode::NOT_FOUND, format!("No channel {}", channel_id)).into_response();
+    };
+    let bans: Vec<_> = {
+        let mut bans = server.channel_bans(&channel_id);
+        bans.sort_by_key(|(_, ban)| ban.expiry);
+        bans.into_iter()
+            .map(|(user_id, ban)| json!({ "_id": user_id, "expiry": ban.expiry }))
+            .collect()
+    };
+
+    let latencies: HashMap<_, _> = server.round_trips().await.into_iter().map(|(id, _, rtt)| (id, rtt)).collect();
+
+    details["bans"] = json!(bans);
+
+    ppl.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut rows = Vec::with_capacity(ppl.len());
+    for p in ppl {
+        let country = match server.clients.get(&p.id).map(|c| c.value().clone()) {
+            Some(client) => client.read().await.country.clone(),
+            None => None,
+        };
+        let latency = latencies.get(&p.id).copied();
+        let mut row = json!(p);
+        row["latencyMs"] = json!(latency);
+        row["country"] = json!(country);
+        rows.push(row);
+    }
+    details["ppl"] = json!(rows);
+    Json(details).into_response()
+}
+
+async fn clear_chat(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    Path(channel_id): Path<String>,
+) -> Response {
+    if !server.clear_chat(&channel_id).await {
+        return (StatusCode::NOT_FOUND, format!("No channel {}", channel_id)).into_response();
+    }
+    server.audit(AuditEntry::new(caller, "clear chat").channel(channel_id));
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn ban(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    Path(channel_id): Path<String>,
+    body: Result<Json<BanRequest>, JsonRejection>,
+) -> Response {
+    let Json(request) = match body {
+        Ok(body) => body,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if request._id.is_empty() {
+        return (StatusCode::BAD_REQUEST, "_id is required").into_response();
+    }
+    if !(1..=admin::MAX_BAN_MS).contains(&request.ms) {
+        let text = format!("ms has to be between 1 and {}", admin::MAX_BAN_MS);
+        return (StatusCode::BAD_REQUEST, text).into_response();
+    }
+
+    let expiry = admin::ban(&server, &request._id, &channel_id, request.ms, &caller).await;
+    server.audit(
+        AuditEntry::new(caller, "ban")
+            .channel(channel_id)
+            .target(request._id)
+            .details(json!({ "ms": request.ms })),
+    );
+    Json(json!({ "expiry": expiry })).into_response()
+}
+
+async fn unban(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    Path((channel_id, user_id)): Path<(String, String)>,
+) -> Response {
+    if server.active_ban(&user_id, &channel_id).is_none() {
+        return (StatusCode::NOT_FOUND, format!("{} isn't banned from {}", user_id, channel_id)).into_response();
+    }
+    server.remove_ban(&user_id, &channel_id);
+    server.audit(AuditEntry::new(caller, "unban").channel(channel_id).target(user_id));
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn kick(
+    State(server): State<Arc<Server>>,
+    Extension(Caller(caller)): Extension<Caller>,
+    body: Result<Json<KickRequest>, JsonRejection>,
+) -> Response {
+    let Json(request) = match body {
+        Ok(body) => body,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let closed = server.kick(&request.id);
+    if closed == 0 {
+        return (StatusCode::NOT_FOUND, format!("No connection or user {}", request.id)).into_response();
+    }
+    server.audit(AuditEntry::new(caller, "kick").target(request.id));
+    Json(json!({ "closed": closed })).into_response()
+}
@@ -0,0 +1,168 @@
+use crate::server::Server;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tower_http::services::ServeDir;
+
+/// Serves the web client from `client_dir` when that's set. Otherwise it comes from the copy
+/// compiled into the binary with the `embed-client` feature, or from `client/` without it.
+/// A `.br` or `.gz` file next to an asset is sent instead to clients that accept it. With
+/// `room_links` on, room paths like `/myroom` get the client page, which joins the room.
+pub async fn serve(State(server): State<Arc<Server>>, mut request: Request) -> Response {
+    let dir = server.config().client_dir.clone();
+    if server.config().room_links && is_room_path(request.uri().path()) {
+        *request.uri_mut() = Uri::from_static("/");
+    }
+    let path = request.uri().path().to_string();
+    let headers = request.headers().clone();
+
+    #[cfg(feature = "embed-client")]
+    if dir.is_none() {
+        return embedded::serve(&path, &headers);
+    }
+
+    let dir = dir.unwrap_or_else(|| PathBuf::from("client"));
+    let service = ServeDir::new(dir)
+        .append_index_html_on_directories(true)
+        .precompressed_br()
+        .precompressed_gzip();
+    let response = match service.oneshot(request).await {
+        Ok(response) => response.into_response(),
+        Err(never) => match never {},
+    };
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    // ServeDir already does Last-Modified, the ETag is built from that and the length
+    let mut hasher = Sha256::new();
+    for name in [header::LAST_MODIFIED, header::CONTENT_LENGTH, header::CONTENT_ENCODING] {
+        if let Some(value) = response.headers().get(name) {
+            hasher.update(value.as_bytes());
+        }
+        hasher.update(b"\0");
+    }
+    let etag = format!("W/\"{}\"", hex::encode(&hasher.finalize()[..12]));
+    with_cache_headers(response, &path, &headers, &etag)
+}
+
+/// Adds `ETag` and `Cache-Control`, turning the response into a 304 when the client already
+/// has this version.
+fn with_cache_headers(mut response: Response, path: &str, request_headers: &HeaderMap, etag: &str) -> Response {
+    let fresh = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|t| matches_etag(t.trim(), etag)));
+    if fresh {
+        response = StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control(path)));
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response
+}
+
+fn matches_etag(tag: &str, etag: &str) -> bool {
+    // If-None-Match uses weak comparison
+    tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+}
+
+/// Files with a content hash in the name never change, pages have to be rechecked every time
+/// so new deploys show up, and everything else can be reused for a while.
+fn cache_control(path: &str) -> &'static str {
+    if is_hashed(path) {
+        "public, max-age=31536000, immutable"
+    } else if path.ends_with('/') || path.ends_with(".html") {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+/// A single path segment without an extension, which the client takes as a room name.
+fn is_room_path(path: &str) -> bool {
+    let name = path.trim_start_matches('/');
+    !name.is_empty() && !name.contains(['/', '.'])
+}
+
+/// Names like `app.3f9a1c2b.js` or `style-3f9a1c2b.css`.
+fn is_hashed(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split(['.', '-'])
+        .rev()
+        .skip(1)
+        .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(feature = "embed-client")]
+mod embedded {
+    use super::with_cache_headers;
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use rust_embed::RustEmbed;
+
+    #[derive(RustEmbed)]
+    #[folder = "client/"]
+    struct Client;
+
+    pub fn serve(path: &str, request_headers: &HeaderMap) -> Response {
+        let name = path.trim_start_matches('/');
+        let name = if name.is_empty() || name.ends_with('/') {
+            format!("{}index.html", name)
+        } else {
+            name.to_string()
+        };
+
+        let Some(original) = Client::get(&name) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let precompressed = [("br", "br"), ("gz", "gzip")]
+            .into_iter()
+            .filter(|(_, encoding)| accepts_encoding(request_headers, encoding))
+            .find_map(|(ext, encoding)| Client::get(&format!("{}.{}", name, ext)).map(|file| (file, encoding)));
+
+        let content_type = original.metadata.mimetype().to_string();
+        let last_modified = original
+            .metadata
+            .last_modified()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs as i64, 0))
+            .map(|dt| dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+        let (file, encoding) = match precompressed {
+            Some((file, encoding)) => (file, Some(encoding)),
+            None => (original, None),
+        };
+        let etag = format!("\"{}\"", hex::encode(&file.metadata.sha256_hash()[..12]));
+
+        let mut response = ([(header::CONTENT_TYPE, content_type)], file.data).into_response();
+        let headers = response.headers_mut();
+        if let Some(encoding) = encoding {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        if let Some(last_modified) = last_modified.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(header::LAST_MODIFIED, last_modified);
+        }
+        with_cache_headers(response, path, request_headers, &etag)
+    }
+
+    fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+        headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accepted| {
+                accepted.split(',').any(|entry| {
+                    let mut parts = entry.split(';');
+                    let name = parts.next().unwrap_or("").trim();
+                    let refused = parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+                    name.eq_ignore_ascii_case(encoding) && !refused
+                })
+            })
+    }
+}
@@ -0,0 +1,140 @@
+use crate::utils::current_time_ms;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::error;
+
+/// One moderation action: a kickban, chset, admin command and so on.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub t: u64,
+    /// User `_id` when it came from a room, otherwise where it came from (`control socket`,
+    /// `grpc`, or the admin API user).
+    pub actor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor_name: Option<String>,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub details: serde_json::Value,
+}
+
+impl AuditEntry {
+    pub fn new(actor: impl Into<String>, action: impl Into<String>) -> Self {
+        Self {
+            t: current_time_ms(),
+            actor: actor.into(),
+            actor_name: None,
+            action: action.into(),
+            channel: None,
+            target: None,
+            details: serde_json::Value::Null,
+        }
+    }
+
+    pub fn actor_name(mut self, name: impl Into<String>) -> Self {
+        self.actor_name = Some(name.into());
+        self
+    }
+
+    pub fn channel(mut self, channel_id: impl Into<String>) -> Self {
+        self.channel = Some(channel_id.into());
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Filters for `AuditLog::query`, taken from the query string of `GET /api/admin/audit`.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub channel: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: Option<String>,
+    /// Only entries at or after this time (ms).
+    pub since: Option<u64>,
+    /// Newest this many, 100 when unset.
+    pub limit: Option<usize>,
+}
+
+/// The most recent entries, kept in memory, and every entry appended to the configured file.
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Opens `file` for appending when there is one. Failing to is logged, and the log then
+    /// only lives in memory.
+    pub fn new(file: Option<&Path>) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            file: file.and_then(|path| {
+                open(path).map_err(|e| error!("Failed to open audit log {}: {}", path.display(), e)).ok()
+            }),
+        }
+    }
+
+    /// Keeps the entry in memory and writes it straight to the file, so none are lost however
+    /// many come at once. Moderation is rare enough for the write to happen inline.
+    pub fn record(&self, entry: AuditEntry, capacity: usize) {
+        if let Some(file) = &self.file {
+            match serde_json::to_string(&entry) {
+                Ok(mut line) => {
+                    line.push('\n');
+                    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Err(e) = file.write_all(line.as_bytes()).and_then(|()| file.flush()) {
+                        error!("Failed to write audit log: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to serialize audit entry: {}", e),
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.push_back(entry);
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Matching entries, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut matching: Vec<AuditEntry> = entries
+            .iter()
+            .rev()
+            .filter(|e| query.channel.as_ref().is_none_or(|c| e.channel.as_ref() == Some(c)))
+            .filter(|e| query.actor.as_ref().is_none_or(|a| &e.actor == a))
+            .filter(|e| query.target.as_ref().is_none_or(|t| e.target.as_ref() == Some(t)))
+            .filter(|e| query.action.as_ref().is_none_or(|a| &e.action == a))
+            .filter(|e| query.since.is_none_or(|since| e.t >= since))
+            .take(query.limit.unwrap_or(100))
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<Mutex<File>> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?))
+}
@@ -0,0 +1,92 @@
+use crate::config::Config;
+use crate::server::Server;
+use crate::utils::secret_eq;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What an HTTP admin credential may do. Each route says which level it needs, and higher
+/// levels include the lower ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Looking things up, e.g. exporting a user's data.
+    #[default]
+    Read,
+    /// Anything that changes state or reaches users, e.g. purges and announcements.
+    Write,
+}
+
+/// Name of the credential a request was let in with, for handlers that need to say who did
+/// something.
+#[derive(Debug, Clone)]
+pub struct Caller(pub String);
+
+/// Middleware for the admin routes, used with `from_fn_with_state((server, access), require)`.
+/// Takes `Authorization: Bearer <secret>` or basic auth with a name from `api_users`
+/// (`admin` for `admin_password`). With no credentials configured the routes don't exist.
+pub async fn require(
+    State((server, needed)): State<(Arc<Server>, Access)>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = server.config();
+    if config.admin_password.is_none() && config.api_users.is_empty() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match authenticate(&config, request.headers()) {
+        Some((name, access)) if access >= needed => {
+            info!("Admin API {} {} by {}", request.method(), request.uri().path(), name);
+            request.extensions_mut().insert(Caller(name));
+            next.run(request).await
+        }
+        Some((name, _)) => {
+            warn!("{} isn't allowed to {} {}", name, request.method(), request.uri().path());
+            StatusCode::FORBIDDEN.into_response()
+        }
+        None => {
+            warn!("Rejected admin API request with bad or missing credentials");
+            let mut response = StatusCode::UNAUTHORIZED.into_response();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"mpp admin\""));
+            response
+        }
+    }
+}
+
+/// The name and access level the request's credentials belong to.
+fn authenticate(config: &Config, headers: &HeaderMap) -> Option<(String, Access)> {
+    let auth = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+
+    if let Some(token) = auth.strip_prefix("Bearer ") {
+        if config.admin_password.as_deref().is_some_and(|password| secret_eq(password, token)) {
+            return Some(("admin".to_string(), Access::Write));
+        }
+        return config
+            .api_users
+            .iter()
+            .find(|user| secret_eq(&user.secret, token))
+            .map(|user| (user.name.clone(), user.access));
+    }
+
+    let encoded = auth.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, secret) = decoded.split_once(':')?;
+
+    if name == "admin" && config.admin_password.as_deref().is_some_and(|password| secret_eq(password, secret)) {
+        return Some(("admin".to_string(), Access::Write));
+    }
+    config
+        .api_users
+        .iter()
+        .find(|user| user.name == name && secret_eq(&user.secret, secret))
+        .map(|user| (user.name.clone(), user.access))
+}
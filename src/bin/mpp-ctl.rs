@@ -0,0 +1,62 @@
+//! Talks to a running mpp-server through its control socket.
+//!
+//!     mpp-ctl [--socket PATH] channels
+//!     mpp-ctl kick <participant id or user _id>
+//!     mpp-ctl broadcast <message>
+//!     mpp-ctl reload
+//!     mpp-ctl shutdown
+
+#[cfg(unix)]
+fn main() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut socket = std::env::var("MPP_CONTROL_SOCKET").unwrap_or_else(|_| "/tmp/mpp-admin.sock".to_string());
+
+    if args.first().map(String::as_str) == Some("--socket") {
+        if args.len() < 2 {
+            eprintln!("--socket needs a path");
+            std::process::exit(2);
+        }
+        socket = args.remove(1);
+        args.remove(0);
+    }
+
+    if args.is_empty() {
+        eprintln!("Usage: mpp-ctl [--socket PATH] <channels | kick ID | broadcast MESSAGE | reload | shutdown>");
+        std::process::exit(2);
+    }
+
+    let mut stream = match UnixStream::connect(&socket) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Can't connect to {}: {}", socket, e);
+            std::process::exit(1);
+        }
+    };
+
+    let command = format!("{}\n", args.join(" "));
+    let mut reply = String::new();
+    if let Err(e) = stream
+        .write_all(command.as_bytes())
+        .and_then(|_| stream.read_to_string(&mut reply))
+    {
+        eprintln!("Control socket error: {}", e);
+        std::process::exit(1);
+    }
+
+    match reply.strip_prefix("ERR ") {
+        Some(err) => {
+            eprint!("{}", err);
+            std::process::exit(1);
+        }
+        None => print!("{}", reply.strip_prefix("OK ").unwrap_or(&reply)),
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("mpp-ctl needs Unix domain sockets");
+    std::process::exit(1);
+}
@@ -0,0 +1,226 @@
+//! Simulates a crowd of clients against a running mpp-server and reports how long notes take
+//! to reach the other people in the room.
+//!
+//!     mpp-loadtest [--url ws://127.0.0.1:8080/ws] [--clients 100] [--channels 10]
+//!                  [--notes 5] [--cursor 10] [--duration 30] [--ramp-ms 10]
+//!
+//! Clients are spread evenly over rooms named `loadtest-0`, `loadtest-1`, ... `--notes` and
+//! `--cursor` are messages per second per client. Latency is measured from the note's `t`, so
+//! it's only meaningful with the server on a machine whose clock agrees with this one (the
+//! same machine, ideally). Turn off `[screening]` or raise its limits first, since every
+//! connection comes from the same IP.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+
+struct Options {
+    url: String,
+    clients: usize,
+    channels: usize,
+    notes_per_sec: f64,
+    cursor_per_sec: f64,
+    duration: Duration,
+    ramp: Duration,
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    failed: AtomicU64,
+    notes_sent: AtomicU64,
+    cursors_sent: AtomicU64,
+    notes_received: AtomicU64,
+    /// Milliseconds from send to receive for every note seen.
+    latencies: Mutex<Vec<u64>>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        url: "ws://127.0.0.1:8080/ws".to_string(),
+        clients: 100,
+        channels: 10,
+        notes_per_sec: 5.0,
+        cursor_per_sec: 10.0,
+        duration: Duration::from_secs(30),
+        ramp: Duration::from_millis(10),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+        let number = |v: &str| v.parse::<f64>().map_err(|_| format!("{} needs a number, got {:?}", flag, v));
+        match flag.as_str() {
+            "--url" => options.url = value,
+            "--clients" => options.clients = number(&value)? as usize,
+            "--channels" => options.channels = (number(&value)? as usize).max(1),
+            "--notes" => options.notes_per_sec = number(&value)?,
+            "--cursor" => options.cursor_per_sec = number(&value)?,
+            "--duration" => options.duration = Duration::from_secs_f64(number(&value)?.max(0.0)),
+            "--ramp-ms" => options.ramp = Duration::from_millis(number(&value)?.max(0.0) as u64),
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+    Ok(options)
+}
+
+/// Ticks `per_sec` times a second, or never when that's 0.
+fn ticker(per_sec: f64) -> Option<tokio::time::Interval> {
+    if per_sec <= 0.0 {
+        return None;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / per_sec));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(interval)
+}
+
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn run_client(options: Arc<Options>, index: usize, stats: Arc<Stats>, deadline: Instant) -> anyhow::Result<()> {
+    let (socket, _) = tokio_tungstenite::connect_async(options.url.as_str()).await?;
+    let (mut write, mut read) = socket.split();
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    let room = format!("loadtest-{}", index % options.channels);
+    let hello = json!([{ "m": "hi" }, { "m": "ch", "_id": room }]);
+    write.send(Message::Text(hello.to_string())).await?;
+
+    let mut notes = ticker(options.notes_per_sec);
+    let mut cursor = ticker(options.cursor_per_sec);
+    let keys = ["a1", "c2", "e3", "g4", "b5", "d6"];
+    let mut sent = 0usize;
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            _ = tick(&mut notes) => {
+                let key = keys[sent % keys.len()];
+                sent += 1;
+                let msg = json!([{ "m": "n", "t": now_ms(), "n": [{ "n": key, "v": 0.5 }] }]);
+                write.send(Message::Text(msg.to_string())).await?;
+                stats.notes_sent.fetch_add(1, Ordering::Relaxed);
+            }
+            _ = tick(&mut cursor) => {
+                let x = (sent * 7 % 100) as f64;
+                let msg = json!([{ "m": "m", "x": x, "y": 50.0 }]);
+                write.send(Message::Text(msg.to_string())).await?;
+                stats.cursors_sent.fetch_add(1, Ordering::Relaxed);
+            }
+            msg = read.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => anyhow::bail!("server closed the connection"),
+                };
+                record_notes(&text, &stats);
+            }
+        }
+    }
+
+    let _ = write.send(Message::Text(json!([{ "m": "bye" }]).to_string())).await;
+    let _ = write.close().await;
+    Ok(())
+}
+
+fn record_notes(text: &str, stats: &Stats) {
+    let Ok(serde_json::Value::Array(messages)) = serde_json::from_str(text) else { return };
+    let now = now_ms();
+    let mut latencies = Vec::new();
+    for msg in &messages {
+        if msg.get("m").and_then(|m| m.as_str()) != Some("n") {
+            continue;
+        }
+        if let Some(t) = msg.get("t").and_then(|t| t.as_u64()) {
+            latencies.push(now.saturating_sub(t));
+        }
+    }
+    if latencies.is_empty() {
+        return;
+    }
+    stats.notes_received.fetch_add(latencies.len() as u64, Ordering::Relaxed);
+    stats.latencies.lock().unwrap_or_else(|e| e.into_inner()).extend(latencies);
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: mpp-loadtest [--url URL] [--clients N] [--channels N] [--notes PER_SEC] \
+                 [--cursor PER_SEC] [--duration SECS] [--ramp-ms MS]"
+            );
+            std::process::exit(2);
+        }
+    };
+
+    println!(
+        "{} clients in {} rooms against {}, {} notes/s and {} cursor moves/s each, for {:?}",
+        options.clients, options.channels, options.url, options.notes_per_sec, options.cursor_per_sec, options.duration
+    );
+
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+    let deadline = started + options.ramp.mul_f64(options.clients as f64) + options.duration;
+    let mut tasks = Vec::with_capacity(options.clients);
+    for index in 0..options.clients {
+        let (client_options, stats) = (options.clone(), stats.clone());
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = run_client(client_options, index, stats.clone(), deadline).await {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Client {}: {}", index, e);
+            }
+        }));
+        tokio::time::sleep(options.ramp).await;
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut latencies = std::mem::take(&mut *stats.latencies.lock().unwrap_or_else(|e| e.into_inner()));
+    latencies.sort_unstable();
+
+    println!();
+    println!("Connected      {} ({} failed)", stats.connected.load(Ordering::Relaxed), stats.failed.load(Ordering::Relaxed));
+    println!("Notes sent     {}", stats.notes_sent.load(Ordering::Relaxed));
+    println!("Cursors sent   {}", stats.cursors_sent.load(Ordering::Relaxed));
+    println!(
+        "Notes received {} ({:.0}/s)",
+        stats.notes_received.load(Ordering::Relaxed),
+        stats.notes_received.load(Ordering::Relaxed) as f64 / elapsed
+    );
+    println!(
+        "Latency ms     p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        percentile(&latencies, 99.9),
+        latencies.last().copied().unwrap_or(0)
+    );
+}
@@ -0,0 +1,210 @@
+use crate::config::BotSpec;
+use crate::events::ServerEvent;
+use crate::handlers::MessageHandler;
+use crate::messages::Outgoing;
+use crate::server::Server;
+use crate::types::{IncomingMessage, Note, Participant};
+use crate::utils::generate_client_id;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+/// A bot compiled into the server. It sits in one room as a participant with no socket,
+/// gets told what happens there and answers through `BotContext::send`. Callbacks run one
+/// at a time on the bot's own task, so they can keep state in `self` without locking.
+pub trait Bot: Send + 'static {
+    /// Someone new showed up in the room (not the bot itself).
+    fn on_join(&mut self, _ctx: &mut BotContext, _participant: &Participant) {}
+
+    /// Someone else said something in the room.
+    fn on_chat(&mut self, _ctx: &mut BotContext, _from: &Participant, _message: &str) {}
+
+    /// Someone else played notes. `from` is their participant id.
+    fn on_note(&mut self, _ctx: &mut BotContext, _from: &str, _notes: &[Note]) {}
+}
+
+/// What a bot gets to act through. Messages it sends are handled once the callback returns,
+/// exactly as if its client had sent them, so the usual rules (quota, crown, rate limits)
+/// apply.
+pub struct BotContext {
+    id: String,
+    channel_id: String,
+    outbox: Vec<serde_json::Value>,
+}
+
+impl BotContext {
+    /// The bot's own participant id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn channel_id(&self) -> &str {
+        &self.channel_id
+    }
+
+    /// Queues any client message, e.g. `{"m": "n", ...}` or `{"m": "kickban", ...}`.
+    pub fn send(&mut self, message: serde_json::Value) {
+        self.outbox.push(message);
+    }
+
+    pub fn chat(&mut self, message: impl Into<String>) {
+        self.send(json!({ "m": "a", "message": message.into() }));
+    }
+}
+
+pub type BotFactory = fn(&BotSpec) -> Box<dyn Bot>;
+
+/// Every kind of bot `[[bots.run]]` can name. Forks add theirs here.
+pub fn registry() -> HashMap<&'static str, BotFactory> {
+    let mut bots: HashMap<&'static str, BotFactory> = HashMap::new();
+    bots.insert("welcome", |spec| Box::new(WelcomeBot::new(spec)));
+    bots
+}
+
+/// Starts the configured bots, skipping (and logging) ones of a kind nobody compiled in.
+pub fn spawn(server: &Arc<Server>, specs: &[BotSpec]) {
+    let registry = registry();
+    for spec in specs {
+        let Some(factory) = registry.get(spec.kind.as_str()) else {
+            warn!("Unknown bot kind {:?} for {}, not starting it", spec.kind, spec.channel);
+            continue;
+        };
+        let bot = factory(spec);
+        tokio::spawn(run(Arc::downgrade(server), spec.clone(), bot));
+    }
+}
+
+async fn run(server: Weak<Server>, spec: BotSpec, mut bot: Box<dyn Bot>) {
+    let Some(strong) = server.upgrade() else { return };
+    let shutdown = strong.shutdown.clone();
+    let handler = MessageHandler::new(strong.clone());
+    let user_id = generate_client_id(&format!("bot:{}:{}", spec.kind, spec.channel));
+    let client_id = strong.add_virtual_client(user_id);
+
+    let mut set = json!({});
+    if let Some(name) = &spec.name {
+        set["name"] = json!(name);
+    }
+    if let Some(color) = &spec.color {
+        set["color"] = json!(color);
+    }
+    let mut ctx = BotContext {
+        id: client_id.clone(),
+        channel_id: spec.channel.clone(),
+        outbox: vec![
+            json!({ "m": "hi" }),
+            json!({ "m": "userset", "set": set }),
+            json!({ "m": "ch", "_id": spec.channel }),
+        ],
+    };
+    deliver(&handler, &mut ctx).await;
+
+    let Some(mut rx) = strong.with_channel(&spec.channel, |_, channel| channel.tx.subscribe()).await else {
+        warn!("Bot {} couldn't join {}", spec.kind, spec.channel);
+        return;
+    };
+    let mut events = strong.events.subscribe();
+    drop(strong);
+    info!("Bot {} running in {} as {}", spec.kind, spec.channel, client_id);
+
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            event = events.recv() => {
+                match event {
+                    Ok(ServerEvent::UserJoined { channel_id, participant })
+                        if channel_id == spec.channel && participant.id != client_id =>
+                    {
+                        if moved_out(&server, &spec, &client_id).await {
+                            break;
+                        }
+                        bot.on_join(&mut ctx, &participant);
+                        deliver(&handler, &mut ctx).await;
+                    }
+                    Err(RecvError::Closed) => break,
+                    _ => {}
+                }
+                continue;
+            }
+            _ = shutdown.cancelled() => break,
+        };
+        // Kicked or banned into another room: its old room's traffic is no business of
+        // its anymore, and anything it said would land in the new one
+        if moved_out(&server, &spec, &client_id).await {
+            break;
+        }
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(n)) => {
+                debug!("Bot {} in {} missed {} messages", spec.kind, spec.channel, n);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if msg.is_excluded(&client_id) {
+            continue;
+        }
+
+        let Some(messages) = msg.messages().as_array() else { continue };
+        for value in messages {
+            let Ok(outgoing) = serde_json::from_value::<Outgoing>(value.clone()) else { continue };
+            match outgoing {
+                Outgoing::A { a, p, .. } if p.id != client_id => bot.on_chat(&mut ctx, &p, &a),
+                Outgoing::N { n, p, .. } if p != client_id => bot.on_note(&mut ctx, &p, &n),
+                _ => {}
+            }
+        }
+
+        if !ctx.outbox.is_empty() {
+            deliver(&handler, &mut ctx).await;
+        }
+    }
+
+    info!("Bot {} in {} stopped", spec.kind, spec.channel);
+    if let Some(server) = server.upgrade() {
+        server.handle_disconnect(&client_id, false).await;
+    }
+}
+
+/// Whether the bot's client has left the room it was started in, or is gone altogether.
+async fn moved_out(server: &Weak<Server>, spec: &BotSpec, client_id: &str) -> bool {
+    let Some(server) = server.upgrade() else { return true };
+    let Some(client) = server.clients.get(client_id).map(|c| c.value().clone()) else { return true };
+    let moved = client.read().await.channel_id.as_deref() != Some(spec.channel.as_str());
+    if moved {
+        info!("Bot {} was moved out of {}", spec.kind, spec.channel);
+    }
+    moved
+}
+
+async fn deliver(handler: &MessageHandler, ctx: &mut BotContext) {
+    for message in std::mem::take(&mut ctx.outbox) {
+        match serde_json::from_value::<IncomingMessage>(message) {
+            // Replies would go to a socket the bot doesn't have
+            Ok(message) => {
+                let _ = handler.handle_message(&ctx.id, message).await;
+            }
+            Err(e) => warn!("Bot {} sent a malformed message: {}", ctx.id, e),
+        }
+    }
+}
+
+/// Greets everyone who joins with `options.message`, where `{name}` is their name.
+struct WelcomeBot {
+    message: String,
+}
+
+impl WelcomeBot {
+    fn new(spec: &BotSpec) -> Self {
+        let message = spec.options.get("message").cloned().unwrap_or_else(|| "Welcome, {name}!".to_string());
+        Self { message }
+    }
+}
+
+impl Bot for WelcomeBot {
+    fn on_join(&mut self, ctx: &mut BotContext, participant: &Participant) {
+        ctx.chat(self.message.replace("{name}", &participant.name));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::{Config, NoteQuotaConfig};
+use crate::events::{EventBus, ServerEvent};
+use crate::server::Server;
+use crate::storage::{self, Storage};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+type Subscriber = Box<dyn FnOnce(&EventBus) + Send>;
+
+/// Sets up a `Server` for embedding. Starts from `Config::default()` (or a config you pass
+/// in) rather than reading `config.toml`, though a `reload` from the admin tools still does.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    storage: Option<Arc<dyn Storage>>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Only recorded in the config; binding a listener is up to whoever serves `router`.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Where rooms, bans and profiles go, instead of what `[storage]` says.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Words flagged in chat, and masked in rooms with `no cussing` on.
+    pub fn filter_words(mut self, words: Vec<String>) -> Self {
+        self.config.filter_words = words;
+        self
+    }
+
+    pub fn note_quota(mut self, note_quota: NoteQuotaConfig) -> Self {
+        self.config.note_quota = note_quota;
+        self
+    }
+
+    /// Loads a WebAssembly hook module, see `plugins::PluginHost`.
+    pub fn plugin(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.plugins.modules.push(path.into());
+        self
+    }
+
+    /// Runs `handler` for every `ServerEvent` once the server is built, see
+    /// `EventBus::subscribe_with`.
+    pub fn on_event<F, Fut>(mut self, name: &'static str, handler: F) -> Self
+    where
+        F: FnMut(ServerEvent) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.subscribers.push(Box::new(move |events: &EventBus| events.subscribe_with(name, handler)));
+        self
+    }
+
+    /// Anything else in the config.
+    pub fn configure(mut self, f: impl FnOnce(&mut Config)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Starts the server's background tasks, so it has to be called inside a Tokio runtime.
+    pub fn build(self) -> Arc<Server> {
+        let storage = self.storage.unwrap_or_else(|| storage::from_config(&self.config));
+        let server = Server::with_storage(&self.config, storage);
+        for subscribe in self.subscribers {
+            subscribe(&server.events);
+        }
+        server
+    }
+}
@@ -0,0 +1,42 @@
+use crate::config::{CaptchaConfig, CaptchaProvider};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::debug;
+
+#[derive(Deserialize)]
+struct SiteVerify {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+impl CaptchaProvider {
+    fn verify_url(self) -> &'static str {
+        match self {
+            CaptchaProvider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            CaptchaProvider::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+        }
+    }
+}
+
+/// Asks the provider whether `token`, what the widget on the client produced, is a real
+/// solve. Tokens are single use, so a client that fails has to solve a new one.
+pub async fn verify(config: &CaptchaConfig, token: &str) -> anyhow::Result<bool> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(reqwest::Client::new);
+
+    let result: SiteVerify = client
+        .post(config.provider.verify_url())
+        .form(&[("secret", config.secret.as_str()), ("response", token)])
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if !result.success {
+        debug!("CAPTCHA token rejected: {:?}", result.error_codes);
+    }
+    Ok(result.success)
+}
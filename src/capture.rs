@@ -0,0 +1,181 @@
+use crate::server::Codec;
+use crate::utils::current_time_ms;
+use axum::extract::ws::Message;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Lines waiting to be written before new frames start getting dropped.
+const QUEUE_LEN: usize = 4096;
+
+/// Credentials that are blanked out of captured frames, as (message type, field).
+const SECRETS: &[(&str, &str)] = &[
+    ("hi", "token"),
+    ("hi", "botToken"),
+    ("hi", "captcha"),
+    ("admin message", "password"),
+];
+
+/// Debug capture of raw frames to and from selected connections, for the `capture` admin
+/// command. Each frame is a line of JSON in a file under `capture_dir`.
+#[derive(Default)]
+pub struct Capture {
+    /// Checked on every frame so the lock is only taken while a capture is running.
+    active: AtomicBool,
+    session: RwLock<Option<Session>>,
+}
+
+struct Session {
+    path: PathBuf,
+    clients: HashSet<String>,
+    channels: HashSet<String>,
+    tx: mpsc::Sender<String>,
+}
+
+#[derive(Serialize)]
+struct Frame<'a> {
+    t: u64,
+    dir: &'static str,
+    client: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    /// Base64 of a binary frame.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary: Option<String>,
+}
+
+impl Capture {
+    /// Starts capturing frames of these connections (participant ids) and of everyone in
+    /// these channels, replacing any capture already running. Returns the file path.
+    pub fn start(&self, dir: &Path, clients: Vec<String>, channels: Vec<String>) -> PathBuf {
+        let path = dir.join(format!("capture-{}.jsonl", current_time_ms()));
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write(path.clone(), rx));
+
+        let session = Session {
+            path: path.clone(),
+            clients: clients.into_iter().collect(),
+            channels: channels.into_iter().collect(),
+            tx,
+        };
+        *self.session.write().unwrap_or_else(|e| e.into_inner()) = Some(session);
+        self.active.store(true, Ordering::Relaxed);
+        path
+    }
+
+    /// Stops the running capture and returns the file it went to.
+    pub fn stop(&self) -> Option<PathBuf> {
+        self.active.store(false, Ordering::Relaxed);
+        let session = self.session.write().unwrap_or_else(|e| e.into_inner()).take()?;
+        Some(session.path)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn inbound(&self, client_id: &str, channel_id: Option<&str>, msg: &Message) {
+        self.record("in", client_id, channel_id, msg);
+    }
+
+    pub fn outbound(&self, client_id: &str, channel_id: Option<&str>, msg: &Message) {
+        self.record("out", client_id, channel_id, msg);
+    }
+
+    fn record(&self, dir: &'static str, client_id: &str, channel_id: Option<&str>, msg: &Message) {
+        if !self.is_active() {
+            return;
+        }
+        let session = self.session.read().unwrap_or_else(|e| e.into_inner());
+        let Some(session) = session.as_ref() else { return };
+        let wanted = session.clients.contains(client_id)
+            || channel_id.is_some_and(|c| session.channels.contains(c));
+        if !wanted {
+            return;
+        }
+
+        let redacted = redact(msg);
+        let (text, binary) = match redacted.as_ref().unwrap_or(msg) {
+            Message::Text(text) => (Some(text.as_str()), None),
+            Message::Binary(data) => (None, Some(base64::engine::general_purpose::STANDARD.encode(data))),
+            _ => return,
+        };
+        let frame = Frame {
+            t: current_time_ms(),
+            dir,
+            client: client_id,
+            channel: channel_id,
+            text,
+            binary,
+        };
+        match serde_json::to_string(&frame) {
+            // A full queue means the disk can't keep up; losing frames beats stalling sockets
+            Ok(line) => {
+                let _ = session.tx.try_send(line);
+            }
+            Err(e) => warn!("Failed to serialize captured frame: {}", e),
+        }
+    }
+}
+
+/// A copy of the frame with anything in `SECRETS` replaced, or None if it has none (or
+/// doesn't parse, in which case there's nothing to find them in either).
+fn redact(msg: &Message) -> Option<Message> {
+    let (codec, data) = match msg {
+        Message::Text(text) => (Codec::Json, text.as_bytes()),
+        Message::Binary(data) => (Codec::MsgPack, &data[..]),
+        _ => return None,
+    };
+    let mut messages = codec.decode(data).ok()?;
+    let mut redacted = false;
+    for message in &mut messages {
+        let Some(kind) = message.get("m").and_then(|m| m.as_str()).map(str::to_string) else { continue };
+        for (_, field) in SECRETS.iter().filter(|(secret_in, _)| *secret_in == kind) {
+            if let Some(value) = message.get_mut(*field) {
+                *value = serde_json::Value::from("[redacted]");
+                redacted = true;
+            }
+        }
+    }
+    if !redacted {
+        return None;
+    }
+    codec.encode(&serde_json::Value::Array(messages)).ok()
+}
+
+async fn write(path: PathBuf, mut rx: mpsc::Receiver<String>) {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(dir).await {
+            error!("Failed to create {} for captures: {}", dir.display(), e);
+        }
+    }
+    let mut file = match File::create(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to create capture file {}: {}", path.display(), e);
+            return;
+        }
+    };
+    info!("Capturing frames to {}", path.display());
+
+    while let Some(mut line) = rx.recv().await {
+        line.push('\n');
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            error!("Failed to write capture file {}: {}", path.display(), e);
+            return;
+        }
+    }
+    if let Err(e) = file.flush().await {
+        error!("Failed to flush capture file {}: {}", path.display(), e);
+    }
+    info!("Capture to {} stopped", path.display());
+}
@@ -0,0 +1,33 @@
+use crate::config::ChallengeConfig;
+use rand::Rng;
+
+/// Picks the `code` to send in the `b` message and the answer the client has to put in
+/// `hi.code`. Returns `None` when challenges are off.
+pub fn issue(config: &ChallengeConfig) -> Option<(String, String)> {
+    match config {
+        ChallengeConfig::None => None,
+        ChallengeConfig::Static { code, answer } => Some((code.clone(), answer.clone())),
+        ChallengeConfig::Script => {
+            // Needs a JS engine (or a bot written against this exact script) to answer, which
+            // is enough to stop most naive bots. The answer itself never appears in the code.
+            let mut rng = rand::thread_rng();
+            let masked: Vec<u8> = (0..12).map(|_| rng.gen()).collect();
+            let key: Vec<u8> = (0..12).map(|_| rng.gen()).collect();
+            let answer: String = masked.iter().zip(&key).map(|(m, k)| format!("{:02x}", m ^ k)).collect();
+            let code = format!(
+                "~var k={:?};return {:?}.map(function(n,i){{return (\"0\"+(n^k[i]).toString(16)).slice(-2)}}).join(\"\")",
+                key, masked
+            );
+            Some((code, answer))
+        }
+    }
+}
+
+/// Clients send the evaluated code back as a string, some older ones as a number.
+pub fn answer_matches(expected: &str, answer: Option<&serde_json::Value>) -> bool {
+    match answer {
+        Some(serde_json::Value::String(s)) => s == expected,
+        Some(serde_json::Value::Number(n)) => n.to_string() == expected,
+        _ => false,
+    }
+}
@@ -0,0 +1,757 @@
+use crate::audit::AuditEntry;
+use crate::config::{BlackMidiConfig, Protocol};
+use crate::events::ServerEvent;
+use crate::messages::{self, Outgoing};
+use crate::server::Server;
+use crate::types::{
+    Channel, ChatMessage, Note, NoteQuotaParams, Participant, Position, MAX_CURSOR_THROTTLE_MS, MAX_NOTE_BATCH_MS,
+};
+use crate::utils::{current_time_ms, derive_color2, generate_random_id, mask_words, validate_color};
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+/// What can be done to a channel. Its task owns the `Channel` and runs these one at a time,
+/// so they can't interleave, and there's no lock on it to hold across a wait. A command sent
+/// to a room that has closed gets no answer. Send them with `Server::channel_command`, or
+/// `Server::with_channel` for the one-off reads and changes that don't have their own.
+pub enum ChannelCommand {
+    /// Adds a participant (or takes over the seat of their dropped connection) and subscribes
+    /// them to the room's broadcasts.
+    Join {
+        participant: Participant,
+        reply: oneshot::Sender<Joined>,
+    },
+    /// Takes a participant out, dropping the crown if they held it. With `pass_crown` it goes
+    /// to someone else in the room where the room's settings allow. Answers with the new
+    /// crown holder.
+    Leave {
+        client_id: String,
+        user_id: String,
+        pass_crown: bool,
+        reply: oneshot::Sender<Option<String>>,
+    },
+    /// Adds a chat message to the history and sends it to the room. Answers false if the
+    /// room has chat turned off.
+    Chat {
+        participant: Participant,
+        message: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Applies a crown holder's `chset` and sends the room the new settings. Answers None if
+    /// `client_id` can't change them.
+    Set {
+        client_id: String,
+        set: serde_json::Value,
+        reply: oneshot::Sender<Option<SettingsChange>>,
+    },
+    /// Plays a participant's notes: records them and sends them to the room, right away or
+    /// with the room's next note batch. With `black_midi` set the player went over those
+    /// limits, so their notes are muted in the room instead.
+    Note {
+        client_id: String,
+        user_id: String,
+        notes: Vec<Note>,
+        t: u64,
+        black_midi: Option<BlackMidiConfig>,
+        reply: oneshot::Sender<NotePlayed>,
+    },
+    /// A participant's `chown`: picking up a dropped crown (`target_id` is themselves),
+    /// handing it to `target_id`, or dropping it with no target. Answers whether the room
+    /// was sent an update.
+    Chown {
+        participant: Participant,
+        target_id: Option<String>,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Adds a spectator and subscribes them to the room's broadcasts. Answers with the `ch`
+    /// and `c` to send them.
+    Spectate {
+        client_id: String,
+        reply: oneshot::Sender<serde_json::Value>,
+    },
+    StopSpectating {
+        client_id: String,
+        reply: oneshot::Sender<()>,
+    },
+    /// Sends messages to the participants connected to this instance, see
+    /// `Server::deliver_to_channel`.
+    Broadcast {
+        messages: serde_json::Value,
+        exclude: Option<String>,
+    },
+    /// Closes the room if nobody is in it or watching, answering with its final state. The
+    /// caller takes it out of `Server::channels`.
+    CloseIfEmpty { reply: oneshot::Sender<Option<Channel>> },
+    /// Anything else, run against the room in its turn, see `Server::with_channel`.
+    With(ChannelFn),
+}
+
+/// Reads or changes a room from inside its task. It can't wait on anything, so nothing else
+/// is held up behind it.
+pub type ChannelFn = Box<dyn FnOnce(&Server, &mut Channel) + Send>;
+
+/// What a join needs to tell the room afterwards.
+pub struct Joined {
+    /// The participant as they ended up in the room, with a resumed seat's name and color.
+    pub participant: Participant,
+    /// Participant id of the dropped connection this one took over from.
+    pub resumed: Option<String>,
+    /// Whether the owner got their crown back from someone else.
+    pub reclaimed: bool,
+    /// The `ch` for the joining client, `p` already filled in.
+    pub channel_msg: Outgoing,
+    pub chat_history: VecDeque<ChatMessage>,
+}
+
+pub enum NotePlayed {
+    Played,
+    /// Muted, crownsolo, or the room closed.
+    Dropped,
+    /// The player was just muted for black MIDI. The crown holder should hear about it.
+    Muted { crown_holder: Option<String>, secs: u64 },
+}
+
+pub struct SettingsChange {
+    pub changed: bool,
+    /// Participants whose note quota needs refreshing because the room's changed.
+    pub quota_changed_for: Vec<String>,
+}
+
+/// Starts the task for a channel that was just opened. The task owns the room from then on:
+/// everything else gets at it through the returned sender. It ends when `CloseIfEmpty`
+/// closes the room, handing the state back, or once the server is gone.
+pub fn spawn(server: Weak<Server>, mut channel: Channel) -> mpsc::UnboundedSender<ChannelCommand> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let channel_id = channel._id.clone();
+        while let Some(command) = rx.recv().await {
+            let Some(server) = server.upgrade() else { break };
+            let crown_before = crown_holder(&channel);
+            match command {
+                ChannelCommand::Join { participant, reply } => {
+                    let joined = join(&server, &mut channel, participant);
+                    // Taking over a dropped connection's seat isn't anyone new arriving
+                    if joined.resumed.is_none() {
+                        server.emit(ServerEvent::UserJoined {
+                            channel_id: channel_id.clone(),
+                            participant: joined.participant.clone(),
+                        });
+                    }
+                    let _ = reply.send(joined);
+                }
+                ChannelCommand::Leave {
+                    client_id,
+                    user_id,
+                    pass_crown,
+                    reply,
+                } => {
+                    let passed_to = leave(&server, &mut channel, &client_id, &user_id, pass_crown);
+                    server.emit(ServerEvent::UserLeft {
+                        channel_id: channel_id.clone(),
+                        participant_id: client_id,
+                        user_id,
+                    });
+                    let _ = reply.send(passed_to);
+                }
+                ChannelCommand::Chat {
+                    participant,
+                    message,
+                    reply,
+                } => {
+                    let _ = reply.send(chat(&server, &mut channel, participant, &message));
+                }
+                ChannelCommand::Set { client_id, set, reply } => {
+                    let _ = reply.send(apply_settings(&server, &mut channel, &client_id, &set));
+                }
+                ChannelCommand::Note {
+                    client_id,
+                    user_id,
+                    notes,
+                    t,
+                    black_midi,
+                    reply,
+                } => {
+                    let _ = reply.send(note(&server, &mut channel, client_id, &user_id, notes, t, black_midi));
+                }
+                ChannelCommand::Chown {
+                    participant,
+                    target_id,
+                    reply,
+                } => {
+                    let _ = reply.send(chown(&server, &mut channel, &participant, target_id.as_deref()));
+                }
+                ChannelCommand::Spectate { client_id, reply } => {
+                    let _ = reply.send(spectate(&server, &mut channel, &client_id));
+                }
+                ChannelCommand::StopSpectating { client_id, reply } => {
+                    channel.spectators.remove(&client_id);
+                    let _ = reply.send(());
+                }
+                ChannelCommand::Broadcast { messages, exclude } => {
+                    server.deliver_in(&channel, &messages, exclude.as_deref());
+                }
+                ChannelCommand::CloseIfEmpty { reply } => {
+                    if channel.participants.is_empty() && channel.spectators.is_empty() {
+                        // Whatever is still queued behind this finds the room closed
+                        let _ = reply.send(Some(channel));
+                        return;
+                    }
+                    let _ = reply.send(None);
+                }
+                ChannelCommand::With(f) => f(&server, &mut channel),
+            }
+
+            let crown_after = crown_holder(&channel);
+            if crown_after != crown_before {
+                server.emit(ServerEvent::CrownChanged {
+                    channel_id: channel_id.clone(),
+                    from: crown_before,
+                    to: crown_after,
+                });
+            }
+        }
+    });
+    tx
+}
+
+fn crown_holder(channel: &Channel) -> Option<String> {
+    channel.crown.as_ref().and_then(|c| c.participant_id.clone())
+}
+
+fn join(server: &Server, channel: &mut Channel, mut participant: Participant) -> Joined {
+    let client_id = participant.id.clone();
+    let user_id = participant._id.clone();
+
+    // Taking over from a dropped connection of the same user: same name, color, cursor
+    // and crown, just under the new participant id
+    let resumed = match channel.disconnected_participant(&user_id) {
+        Some(old_id) => {
+            channel.disconnected.remove(&old_id);
+            if let Some(old) = channel.participants.remove(&old_id) {
+                participant.name = old.name;
+                participant.color = old.color;
+                participant.x = old.x;
+                participant.y = old.y;
+            }
+            if let Some(crown) = channel.crown.as_mut().filter(|c| c.participant_id.as_deref() == Some(old_id.as_str())) {
+                crown.participant_id = Some(client_id.clone());
+            }
+            debug!("{} took over {}'s seat in {}", client_id, old_id, channel._id);
+            Some(old_id)
+        }
+        None => None,
+    };
+
+    channel.participants.insert(client_id.clone(), participant.clone());
+    channel.activity.peak_participants = channel.activity.peak_participants.max(channel.participants.len());
+    server.subscribe_to_channel(&client_id, channel);
+
+    let is_owner = channel.owner_id.as_deref() == Some(user_id.as_str());
+    let reserved = channel.crown_reserved();
+    let auto_pass = channel.auto_passes_crown(server.config().auto_pass_crown);
+    let mut reclaimed = false;
+    if let Some(crown) = &mut channel.crown {
+        // Without auto_pass a dropped crown only goes back to whoever dropped it
+        let free = !reserved && (auto_pass || crown.user_id.as_deref().is_none_or(|id| id == user_id));
+        if crown.participant_id.is_none() && (is_owner || free) {
+            crown.participant_id = Some(client_id.clone());
+            crown.user_id = Some(user_id);
+            crown.time = current_time_ms();
+            reclaimed = is_owner && channel.participants.len() > 1;
+        }
+    }
+
+    let mut channel_msg = channel.update();
+    if let Outgoing::Ch { p, .. } = &mut channel_msg {
+        *p = Some(client_id);
+    }
+
+    Joined {
+        participant,
+        resumed,
+        reclaimed,
+        channel_msg,
+        chat_history: channel.chat_history.clone(),
+    }
+}
+
+fn leave(server: &Server, channel: &mut Channel, client_id: &str, user_id: &str, pass_crown: bool) -> Option<String> {
+    channel.disconnected.remove(client_id);
+    channel.participants.remove(client_id);
+    let auto_pass = channel.auto_passes_crown(server.config().auto_pass_crown);
+    channel.release_crown(client_id, user_id, auto_pass);
+
+    if pass_crown && auto_pass {
+        Server::pass_unheld_crown(channel, false)
+    } else {
+        None
+    }
+}
+
+fn chat(server: &Server, channel: &mut Channel, participant: Participant, message: &str) -> bool {
+    let config = server.config();
+    if !channel.settings.chat.unwrap_or(false) {
+        return false;
+    }
+    let channel_id = channel._id.clone();
+    let message = filter_chat(server, channel, &participant, message);
+
+    let t = current_time_ms();
+    let id = (config.protocol == Protocol::MppClone).then(generate_random_id);
+    let chat_msg = Outgoing::A {
+        a: message.clone(),
+        p: participant.clone(),
+        t,
+        id: id.clone(),
+    };
+    channel.push_chat(
+        ChatMessage {
+            m: "a".to_string(),
+            a: message.clone(),
+            p: participant.clone(),
+            t,
+            id,
+        },
+        config.chat_history_len,
+    );
+    channel.activity.chat.record(t, 1);
+
+    server.chat_changed(&channel_id);
+    server.emit(ServerEvent::ChatPosted {
+        channel_id: channel_id.clone(),
+        user_id: participant._id,
+        name: participant.name,
+        message,
+        t,
+    });
+    server.broadcast_in(channel, &messages::batch(&[chat_msg]), None);
+    true
+}
+
+fn note(
+    server: &Arc<Server>,
+    channel: &mut Channel,
+    client_id: String,
+    user_id: &str,
+    notes: Vec<Note>,
+    t: u64,
+    black_midi: Option<BlackMidiConfig>,
+) -> NotePlayed {
+    let now = current_time_ms();
+    let channel_id = channel._id.clone();
+
+    if let Some(limits) = black_midi {
+        info!("Muting notes from {} in {} for black MIDI", user_id, channel_id);
+        channel.note_mutes.retain(|_, until| *until > now);
+        channel.note_mutes.insert(user_id.to_string(), now + limits.mute_secs * 1000);
+        let crown_holder = channel.crown.as_ref().and_then(|c| c.participant_id.clone());
+        return NotePlayed::Muted {
+            crown_holder,
+            secs: limits.mute_secs,
+        };
+    }
+
+    if channel.note_mutes.get(user_id).is_some_and(|until| *until > now) {
+        return NotePlayed::Dropped;
+    }
+    if channel.settings.crownsolo == Some(true) {
+        if let Some(crown) = &channel.crown {
+            if crown.participant_id.as_deref() != Some(client_id.as_str()) {
+                return NotePlayed::Dropped;
+            }
+        }
+    }
+
+    if let Some(recording) = channel.recording.as_mut() {
+        recording.record(t, &notes);
+    }
+    channel.activity.notes.record(now, notes.len() as u32);
+
+    let batch_ms = channel.note_batch_ms(server.config().note_batch_ms);
+    if batch_ms > 0 {
+        // The first message of a window starts the timer that sends the whole window
+        let first = channel.note_batch.is_empty();
+        channel.note_batch.push((client_id, t, notes));
+        if first {
+            let server = server.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(batch_ms)).await;
+                server.flush_note_batch(&channel_id).await;
+            });
+        }
+        return NotePlayed::Played;
+    }
+
+    let note_msg = Outgoing::N {
+        t,
+        n: notes,
+        p: client_id,
+    };
+    server.broadcast_in(channel, &messages::batch(&[note_msg]), None);
+    NotePlayed::Played
+}
+
+fn chown(server: &Server, channel: &mut Channel, participant: &Participant, target_id: Option<&str>) -> bool {
+    if channel.settings.lobby {
+        return false;
+    }
+    let client_id = participant.id.as_str();
+
+    // A crown held for its absent owner isn't up for grabs
+    let reserved_for_other = channel.crown_reserved() && channel.owner_id.as_deref() != Some(participant._id.as_str());
+    let position = channel.participants.get(client_id).map(|p| Position { x: p.x, y: p.y });
+    let target_position = target_id
+        .and_then(|id| channel.participants.get(id))
+        .map(|p| (p._id.clone(), Position { x: p.x, y: p.y }));
+
+    let crown_before = channel.crown.clone();
+    let Some(crown) = channel.crown.as_mut() else { return false };
+
+    if crown.participant_id.as_deref() != Some(client_id) {
+        // Picking up a dropped crown is a chown to yourself
+        let Some(position) = position.filter(|_| target_id == Some(client_id) && !reserved_for_other) else {
+            return false;
+        };
+        if !crown.can_claim(&participant._id, &position, current_time_ms()) {
+            return false;
+        }
+        let lying_at = crown.end_pos.clone();
+        crown.give(client_id, &participant._id, &lying_at, &position, current_time_ms());
+    } else if let Some(target_id) = target_id {
+        let from = position.unwrap_or(Position { x: participant.x, y: participant.y });
+        let Some((target_user_id, to)) = target_position else { return false };
+        crown.give(target_id, &target_user_id, &from, &to, current_time_ms());
+    } else {
+        let at = position.unwrap_or(Position { x: participant.x, y: participant.y });
+        crown.drop_at(&at, current_time_ms());
+        crown.user_id = Some(participant._id.clone());
+    }
+
+    let channel_id = channel._id.clone();
+    if let Some(crown) = channel.crown.as_ref().filter(|c| Some(*c) != crown_before.as_ref()) {
+        // Dropping it leaves no holder, which shows as a null id
+        let mut entry = AuditEntry::new(participant._id.as_str(), "chown")
+            .actor_name(participant.name.as_str())
+            .channel(channel_id.as_str())
+            .details(serde_json::json!({ "id": crown.participant_id }));
+        if let Some(user_id) = crown.participant_id.as_ref().and(crown.user_id.as_ref()) {
+            entry = entry.target(user_id.as_str());
+        }
+        server.audit(entry);
+    }
+
+    server.broadcast_in(channel, &messages::batch(&[channel.update()]), None);
+    true
+}
+
+fn spectate(server: &Server, channel: &mut Channel, client_id: &str) -> serde_json::Value {
+    channel.spectators.insert(client_id.to_string());
+    server.subscribe_to_channel(client_id, channel);
+    serde_json::json!([
+        channel.update(),
+        {
+            "m": "c",
+            "c": channel.chat_history
+        }
+    ])
+}
+
+/// Reports `message` when it has one of `filter_words` in it, and masks them if the room
+/// has "no cussing" on.
+pub fn filter_chat(server: &Server, channel: &Channel, participant: &Participant, message: &str) -> String {
+    let config = server.config();
+    let lowered = message.to_lowercase();
+    if let Some(word) = config.filter_words.iter().find(|w| lowered.contains(&w.to_lowercase())) {
+        server.emit(ServerEvent::ChatFiltered {
+            channel_id: channel._id.clone(),
+            user_id: participant._id.clone(),
+            name: participant.name.clone(),
+            message: message.to_string(),
+            word: word.clone(),
+        });
+    }
+
+    if channel.settings.no_cussing.unwrap_or(false) {
+        mask_words(message, &config.filter_words)
+    } else {
+        message.to_string()
+    }
+}
+
+fn apply_settings(
+    server: &Server,
+    channel: &mut Channel,
+    client_id: &str,
+    set: &serde_json::Value,
+) -> Option<SettingsChange> {
+    let config = server.config();
+
+    if let Some(crown) = &channel.crown {
+        if crown.participant_id.as_deref() != Some(client_id) {
+            return None;
+        }
+    }
+
+    if server.is_special_channel(&channel._id) {
+        return None;
+    }
+    let settings_before = channel.settings.clone();
+
+    if let Some(color) = set.get("color").and_then(|c| c.as_str()) {
+        match validate_color(color, &config.color_palette) {
+            Some(color) => {
+                // Clients that only send color still get a two-tone background
+                if set.get("color2").is_none() {
+                    let color2 = derive_color2(&color);
+                    if validate_color(&color2, &config.color_palette).is_some() {
+                        channel.settings.color2 = Some(color2);
+                    }
+                }
+                channel.settings.color = color;
+            }
+            None => debug!("Ignoring invalid channel color {:?} from {}", color, client_id),
+        }
+    }
+    if let Some(visible) = set.get("visible").and_then(|v| v.as_bool()) {
+        channel.settings.visible = visible;
+    }
+    if let Some(chat) = set.get("chat").and_then(|c| c.as_bool()) {
+        channel.settings.chat = Some(chat);
+    }
+    if let Some(crownsolo) = set.get("crownsolo").and_then(|c| c.as_bool()) {
+        channel.settings.crownsolo = Some(crownsolo);
+    }
+    match set.get("color2") {
+        Some(serde_json::Value::Null) => channel.settings.color2 = None,
+        Some(color2) => match color2.as_str().and_then(|c| validate_color(c, &config.color_palette)) {
+            Some(color2) => channel.settings.color2 = Some(color2),
+            None => debug!("Ignoring invalid color2 {} from {}", color2, client_id),
+        },
+        None => {}
+    }
+    match set.get("limit") {
+        Some(serde_json::Value::Null) => channel.settings.limit = None,
+        Some(limit) => match limit.as_u64().filter(|l| (1..=99).contains(l)) {
+            Some(limit) => channel.settings.limit = Some(limit as u32),
+            None => debug!("Ignoring invalid limit {} from {}", limit, client_id),
+        },
+        None => {}
+    }
+    if let Some(noindex) = set.get("noindex").and_then(|n| n.as_bool()) {
+        channel.settings.noindex = Some(noindex);
+    }
+    if let Some(no_cussing) = set.get("no cussing").and_then(|n| n.as_bool()) {
+        channel.settings.no_cussing = Some(no_cussing);
+    }
+    match set.get("autoPassCrown") {
+        Some(serde_json::Value::Null) => channel.settings.auto_pass_crown = None,
+        Some(auto_pass) => match auto_pass.as_bool() {
+            Some(auto_pass) => channel.settings.auto_pass_crown = Some(auto_pass),
+            None => debug!("Ignoring invalid autoPassCrown {} from {}", auto_pass, client_id),
+        },
+        None => {}
+    }
+    match set.get("noteBatchMs") {
+        Some(serde_json::Value::Null) => channel.settings.note_batch_ms = None,
+        Some(ms) => match ms.as_u64().filter(|ms| *ms <= MAX_NOTE_BATCH_MS) {
+            Some(ms) => channel.settings.note_batch_ms = Some(ms),
+            None => debug!("Ignoring invalid noteBatchMs {} from {}", ms, client_id),
+        },
+        None => {}
+    }
+    match set.get("cursorThrottleMs") {
+        Some(serde_json::Value::Null) => channel.settings.cursor_throttle_ms = None,
+        Some(ms) => match ms.as_u64().filter(|ms| *ms <= MAX_CURSOR_THROTTLE_MS) {
+            Some(ms) => channel.settings.cursor_throttle_ms = Some(ms),
+            None => debug!("Ignoring invalid cursorThrottleMs {} from {}", ms, client_id),
+        },
+        None => {}
+    }
+    match set.get("minOnlineTime") {
+        Some(serde_json::Value::Null) => channel.settings.min_online_time = None,
+        Some(min) => match min.as_u64().filter(|m| *m <= 86_400_000) {
+            Some(min) => channel.settings.min_online_time = Some(min),
+            None => debug!("Ignoring invalid minOnlineTime {} from {}", min, client_id),
+        },
+        None => {}
+    }
+    match set.get("chatHistoryLength") {
+        Some(serde_json::Value::Null) => channel.settings.chat_history_length = None,
+        Some(len) => match len.as_u64().filter(|l| *l <= config.chat_history_len as u64) {
+            Some(len) => channel.settings.chat_history_length = Some(len as u32),
+            None => debug!("Ignoring invalid chatHistoryLength {} from {}", len, client_id),
+        },
+        None => {}
+    }
+    channel.trim_chat_history(config.chat_history_len);
+
+    let mut quota_changed = false;
+    if let Some(note_quota) = set.get("noteQuota") {
+        let cap = config.max_channel_quota;
+        match serde_json::from_value::<Option<NoteQuotaParams>>(note_quota.clone()) {
+            Ok(Some(params))
+                if params.allowance > 0
+                    && params.max >= params.allowance
+                    && params.allowance <= cap.allowance
+                    && params.max <= cap.max =>
+            {
+                quota_changed = channel.settings.note_quota != Some(params);
+                channel.settings.note_quota = Some(params);
+            }
+            Ok(None) => {
+                quota_changed = channel.settings.note_quota.is_some();
+                channel.settings.note_quota = None;
+            }
+            _ => debug!("Ignoring invalid noteQuota {} from {}", note_quota, client_id),
+        }
+    }
+    let quota_changed_for: Vec<String> = if quota_changed {
+        channel.participants.keys().cloned().collect()
+    } else {
+        Vec::new()
+    };
+
+    let changed = channel.settings != settings_before;
+    server.broadcast_in(channel, &messages::batch(&[channel.update()]), None);
+    server.queue_ls_update(&channel._id);
+
+    Some(SettingsChange {
+        changed,
+        quota_changed_for,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn participant(id: &str, user_id: &str) -> Participant {
+        Participant {
+            id: id.to_string(),
+            _id: user_id.to_string(),
+            name: "Anonymous".to_string(),
+            color: "#777777".to_string(),
+            x: 0.0,
+            y: 0.0,
+            tag: None,
+            afk: false,
+            typing: false,
+        }
+    }
+
+    fn played(
+        id: &str,
+        user_id: &str,
+        black_midi: Option<BlackMidiConfig>,
+    ) -> impl FnOnce(oneshot::Sender<NotePlayed>) -> ChannelCommand {
+        let (client_id, user_id) = (id.to_string(), user_id.to_string());
+        move |reply| ChannelCommand::Note {
+            client_id,
+            user_id,
+            notes: vec![Note {
+                n: "c3".to_string(),
+                v: Some(0.5),
+                d: None,
+                s: None,
+            }],
+            t: current_time_ms(),
+            black_midi,
+            reply,
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_run_against_the_room() {
+        let server = Server::new(&Config::default());
+        server.open_channel(server.create_default_channel("room"));
+        let holder = || server.with_channel("room", |_, channel| crown_holder(channel));
+
+        let alice = participant("p1", "alice");
+        let joined = server.channel_command("room", |reply| ChannelCommand::Join {
+            participant: alice.clone(),
+            reply,
+        });
+        assert!(joined.await.is_some_and(|j| j.resumed.is_none()));
+        assert_eq!(holder().await, Some(Some("p1".to_string())));
+
+        // Only the holder can drop the crown
+        let bob = participant("p2", "bob");
+        let moved = server.channel_command("room", |reply| ChannelCommand::Chown {
+            participant: bob,
+            target_id: None,
+            reply,
+        });
+        assert_eq!(moved.await, Some(false));
+        let moved = server.channel_command("room", |reply| ChannelCommand::Chown {
+            participant: alice,
+            target_id: None,
+            reply,
+        });
+        assert_eq!(moved.await, Some(true));
+        assert_eq!(holder().await, Some(None));
+
+        assert!(matches!(
+            server.channel_command("room", played("p1", "alice", None)).await,
+            Some(NotePlayed::Played)
+        ));
+        let limits = BlackMidiConfig::default();
+        let muted = server
+            .channel_command("room", played("p1", "alice", Some(limits)))
+            .await;
+        assert!(matches!(
+            muted,
+            Some(NotePlayed::Muted {
+                crown_holder: None,
+                secs: 60
+            })
+        ));
+        assert!(matches!(
+            server.channel_command("room", played("p1", "alice", None)).await,
+            Some(NotePlayed::Dropped)
+        ));
+
+        let spectate = server.channel_command("room", |reply| ChannelCommand::Spectate {
+            client_id: "p3".to_string(),
+            reply,
+        });
+        assert!(spectate.await.is_some());
+        let watching = server.with_channel("room", |_, channel| channel.spectators.contains("p3"));
+        assert_eq!(watching.await, Some(true));
+        let stop = server.channel_command("room", |reply| ChannelCommand::StopSpectating {
+            client_id: "p3".to_string(),
+            reply,
+        });
+        stop.await;
+        let watching = server.with_channel("room", |_, channel| channel.spectators.len());
+        assert_eq!(watching.await, Some(0));
+
+        // Alice is still in, so the room stays open
+        let closed = server.channel_command("room", |reply| ChannelCommand::CloseIfEmpty { reply });
+        assert!(matches!(closed.await, Some(None)));
+        let left = server.channel_command("room", |reply| ChannelCommand::Leave {
+            client_id: "p1".to_string(),
+            user_id: "alice".to_string(),
+            pass_crown: true,
+            reply,
+        });
+        assert_eq!(left.await, Some(None));
+
+        // Once it's closed, whatever was queued behind that gets no answer
+        let (reply, closed) = oneshot::channel();
+        let (spectate, answer) = oneshot::channel();
+        let commands = server.channels.get("room").map(|c| c.value().clone()).unwrap();
+        commands.send(ChannelCommand::CloseIfEmpty { reply }).unwrap();
+        commands
+            .send(ChannelCommand::Spectate {
+                client_id: "p3".to_string(),
+                reply: spectate,
+            })
+            .unwrap();
+        assert!(closed.await.unwrap().is_some_and(|channel| channel.participants.is_empty()));
+        assert!(answer.await.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::ChatLogConfig;
+use crate::events::ServerEvent;
+use chrono::{NaiveDate, Utc};
+use std::path::Path;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, warn};
+
+/// Appends chat to `chat-YYYY-MM-DD.jsonl` in the configured directory, one file per UTC day,
+/// deleting files older than `retention_days` whenever a new day starts.
+pub fn spawn(config: ChatLogConfig, mut events: broadcast::Receiver<ServerEvent>) {
+    tokio::spawn(async move {
+        let mut current: Option<(NaiveDate, File)> = None;
+
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Chat logger fell behind, {} events were not logged", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let ServerEvent::ChatPosted { channel_id, user_id, name, message, t } = event else {
+                continue;
+            };
+
+            let today = Utc::now().date_naive();
+            if current.as_ref().is_none_or(|(date, _)| *date != today) {
+                if let Some((_, mut yesterday)) = current.take() {
+                    let _ = yesterday.flush().await;
+                }
+                current = match open_day(&config.dir, today).await {
+                    Ok(file) => Some((today, file)),
+                    Err(e) => {
+                        error!("Failed to open chat log in {}: {}", config.dir.display(), e);
+                        None
+                    }
+                };
+                if config.retention_days > 0 {
+                    purge_old(&config.dir, today, config.retention_days).await;
+                }
+            }
+
+            let Some((_, file)) = current.as_mut() else { continue };
+
+            let mut line = serde_json::json!({
+                "t": t,
+                "channel": channel_id,
+                "_id": user_id,
+                "name": name,
+                "message": message,
+            })
+            .to_string();
+            line.push('\n');
+
+            // Flushed once whatever has queued up meanwhile is written, so a burst of chat
+            // isn't a flush per line but nothing sits in the buffer when it goes quiet
+            let written = match file.write_all(line.as_bytes()).await {
+                Ok(()) if events.is_empty() => file.flush().await,
+                result => result,
+            };
+            if let Err(e) = written {
+                error!("Failed to write chat log: {}", e);
+                current = None;
+            }
+        }
+    });
+}
+
+async fn open_day(dir: &Path, date: NaiveDate) -> std::io::Result<File> {
+    fs::create_dir_all(dir).await?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(format!("chat-{}.jsonl", date.format("%Y-%m-%d"))))
+        .await
+}
+
+async fn purge_old(dir: &Path, today: NaiveDate, retention_days: u32) {
+    let Ok(mut entries) = fs::read_dir(dir).await else { return };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some(date) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("chat-"))
+            .and_then(|n| n.strip_suffix(".jsonl"))
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        else {
+            continue;
+        };
+
+        if (today - date).num_days() > retention_days as i64 {
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => debug!("Deleted old chat log {}", entry.path().display()),
+                Err(e) => error!("Failed to delete old chat log {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use crate::server::Server;
+use crate::types::BanInfo;
+use crate::utils::generate_random_id;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+/// Something that happened on one instance which every other instance needs to replay
+/// locally for the cluster to look like a single server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Broadcast {
+        channel_id: String,
+        messages: serde_json::Value,
+        exclude: Option<String>,
+    },
+    Ls {
+        messages: serde_json::Value,
+    },
+    Ban {
+        user_id: String,
+        channel_id: String,
+        expiry: u64,
+    },
+    Unban {
+        user_id: String,
+        channel_id: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    origin: String,
+    event: ClusterEvent,
+}
+
+pub struct Cluster {
+    instance_id: String,
+    outgoing: mpsc::UnboundedSender<Envelope>,
+}
+
+impl Cluster {
+    /// Connects lazily: publishing and subscribing both run in background tasks that keep
+    /// retrying, so a Redis outage degrades to single-instance behaviour instead of failing.
+    pub fn start(server: &Arc<Server>, redis_url: &str, topic: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+        let instance_id = generate_random_id();
+        let (outgoing, rx) = mpsc::unbounded_channel();
+
+        info!("Cluster mode enabled, instance {} on topic {}", instance_id, topic);
+
+        tokio::spawn(run_publisher(client.clone(), topic.to_string(), rx));
+        tokio::spawn(run_subscriber(
+            client,
+            topic.to_string(),
+            instance_id.clone(),
+            Arc::downgrade(server),
+        ));
+
+        Ok(Self { instance_id, outgoing })
+    }
+
+    pub fn publish(&self, event: ClusterEvent) {
+        let envelope = Envelope {
+            origin: self.instance_id.clone(),
+            event,
+        };
+        if self.outgoing.send(envelope).is_err() {
+            warn!("Cluster publisher is gone, dropping event");
+        }
+    }
+}
+
+async fn run_publisher(client: redis::Client, topic: String, mut rx: mpsc::UnboundedReceiver<Envelope>) {
+    let mut conn = None;
+    let mut last_attempt: Option<tokio::time::Instant> = None;
+
+    while let Some(envelope) = rx.recv().await {
+        let payload = match serde_json::to_string(&envelope) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Failed to serialize cluster event: {}", e);
+                continue;
+            }
+        };
+
+        if conn.is_none() {
+            // While Redis is down, events are dropped rather than retried on every one
+            if last_attempt.is_some_and(|t| t.elapsed() < Duration::from_secs(2)) {
+                continue;
+            }
+            last_attempt = Some(tokio::time::Instant::now());
+
+            match client.get_multiplexed_async_connection().await {
+                Ok(c) => conn = Some(c),
+                Err(e) => {
+                    error!("Failed to connect to Redis for publishing: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(c) = conn.as_mut() {
+            if let Err(e) = c.publish::<_, _, ()>(&topic, payload).await {
+                error!("Failed to publish cluster event: {}", e);
+                conn = None;
+            }
+        }
+    }
+}
+
+async fn run_subscriber(client: redis::Client, topic: String, instance_id: String, server: Weak<Server>) {
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => {
+                if let Err(e) = pubsub.subscribe(&topic).await {
+                    error!("Failed to subscribe to {}: {}", topic, e);
+                } else {
+                    debug!("Subscribed to cluster topic {}", topic);
+                    let mut stream = pubsub.on_message();
+                    while let Some(msg) = stream.next().await {
+                        let Some(server) = server.upgrade() else { return };
+                        let envelope: Envelope = match serde_json::from_slice(msg.get_payload_bytes()) {
+                            Ok(e) => e,
+                            Err(e) => {
+                                warn!("Ignoring malformed cluster message: {}", e);
+                                continue;
+                            }
+                        };
+                        if envelope.origin != instance_id {
+                            apply(&server, envelope.event).await;
+                        }
+                    }
+                    warn!("Lost cluster subscription, reconnecting");
+                }
+            }
+            Err(e) => error!("Failed to connect to Redis for subscribing: {}", e),
+        }
+
+        if server.strong_count() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+async fn apply(server: &Server, event: ClusterEvent) {
+    match event {
+        ClusterEvent::Broadcast { channel_id, messages, exclude } => {
+            server
+                .deliver_to_channel(&channel_id, &messages, exclude.as_deref())
+                .await;
+        }
+        ClusterEvent::Ls { messages } => {
+            server.deliver_ls_update(&messages).await;
+        }
+        ClusterEvent::Ban { user_id, channel_id, expiry } => {
+            server.banned_users.insert((user_id, channel_id), BanInfo { expiry });
+        }
+        ClusterEvent::Unban { user_id, channel_id } => {
+            server.banned_users.remove(&(user_id, channel_id));
+        }
+    }
+}
@@ -0,0 +1,1031 @@
+use crate::admin::Announcement;
+use crate::auth::Access;
+use crate::schedule::Schedule;
+use crate::types::{ChannelSettings, NoteQuotaParams};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+    pub cluster: Option<ClusterConfig>,
+    pub admin_password: Option<String>,
+    /// More credentials for the HTTP admin API, on top of `admin_password` (which has full access).
+    pub api_users: Vec<ApiUser>,
+    pub recordings_dir: PathBuf,
+    /// Where the `capture` admin command writes frame captures.
+    pub capture_dir: PathBuf,
+    /// Serve the web client from this directory instead of the built-in copy or `client/`.
+    pub client_dir: Option<PathBuf>,
+    pub bots: BotConfig,
+    pub webhook: Option<WebhookConfig>,
+    pub filter_words: Vec<String>,
+    pub channel_join_interval_ms: u64,
+    pub ping_interval_secs: u64,
+    pub ping_timeout_secs: u64,
+    pub color_palette: Vec<String>,
+    /// Sent in `hi`. With more than one message, new connections get them in turn.
+    pub motd: Vec<String>,
+    /// Fixed note quotas for specific rooms, applied when the room is created.
+    pub channel_quotas: HashMap<String, NoteQuotaParams>,
+    /// Upper bound for what a crown can set as `noteQuota` in chset.
+    pub max_channel_quota: NoteQuotaParams,
+    pub chat_flood: ChatFloodConfig,
+    /// How often a client can send each message type, see `RateLimits`.
+    pub rate_limits: RateLimits,
+    /// Most rooms open at once. 0 doesn't limit it.
+    pub max_channels: usize,
+    /// Most open rooms one user can own at once. 0 doesn't limit it.
+    pub max_channels_per_user: usize,
+    /// Regexes for names nobody can open a room under. Each has to match the whole name.
+    pub reserved_channel_names: Vec<NamePattern>,
+    /// Settings new rooms start with, by name, see `ChannelPresets`.
+    pub channel_presets: ChannelPresets,
+    /// Which preset a new room gets by its name. The first rule whose pattern matches the
+    /// whole name wins; rooms no rule matches get "lobby" or "default".
+    pub channel_preset_rules: Vec<PresetRule>,
+    /// Let `/?room=<name>&<chset key>=<value>...` links pre-configure the room for whoever
+    /// opens them and creates it, see the `room_links` module.
+    pub room_links: bool,
+    /// Joins to "lobby" overflow into "lobby2", "lobby3"... past this many people. 0 disables.
+    pub lobby_capacity: usize,
+    pub ban_sweep_interval_secs: u64,
+    /// Tell users who are still connected when one of their bans runs out.
+    pub notify_ban_expiry: bool,
+    /// How long a room owner's crown is held for them after they leave before it goes to
+    /// someone else in the room. 0 holds it for as long as the room exists.
+    pub crown_hold_secs: u64,
+    /// Whether a crown whose holder leaves goes to someone else in the room. Rooms can
+    /// override it with `autoPassCrown`.
+    pub auto_pass_crown: bool,
+    /// How long a participant whose socket dropped stays in the room (keeping the crown) for
+    /// the same user to reconnect and take over. 0 removes them right away.
+    pub reconnect_grace_secs: u64,
+    pub afk: AfkConfig,
+    pub time_sync: TimeSyncConfig,
+    pub chat_log: Option<ChatLogConfig>,
+    pub audit_log: AuditLogConfig,
+    pub reports: ReportsConfig,
+    /// Keeps owned rooms and bans in a SQLite database across restarts. Off when unset.
+    pub storage: Option<StorageConfig>,
+    /// Writes open rooms, crowns and bans to a file now and then, for `--restore`. Off when
+    /// unset.
+    pub snapshot: Option<SnapshotConfig>,
+    /// Answer `/top` in chat with the leaderboard, shown only to whoever asked, instead of
+    /// relaying it. Needs `storage`.
+    pub top_command: bool,
+    /// Unix socket for `mpp-ctl`. Off when unset.
+    pub control_socket: Option<PathBuf>,
+    pub grpc: Option<GrpcConfig>,
+    pub plugins: PluginConfig,
+    /// Minimum time between relayed "started typing" messages per client.
+    pub typing_interval_ms: u64,
+    /// Largest `data` payload (as JSON) accepted in a `custom` message.
+    pub custom_max_bytes: usize,
+    pub challenge: ChallengeConfig,
+    /// Makes clients solve a CAPTCHA before they can join a channel. Off when unset.
+    pub captcha: Option<CaptchaConfig>,
+    /// Enables token identities: `hi` answers with a signed `token` the client stores and
+    /// sends back, and `_id` comes from it instead of the IP. Changing it resets everyone.
+    pub token_secret: Option<String>,
+    /// Which clients the wire format is shaped for, see `Protocol`.
+    pub protocol: Protocol,
+    /// `protocol = "mppclone"` only: the color of each participant tag, by its text.
+    pub tag_colors: HashMap<String, String>,
+    pub screening: Option<ScreeningConfig>,
+    /// VPN and proxy detection for new connections. Off when unset.
+    pub ip_reputation: Option<IpReputationConfig>,
+    /// Country lookups and region policies for new connections. Off when unset.
+    pub geoip: Option<GeoIpConfig>,
+    /// Channel list changes are collected and sent to `+ls` subscribers in one batch this often.
+    pub ls_interval_ms: u64,
+    /// Messages that can wait for one slow client, both in its own queue and in each channel's
+    /// broadcast. Past half of it notes and cursors are dropped for that client, when it's full
+    /// the client is disconnected.
+    pub send_queue_capacity: usize,
+    /// Largest WebSocket message accepted from a client. Bigger ones close the connection.
+    pub max_message_bytes: usize,
+    /// Connections that haven't sent `hi` this long after connecting are closed. 0 waits forever.
+    pub handshake_timeout_secs: u64,
+    /// Connections that send nothing at all, not even a pong, for this long are closed. Has to
+    /// be longer than `ping_interval_secs`. 0 turns it off.
+    pub read_timeout_secs: u64,
+    /// Messages that fail to parse within `malformed_window_secs` before the connection is
+    /// closed as abusive. 0 never closes.
+    pub malformed_strikes: u32,
+    pub malformed_window_secs: u64,
+    pub message_rate: MessageRateConfig,
+    pub note_quota: NoteQuotaConfig,
+    /// Escalating note mutes for running out of note quota over and over. Off when unset.
+    pub quota_penalty: Option<QuotaPenaltyConfig>,
+    /// Note timestamps further than this from server time are pulled back to the edge.
+    pub note_time_window_ms: u64,
+    /// Note entries past this in one `n` message are dropped.
+    pub max_notes_per_message: usize,
+    /// Largest per-note `d` (delay) that is relayed.
+    pub note_max_delay_ms: u64,
+    /// Window (ms) for collecting a room's notes into one broadcast. 0 relays each note
+    /// message as it comes in. Rooms can override it with `noteBatchMs`.
+    pub note_batch_ms: u64,
+    /// Least time (ms) between one client's cursor updates, more often ones are dropped.
+    /// Rooms can raise it with `cursorThrottleMs`.
+    pub cursor_throttle_ms: u64,
+    /// Round cursor positions to one decimal before relaying them, and drop moves that
+    /// don't change the rounded position.
+    pub quantize_cursor: bool,
+    pub black_midi: Option<BlackMidiConfig>,
+    /// Black MIDI thresholds for specific rooms, used instead of `black_midi` (which doesn't
+    /// have to be set for these to apply).
+    pub channel_black_midi: HashMap<String, BlackMidiConfig>,
+    /// Chat messages kept per room and sent in `c` on join. Crowns can lower it for their
+    /// room with `chatHistoryLength`.
+    pub chat_history_len: usize,
+    pub chat_persistence: ChatPersistenceConfig,
+    /// Sent to everyone connected whenever their schedule matches.
+    pub announcements: Vec<ScheduledAnnouncement>,
+    /// IPv6 clients are identified by this many leading bits of their address, so rotating
+    /// through a prefix doesn't give them a new `_id`. 128 uses the full address.
+    pub ipv6_prefix_len: u8,
+}
+
+/// A regex anchored to the whole string, compiled when the config is read.
+#[derive(Debug, Clone)]
+pub struct NamePattern(Regex);
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+impl<'de> Deserialize<'de> for NamePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&format!("^(?:{})$", pattern))
+            .map(NamePattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Named `ChannelSettings` for new rooms. "lobby" (lobbies and `test/` rooms) and "default"
+/// (everything else) always exist; the config can change them and add more. Fields a preset
+/// leaves out keep the built-in preset's values, or for other presets the configured
+/// "default" ones. `channel_quotas` still overrides `noteQuota`.
+#[derive(Debug, Clone)]
+pub struct ChannelPresets(HashMap<String, ChannelSettings>);
+
+impl ChannelPresets {
+    pub fn get(&self, name: &str) -> Option<&ChannelSettings> {
+        self.0.get(name)
+    }
+}
+
+impl Default for ChannelPresets {
+    fn default() -> Self {
+        Self(HashMap::from([
+            ("lobby".to_string(), ChannelSettings::lobby()),
+            ("default".to_string(), ChannelSettings::default()),
+        ]))
+    }
+}
+
+impl<'de> Deserialize<'de> for ChannelPresets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        type Fields = serde_json::Map<String, serde_json::Value>;
+        let mut configured = HashMap::<String, Fields>::deserialize(deserializer)?;
+        let mut presets = Self::default();
+        // "default" goes first, since presets that aren't built in are laid over it
+        let mut names: Vec<String> = configured.keys().filter(|name| *name != "default").cloned().collect();
+        names.sort();
+        names.insert(0, "default".to_string());
+        for name in names {
+            let Some(fields) = configured.remove(&name) else { continue };
+            let base = presets.0.get(&name).or_else(|| presets.0.get("default")).cloned().unwrap_or_default();
+            let mut merged = match serde_json::to_value(base) {
+                Ok(serde_json::Value::Object(base)) => base,
+                _ => Fields::new(),
+            };
+            merged.extend(fields);
+            let settings = serde_json::from_value(serde_json::Value::Object(merged))
+                .map_err(|e| serde::de::Error::custom(format!("channel preset {:?}: {}", name, e)))?;
+            presets.0.insert(name, settings);
+        }
+        Ok(presets)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PresetRule {
+    pub pattern: NamePattern,
+    pub preset: String,
+}
+
+/// Sent as `Authorization: Bearer <secret>`, or as basic auth with `name` and `secret`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiUser {
+    pub name: String,
+    pub secret: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+/// An `Announcement` with a cron-style schedule, see `Schedule`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledAnnouncement {
+    pub schedule: Schedule,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+/// The `b` handshake: the server sends `{"m": "b", "code": ...}` on connect and only accepts
+/// a `hi` whose `code` is the right answer.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ChallengeConfig {
+    #[default]
+    None,
+    /// Fixed code and expected answer, e.g. for a client build that embeds the token.
+    Static { code: String, answer: String },
+    /// A fresh script per connection that a browser has to evaluate.
+    Script,
+}
+
+/// A Cloudflare Turnstile or hCaptcha gate: the first `hi` has to carry the widget's token as
+/// `captcha`, which is checked with the provider using `secret` before the client can join a
+/// channel. A bad token closes the connection. Bots with a `botToken` skip it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CaptchaConfig {
+    #[serde(default)]
+    pub provider: CaptchaProvider,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaProvider {
+    #[default]
+    Turnstile,
+    Hcaptcha,
+}
+
+/// Flags connections from VPNs, proxies and the like, see `reputation::IpReputation`, and
+/// decides what happens to them with `action`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IpReputationConfig {
+    /// Flagged ranges, e.g. "198.51.100.0/24".
+    pub ranges: Vec<String>,
+    /// Files with one range per line, `#` starts a comment. Read at startup.
+    pub lists: Vec<PathBuf>,
+    /// Asked about addresses none of the ranges match.
+    pub api: Option<IpReputationApi>,
+    pub action: IpReputationAction,
+    /// Participant tag for flagged connections with `action = "tag"`.
+    pub tag: String,
+    /// Rooms flagged connections can join with `action = "restrict"`. Trying anything else
+    /// lands them in the first one.
+    pub allowed_channels: Vec<String>,
+}
+
+impl Default for IpReputationConfig {
+    fn default() -> Self {
+        Self {
+            ranges: Vec::new(),
+            lists: Vec::new(),
+            api: None,
+            action: IpReputationAction::Tag,
+            tag: "proxy".to_string(),
+            allowed_channels: Vec::new(),
+        }
+    }
+}
+
+/// An HTTP lookup service. `url` and `pointer` can contain `{ip}`; the connection is flagged
+/// when the value at the JSON pointer is true, a non-zero number or "yes".
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IpReputationApi {
+    pub url: String,
+    pub pointer: String,
+    #[serde(default = "default_reputation_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_reputation_cache_secs")]
+    pub cache_secs: u64,
+}
+
+fn default_reputation_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_reputation_cache_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpReputationAction {
+    /// Refuse the WebSocket upgrade with 403.
+    Block,
+    /// Let them in with `tag` on their participant.
+    #[default]
+    Tag,
+    /// Only let them into `allowed_channels`.
+    Restrict,
+}
+
+/// A MaxMind country or city database to tell where connections come from, see
+/// `geoip::GeoIp`. Connections refused by the country lists get a 403.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GeoIpConfig {
+    pub database: PathBuf,
+    /// ISO country codes that may connect. Empty lets everyone in.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+    /// Refuse connections whose country can't be told (private addresses, gaps in the
+    /// database).
+    #[serde(default)]
+    pub block_unknown: bool,
+    /// Country code to room: people from there asking for "lobby" go here instead. These
+    /// rooms are lobbies, without a crown or settings.
+    #[serde(default)]
+    pub lobbies: HashMap<String, String>,
+}
+
+/// Bot screening on connect. Connections score a point for each connection from their IP past
+/// `max_connects` within `window_secs`, and for each strike against the IP in that window.
+/// Strikes come from flooding more than `early_note_limit` note messages in the first
+/// `early_window_ms`, or from not sending `hi` within `hi_timeout_secs` (the connection is
+/// closed). At `threshold` points the `action` kicks in.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ScreeningConfig {
+    pub window_secs: u64,
+    pub max_connects: usize,
+    pub early_window_ms: u64,
+    pub early_note_limit: u32,
+    pub hi_timeout_secs: u64,
+    pub threshold: u32,
+    pub action: ScreeningAction,
+    /// How long after connecting a flagged client has to wait before joining a channel.
+    pub join_delay_ms: u64,
+}
+
+impl Default for ScreeningConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            max_connects: 5,
+            early_window_ms: 5000,
+            early_note_limit: 50,
+            hi_timeout_secs: 10,
+            threshold: 2,
+            action: ScreeningAction::Challenge,
+            join_delay_ms: 15_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreeningAction {
+    /// Send a script `b` challenge even when `challenge` is off.
+    #[default]
+    Challenge,
+    /// Hold channel joins until `join_delay_ms` after connecting.
+    Delay,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SnapshotConfig {
+    #[serde(default = "default_snapshot_path")]
+    pub path: PathBuf,
+    /// Also written when shutdown begins.
+    #[serde(default = "default_snapshot_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_snapshot_path() -> PathBuf {
+    PathBuf::from("snapshot.json")
+}
+
+fn default_snapshot_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GrpcConfig {
+    #[serde(default = "default_grpc_port")]
+    pub port: u16,
+    pub token: String,
+}
+
+fn default_grpc_port() -> u16 {
+    50051
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ChatLogConfig {
+    #[serde(default = "default_chat_log_dir")]
+    pub dir: PathBuf,
+    /// Days of logs to keep. 0 keeps them forever.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditLogConfig {
+    /// Moderation actions kept in memory for the admin API.
+    pub capacity: usize,
+    /// Also appended here as JSON lines, when set.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            file: None,
+        }
+    }
+}
+
+/// Chat history kept across restarts, with `[storage]`. Owned rooms always keep theirs as
+/// part of the saved room.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChatPersistenceConfig {
+    /// Rooms without an owner (lobbies included) whose chat is kept too, by name.
+    pub rooms: Vec<NamePattern>,
+    /// How long after chat changes in a room it's written out, so a burst of messages is
+    /// one write. Read at startup.
+    pub save_delay_ms: u64,
+}
+
+impl Default for ChatPersistenceConfig {
+    fn default() -> Self {
+        Self {
+            rooms: Vec::new(),
+            save_delay_ms: 5000,
+        }
+    }
+}
+
+impl ChatPersistenceConfig {
+    pub fn keeps(&self, channel_id: &str) -> bool {
+        self.rooms.iter().any(|pattern| pattern.matches(channel_id))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReportsConfig {
+    /// Reports kept in memory for the admin API and dashboard.
+    pub capacity: usize,
+    /// Latest chat messages of the room saved with each report.
+    pub context_messages: usize,
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 500,
+            context_messages: 20,
+        }
+    }
+}
+
+/// WebAssembly hook modules, see `plugins::PluginHost`. Only loaded with the `plugins`
+/// feature.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PluginConfig {
+    pub modules: Vec<PathBuf>,
+    /// Instructions (roughly) a hook call may run before it's cut off.
+    pub fuel: u64,
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        Self {
+            modules: Vec::new(),
+            fuel: 10_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default = "default_storage_path")]
+    pub path: PathBuf,
+    /// How often rooms that are still open get written out. They're also saved when they
+    /// close and on shutdown.
+    #[serde(default = "default_storage_save_interval_secs")]
+    pub save_interval_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: default_storage_path(),
+            save_interval_secs: default_storage_save_interval_secs(),
+        }
+    }
+}
+
+fn default_storage_path() -> PathBuf {
+    PathBuf::from("mpp.db")
+}
+
+fn default_storage_save_interval_secs() -> u64 {
+    60
+}
+
+fn default_chat_log_dir() -> PathBuf {
+    PathBuf::from("chatlogs")
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+/// Idle handling. Only real input counts as activity, not `t` pings or `+ls`/`-ls`.
+/// Either timeout can be 0 to turn it off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AfkConfig {
+    pub idle_secs: u64,
+    pub disconnect_secs: u64,
+}
+
+impl Default for AfkConfig {
+    fn default() -> Self {
+        Self {
+            idle_secs: 300,
+            disconnect_secs: 0,
+        }
+    }
+}
+
+/// Wire details that differ between the reference MPP client and mppclone-based clients and
+/// bots. `MppClone` sends participant tags as `{text, color}` objects, gives chat messages an
+/// `id` (in `a` and the `c` history), adds an empty `permissions` to `hi` and sends `nq`
+/// after every join instead of only when the quota changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Mpp,
+    MppClone,
+}
+
+/// Clock sync for `t`. Pings carry their send time, so every pong is a round trip sample;
+/// the first `warmup_pings` go out a second apart to get an estimate quickly. `t` replies
+/// and pushes give the server time as of when they should arrive. 0 `push_interval_secs`
+/// only answers the client's own `t`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimeSyncConfig {
+    /// Round trip and clock offset samples kept per connection.
+    pub samples: usize,
+    pub warmup_pings: u32,
+    /// How often a corrected `t` is pushed to each client unasked.
+    pub push_interval_secs: u64,
+}
+
+impl Default for TimeSyncConfig {
+    fn default() -> Self {
+        Self {
+            samples: 8,
+            warmup_pings: 4,
+            push_interval_secs: 60,
+        }
+    }
+}
+
+/// The note quota profiles handed out by room type, and how the quota is run. The defaults
+/// are the reference MPP values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NoteQuotaConfig {
+    pub lobby: NoteQuotaParams,
+    pub normal: NoteQuotaParams,
+    /// For whoever holds the crown in a normal room.
+    pub crown: NoteQuotaParams,
+    /// How often `allowance` points are added back.
+    pub tick_ms: u64,
+    /// Ticks of history looked at before a client is treated as spamming.
+    pub max_hist_len: usize,
+}
+
+impl Default for NoteQuotaConfig {
+    fn default() -> Self {
+        Self {
+            lobby: NoteQuotaParams::LOBBY,
+            normal: NoteQuotaParams::NORMAL,
+            crown: NoteQuotaParams::CROWN,
+            tick_ms: 2000,
+            max_hist_len: 3,
+        }
+    }
+}
+
+/// Ceiling on messages per second from one connection across all types, on top of the
+/// per-type limits. 0 turns it off.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct MessageRateConfig {
+    pub max_per_second: u32,
+    pub action: MessageRateAction,
+}
+
+impl Default for MessageRateConfig {
+    fn default() -> Self {
+        Self {
+            max_per_second: 200,
+            action: MessageRateAction::Throttle,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageRateAction {
+    /// Drop whatever is over the limit until the next second.
+    #[default]
+    Throttle,
+    /// Close the connection (code 1008).
+    Disconnect,
+}
+
+/// A token bucket: `burst` at once, refilled at `per_second`. 0 per second turns it off.
+/// A field left out of an entry is 1.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RateConfig {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        Self {
+            per_second: 1.0,
+            burst: 1,
+        }
+    }
+}
+
+/// Message type to the `RateConfig` each connection gets for it, checked before the message
+/// is handled. Types without an entry aren't limited here (notes have their quota and cursor
+/// moves `cursor_throttle_ms`). What the config sets is laid over the defaults, so a
+/// `[rate_limits]` table only needs the types it changes.
+#[derive(Debug, Clone)]
+pub struct RateLimits(HashMap<String, RateConfig>);
+
+impl RateLimits {
+    pub fn get(&self, message_type: &str) -> Option<&RateConfig> {
+        self.0.get(message_type)
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let limit = |per_second, burst| RateConfig { per_second, burst };
+        Self(HashMap::from([
+            ("a".to_string(), limit(1.0, 4)),
+            ("ch".to_string(), limit(1.0, 3)),
+            ("chset".to_string(), limit(1.0, 3)),
+            ("chown".to_string(), limit(1.0, 3)),
+            ("dm".to_string(), limit(0.5, 3)),
+            ("kickban".to_string(), limit(1.0, 3)),
+            ("report".to_string(), limit(0.1, 2)),
+            ("userset".to_string(), limit(1.0, 3)),
+            ("t".to_string(), limit(2.0, 10)),
+        ]))
+    }
+}
+
+impl<'de> Deserialize<'de> for RateLimits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut limits = Self::default();
+        limits.0.extend(HashMap::<String, RateConfig>::deserialize(deserializer)?);
+        Ok(limits)
+    }
+}
+
+/// Automatic mute for chat spam: the same message `max_duplicates` times in a row within
+/// `duplicate_window_ms`, or more than `max_messages` within `window_ms`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChatFloodConfig {
+    pub duplicate_window_ms: u64,
+    pub max_duplicates: u32,
+    pub window_ms: u64,
+    pub max_messages: usize,
+    pub mute_ms: u64,
+}
+
+impl Default for ChatFloodConfig {
+    fn default() -> Self {
+        Self {
+            duplicate_window_ms: 10_000,
+            max_duplicates: 3,
+            window_ms: 10_000,
+            max_messages: 8,
+            mute_ms: 30_000,
+        }
+    }
+}
+
+/// Black MIDI detection, on top of the note quota: playing at least `notes_per_second` note
+/// starts for `sustain_secs` seconds in a row mutes the player's notes in that room for
+/// `mute_secs` and tells the crown holder.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BlackMidiConfig {
+    pub notes_per_second: u32,
+    pub sustain_secs: u32,
+    pub mute_secs: u64,
+}
+
+impl Default for BlackMidiConfig {
+    fn default() -> Self {
+        Self {
+            notes_per_second: 1000,
+            sustain_secs: 3,
+            mute_secs: 60,
+        }
+    }
+}
+
+/// Progressive penalties for note quota violations: `violations` failed spends within
+/// `window_ms` mute the player's notes for the next entry of `mute_secs` (the last one
+/// repeats). `reset_after_secs` without a violation, counted from the end of the last mute,
+/// starts them over at the first entry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuotaPenaltyConfig {
+    pub violations: u32,
+    pub window_ms: u64,
+    pub mute_secs: Vec<u64>,
+    pub reset_after_secs: u64,
+}
+
+impl Default for QuotaPenaltyConfig {
+    fn default() -> Self {
+        Self {
+            violations: 5,
+            window_ms: 10_000,
+            mute_secs: vec![30, 120, 600],
+            reset_after_secs: 600,
+        }
+    }
+}
+
+/// Pre-shared tokens that bots send as `botToken` in `hi` to get the `bot` tag, a bigger
+/// note quota and no cursor throttle.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BotConfig {
+    pub tokens: Vec<String>,
+    pub quota_allowance: i32,
+    pub quota_max: i32,
+    /// Bots compiled into the server (see `bots::registry`) to start in rooms at startup.
+    pub run: Vec<BotSpec>,
+}
+
+/// One bot from `[[bots.run]]`: which kind, the room it sits in and how it shows up there.
+/// `options` is whatever that kind of bot reads, like the welcome bot's `message`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BotSpec {
+    pub kind: String,
+    pub channel: String,
+    pub name: Option<String>,
+    pub color: Option<String>,
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default = "default_true")]
+    pub channel_created: bool,
+    #[serde(default = "default_true")]
+    pub user_banned: bool,
+    #[serde(default = "default_true")]
+    pub filtered_chat: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            quota_allowance: 16000,
+            quota_max: 48000,
+            run: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClusterConfig {
+    pub redis_url: String,
+    #[serde(default = "default_cluster_topic")]
+    pub topic: String,
+}
+
+fn default_cluster_topic() -> String {
+    "mpp-cluster".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 8080,
+            tls: None,
+            cluster: None,
+            admin_password: None,
+            api_users: Vec::new(),
+            recordings_dir: PathBuf::from("recordings"),
+            capture_dir: PathBuf::from("captures"),
+            client_dir: None,
+            bots: BotConfig::default(),
+            webhook: None,
+            filter_words: Vec::new(),
+            channel_join_interval_ms: 1000,
+            ping_interval_secs: 30,
+            ping_timeout_secs: 90,
+            color_palette: Vec::new(),
+            motd: vec!["Welcome to Multiplayer Piano!".to_string()],
+            channel_quotas: HashMap::new(),
+            max_channel_quota: NoteQuotaParams { allowance: 1200, max: 3600 },
+            chat_flood: ChatFloodConfig::default(),
+            rate_limits: RateLimits::default(),
+            max_channels: 0,
+            max_channels_per_user: 0,
+            reserved_channel_names: Vec::new(),
+            channel_presets: ChannelPresets::default(),
+            channel_preset_rules: Vec::new(),
+            room_links: false,
+            lobby_capacity: 20,
+            ban_sweep_interval_secs: 60,
+            notify_ban_expiry: true,
+            crown_hold_secs: 300,
+            auto_pass_crown: true,
+            reconnect_grace_secs: 10,
+            afk: AfkConfig::default(),
+            time_sync: TimeSyncConfig::default(),
+            chat_log: None,
+            audit_log: AuditLogConfig::default(),
+            reports: ReportsConfig::default(),
+            chat_persistence: ChatPersistenceConfig::default(),
+            storage: None,
+            snapshot: None,
+            top_command: false,
+            control_socket: None,
+            grpc: None,
+            plugins: PluginConfig::default(),
+            typing_interval_ms: 1000,
+            custom_max_bytes: 32768,
+            challenge: ChallengeConfig::None,
+            captcha: None,
+            token_secret: None,
+            protocol: Protocol::Mpp,
+            tag_colors: HashMap::new(),
+            screening: None,
+            ip_reputation: None,
+            geoip: None,
+            ls_interval_ms: 500,
+            send_queue_capacity: 1024,
+            max_message_bytes: 65536,
+            handshake_timeout_secs: 10,
+            read_timeout_secs: 120,
+            malformed_strikes: 5,
+            malformed_window_secs: 60,
+            message_rate: MessageRateConfig::default(),
+            note_quota: NoteQuotaConfig::default(),
+            quota_penalty: None,
+            note_time_window_ms: 2000,
+            max_notes_per_message: 200,
+            note_max_delay_ms: 1000,
+            note_batch_ms: 0,
+            cursor_throttle_ms: 50,
+            quantize_cursor: false,
+            black_midi: None,
+            channel_black_midi: HashMap::new(),
+            chat_history_len: 32,
+            announcements: Vec::new(),
+            ipv6_prefix_len: 64,
+        }
+    }
+}
+
+impl Config {
+    /// Settings a new room called `channel_id` starts with: the preset of the first
+    /// `channel_preset_rules` entry matching its name, otherwise "lobby" for `special` rooms
+    /// and "default" for the rest.
+    pub fn channel_preset(&self, channel_id: &str, special: bool) -> ChannelSettings {
+        let fallback = if special { "lobby" } else { "default" };
+        let name = self
+            .channel_preset_rules
+            .iter()
+            .find(|rule| rule.pattern.matches(channel_id))
+            .map_or(fallback, |rule| rule.preset.as_str());
+        let preset = self.channel_presets.get(name).or_else(|| {
+            tracing::warn!("No channel preset {:?}, {} gets {:?}", name, channel_id, fallback);
+            self.channel_presets.get(fallback)
+        });
+        let mut settings = preset.cloned().unwrap_or_default();
+        if let Some(quota) = self.channel_quotas.get(channel_id) {
+            settings.note_quota = Some(*quota);
+        }
+        settings
+    }
+
+    /// Reads `config.toml` (or the file named by `MPP_CONFIG`) if it exists, then applies
+    /// environment overrides so existing `.env` setups keep working.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = std::env::var("MPP_CONFIG").unwrap_or_else(|_| "config.toml".to_string());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(anyhow::anyhow!("Failed to read config file {}: {}", path, e)),
+        };
+
+        if let Some(port) = std::env::var("WS_PORT").ok().and_then(|s| s.parse().ok()) {
+            config.port = port;
+        }
+
+        if let (Ok(cert), Ok(key)) = (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+            config.tls = Some(TlsConfig {
+                cert: cert.into(),
+                key: key.into(),
+            });
+        }
+
+        if let Ok(password) = std::env::var("ADMIN_PASSWORD") {
+            config.admin_password = Some(password);
+        }
+
+        if let Ok(redis_url) = std::env::var("REDIS_URL") {
+            let topic = config.cluster.take().map(|c| c.topic).unwrap_or_else(default_cluster_topic);
+            config.cluster = Some(ClusterConfig { redis_url, topic });
+        }
+
+        Ok(config)
+    }
+
+    /// Puts back the running value of every setting that is only read at startup, and
+    /// returns the names of the ones that differed so a reload can report them.
+    pub fn keep_startup_settings(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        keep("port", &mut self.port, &running.port, &mut changed);
+        keep("tls", &mut self.tls, &running.tls, &mut changed);
+        keep("cluster", &mut self.cluster, &running.cluster, &mut changed);
+        keep("control_socket", &mut self.control_socket, &running.control_socket, &mut changed);
+        keep("grpc", &mut self.grpc, &running.grpc, &mut changed);
+        keep("snapshot", &mut self.snapshot, &running.snapshot, &mut changed);
+        keep("webhook", &mut self.webhook, &running.webhook, &mut changed);
+        keep("chat_log", &mut self.chat_log, &running.chat_log, &mut changed);
+        keep("storage", &mut self.storage, &running.storage, &mut changed);
+        keep("audit_log.file", &mut self.audit_log.file, &running.audit_log.file, &mut changed);
+        keep("screening", &mut self.screening, &running.screening, &mut changed);
+        keep("ip_reputation", &mut self.ip_reputation, &running.ip_reputation, &mut changed);
+        keep("geoip", &mut self.geoip, &running.geoip, &mut changed);
+        keep("protocol", &mut self.protocol, &running.protocol, &mut changed);
+        keep("ping_interval_secs", &mut self.ping_interval_secs, &running.ping_interval_secs, &mut changed);
+        keep("ban_sweep_interval_secs", &mut self.ban_sweep_interval_secs, &running.ban_sweep_interval_secs, &mut changed);
+        keep("ls_interval_ms", &mut self.ls_interval_ms, &running.ls_interval_ms, &mut changed);
+        keep("note_quota.tick_ms", &mut self.note_quota.tick_ms, &running.note_quota.tick_ms, &mut changed);
+        keep("send_queue_capacity", &mut self.send_queue_capacity, &running.send_queue_capacity, &mut changed);
+        keep("bots.run", &mut self.bots.run, &running.bots.run, &mut changed);
+        keep("plugins", &mut self.plugins, &running.plugins, &mut changed);
+        changed
+    }
+}
+
+fn keep<T: PartialEq + Clone>(name: &'static str, value: &mut T, running: &T, changed: &mut Vec<&'static str>) {
+    if value != running {
+        *value = running.clone();
+        changed.push(name);
+    }
+}
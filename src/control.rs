@@ -0,0 +1,119 @@
+use crate::audit::AuditEntry;
+use crate::server::Server;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, warn};
+
+/// Local control socket for `mpp-ctl`. Each connection sends one command line and gets the
+/// reply back before the socket is closed. Replies start with `OK` or `ERR`.
+pub fn spawn(server: &Arc<Server>, path: &Path) -> std::io::Result<()> {
+    // A previous run that didn't exit cleanly leaves the socket file behind
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = bind_private(path)?;
+    info!("Control socket listening on {}", path.display());
+
+    let server = Arc::downgrade(server);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Control socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            if server.strong_count() == 0 {
+                break;
+            }
+            tokio::spawn(handle(stream, server.clone()));
+        }
+    });
+
+    Ok(())
+}
+
+/// Binds the socket inside a fresh 0700 directory, tightens it to 0600 and only then moves
+/// it to `path`, so no other user can connect in between.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}", std::process::id()));
+    let staging = PathBuf::from(staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&staging)?;
+    bound
+}
+
+async fn handle(stream: UnixStream, server: Weak<Server>) {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(read).read_line(&mut line).await {
+        warn!("Failed to read control command: {}", e);
+        return;
+    }
+
+    let Some(server) = server.upgrade() else { return };
+    let reply = match execute(&server, line.trim()).await {
+        Ok(text) => format!("OK {}\n", text),
+        Err(text) => format!("ERR {}\n", text),
+    };
+
+    if let Err(e) = write.write_all(reply.as_bytes()).await {
+        warn!("Failed to write control reply: {}", e);
+    }
+}
+
+async fn execute(server: &Server, line: &str) -> Result<String, String> {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+
+    match command {
+        "channels" => {
+            let mut lines = server
+                .with_channels(|_, channel| format!("{}\t{}", channel._id, channel.participants.len()))
+                .await;
+            lines.sort();
+            Ok(format!("{} channels\n{}", lines.len(), lines.join("\n")))
+        }
+        "kick" if !args.is_empty() => {
+            match server.kick(args) {
+                0 => Err(format!("No connection or user {}", args)),
+                n => {
+                    server.audit(AuditEntry::new("control socket", "kick").target(args));
+                    Ok(format!("Closed {} connection(s)", n))
+                }
+            }
+        }
+        "broadcast" if !args.is_empty() => {
+            server.audit(AuditEntry::new("control socket", "broadcast").details(serde_json::json!({ "text": args })));
+            server.notify_all("", args);
+            Ok(format!("Sent to {} connection(s)", server.ws_senders.len()))
+        }
+        "reload" => {
+            server.audit(AuditEntry::new("control socket", "reload"));
+            crate::admin::reload(server).await
+        }
+        "shutdown" => {
+            server.audit(AuditEntry::new("control socket", "shutdown"));
+            server.begin_shutdown().await;
+            Ok("Shutting down".to_string())
+        }
+        "kick" | "broadcast" => Err(format!("Usage: {} <argument>", command)),
+        _ => Err(format!("Unknown command {:?}, try channels, kick, broadcast, reload or shutdown", command)),
+    }
+}
@@ -0,0 +1,16 @@
+use crate::auth::{self, Access};
+use crate::server::Server;
+use axum::{middleware, response::Html, routing::get, Router};
+use std::sync::Arc;
+
+/// Moderation dashboard at `/dashboard`: one page that lists rooms, people, chat and bans and
+/// does everything through the admin API, so it needs the same credentials. Kicking, banning
+/// and clearing chat need `write` access.
+pub fn router(server: &Arc<Server>) -> Router<Arc<Server>> {
+    let read = middleware::from_fn_with_state((server.clone(), Access::Read), auth::require);
+    Router::new().route("/dashboard", get(page).route_layer(read))
+}
+
+async fn page() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
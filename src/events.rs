@@ -0,0 +1,95 @@
+use crate::audit::AuditEntry;
+use crate::types::Participant;
+use std::future::Future;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// Things that happen on the server that side systems (webhooks etc.) may want to react to.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    ChannelCreated {
+        channel_id: String,
+    },
+    UserJoined {
+        channel_id: String,
+        participant: Participant,
+    },
+    UserLeft {
+        channel_id: String,
+        participant_id: String,
+        user_id: String,
+    },
+    /// The crown moved between participant ids, `None` being nobody holding it.
+    CrownChanged {
+        channel_id: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    UserBanned {
+        channel_id: String,
+        user_id: String,
+        name: String,
+        banned_by: String,
+        duration_ms: u64,
+    },
+    ChatFiltered {
+        channel_id: String,
+        user_id: String,
+        name: String,
+        message: String,
+        word: String,
+    },
+    ChatPosted {
+        channel_id: String,
+        user_id: String,
+        name: String,
+        message: String,
+        t: u64,
+    },
+    Moderation(AuditEntry),
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(1024);
+        Self { tx }
+    }
+
+    pub fn emit(&self, event: ServerEvent) {
+        // No subscribers is fine, nobody cares about this event
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Runs `handler` on its own task for every event from now on, one at a time, until the
+    /// bus is gone. `name` is for the log line when it falls behind and misses some.
+    pub fn subscribe_with<F, Fut>(&self, name: &'static str, mut handler: F)
+    where
+        F: FnMut(ServerEvent) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => handler(event).await,
+                    Err(RecvError::Lagged(n)) => warn!("{} fell behind, skipped {} events", name, n),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
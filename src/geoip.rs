@@ -0,0 +1,46 @@
+use crate::config::GeoIpConfig;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+/// Country lookups against a MaxMind database (GeoLite2 or GeoIP2, Country or City), and the
+/// region policy that goes with them.
+pub struct GeoIp {
+    pub config: GeoIpConfig,
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(config: GeoIpConfig) -> anyhow::Result<Self> {
+        let reader = Reader::open_readfile(&config.database)?;
+        Ok(Self { config, reader })
+    }
+
+    /// ISO code of the country `ip` is in, `None` for private addresses and ones the
+    /// database doesn't know.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let found: geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+        found.country?.iso_code.map(str::to_string)
+    }
+
+    /// Whether someone from `country` may connect under `allowed_countries`,
+    /// `blocked_countries` and `block_unknown`.
+    pub fn allows(&self, country: Option<&str>) -> bool {
+        let Some(country) = country else { return !self.config.block_unknown };
+        let listed = |list: &[String]| list.iter().any(|c| c.eq_ignore_ascii_case(country));
+        !listed(&self.config.blocked_countries)
+            && (self.config.allowed_countries.is_empty() || listed(&self.config.allowed_countries))
+    }
+
+    /// The room people from `country` get instead of "lobby", if there's one for them.
+    pub fn lobby(&self, country: &str) -> Option<&str> {
+        self.config
+            .lobbies
+            .iter()
+            .find(|(code, _)| code.eq_ignore_ascii_case(country))
+            .map(|(_, lobby)| lobby.as_str())
+    }
+
+    pub fn is_lobby(&self, channel_id: &str) -> bool {
+        self.config.lobbies.values().any(|lobby| lobby == channel_id)
+    }
+}
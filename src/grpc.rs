@@ -0,0 +1,168 @@
+use crate::admin;
+use crate::audit::AuditEntry;
+use crate::config::GrpcConfig;
+use crate::server::Server;
+use crate::utils::{secret_eq, validate_color};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+pub mod proto {
+    tonic::include_proto!("mpp.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+struct AdminService {
+    server: Arc<Server>,
+}
+
+#[derive(Clone)]
+struct TokenCheck {
+    expected: String,
+}
+
+impl Interceptor for TokenCheck {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match request.metadata().get("authorization").and_then(|v| v.to_str().ok()) {
+            Some(auth) if secret_eq(auth, &self.expected) => Ok(request),
+            _ => Err(Status::unauthenticated("Bad or missing admin token")),
+        }
+    }
+}
+
+pub fn spawn(server: &Arc<Server>, config: &GrpcConfig) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    let service = AdminServer::with_interceptor(
+        AdminService { server: server.clone() },
+        TokenCheck {
+            expected: format!("Bearer {}", config.token),
+        },
+    );
+
+    let shutdown = server.shutdown.clone();
+    tokio::spawn(async move {
+        info!("gRPC admin service listening on {}", addr);
+        let result = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_shutdown(addr, shutdown.cancelled_owned())
+            .await;
+        if let Err(e) = result {
+            error!("gRPC admin service failed: {}", e);
+        }
+    });
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_channels(
+        &self,
+        _request: Request<proto::ListChannelsRequest>,
+    ) -> Result<Response<proto::ListChannelsResponse>, Status> {
+        let mut list = self
+            .server
+            .with_channels(|_, channel| proto::Channel {
+                id: channel._id.clone(),
+                participants: channel.participants.len() as u32,
+                lobby: channel.settings.lobby,
+                visible: channel.settings.visible,
+                crown_user_id: channel.crown.as_ref().and_then(|c| c.user_id.clone()),
+            })
+            .await;
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(Response::new(proto::ListChannelsResponse { channels: list }))
+    }
+
+    async fn kick(&self, request: Request<proto::KickRequest>) -> Result<Response<proto::KickResponse>, Status> {
+        let id = request.into_inner().id;
+        let closed = self.server.kick(&id);
+        if closed > 0 {
+            self.server.audit(AuditEntry::new("grpc", "kick").target(id));
+        }
+        Ok(Response::new(proto::KickResponse { closed: closed as u32 }))
+    }
+
+    async fn ban(&self, request: Request<proto::BanRequest>) -> Result<Response<proto::BanResponse>, Status> {
+        let req = request.into_inner();
+        if req.user_id.is_empty() || req.channel_id.is_empty() {
+            return Err(Status::invalid_argument("user_id and channel_id are required"));
+        }
+        if !(1..=admin::MAX_BAN_MS).contains(&req.duration_ms) {
+            let message = format!("duration_ms has to be between 1 and {}", admin::MAX_BAN_MS);
+            return Err(Status::invalid_argument(message));
+        }
+
+        let expiry = admin::ban(&self.server, &req.user_id, &req.channel_id, req.duration_ms, "admin").await;
+        self.server.audit(
+            AuditEntry::new("grpc", "ban")
+                .channel(&req.channel_id)
+                .target(&req.user_id)
+                .details(serde_json::json!({ "ms": req.duration_ms })),
+        );
+
+        Ok(Response::new(proto::BanResponse { expiry }))
+    }
+
+    async fn broadcast(
+        &self,
+        request: Request<proto::BroadcastRequest>,
+    ) -> Result<Response<proto::BroadcastResponse>, Status> {
+        let req = request.into_inner();
+        if req.text.is_empty() {
+            return Err(Status::invalid_argument("text is required"));
+        }
+
+        self.server.notify_all(&req.title, &req.text);
+        self.server.audit(
+            AuditEntry::new("grpc", "broadcast").details(serde_json::json!({ "title": req.title, "text": req.text })),
+        );
+        Ok(Response::new(proto::BroadcastResponse {
+            recipients: self.server.ws_senders.len() as u32,
+        }))
+    }
+
+    async fn set_channel_settings(
+        &self,
+        request: Request<proto::SetChannelSettingsRequest>,
+    ) -> Result<Response<proto::SetChannelSettingsResponse>, Status> {
+        let req = request.into_inner();
+        let color = match &req.color {
+            Some(color) => Some(
+                validate_color(color, &self.server.config().color_palette)
+                    .ok_or_else(|| Status::invalid_argument(format!("Invalid color {}", color)))?,
+            ),
+            None => None,
+        };
+
+        let (visible, chat, crownsolo) = (req.visible, req.chat, req.crownsolo);
+        let set = self
+            .server
+            .with_channel(&req.channel_id, move |_, channel| {
+                if let Some(color) = color {
+                    channel.settings.color = color;
+                }
+                if let Some(visible) = visible {
+                    channel.settings.visible = visible;
+                }
+                if let Some(chat) = chat {
+                    channel.settings.chat = Some(chat);
+                }
+                if let Some(crownsolo) = crownsolo {
+                    channel.settings.crownsolo = Some(crownsolo);
+                }
+                serde_json::to_value(&channel.settings).unwrap_or_default()
+            })
+            .await
+            .ok_or_else(|| Status::not_found(format!("No channel {}", req.channel_id)))?;
+
+        self.server
+            .audit(AuditEntry::new("grpc", "chset").channel(&req.channel_id).details(serde_json::json!({ "set": set })));
+        self.server.broadcast_channel_info(&req.channel_id).await;
+        self.server.queue_ls_update(&req.channel_id);
+
+        Ok(Response::new(proto::SetChannelSettingsResponse {}))
+    }
+}
@@ -1,8 +1,27 @@
-use crate::server::Server;
-use crate::types::{Crown, IncomingMessage, Participant, Position};
-use crate::utils::current_time_ms;
+use crate::admin::{self, AdminCommand};
+use crate::audit::AuditEntry;
+use crate::captcha;
+use crate::challenge;
+use crate::channel_task::{self, ChannelCommand, Joined, NotePlayed};
+use crate::config::{ChallengeConfig, Protocol};
+use crate::events::ServerEvent;
+use crate::identity;
+use crate::messages::{self, BanEntry, Notification, Outgoing};
+use crate::plugins::Hook;
+use crate::reports::Report;
+use crate::room_links;
+use crate::server::{Codec, Server};
+use crate::storage::UserProfile;
+use crate::types::{
+    Capability, IncomingMessage, LeaderboardMetric, LeaderboardWindow, Note, NoteQuota, NoteQuotaParams, Participant,
+};
+use crate::utils::{current_time_ms, sanitize_chat, sanitize_name, secret_eq, validate_color};
+use std::collections::HashSet;
 use std::sync::Arc;
-use tracing::{debug, warn};
+use tracing::{debug, error, info, warn};
+
+/// Most user `_id`s one connection's block list holds.
+const MAX_BLOCKED: usize = 1000;
 
 pub struct MessageHandler {
     server: Arc<Server>,
@@ -18,8 +37,41 @@ impl MessageHandler {
         client_id: &str,
         msg: IncomingMessage,
     ) -> Option<Vec<serde_json::Value>> {
+        if msg.m == "n" {
+            self.server.screen_note(client_id).await;
+        }
+
+        let challenges_on = !matches!(self.server.config().challenge, ChallengeConfig::None) || self.server.screener.is_some();
+        if challenges_on && msg.m != "hi" {
+            let pending = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+                Some(client) => client.read().await.challenge.is_some(),
+                None => true,
+            };
+            if pending {
+                debug!("Ignoring {} from {} before the challenge was answered", msg.m, client_id);
+                return None;
+            }
+        }
+
+        if !self.check_rate(client_id, &msg.m).await {
+            return None;
+        }
+        if !self.supports(client_id, &msg.m).await {
+            debug!("Ignoring {} from {}, it didn't list the capability for it", msg.m, client_id);
+            return None;
+        }
+
+        if !matches!(msg.m.as_str(), "t" | "+ls" | "-ls" | "bye") {
+            self.server.mark_active(client_id).await;
+        }
+
         match msg.m.as_str() {
-            "hi" => self.handle_hi(client_id).await,
+            "hi" => {
+                if !self.check_captcha(client_id, &msg.data).await {
+                    return None;
+                }
+                self.handle_hi(client_id, &msg.data).await
+            }
             "bye" => {
                 self.handle_bye(client_id).await;
                 None
@@ -29,11 +81,28 @@ impl MessageHandler {
                 self.handle_minus_ls(client_id).await;
                 None
             }
-            "t" => self.handle_time(&msg.data).await,
+            "t" => self.handle_time(client_id, &msg.data).await,
             "a" => {
                 self.handle_chat(client_id, &msg.data).await;
                 None
             }
+            "dm" => {
+                self.handle_dm(client_id, &msg.data).await;
+                None
+            }
+            "clearchat" => {
+                let keep = msg.data.get("keep").and_then(|k| k.as_u64()).unwrap_or(0);
+                self.handle_clear_chat(client_id, keep as usize).await;
+                None
+            }
+            "block" => {
+                self.handle_block(client_id, &msg.data);
+                None
+            }
+            "report" => {
+                self.handle_report(client_id, &msg.data).await;
+                None
+            }
             "n" => {
                 self.handle_note(client_id, &msg.data).await;
                 None
@@ -66,7 +135,30 @@ impl MessageHandler {
                 self.handle_unban(client_id, &msg.data).await;
                 None
             }
+            "bans" => self.handle_bans(client_id).await,
+            "stats" => self.handle_stats(client_id).await,
             "devices" => self.handle_devices(client_id, &msg.data).await,
+            "record" => {
+                self.handle_record(client_id, &msg.data).await;
+                None
+            }
+            "admin message" => self.handle_admin_message(client_id, &msg.data).await,
+            "+custom" => {
+                self.server.subscribed_to_custom.insert(client_id.to_string(), true);
+                None
+            }
+            "-custom" => {
+                self.server.subscribed_to_custom.remove(client_id);
+                None
+            }
+            "custom" => {
+                self.handle_custom(client_id, &msg.data).await;
+                None
+            }
+            "typing" => {
+                self.handle_typing(client_id, &msg.data).await;
+                None
+            }
             _ => {
                 warn!("Unknown message type '{}' from {}", msg.m, client_id);
                 None
@@ -74,92 +166,219 @@ impl MessageHandler {
         }
     }
 
-    async fn handle_hi(&self, client_id: &str) -> Option<Vec<serde_json::Value>> {
-        let client_ref = self.server.clients.get(client_id)?;
-        let mut client = client_ref.value().write().await;
+    /// Checks `hi.captcha` for a client that still owes one. Returns false if the connection
+    /// was closed for a bad token. If the provider can't be reached the client stays pending,
+    /// so it gets in but can't join a channel.
+    async fn check_captcha(&self, client_id: &str, data: &serde_json::Value) -> bool {
+        let Some(config) = self.server.config().captcha.clone() else { return true };
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else { return true };
+        if !client.read().await.captcha_pending {
+            return true;
+        }
+
+        let is_bot = data
+            .get("botToken")
+            .and_then(|t| t.as_str())
+            .is_some_and(|token| self.server.config().bots.tokens.iter().any(|t| secret_eq(t, token)));
+        let solved = match data.get("captcha").and_then(|t| t.as_str()) {
+            _ if is_bot => Ok(true),
+            Some(token) => captcha::verify(&config, token).await,
+            None => Ok(false),
+        };
+        match solved {
+            Ok(true) => {
+                client.write().await.captcha_pending = false;
+                true
+            }
+            Ok(false) => {
+                warn!("Client {} failed the CAPTCHA", client_id);
+                self.server.kick(client_id);
+                false
+            }
+            Err(e) => {
+                error!("Couldn't verify {}'s CAPTCHA: {:#}", client_id, e);
+                let notification = Notification::short(
+                    format!("Notification-captcha-{}", current_time_ms()),
+                    "Your CAPTCHA couldn't be checked right now. Reload to try again.",
+                    10_000,
+                );
+                self.server.send_messages(client_id, &[notification]).await;
+                true
+            }
+        }
+    }
+
+    async fn handle_hi(&self, client_id: &str, data: &serde_json::Value) -> Option<Vec<serde_json::Value>> {
+        let client_ref = self.server.clients.get(client_id).map(|c| c.value().clone())?;
+        let mut client = client_ref.write().await;
+
+        if let Some(expected) = &client.challenge {
+            if !challenge::answer_matches(expected, data.get("code")) {
+                warn!("Client {} failed the connection challenge", client_id);
+                drop(client);
+                self.server.kick(client_id);
+                return None;
+            }
+            client.challenge = None;
+        }
+
+        let mut identity_token = None;
+        if let Some(secret) = &self.server.config().token_secret {
+            if client.participant.is_none() {
+                let presented = data.get("token").and_then(|t| t.as_str());
+                let (user_id, token) = match presented.and_then(|t| identity::verify(secret, t).map(|id| (id, t))) {
+                    Some((user_id, token)) => (user_id, token.to_string()),
+                    None => {
+                        if presented.is_some() {
+                            debug!("Client {} sent an invalid identity token, minting a new one", client_id);
+                        }
+                        identity::mint(secret)
+                    }
+                };
+
+                let old_user_id = std::mem::replace(&mut client.user_id, user_id.clone());
+                self.server.change_user_id(client_id, &old_user_id, &user_id);
+                identity_token = Some(token);
+            }
+        }
 
+        if let Some(token) = data.get("botToken").and_then(|t| t.as_str()) {
+            let config = self.server.config();
+            let bots = &config.bots;
+            if bots.tokens.iter().any(|t| secret_eq(t, token)) {
+                if !client.is_bot {
+                    debug!("Client {} authenticated as a bot", client_id);
+                    client.is_bot = true;
+                    let params = NoteQuotaParams {
+                        allowance: bots.quota_allowance,
+                        max: bots.quota_max,
+                    };
+                    client.note_quota = NoteQuota::new(params, config.note_quota.max_hist_len);
+                }
+            } else {
+                warn!("Client {} sent an invalid bot token", client_id);
+            }
+        }
+
+        // Storage is read without holding the client's lock; messages from one connection
+        // are handled in order, so nothing else changes it meanwhile
+        let profile = match &client.participant {
+            Some(_) => None,
+            None => {
+                let user_id = client.user_id.clone();
+                drop(client);
+                let profile = self.server.load_profile(&user_id).await;
+                client = client_ref.write().await;
+                profile
+            }
+        };
         let participant = Participant {
             id: client_id.to_string(),
             _id: client.user_id.clone(),
-            name: "Anonymous".to_string(),
-            color: format!("#{}", &client.user_id[..6.min(client.user_id.len())]),
+            name: profile.as_ref().map_or_else(|| "Anonymous".to_string(), |p| p.name.clone()),
+            color: profile
+                .map(|p| p.color)
+                .unwrap_or_else(|| format!("#{}", &client.user_id[..6.min(client.user_id.len())])),
             x: 0.0,
             y: 0.0,
+            tag: self.server.participant_tag(&client),
+            afk: false,
+            typing: false,
         };
 
         client.participant = Some(participant.clone());
 
-        let response = vec![
-            serde_json::json!({
-                "m": "hi",
-                "u": participant,
-                "t": current_time_ms(),
-                "v": "1.0.0",
-                "motd": "Welcome to Multiplayer Piano!"
-            }),
-            client.note_quota.get_params(),
-        ];
+        if let Some(version) = data.get("version").and_then(|v| v.as_str()) {
+            client.client_version = Some(version.chars().take(64).collect());
+        }
+        if let Some(listed) = data.get("capabilities").and_then(|c| c.as_array()) {
+            let mut capabilities = Vec::new();
+            for capability in listed.iter().filter_map(|c| c.as_str()).filter_map(Capability::from_name) {
+                if !capabilities.contains(&capability) {
+                    capabilities.push(capability);
+                }
+            }
+            client.capabilities = Some(capabilities);
+        }
+
+        // Legacy clients don't send a codec and get exactly the old response
+        let codec = data
+            .get("codec")
+            .and_then(|c| c.as_str())
+            .and_then(Codec::from_name)
+            .filter(|codec| *codec == Codec::Json || client.supports(Capability::MsgPack));
+        if let Some(codec) = codec {
+            if let Some(mut sender) = self.server.ws_senders.get_mut(client_id) {
+                sender.codec = codec;
+            }
+        }
+
+        let hi = Outgoing::Hi {
+            u: participant,
+            t: current_time_ms(),
+            v: "1.0.0".to_string(),
+            motd: self.server.next_motd(),
+            token: identity_token,
+            codec: codec.map(|c| c.name().to_string()),
+            capabilities: client.capabilities.clone(),
+            permissions: (self.server.config().protocol == Protocol::MppClone).then(|| serde_json::json!({})),
+        };
 
-        Some(response)
+        Some(vec![hi.to_value(), client.note_quota.get_params().to_value()])
     }
 
     async fn handle_bye(&self, client_id: &str) {
-        self.server.handle_disconnect(client_id).await;
+        self.server.handle_disconnect(client_id, false).await;
     }
 
     async fn handle_plus_ls(&self, client_id: &str) -> Option<Vec<serde_json::Value>> {
         self.server.subscribed_to_ls.insert(client_id.to_string(), true);
 
-        let mut channels_data = Vec::new();
-        for channel_ref in self.server.channels.iter() {
-            let channel = channel_ref.value().read().await;
-            if channel.settings.visible {
-                channels_data.push(serde_json::json!({
-                    "_id": channel._id,
-                    "count": channel.participants.len(),
-                    "crown": if channel.settings.lobby { None } else { channel.crown.as_ref() },
-                    "settings": &channel.settings
-                }));
-            }
-        }
+        let channels_data: Vec<_> = self
+            .server
+            .with_channels(|_, channel| channel.settings.visible.then(|| channel.ls_entry()))
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
 
-        Some(vec![serde_json::json!({
-            "m": "ls",
-            "c": true,
-            "u": channels_data
-        })])
+        Some(vec![Outgoing::Ls { c: true, u: channels_data }.to_value()])
     }
 
     async fn handle_minus_ls(&self, client_id: &str) {
         self.server.subscribed_to_ls.remove(client_id);
     }
 
-    async fn handle_time(&self, data: &serde_json::Value) -> Option<Vec<serde_json::Value>> {
-        let e = data.get("e")?;
+    /// Answers with the server time as of when the reply should arrive, and takes `e` (the
+    /// client's clock) as a sample for its offset.
+    async fn handle_time(&self, client_id: &str, data: &serde_json::Value) -> Option<Vec<serde_json::Value>> {
+        let e = data.get("e")?.clone();
 
-        Some(vec![serde_json::json!({
-            "m": "t",
-            "t": current_time_ms(),
-            "e": e
-        })])
+        let now = current_time_ms();
+        let mut t = now;
+        if let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) {
+            let mut client = client.write().await;
+            if let Some(client_time) = e.as_f64() {
+                client.clock.record_client_time(client_time as i64, now, self.server.config().time_sync.samples);
+            }
+            t = client.clock.arrival_time(now);
+        }
+        Some(vec![Outgoing::T { t, e }.to_value()])
     }
 
     async fn handle_chat(&self, client_id: &str, data: &serde_json::Value) {
-        let message = match data.get("message").and_then(|m| m.as_str()) {
+        let message = match data.get("message").and_then(|m| m.as_str()).and_then(sanitize_chat) {
             Some(m) => m,
             None => return,
         };
-        
-        if message.len() > 256 {
-            return;
-        }
+        let message = message.as_str();
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let mut client = client_ref.write().await;
         let channel_id = match client.channel_id.as_ref() {
             Some(id) => id.clone(),
             None => return,
@@ -169,56 +388,306 @@ impl MessageHandler {
             Some(p) => p.clone(),
             None => return,
         };
+
+        let now = current_time_ms();
+        if client.chat_guard.is_muted(now) || client.chat_guard.record(message, now, &self.server.config().chat_flood) {
+            let remaining = client.chat_guard.muted_until.unwrap_or(now).saturating_sub(now);
+            drop(client);
+            drop(client_ref);
+
+            let text = format!(
+                "You're sending messages too fast. You can chat again in {} seconds.",
+                remaining.div_ceil(1000)
+            );
+            let notification = Notification::short(format!("Notification-mute-{}", now), text, 5000);
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
+        }
         drop(client);
+        drop(client_ref);
 
-        let channel_ref = match self.server.channels.get(&channel_id) {
-            Some(c) => c,
-            None => return,
+        if let Some(args) = message.strip_prefix("/clear").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            if self.holds_crown(client_id, &channel_id).await {
+                let keep = args.trim().parse().unwrap_or(0);
+                self.handle_clear_chat(client_id, keep).await;
+                return;
+            }
+        }
+
+        if self.server.config().top_command {
+            if let Some(args) = message.strip_prefix("/top").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                self.handle_top_command(client_id, args).await;
+                return;
+            }
+        }
+
+        let mut message = message.to_string();
+        if !self.server.plugins.is_empty() {
+            let event = serde_json::json!({ "channel": channel_id, "participant": participant, "message": message });
+            let Some(event) = self.server.plugins.run(Hook::Chat, event).await else { return };
+            match event.get("message").and_then(|m| m.as_str()).and_then(sanitize_chat) {
+                Some(changed) => message = changed,
+                None => return,
+            }
+        }
+
+        let sent = self
+            .server
+            .channel_command(&channel_id, |reply| ChannelCommand::Chat {
+                participant,
+                message,
+                reply,
+            })
+            .await;
+        if sent == Some(true) {
+            if let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) {
+                client.write().await.stats.messages += 1;
+            }
+            self.server.set_typing(client_id, false).await;
+        }
+    }
+
+    /// `{"m": "dm", "_id": <participant id>, "message": ...}`, for someone in the sender's
+    /// room. Goes through the room's chat filter, and isn't sent while the sender is muted,
+    /// the room has chat off or the recipient turned DMs off.
+    async fn handle_dm(&self, client_id: &str, data: &serde_json::Value) {
+        let Some(message) = data.get("message").and_then(|m| m.as_str()).and_then(sanitize_chat) else { return };
+        let Some(target_id) = data.get("_id").and_then(|id| id.as_str()).filter(|id| *id != client_id) else {
+            return;
         };
-        
-        let channel = channel_ref.value().write().await;
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let client = client.read().await;
+        let (Some(channel_id), Some(sender)) = (client.channel_id.clone(), client.participant.clone()) else { return };
+        let muted = client.chat_guard.is_muted(current_time_ms());
+        drop(client);
+        if muted {
+            return;
+        }
+
+        // None when the room has chat off, Some(None) when the recipient isn't in it
+        let target = target_id.to_string();
+        let from = sender.clone();
+        let found = self.server.with_channel(&channel_id, move |server, channel| {
+            if !channel.settings.chat.unwrap_or(false) {
+                return None;
+            }
+            let recipient = channel.participants.get(&target).cloned()?;
+            Some(Some((recipient, channel_task::filter_chat(server, channel, &from, &message))))
+        });
+        let Some(found) = found.await.flatten() else { return };
+        let Some((recipient, message)) = found else {
+            let notification = Notification::short(
+                format!("Notification-dm-{}", current_time_ms()),
+                "That person isn't in this room.",
+                3000,
+            );
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
+        };
+
+        if self.server.is_blocking(&recipient.id, &sender._id) {
+            return;
+        }
+        if self.server.dms_off.contains_key(&recipient._id) {
+            let notification = Notification::short(
+                format!("Notification-dm-{}", current_time_ms()),
+                format!("{} isn't taking direct messages.", recipient.name),
+                3000,
+            );
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
+        }
+
+        let recipient_id = recipient.id.clone();
+        let dm = [Outgoing::Dm {
+            a: message,
+            sender,
+            recipient,
+            t: current_time_ms(),
+        }];
+        self.server.send_messages(&recipient_id, &dm).await;
+        self.server.send_messages(client_id, &dm).await;
+    }
+
+    /// `{"m": "block", "_ids": [...]}` replaces the connection's block list with those user
+    /// `_id`s. Their chat, notes, cursors and DMs aren't sent to it anymore.
+    fn handle_block(&self, client_id: &str, data: &serde_json::Value) {
+        let Some(ids) = data.get("_ids").and_then(|ids| ids.as_array()) else { return };
+        let blocked: HashSet<String> = ids
+            .iter()
+            .filter_map(|id| id.as_str())
+            .take(MAX_BLOCKED)
+            .map(str::to_string)
+            .collect();
+        let Some(sender) = self.server.ws_senders.get(client_id) else { return };
+        debug!("{} blocks {} users", client_id, blocked.len());
+        *sender.blocked.write().unwrap_or_else(|e| e.into_inner()) = blocked;
+    }
+
+    /// `{"m": "report", "_id": <user _id>, "reason": ...}` queues a report for moderators,
+    /// with the latest chat of the reporter's room as context.
+    async fn handle_report(&self, client_id: &str, data: &serde_json::Value) {
+        let target = data.get("_id").and_then(|id| id.as_str());
+        let Some(target) = target.filter(|id| !id.is_empty() && id.len() <= 64) else { return };
+        let reason = data.get("reason").and_then(|r| r.as_str()).and_then(sanitize_chat).unwrap_or_default();
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let client = client.read().await;
+        if client.user_id == target {
+            return;
+        }
+        let reporter_name = client.participant.as_ref().map(|p| p.name.clone()).unwrap_or_default();
+        let mut report = Report::new(client.user_id.clone(), reporter_name, target.to_string(), reason);
+        report.channel = client.channel_id.clone().or_else(|| client.spectating.clone());
+        drop(client);
+
+        let config = self.server.config();
+        if let Some(channel_id) = report.channel.clone() {
+            let (target, context_messages) = (target.to_string(), config.reports.context_messages);
+            let seen = self.server.with_channel(&channel_id, move |_, channel| {
+                let in_room = channel.participants.values().find(|p| p._id == target);
+                let in_chat = channel.chat_history.iter().rev().map(|m| &m.p).find(|p| p._id == target);
+                let target_name = in_room.or(in_chat).map(|p| p.name.clone());
+                let skip = channel.chat_history.len().saturating_sub(context_messages);
+                (target_name, channel.chat_history.iter().skip(skip).cloned().collect())
+            });
+            if let Some((target_name, context)) = seen.await {
+                report.target_name = target_name;
+                report.context = context;
+            }
+        }
 
-        if !channel.settings.chat.unwrap_or(false) {
+        info!("{} reported {} in {:?}: {}", report.reporter, report.target, report.channel, report.reason);
+        self.server.reports.add(report, config.reports.capacity);
+        let notification = Notification::short(
+            format!("Notification-report-{}", current_time_ms()),
+            "Thanks, your report was sent to the moderators.",
+            5000,
+        );
+        self.server.send_messages(client_id, &[notification]).await;
+    }
+
+    /// `{"m": "clearchat", "keep": n}` or `/clear [n]` in chat, crown only: clears the room's
+    /// chat for everyone but the latest `n` messages (none by default).
+    async fn handle_clear_chat(&self, client_id: &str, keep: usize) {
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let client = client.read().await;
+        let (Some(channel_id), Some(participant)) = (client.channel_id.clone(), client.participant.clone()) else {
+            return;
+        };
+        drop(client);
+        if !self.holds_crown(client_id, &channel_id).await || !self.server.truncate_chat(&channel_id, keep).await {
             return;
         }
+        self.server.audit(
+            AuditEntry::new(participant._id, "clear chat")
+                .actor_name(participant.name)
+                .channel(channel_id)
+                .details(serde_json::json!({ "keep": keep })),
+        );
+    }
 
-        let chat_msg = serde_json::json!({
-            "m": "a",
-            "a": &message[..256.min(message.len())],
-            "p": participant,
-            "t": current_time_ms()
+    /// Whether the client has the crown of `channel_id`, which lobbies never have.
+    async fn holds_crown(&self, client_id: &str, channel_id: &str) -> bool {
+        let client_id = client_id.to_string();
+        let holds = self.server.with_channel(channel_id, move |_, channel| {
+            !channel.settings.lobby
+                && channel.crown.as_ref().is_some_and(|crown| crown.participant_id.as_deref() == Some(client_id.as_str()))
         });
+        holds.await.unwrap_or(false)
+    }
+
+    /// `/top [notes|online] [day|week|all]`, answered with a notification.
+    async fn handle_top_command(&self, client_id: &str, args: &str) {
+        let mut metric = LeaderboardMetric::default();
+        let mut window = LeaderboardWindow::default();
+        for arg in args.split_whitespace() {
+            match arg {
+                "notes" => metric = LeaderboardMetric::Notes,
+                "online" | "time" => metric = LeaderboardMetric::Online,
+                "day" | "today" => window = LeaderboardWindow::Day,
+                "week" => window = LeaderboardWindow::Week,
+                "all" => window = LeaderboardWindow::All,
+                _ => {}
+            }
+        }
 
-        drop(channel);
-        self.server.broadcast_to_channel(&channel_id, &serde_json::json!([chat_msg]), None).await;
+        let entries = self.server.leaderboard(metric, window, 10).await;
+        let text = if entries.is_empty() {
+            "Nobody is on the leaderboard yet.".to_string()
+        } else {
+            let ranks: Vec<_> = entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    let name = entry.name.as_deref().unwrap_or(&entry.user_id);
+                    format!("{}. {} ({})", i + 1, name, metric.format(entry.value))
+                })
+                .collect();
+            ranks.join(", ")
+        };
+        let notification = Notification::short(format!("Notification-top-{}", current_time_ms()), text, 15_000);
+        self.server.send_messages(client_id, &[notification]).await;
     }
 
     async fn handle_note(&self, client_id: &str, data: &serde_json::Value) {
-        let notes = match data.get("n").and_then(|n| n.as_array()) {
-            Some(n) => n,
+        let config = self.server.config();
+        let notes: Vec<Note> = match data.get("n").and_then(|n| n.as_array()) {
+            Some(n) => n
+                .iter()
+                .take(config.max_notes_per_message)
+                .filter_map(|note| Note::parse(note, config.note_max_delay_ms))
+                .collect(),
             None => return,
         };
+        if notes.is_empty() {
+            return;
+        }
         
-        let needed = notes.len() as i32;
+        // Like the reference client, only starting a note costs quota, not releasing one
+        let needed = notes.iter().filter(|note| !note.is_stop()).count() as i32;
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let mut client = client_ref.value().write().await;
+        let mut client = client_ref.write().await;
+        let now = current_time_ms();
+
+        if client.quota_penalty.is_muted(now) {
+            let remaining = client.quota_penalty.muted_until.unwrap_or(now).saturating_sub(now);
+            if client.quota_penalty.should_remind(now) {
+                drop(client);
+                drop(client_ref);
+                self.server.send_messages(client_id, &[quota_mute_notice(remaining)]).await;
+            }
+            return;
+        }
 
+        client.note_quota.catch_up(self.server.quota_ticks());
         if !client.note_quota.spend(needed) {
             warn!("Client {} exceeded note quota", client_id);
-            let notification = serde_json::json!([{
-                "m": "notification",
-                "text": "You're playing too fast! Slow down.",
-                "class": "short",
-                "duration": 2000
-            }]);
-            let msg_str = serde_json::to_string(&notification).unwrap_or_default();
+            let muted_for = config
+                .quota_penalty
+                .as_ref()
+                .and_then(|penalty| client.quota_penalty.record(now, penalty));
+            let notification = match muted_for {
+                Some(ms) => {
+                    info!("Muted {}'s notes for {} ms after repeated quota violations", client_id, ms);
+                    // Counts as the first reminder
+                    client.quota_penalty.should_remind(now);
+                    quota_mute_notice(ms)
+                }
+                None => Outgoing::Notification(Notification {
+                    id: None,
+                    title: None,
+                    text: "You're playing too fast! Slow down.".to_string(),
+                    class: "short".to_string(),
+                    duration: 2000,
+                }),
+            };
             drop(client);
-            self.server.send_to_client(client_id, &msg_str).await;
+            self.server.send_messages(client_id, &[notification]).await;
             return;
         }
 
@@ -226,34 +695,44 @@ impl MessageHandler {
             Some(id) => id.clone(),
             None => return,
         };
+        client.stats.notes += needed as u64;
+        let black_midi = self
+            .server
+            .black_midi_limits(&channel_id)
+            .filter(|limits| client.note_density.record(now, needed as u32, limits));
+        let user_id = client.user_id.clone();
+        let name = client.participant.as_ref().map(|p| p.name.clone()).unwrap_or_default();
         drop(client);
 
-        let channel_ref = match self.server.channels.get(&channel_id) {
-            Some(c) => c,
-            None => return,
-        };
-        
-        let channel = channel_ref.value().read().await;
-
-        if let Some(crownsolo) = channel.settings.crownsolo {
-            if crownsolo {
-                if let Some(crown) = &channel.crown {
-                    if crown.participant_id.as_deref() != Some(client_id) {
-                        return;
-                    }
-                }
-            }
+        // Other clients schedule playback off `t`, so it can't be allowed to point far away
+        let window = config.note_time_window_ms;
+        let t = data
+            .get("t")
+            .and_then(|t| t.as_f64())
+            .map(|t| (t.max(0.0) as u64).clamp(now.saturating_sub(window), now + window))
+            .unwrap_or(now);
+
+        let played = self
+            .server
+            .channel_command(&channel_id, |reply| ChannelCommand::Note {
+                client_id: client_id.to_string(),
+                user_id,
+                notes,
+                t,
+                black_midi,
+                reply,
+            })
+            .await;
+        let Some(NotePlayed::Muted { crown_holder, secs }) = played else { return };
+
+        let text = format!("Your notes are muted here for {} seconds for playing too many at once.", secs);
+        let notice = Notification::short(format!("Notification-blackmidi-{}", now), text, 5000);
+        self.server.send_messages(client_id, &[notice]).await;
+        if let Some(crown_holder) = crown_holder.filter(|p| p != client_id) {
+            let text = format!("{} was muted for {} seconds for black MIDI spam.", name, secs);
+            let notice = Notification::short(format!("Notification-blackmidi-{}", now), text, 5000);
+            self.server.send_messages(&crown_holder, &[notice]).await;
         }
-
-        let note_msg = serde_json::json!({
-            "m": "n",
-            "t": data.get("t"),
-            "n": notes,
-            "p": client_id
-        });
-
-        drop(channel);
-        self.server.broadcast_to_channel(&channel_id, &serde_json::json!([note_msg]), None).await;
     }
 
     async fn handle_movement(&self, client_id: &str, data: &serde_json::Value) {
@@ -289,40 +768,190 @@ impl MessageHandler {
             None => return,
         };
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let mut client = client_ref.value().write().await;
-
+        let Some(channel_id) = client_ref.read().await.channel_id.clone() else { return };
+        let config = self.server.config();
+        let default_ms = config.cursor_throttle_ms;
+        let throttle_ms = self
+            .server
+            .with_channel(&channel_id, move |_, channel| channel.cursor_throttle_ms(default_ms))
+            .await
+            .unwrap_or(default_ms);
+
+        let mut client = client_ref.write().await;
         let now = current_time_ms();
         if let Some(last_move) = client.last_move_time {
-            if now - last_move < 50 {
+            if now.saturating_sub(last_move) < throttle_ms && !client.is_bot {
                 return;
             }
         }
         client.last_move_time = Some(now);
 
+        let (x, y) = if config.quantize_cursor {
+            ((x * 10.0).round() / 10.0, (y * 10.0).round() / 10.0)
+        } else {
+            (x, y)
+        };
         if let Some(participant) = &mut client.participant {
+            if config.quantize_cursor && participant.x == x && participant.y == y {
+                return;
+            }
             participant.x = x;
             participant.y = y;
         }
+        drop(client);
 
-        let channel_id = match client.channel_id.as_ref() {
-            Some(id) => id.clone(),
+        let movement = Outgoing::M {
+            id: client_id.to_string(),
+            x,
+            y,
+        };
+
+        self.server.broadcast_to_channel(&channel_id, &messages::batch(&[movement]), Some(client_id)).await;
+    }
+
+    /// MPP Clone style relay of arbitrary bot data. Only connections that sent `+custom`
+    /// receive it, narrowed down by `target.mode`: `subscribed` (the whole channel), `id`,
+    /// `ids` or `_id`.
+    async fn handle_custom(&self, client_id: &str, data: &serde_json::Value) {
+        let payload = match data.get("data") {
+            Some(d) => d,
             None => return,
         };
-        drop(client);
 
-        let movement = serde_json::json!({
-            "m": "m",
-            "id": client_id,
-            "x": x,
-            "y": y
+        let size = serde_json::to_string(payload).map(|s| s.len()).unwrap_or(usize::MAX);
+        if size > self.server.config().custom_max_bytes {
+            debug!("Dropping {} byte custom message from {}", size, client_id);
+            return;
+        }
+
+        let target = data.get("target").cloned();
+
+        let channel_id = {
+            let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+                Some(c) => c,
+                None => return,
+            };
+            let client = client_ref.read().await;
+            match client.channel_id.clone() {
+                Some(id) => id,
+                None => return,
+            }
+        };
+
+        let sender = client_id.to_string();
+        let recipients = self.server.with_channel(&channel_id, move |server, channel| {
+            let target = target.as_ref();
+            let mode = target
+                .and_then(|t| t.get("mode"))
+                .and_then(|m| m.as_str())
+                .unwrap_or("subscribed");
+            let target_str = |key: &str| target.and_then(|t| t.get(key)).and_then(|v| v.as_str());
+
+            channel
+                .participants
+                .values()
+                .filter(|p| match mode {
+                    "subscribed" => true,
+                    "id" => target_str("id") == Some(p.id.as_str()),
+                    "_id" => target_str("_id") == Some(p._id.as_str()),
+                    "ids" => target
+                        .and_then(|t| t.get("ids"))
+                        .and_then(|ids| ids.as_array())
+                        .is_some_and(|ids| ids.iter().take(32).any(|id| id.as_str() == Some(p.id.as_str()))),
+                    _ => false,
+                })
+                .filter(|p| p.id != sender && server.subscribed_to_custom.contains_key(&p.id))
+                .map(|p| p.id.clone())
+                .collect::<Vec<_>>()
         });
+        let recipients = recipients.await.unwrap_or_default();
+
+        if recipients.is_empty() {
+            return;
+        }
 
-        self.server.broadcast_to_channel(&channel_id, &serde_json::json!([movement]), Some(client_id)).await;
+        let msg = serde_json::json!([{
+            "m": "custom",
+            "data": payload,
+            "p": client_id
+        }]);
+        let msg_str = serde_json::to_string(&msg).unwrap_or_default();
+        for recipient in recipients {
+            self.server.send_to_client(&recipient, &msg_str).await;
+        }
+    }
+
+    async fn handle_typing(&self, client_id: &str, data: &serde_json::Value) {
+        let typing = data.get("typing").and_then(|t| t.as_bool()).unwrap_or(true);
+
+        if typing {
+            let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+                Some(c) => c,
+                None => return,
+            };
+
+            let mut client = client_ref.write().await;
+            let now = current_time_ms();
+            if let Some(last_typing) = client.last_typing_time {
+                if now.saturating_sub(last_typing) < self.server.config().typing_interval_ms {
+                    return;
+                }
+            }
+            client.last_typing_time = Some(now);
+        }
+
+        // Stopping always goes through so indicators never get stuck on
+        self.server.set_typing(client_id, typing).await;
+    }
+
+    /// Whether the client listed the capability a message type needs in `hi`, see
+    /// `ClientData::supports`.
+    async fn supports(&self, client_id: &str, message_type: &str) -> bool {
+        let capability = match message_type {
+            "+custom" | "-custom" | "custom" => Capability::Custom,
+            "typing" => Capability::Typing,
+            _ => return true,
+        };
+        match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+            Some(client) => client.read().await.supports(capability),
+            None => false,
+        }
+    }
+
+    /// Spends a token from the client's bucket for `message_type` under `rate_limits`. Over
+    /// the limit the message is dropped, and for anything but time sync the client is told.
+    async fn check_rate(&self, client_id: &str, message_type: &str) -> bool {
+        let config = self.server.config();
+        let Some(rate) = config.rate_limits.get(message_type) else { return true };
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else {
+            return false;
+        };
+
+        let now = current_time_ms();
+        let allowed = client
+            .write()
+            .await
+            .rate_limits
+            .entry(message_type.to_string())
+            .or_default()
+            .take(now, rate);
+        if !allowed {
+            debug!("Dropping {} from {}, over its rate limit", message_type, client_id);
+            if message_type != "t" {
+                let notification = Notification::short(
+                    format!("Notification-rate-{}", message_type),
+                    "You're doing that too fast. Wait a moment and try again.",
+                    3000,
+                );
+                self.server.send_messages(client_id, &[notification]).await;
+            }
+        }
+        allowed
     }
 
     async fn handle_userset(&self, client_id: &str, data: &serde_json::Value) {
@@ -330,124 +959,304 @@ impl MessageHandler {
             Some(s) => s,
             None => return,
         };
+
+        if let Some(allow) = set.get("dms").and_then(|d| d.as_bool()) {
+            self.set_dms(client_id, allow).await;
+        }
         
         let name = match set.get("name").and_then(|n| n.as_str()) {
             Some(n) => n,
             None => return,
         };
         
-        let trimmed_name = name.trim();
-
-        if trimmed_name.is_empty() || trimmed_name.len() > 40 {
-            return;
-        }
+        let name = match sanitize_name(name) {
+            Some(n) => n,
+            None => return,
+        };
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let mut client = client_ref.value().write().await;
+        let mut client = client_ref.write().await;
 
         if let Some(participant) = &mut client.participant {
-            participant.name = trimmed_name.to_string();
+            participant.name = name;
             if let Some(color) = set.get("color").and_then(|c| c.as_str()) {
-                participant.color = color.to_string();
+                match validate_color(color, &self.server.config().color_palette) {
+                    Some(color) => participant.color = color,
+                    None => debug!("Ignoring invalid color {:?} from {}", color, client_id),
+                }
             }
         }
 
-        let channel_id = match client.channel_id.as_ref() {
-            Some(id) => id.clone(),
-            None => return,
-        };
-        
-        let user_id = client.user_id.clone();
         let participant = match client.participant.as_ref() {
             Some(p) => p.clone(),
             None => return,
         };
+        let channel_id = client.channel_id.clone();
         drop(client);
 
-        let update = serde_json::json!({
-            "m": "p",
-            "id": client_id,
-            "_id": user_id,
-            "name": participant.name,
-            "color": participant.color,
-            "x": participant.x,
-            "y": participant.y
-        });
+        let profile = UserProfile {
+            user_id: participant._id.clone(),
+            name: participant.name.clone(),
+            color: participant.color.clone(),
+        };
+        self.server.persist("profile", move |storage| storage.save_profile(&profile));
 
-        self.server.broadcast_to_channel(&channel_id, &serde_json::json!([update]), None).await;
+        let Some(channel_id) = channel_id else { return };
+        let update = messages::batch(&[Outgoing::P(participant)]);
+        self.server.broadcast_to_channel(&channel_id, &update, None).await;
+    }
+
+    /// Turns direct messages to the client's `_id` on or off, for every tab of theirs.
+    async fn set_dms(&self, client_id: &str, allow: bool) {
+        let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let user_id = client.read().await.user_id.clone();
+        if allow {
+            self.server.dms_off.remove(&user_id);
+        } else {
+            self.server.dms_off.insert(user_id, true);
+        }
     }
 
     async fn handle_channel(&self, client_id: &str, data: &serde_json::Value) {
+        let captcha_pending = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+            Some(client) => client.read().await.captcha_pending,
+            None => return,
+        };
+        if captcha_pending {
+            debug!("Not letting {} join before it solves the CAPTCHA", client_id);
+            return;
+        }
+
+        if let Some(delay) = self.server.join_delay(client_id).await {
+            debug!("Holding join for screened client {} for {:?}", client_id, delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.server.shutdown.cancelled() => return,
+            }
+        }
+
+        let interval = self.server.config().channel_join_interval_ms;
+
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut client = client_ref.write().await;
+        let now = current_time_ms();
+        if let Some(last_join) = client.last_join_time {
+            if now.saturating_sub(last_join) < interval {
+                drop(client);
+                drop(client_ref);
+
+                let notification = Notification::short(
+                    format!("Notification-join-{}", now),
+                    "You're changing channels too fast. Wait a moment and try again.",
+                    3000,
+                );
+                self.server.send_messages(client_id, &[notification]).await;
+                return;
+            }
+        }
+        client.last_join_time = Some(now);
+        let participant = client.participant.clone();
+        drop(client);
+        drop(client_ref);
+
+        if self.server.plugins.is_empty() {
+            self.join_channel(client_id, data).await;
+            return;
+        }
+        let Some(channel_id) = data.get("_id").and_then(|id| id.as_str()) else { return };
+        let event = serde_json::json!({ "channel": channel_id, "participant": participant });
+        let Some(event) = self.server.plugins.run(Hook::Join, event).await else {
+            let text = format!("You can't join {} right now.", channel_id);
+            let notification = Notification::short(format!("Notification-join-{}", now), text, 3000);
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
+        };
+        let mut data = data.clone();
+        if let Some(channel_id) = event.get("channel").and_then(|c| c.as_str()) {
+            data["_id"] = serde_json::json!(channel_id);
+        }
+        self.join_channel(client_id, &data).await;
+    }
+
+    /// Why `user_id` can't open a new room called `channel_id`, if they can't.
+    async fn creation_refusal(&self, channel_id: &str, user_id: &str) -> Option<String> {
+        if self.server.restart_pending() {
+            return Some("The server is about to restart, so no new rooms can be created.".to_string());
+        }
+        if self.server.is_special_channel(channel_id) {
+            return None;
+        }
+
+        let config = self.server.config();
+        if config.reserved_channel_names.iter().any(|pattern| pattern.matches(channel_id)) {
+            return Some(format!("{} can't be used as a room name.", channel_id));
+        }
+        if config.max_channels > 0 && self.server.channels.len() >= config.max_channels {
+            return Some("There are too many rooms open right now. Try joining an existing one.".to_string());
+        }
+        if config.max_channels_per_user > 0 {
+            let user = user_id.to_string();
+            let owners = self.server.with_channels(move |_, channel| channel.owner_id.as_deref() == Some(user.as_str()));
+            let owned = owners.await.into_iter().filter(|owns| *owns).count();
+            if owned >= config.max_channels_per_user {
+                return Some(format!("You can't have more than {} rooms open at once.", config.max_channels_per_user));
+            }
+        }
+        None
+    }
+
+    /// Moves a client into a channel without any rate limiting, also used for kicks.
+    pub async fn join_channel(&self, client_id: &str, data: &serde_json::Value) {
         let channel_id = match data.get("_id").and_then(|id| id.as_str()) {
             Some(id) => id,
             None => return,
         };
+        let Some(channel_id) = self.server.allowed_channel(client_id, channel_id).await else {
+            debug!("{} isn't allowed into any room", client_id);
+            return;
+        };
         
         let channel_id = if channel_id.len() > 512 { "lobby" } else { channel_id };
+        let channel_id = if channel_id == "lobby" {
+            match self.server.regional_lobby(client_id).await {
+                Some(lobby) => lobby,
+                None => self.server.lobby_with_space(client_id).await,
+            }
+        } else {
+            channel_id.to_string()
+        };
+        let channel_id = channel_id.as_str();
+        let spectate = data.get("spectate").and_then(|s| s.as_bool()).unwrap_or(false);
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let client = client_ref.read().await;
         let user_id = client.user_id.clone();
+        let ip = client.ip.clone();
+        let online_for = current_time_ms().saturating_sub(client.connected_at);
         drop(client);
 
-        if let Some(ban) = self.server.banned_users.get(&user_id) {
-            if ban.channel_id == channel_id && ban.expiry > current_time_ms() {
-                let notification = serde_json::json!([{
-                    "m": "notification",
-                    "id": format!("Notification-ban-{}", current_time_ms()),
-                    "title": "",
-                    "text": format!("You are banned from {} until {}.", 
-                        channel_id, 
-                        chrono::DateTime::<chrono::Utc>::from_timestamp((ban.expiry / 1000) as i64, 0)
-                            .map(|dt| dt.to_rfc3339())
-                            .unwrap_or_default()
-                    ),
-                    "class": "short",
-                    "duration": 5000
-                }]);
-                let msg_str = serde_json::to_string(&notification).unwrap_or_default();
-                self.server.send_to_client(client_id, &msg_str).await;
-                return;
+        if let Some(ban) = self.server.active_ban(&user_id, channel_id) {
+            let text = format!("You are banned from {} until {}.", 
+                channel_id, 
+                chrono::DateTime::<chrono::Utc>::from_timestamp((ban.expiry / 1000) as i64, 0)
+                    .map(|dt| dt.to_rfc3339())
+                    .unwrap_or_default()
+            );
+            let notification = Notification::short(format!("Notification-ban-{}", current_time_ms()), text, 5000);
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
+        }
+
+        let (joining, user) = (client_id.to_string(), user_id.clone());
+        let refusal = self.server.with_channel(channel_id, move |_, channel| {
+            let privileged = channel.owner_id.as_deref() == Some(user.as_str())
+                || channel.crown.as_ref().and_then(|c| c.user_id.as_deref()) == Some(user.as_str());
+            let reconnecting = channel.disconnected_participant(&user).is_some();
+            // Spectators take up room under the limit like everyone else
+            let spectators = channel.spectators.iter().filter(|id| **id != joining).count();
+            let occupants = channel.participants.len() + spectators;
+            if channel.participants.contains_key(&joining) || privileged || reconnecting {
+                None
+            } else if channel.settings.limit.is_some_and(|limit| occupants >= limit as usize) {
+                Some(format!("{} is full.", channel._id))
+            } else if spectate {
+                None
+            } else if channel.settings.min_online_time.is_some_and(|min| online_for < min) {
+                Some(format!("You need to be online a little longer before joining {}.", channel._id))
+            } else {
+                None
             }
+        });
+        let refusal = match refusal.await {
+            Some(refusal) => refusal,
+            // Watching a room doesn't open it
+            None if spectate => Some(format!("{} isn't open.", channel_id)),
+            None => None,
+        };
+        if let Some(text) = refusal {
+            let notification = Notification::short(format!("Notification-refused-{}", current_time_ms()), text, 5000);
+            self.server.send_messages(client_id, &[notification]).await;
+            return;
         }
 
+        let mut link_settings = None;
         if !self.server.channels.contains_key(channel_id) {
-            let channel = self.server.create_default_channel(channel_id);
-            self.server.channels.insert(channel_id.to_string(), Arc::new(tokio::sync::RwLock::new(channel)));
-            self.server.broadcast_ls_update(channel_id, false).await;
-        }
-
-        let mut client = client_ref.value().write().await;
-        if let Some(old_channel_id) = &client.channel_id {
-            if old_channel_id != channel_id {
-                if let Some(channel_ref) = self.server.channels.get(old_channel_id) {
-                    let mut channel = channel_ref.value().write().await;
-                    channel.participants.remove(client_id);
-
-                    if let Some(crown) = &mut channel.crown {
-                        if crown.participant_id.as_deref() == Some(client_id) {
-                            crown.participant_id = None;
-                            crown.user_id = None;
-                        }
-                    }
-                }
-                
-                let bye_msg = serde_json::json!([{
-                    "m": "bye",
-                    "p": client_id
-                }]);
-                self.server.broadcast_to_channel(old_channel_id, &bye_msg, Some(client_id)).await;
+            if let Some(text) = self.creation_refusal(channel_id, &user_id).await {
+                let notification = Notification::short(format!("Notification-refused-{}", current_time_ms()), text, 5000);
+                self.server.send_messages(client_id, &[notification]).await;
+                return;
+            }
+            let mut channel = self.server.create_default_channel(channel_id);
+            // A room back from storage keeps the owner it had
+            if channel.crown.is_some() && channel.owner_id.is_none() {
+                channel.owner_id = Some(user_id.clone());
+            }
+            if channel.owner_id.as_deref() == Some(user_id.as_str()) {
+                link_settings = room_links::take(&self.server, &ip, channel_id);
+            }
+            self.server.open_channel(channel);
+            self.server.emit(ServerEvent::ChannelCreated {
+                channel_id: channel_id.to_string(),
+            });
+            self.server.queue_ls_update(channel_id);
+        }
+
+        let (old_channel_id, watched) = {
+            let mut client = client_ref.write().await;
+            (client.channel_id.clone(), client.spectating.take())
+        };
+        if let Some(old_channel_id) = old_channel_id.filter(|old| old != channel_id || spectate) {
+            self.server
+                .channel_command(&old_channel_id, |reply| ChannelCommand::Leave {
+                    client_id: client_id.to_string(),
+                    user_id: user_id.clone(),
+                    pass_crown: false,
+                    reply,
+                })
+                .await;
+
+            let bye_msg = messages::batch(&[Outgoing::Bye { p: client_id.to_string() }]);
+            self.server.broadcast_to_channel(&old_channel_id, &bye_msg, Some(client_id)).await;
+            self.server.queue_ls_update(&old_channel_id);
+        }
+        if let Some(watched) = watched {
+            if watched == channel_id {
+                // Staying in the room, so it mustn't be deleted in between
+                self.server
+                    .channel_command(channel_id, |reply| ChannelCommand::StopSpectating {
+                        client_id: client_id.to_string(),
+                        reply,
+                    })
+                    .await;
+            } else {
+                self.server.stop_spectating(client_id, &watched).await;
             }
         }
 
+        let mut client = client_ref.write().await;
+        if spectate {
+            client.channel_id = None;
+            client.spectating = Some(channel_id.to_string());
+            drop(client);
+            self.spectate(client_id, channel_id).await;
+            return;
+        }
+
+        if client.channel_id.as_deref() != Some(channel_id) {
+            client.stats.rooms += 1;
+        }
         client.channel_id = Some(channel_id.to_string());
         
         if client.participant.is_none() {
@@ -458,6 +1267,9 @@ impl MessageHandler {
                 color: format!("#{}", &user_id[..6.min(user_id.len())]),
                 x: 0.0,
                 y: 0.0,
+                tag: self.server.participant_tag(&client),
+                afk: false,
+                typing: false,
             });
         }
 
@@ -467,39 +1279,24 @@ impl MessageHandler {
         };
         drop(client);
 
-        let channel_ref = match self.server.channels.get(channel_id) {
-            Some(c) => c,
-            None => return,
+        let joined = self
+            .server
+            .channel_command(channel_id, |reply| ChannelCommand::Join { participant, reply })
+            .await;
+        let Some(Joined {
+            participant,
+            resumed,
+            reclaimed,
+            channel_msg,
+            mut chat_history,
+        }) = joined
+        else {
+            return;
         };
-        
-        let mut channel = channel_ref.value().write().await;
-        channel.participants.insert(client_id.to_string(), participant.clone());
-
-        if let Some(crown) = &mut channel.crown {
-            if crown.participant_id.is_none() {
-                crown.participant_id = Some(client_id.to_string());
-                crown.user_id = Some(user_id);
-                crown.time = current_time_ms();
-            }
-        }
-
-        let ppl: Vec<_> = channel.participants.values().cloned().collect();
-        let chat_history = channel.chat_history.clone();
-        let channel_info = serde_json::json!({
-            "_id": channel._id,
-            "settings": channel.settings,
-            "crown": channel.crown
-        });
-
-        drop(channel);
 
+        chat_history.retain(|message| !self.server.is_blocking(client_id, &message.p._id));
         let join_msg = serde_json::json!([
-            {
-                "m": "ch",
-                "ch": channel_info,
-                "ppl": ppl,
-                "p": client_id
-            },
+            channel_msg,
             {
                 "m": "c",
                 "c": chat_history
@@ -508,18 +1305,42 @@ impl MessageHandler {
         let msg_str = serde_json::to_string(&join_msg).unwrap_or_default();
         self.server.send_to_client(client_id, &msg_str).await;
 
-        let participant_msg = serde_json::json!([{
-            "m": "p",
-            "id": client_id,
-            "_id": participant._id,
-            "name": participant.name,
-            "color": participant.color,
-            "x": participant.x,
-            "y": participant.y
-        }]);
+        if let Some(old_id) = &resumed {
+            if let Some(p) = client_ref.write().await.participant.as_mut() {
+                *p = participant.clone();
+            }
+            let bye_msg = messages::batch(&[Outgoing::Bye { p: old_id.clone() }]);
+            self.server.broadcast_to_channel(channel_id, &bye_msg, Some(client_id)).await;
+        }
+
+        let participant_msg = messages::batch(&[Outgoing::P(participant)]);
         self.server.broadcast_to_channel(channel_id, &participant_msg, Some(client_id)).await;
+        if reclaimed || resumed.is_some() {
+            self.server.broadcast_channel_info(channel_id).await;
+        }
 
-        self.server.broadcast_ls_update(channel_id, false).await;
+        self.server.queue_ls_update(channel_id);
+        self.server.refresh_note_quota(client_id).await;
+
+        // Settings from the room link they came in by, applied as their first chset
+        if let Some(set) = link_settings {
+            self.handle_channel_settings(client_id, &serde_json::json!({ "set": set })).await;
+        }
+    }
+
+    /// Sends the room's state to a spectator and subscribes them to its traffic, without
+    /// adding them to `ppl`.
+    async fn spectate(&self, client_id: &str, channel_id: &str) {
+        let join_msg = self
+            .server
+            .channel_command(channel_id, |reply| ChannelCommand::Spectate {
+                client_id: client_id.to_string(),
+                reply,
+            })
+            .await;
+        let Some(join_msg) = join_msg else { return };
+
+        self.server.send_to_client(client_id, &join_msg.to_string()).await;
     }
 
     async fn handle_channel_settings(&self, client_id: &str, data: &serde_json::Value) {
@@ -528,73 +1349,66 @@ impl MessageHandler {
             None => return,
         };
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let client = client_ref.read().await;
         let channel_id = match client.channel_id.as_ref() {
             Some(id) => id.clone(),
             None => return,
         };
+        let actor_id = client.user_id.clone();
+        let actor_name = client.participant.as_ref().map(|p| p.name.clone()).unwrap_or_default();
+        let participant = client.participant.clone();
         drop(client);
+        drop(client_ref);
 
-        let channel_ref = match self.server.channels.get(&channel_id) {
-            Some(c) => c,
-            None => return,
-        };
-        
-        let mut channel = channel_ref.value().write().await;
-
-        if let Some(crown) = &channel.crown {
-            if crown.participant_id.as_deref() != Some(client_id) {
-                return;
+        let set = if self.server.plugins.is_empty() {
+            set.clone()
+        } else {
+            let event = serde_json::json!({ "channel": channel_id, "participant": participant, "set": set });
+            let Some(event) = self.server.plugins.run(Hook::Chset, event).await else { return };
+            match event.get("set") {
+                Some(set) => set.clone(),
+                None => return,
             }
-        }
-
-        if channel._id == "lobby" || channel._id.starts_with("test/") {
-            return;
-        }
+        };
 
-        if let Some(color) = set.get("color").and_then(|c| c.as_str()) {
-            channel.settings.color = color.to_string();
+        let change = self
+            .server
+            .channel_command(&channel_id, |reply| ChannelCommand::Set {
+                client_id: client_id.to_string(),
+                set: set.clone(),
+                reply,
+            })
+            .await
+            .flatten();
+        let Some(change) = change else { return };
+
+        if change.changed {
+            self.server.audit(
+                AuditEntry::new(actor_id, "chset")
+                    .actor_name(actor_name)
+                    .channel(channel_id.as_str())
+                    .details(serde_json::json!({ "set": set })),
+            );
         }
-        if let Some(visible) = set.get("visible").and_then(|v| v.as_bool()) {
-            channel.settings.visible = visible;
+        for participant_id in change.quota_changed_for {
+            self.server.refresh_note_quota(&participant_id).await;
         }
-        if let Some(chat) = set.get("chat").and_then(|c| c.as_bool()) {
-            channel.settings.chat = Some(chat);
-        }
-        if let Some(crownsolo) = set.get("crownsolo").and_then(|c| c.as_bool()) {
-            channel.settings.crownsolo = Some(crownsolo);
-        }
-
-        let ppl: Vec<_> = channel.participants.values().cloned().collect();
-        let update_msg = serde_json::json!([{
-            "m": "ch",
-            "ch": {
-                "_id": channel._id,
-                "settings": channel.settings,
-                "crown": channel.crown
-            },
-            "ppl": ppl
-        }]);
-
-        drop(channel);
-        self.server.broadcast_to_channel(&channel_id, &update_msg, None).await;
-        self.server.broadcast_ls_update(&channel_id, false).await;
     }
 
     async fn handle_chown(&self, client_id: &str, data: &serde_json::Value) {
         let target_id = data.get("id").and_then(|id| id.as_str());
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let client = client_ref.read().await;
         let channel_id = match client.channel_id.as_ref() {
             Some(id) => id.clone(),
             None => return,
@@ -605,85 +1419,44 @@ impl MessageHandler {
             None => return,
         };
         drop(client);
-
-        let channel_ref = match self.server.channels.get(&channel_id) {
-            Some(c) => c,
-            None => return,
-        };
-        
-        let mut channel = channel_ref.value().write().await;
-
-        if channel.settings.lobby {
-            return;
-        }
-
-        let crown = match channel.crown.as_mut() {
-            Some(c) => c,
-            None => return,
-        };
-        
-        if crown.participant_id.as_deref() != Some(client_id) {
+        drop(client_ref);
+
+        let moved = self
+            .server
+            .channel_command(&channel_id, |reply| ChannelCommand::Chown {
+                participant,
+                target_id: target_id.map(str::to_string),
+                reply,
+            })
+            .await;
+        if moved != Some(true) {
             return;
         }
 
+        self.server.refresh_note_quota(client_id).await;
         if let Some(target_id) = target_id {
-            if let Some(target_ref) = self.server.clients.get(target_id) {
-                let target = target_ref.value().read().await;
-                let target_participant = match target.participant.as_ref() {
-                    Some(p) => p,
-                    None => return,
-                };
-
-                *crown = Crown {
-                    participant_id: Some(target_id.to_string()),
-                    user_id: Some(target.user_id.clone()),
-                    time: current_time_ms(),
-                    start_pos: Position { x: participant.x, y: participant.y },
-                    end_pos: Position { x: target_participant.x, y: target_participant.y },
-                };
-            }
-        } else {
-            *crown = Crown {
-                participant_id: None,
-                user_id: Some(participant._id.clone()),
-                time: current_time_ms(),
-                start_pos: Position { x: participant.x, y: participant.y },
-                end_pos: Position { x: participant.x, y: participant.y },
-            };
+            self.server.refresh_note_quota(target_id).await;
         }
-
-        let ppl: Vec<_> = channel.participants.values().cloned().collect();
-        let channel_update = serde_json::json!([{
-            "m": "ch",
-            "ch": {
-                "_id": channel._id,
-                "settings": channel.settings,
-                "crown": channel.crown
-            },
-            "ppl": ppl
-        }]);
-
-        drop(channel);
-        self.server.broadcast_to_channel(&channel_id, &channel_update, None).await;
     }
 
     async fn handle_kickban(&self, client_id: &str, data: &serde_json::Value) {
-        let target_user_id = match data.get("_id").and_then(|id| id.as_str()) {
-            Some(id) => id,
-            None => return,
-        };
-        
+        let target_user_id = data.get("_id").and_then(|id| id.as_str());
+        let target_participant_id = data.get("id").and_then(|id| id.as_str());
+        if target_user_id.is_none() && target_participant_id.is_none() {
+            return;
+        }
+
         let duration_ms = match data.get("ms").and_then(|ms| ms.as_u64()) {
-            Some(ms) => ms.min(24 * 60 * 60 * 1000),
+            Some(ms) => ms.min(admin::MAX_BAN_MS),
             None => return,
         };
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let client = client_ref.read().await;
         let channel_id = match client.channel_id.as_ref() {
             Some(id) => id.clone(),
             None => return,
@@ -693,83 +1466,84 @@ impl MessageHandler {
             Some(p) => p.name.clone(),
             None => return,
         };
+        let actor_id = client.user_id.clone();
         drop(client);
 
-        let channel_ref = match self.server.channels.get(&channel_id) {
-            Some(c) => c,
-            None => return,
-        };
-        
-        let channel = channel_ref.value().read().await;
-
-        if channel.settings.lobby {
-            return;
-        }
-
-        if let Some(crown) = &channel.crown {
-            if crown.participant_id.as_deref() != Some(client_id) {
-                return;
+        // A participant id is the exact cursor the crown holder sees, the ban still goes
+        // to its whole user
+        let holder = client_id.to_string();
+        let (user_id, participant_id) = (target_user_id.map(str::to_string), target_participant_id.map(str::to_string));
+        let target = self.server.with_channel(&channel_id, move |_, channel| {
+            if channel.settings.lobby {
+                return None;
             }
-        }
-
-        drop(channel);
+            if let Some(crown) = &channel.crown {
+                if crown.participant_id.as_deref() != Some(holder.as_str()) {
+                    return None;
+                }
+            }
+            match (user_id, participant_id) {
+                (Some(user_id), _) => Some(user_id),
+                (None, Some(participant_id)) => channel.participants.get(&participant_id).map(|p| p._id.clone()),
+                (None, None) => None,
+            }
+        });
+        let Some(target_user_id) = target.await.flatten() else { return };
+        let target_user_id = target_user_id.as_str();
 
-        let mut target_client_id = None;
+        // The user may be in the room from several tabs, all of them get kicked
+        let mut target_client_ids = Vec::new();
         let mut target_name = String::new();
-        
-        for client_entry in self.server.clients.iter() {
-            let c = client_entry.value().read().await;
+
+        let clients: Vec<_> = self.server.clients.iter().map(|c| (c.key().clone(), c.value().clone())).collect();
+        for (id, client) in clients {
+            let c = client.read().await;
             if c.user_id == target_user_id && c.channel_id.as_ref() == Some(&channel_id) {
-                target_client_id = Some(client_entry.key().clone());
+                target_client_ids.push(id);
                 if let Some(p) = &c.participant {
                     target_name = p.name.clone();
                 }
-                break;
             }
         }
 
-        let target_client_id = match target_client_id {
-            Some(id) => id,
-            None => return,
-        };
+        if target_client_ids.is_empty() {
+            return;
+        }
 
         let expiry = current_time_ms() + duration_ms;
-        self.server.banned_users.insert(
-            target_user_id.to_string(),
-            crate::types::BanInfo {
-                channel_id: channel_id.clone(),
-                expiry,
-            },
+        self.server.add_ban(target_user_id, &channel_id, crate::types::BanInfo { expiry });
+
+        self.server.emit(ServerEvent::UserBanned {
+            channel_id: channel_id.clone(),
+            user_id: target_user_id.to_string(),
+            name: target_name.clone(),
+            banned_by: client_name.clone(),
+            duration_ms,
+        });
+        self.server.audit(
+            AuditEntry::new(actor_id.as_str(), "kickban")
+                .actor_name(client_name.as_str())
+                .channel(channel_id.as_str())
+                .target(target_user_id)
+                .details(serde_json::json!({ "ms": duration_ms, "name": target_name })),
         );
 
         let kick_data = serde_json::json!({"_id": "test/awkward"});
-        self.handle_channel(&target_client_id, &kick_data).await;
-
-        let ban_notification = serde_json::json!([{
-            "m": "notification",
-            "id": format!("ban-{}", current_time_ms()),
-            "title": "",
-            "text": format!("You have been banned from {} for {} seconds.", channel_id, duration_ms / 1000),
-            "class": "short",
-            "duration": 5000
-        }]);
-        let msg_str = serde_json::to_string(&ban_notification).unwrap_or_default();
-        self.server.send_to_client(&target_client_id, &msg_str).await;
+        for target_client_id in &target_client_ids {
+            self.join_channel(target_client_id, &kick_data).await;
+        }
 
-        let text = if target_user_id == client_ref.value().read().await.user_id {
+        let text = format!("You have been banned from {} for {} seconds.", channel_id, duration_ms / 1000);
+        let ban_notification = Notification::short(format!("ban-{}", current_time_ms()), text, 5000);
+        self.server.send_messages_to_user(target_user_id, &[ban_notification]).await;
+
+        let text = if target_user_id == actor_id {
             format!("Let it be known that {} kickbanned him/her self.", client_name)
         } else {
             format!("{} banned {} for {} seconds.", client_name, target_name, duration_ms / 1000)
         };
 
-        let broadcast_msg = serde_json::json!([{
-            "m": "notification",
-            "id": format!("ban-{}", current_time_ms()),
-            "title": "",
-            "text": text,
-            "class": "short",
-            "duration": 5000
-        }]);
+        let broadcast_msg = messages::batch(&[Notification::short(format!("ban-{}", current_time_ms()), text, 5000)]);
         self.server.broadcast_to_channel(&channel_id, &broadcast_msg, None).await;
     }
 
@@ -779,47 +1553,169 @@ impl MessageHandler {
             None => return,
         };
 
-        let client_ref = match self.server.clients.get(client_id) {
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
         
-        let client = client_ref.value().read().await;
+        let client = client_ref.read().await;
         let channel_id = match client.channel_id.as_ref() {
             Some(id) => id.clone(),
             None => return,
         };
+        let actor_id = client.user_id.clone();
+        let actor_name = client.participant.as_ref().map(|p| p.name.clone()).unwrap_or_default();
+        drop(client);
+
+        let holder = client_id.to_string();
+        let allowed = self.server.with_channel(&channel_id, move |_, channel| {
+            !channel.settings.lobby
+                && channel.crown.as_ref().is_none_or(|crown| crown.participant_id.as_deref() == Some(holder.as_str()))
+        });
+        if allowed.await != Some(true) {
+            return;
+        }
+
+        self.server.remove_ban(target_user_id, &channel_id);
+        self.server.audit(
+            AuditEntry::new(actor_id, "unban")
+                .actor_name(actor_name)
+                .channel(channel_id.as_str())
+                .target(target_user_id),
+        );
+
+        let text = format!("Unbanned user {}", target_user_id);
+        let notice = messages::batch(&[Notification::short(format!("unban-{}", current_time_ms()), text, 5000)]);
+        self.server.broadcast_to_channel(&channel_id, &notice, None).await;
+    }
+
+    /// The sender's own stats, see `Server::user_stats`.
+    async fn handle_stats(&self, client_id: &str) -> Option<Vec<serde_json::Value>> {
+        let client = self.server.clients.get(client_id).map(|c| c.value().clone())?;
+        let user_id = client.read().await.user_id.clone();
+        let stats = self.server.user_stats(&user_id).await;
+        Some(vec![Outgoing::Stats { stats }.to_value()])
+    }
+
+    /// Lists who is banned from the crown holder's room and until when.
+    async fn handle_bans(&self, client_id: &str) -> Option<Vec<serde_json::Value>> {
+        let client = self.server.clients.get(client_id).map(|c| c.value().clone())?;
+        let channel_id = client.read().await.channel_id.clone()?;
+        let holder = client_id.to_string();
+        let bans = self.server.with_channel(&channel_id, move |server, channel| {
+            if channel.settings.lobby || channel.crown.as_ref()?.participant_id.as_deref() != Some(holder.as_str()) {
+                return None;
+            }
+            let bans: Vec<_> = server
+                .channel_bans(&channel._id)
+                .into_iter()
+                .map(|(user_id, ban)| {
+                    let chatted_as =
+                        channel.chat_history.iter().rev().find(|m| m.p._id == user_id).map(|m| m.p.name.clone());
+                    (user_id, ban, chatted_as)
+                })
+                .collect();
+            Some(bans)
+        });
+        let bans = bans.await.flatten()?;
+
+        let mut entries = Vec::new();
+        for (user_id, ban, chatted_as) in bans {
+            // Whatever they're called now if they're online, else what they last chatted as here
+            let mut name = None;
+            let connections = self.server.user_connections.get(&user_id).map(|c| c.clone()).unwrap_or_default();
+            for id in connections {
+                let Some(client) = self.server.clients.get(&id).map(|c| c.value().clone()) else { continue };
+                name = client.read().await.participant.as_ref().map(|p| p.name.clone());
+                if name.is_some() {
+                    break;
+                }
+            }
+            entries.push(BanEntry { _id: user_id, name: name.or(chatted_as), expiry: ban.expiry });
+        }
+        entries.sort_by_key(|b| b.expiry);
 
-        let channel_ref = match self.server.channels.get(&channel_id) {
+        Some(vec![Outgoing::Bans { b: entries }.to_value()])
+    }
+
+    async fn handle_record(&self, client_id: &str, data: &serde_json::Value) {
+        let on = match data.get("on").and_then(|o| o.as_bool()) {
+            Some(on) => on,
+            None => return,
+        };
+
+        let client_ref = match self.server.clients.get(client_id).map(|c| c.value().clone()) {
             Some(c) => c,
             None => return,
         };
-        
-        let channel = channel_ref.value().read().await;
 
-        if channel.settings.lobby {
+        let client = client_ref.read().await;
+        let channel_id = match client.channel_id.as_ref() {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        drop(client);
+
+        if !self.holds_crown(client_id, &channel_id).await {
             return;
         }
 
-        if let Some(crown) = &channel.crown {
-            if crown.participant_id.as_deref() != Some(client_id) {
-                return;
+        match self.server.set_recording(&channel_id, on).await {
+            Ok(Some(notes)) => {
+                let text = format!("Recording saved ({} notes).", notes);
+                let notification = Notification::short(format!("recording-{}", current_time_ms()), text, 5000);
+                self.server.send_messages(client_id, &[notification]).await;
             }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to toggle recording for {}: {}", channel_id, e),
         }
+    }
 
-        drop(channel);
+    async fn handle_admin_message(&self, client_id: &str, data: &serde_json::Value) -> Option<Vec<serde_json::Value>> {
+        let password = data.get("password").and_then(|p| p.as_str())?;
 
-        self.server.banned_users.remove(target_user_id);
+        match &self.server.config().admin_password {
+            Some(expected) if secret_eq(expected, password) => {}
+            _ => {
+                warn!("Client {} sent an admin message with a bad password", client_id);
+                return None;
+            }
+        }
 
-        let notice = serde_json::json!([{
-            "m": "notification",
-            "id": format!("unban-{}", current_time_ms()),
-            "title": "",
-            "text": format!("Unbanned user {}", target_user_id),
-            "class": "short",
-            "duration": 5000
-        }]);
-        self.server.broadcast_to_channel(&channel_id, &notice, None).await;
+        let msg = data.get("msg")?.clone();
+        let cmd: AdminCommand = match serde_json::from_value(msg.clone()) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                warn!("Invalid admin command from {}: {}", client_id, e);
+                return None;
+            }
+        };
+
+        let text = match admin::execute(&self.server, cmd).await {
+            Ok(serde_json::Value::String(text)) => text,
+            Ok(result) => result.to_string(),
+            Err(e) => e,
+        };
+
+        if let Some(client) = self.server.clients.get(client_id).map(|c| c.value().clone()) {
+            let client = client.read().await;
+            let action = format!("admin {}", msg.get("m").and_then(|m| m.as_str()).unwrap_or_default());
+            let mut entry = AuditEntry::new(client.user_id.as_str(), action)
+                .details(serde_json::json!({ "msg": msg, "result": text }));
+            if let Some(participant) = &client.participant {
+                entry = entry.actor_name(participant.name.as_str());
+            }
+            self.server.audit(entry);
+        }
+
+        let notification = Notification {
+            id: Some(format!("admin-{}", current_time_ms())),
+            title: Some("Admin".to_string()),
+            text,
+            class: "short".to_string(),
+            duration: 5000,
+        };
+        Some(vec![Outgoing::Notification(notification).to_value()])
     }
 
     async fn handle_devices(&self, client_id: &str, data: &serde_json::Value) -> Option<Vec<serde_json::Value>> {
@@ -833,4 +1729,14 @@ impl MessageHandler {
             "list": list
         })])
     }
-}
\ No newline at end of file
+}
+
+/// Shown while notes are muted for quota violations, counting down each time the player tries
+/// to play. It keeps the same id so the client replaces the last one instead of stacking them.
+fn quota_mute_notice(remaining_ms: u64) -> Outgoing {
+    let text = format!(
+        "You kept playing too fast, so your notes are muted for {} more seconds.",
+        remaining_ms.div_ceil(1000)
+    );
+    Notification::short("Notification-quota-mute".to_string(), text, remaining_ms.min(5000))
+}
@@ -0,0 +1,32 @@
+use crate::utils::generate_random_id;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Tokens look like `<_id>.<signature>`, so they can be checked without storing anything.
+pub fn mint(secret: &str) -> (String, String) {
+    let user_id = generate_random_id();
+    let token = format!("{}.{}", user_id, sign(secret, &user_id));
+    (user_id, token)
+}
+
+/// Returns the `_id` a token was issued for, if it was signed with `secret`.
+pub fn verify(secret: &str, token: &str) -> Option<String> {
+    let (user_id, signature) = token.split_once('.')?;
+    if user_id.len() != 24 || !user_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let signature = hex::decode(signature).ok()?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(user_id.as_bytes());
+    mac.verify_truncated_left(&signature).ok()?;
+    Some(user_id.to_string())
+}
+
+fn sign(secret: &str, user_id: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(user_id.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..16])
+}
@@ -0,0 +1,152 @@
+//! An MPP (Multiplayer Piano) server. The `mpp-server` binary is a thin wrapper around this
+//! crate; other projects can embed it in their own axum app:
+//!
+//! ```no_run
+//! # async fn run() {
+//! use mpp_server::ServerBuilder;
+//! use std::net::SocketAddr;
+//!
+//! let server = ServerBuilder::new().filter_words(vec!["badword".into()]).build();
+//! let app = axum::Router::new().merge(mpp_server::router(&server));
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
+//! axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+//! # }
+//! ```
+
+use axum::{
+    extract::{
+        ws::{rejection::WebSocketUpgradeRejection, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Request, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tower_http::cors::CorsLayer;
+
+mod admin;
+mod api;
+mod assets;
+mod audit;
+mod auth;
+pub mod bots;
+mod builder;
+mod captcha;
+mod capture;
+mod challenge;
+mod channel_task;
+mod chatlog;
+mod cluster;
+pub mod config;
+#[cfg(unix)]
+mod control;
+mod dashboard;
+pub mod events;
+mod geoip;
+mod grpc;
+pub mod server;
+pub mod storage;
+pub mod types;
+pub mod handlers;
+mod identity;
+pub mod messages;
+mod midi;
+pub mod plugins;
+mod recording;
+mod reports;
+mod reputation;
+mod room_links;
+mod schedule;
+mod snapshot;
+mod screening;
+mod userdata;
+mod utils;
+mod webhook;
+
+pub use builder::ServerBuilder;
+pub use config::Config;
+pub use server::Server;
+
+/// Everything the server answers over HTTP: the WebSocket on `/` and `/ws`, the admin API,
+/// the dashboard, the MIDI bridge and the web client as the fallback. Serve it with
+/// `into_make_service_with_connect_info::<SocketAddr>()`, since connections are identified
+/// by their address.
+pub fn router(server: &Arc<Server>) -> Router {
+    Router::new()
+        .route("/", get(root_handler))
+        .route("/ws", get(ws_handler))
+        .merge(api::router(server))
+        .merge(dashboard::router(server))
+        .merge(midi::router())
+        .fallback(assets::serve)
+        .layer(CorsLayer::permissive())
+        .with_state(server.clone())
+}
+
+/// Stock MPP clients open their WebSocket on "/", so upgrade requests there are taken as
+/// connections, room links (`/?room=...`) are redirected to their room, and everything else
+/// gets the client page.
+async fn root_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    State(server): State<Arc<Server>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    match ws {
+        Ok(ws) => upgrade(ws, server, addr).await,
+        Err(_) => match room_links::bootstrap(&server, addr, request.uri()) {
+            Some(response) => response,
+            None => assets::serve(State(server), request).await,
+        },
+    }
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(server): State<Arc<Server>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Response {
+    upgrade(ws, server, addr).await
+}
+
+async fn upgrade(ws: WebSocketUpgrade, server: Arc<Server>, addr: SocketAddr) -> Response {
+    if server.shutdown.is_cancelled() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let mut address = server::AddressInfo::default();
+    if let Some(reputation) = &server.reputation {
+        address.ip_flagged = reputation.is_flagged(addr.ip()).await;
+        if address.ip_flagged && reputation.config.action == config::IpReputationAction::Block {
+            tracing::info!("Refused a connection from {}, its address is flagged", addr.ip());
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+    if let Some(geoip) = &server.geoip {
+        address.country = geoip.country(addr.ip());
+        if !geoip.allows(address.country.as_deref()) {
+            tracing::info!(
+                "Refused a connection from {} ({}), its region isn't allowed",
+                addr.ip(),
+                address.country.as_deref().unwrap_or("unknown")
+            );
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    let max_bytes = server.config().max_message_bytes;
+    ws.max_message_size(max_bytes)
+        .max_frame_size(max_bytes)
+        .on_upgrade(move |socket| handle_socket(socket, server, addr, address))
+        .into_response()
+}
+
+async fn handle_socket(socket: WebSocket, server: Arc<Server>, addr: SocketAddr, address: server::AddressInfo) {
+    let ip = utils::normalize_ip(addr.ip(), server.config().ipv6_prefix_len);
+
+    if let Err(e) = server.handle_connection(socket, ip, None, address).await {
+        tracing::error!("Error handling connection: {}", e);
+    }
+}
@@ -1,20 +1,8 @@
-use axum::{
-    extract::ws::{WebSocket, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::get,
-    Router,
-};
-use std::{net::SocketAddr, sync::Arc};
-use tower_http::{cors::CorsLayer, services::ServeDir};
+use axum_server::tls_rustls::RustlsConfig;
+use mpp_server::{Config, Server};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tracing_subscriber::prelude::*;
 
-mod server;
-mod types;
-mod handlers;
-mod utils;
-
-use server::Server;
-
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -25,46 +13,109 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let server = Arc::new(Server::new());
+    let config = Config::load().expect("Failed to load config");
+    let server = Server::new(&config);
+
+    if std::env::args().skip(1).any(|arg| arg == "--restore") {
+        match server.restore_snapshot().await {
+            Ok(count) => tracing::info!("Picked up {} rooms from the last snapshot", count),
+            Err(e) => tracing::error!("Failed to restore the snapshot, starting fresh: {:#}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(server.clone()));
 
-    let app = Router::new()
-        .route("/ws", get(ws_handler)) // Idk how to get this to stay on "/" without getting "Connection header did not include 'upgrade'"
-        .fallback_service(ServeDir::new("client").append_index_html_on_directories(true))
-        .layer(CorsLayer::permissive())
-        .with_state(server.clone());
+    let app = mpp_server::router(&server);
 
-    let port = std::env::var("WS_PORT")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(8080);
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!("Server running on port: {}", port);
+    match &config.tls {
+        Some(tls) => {
+            // reqwest pulls in ring as well, so name it rather than leave rustls to guess
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let rustls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .expect("Failed to load TLS certificate/key");
 
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .expect("Failed to bind");
+            tracing::info!("Server running on port: {} (TLS)", config.port);
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>()
-    )
-    .await
-    .expect("Server error");
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let server = server.clone();
+                async move {
+                    shutdown_signal(server).await;
+                    handle.graceful_shutdown(Some(Duration::from_secs(5)));
+                }
+            });
+
+            axum_server::bind_rustls(addr, rustls_config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .expect("Server error");
+        }
+        None => {
+            tracing::info!("Server running on port: {}", config.port);
+
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .expect("Failed to bind");
+
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>()
+            )
+            .with_graceful_shutdown(shutdown_signal(server.clone()))
+            .await
+            .expect("Server error");
+        }
+    }
+
+    server.wait_for_connections(Duration::from_secs(5)).await;
+    server.save_channels().await;
+    server.flush_user_stats().await;
+    server.writes_done().await;
+    tracing::info!("Server stopped");
 }
 
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    axum::extract::State(server): axum::extract::State<Arc<Server>>,
-    axum::extract::ConnectInfo(addr): axum::extract::ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, server, addr))
+async fn shutdown_signal(server: Arc<Server>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+        _ = server.shutdown.cancelled() => {},
+    }
+
+    server.begin_shutdown().await;
 }
 
-async fn handle_socket(socket: WebSocket, server: Arc<Server>, addr: SocketAddr) {
-    let ip = addr.ip().to_string();
-    
-    if let Err(e) = server.handle_connection(socket, ip).await {
-        tracing::error!("Error handling connection: {}", e);
+#[cfg(unix)]
+async fn reload_on_hangup(server: Arc<Server>) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("Got SIGHUP, reloading config");
+        if let Err(e) = server.reload_config().await {
+            tracing::error!("Failed to reload config, keeping the old one: {}", e);
+        }
     }
-}
\ No newline at end of file
+}
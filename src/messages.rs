@@ -0,0 +1,450 @@
+use crate::types::{Capability, ChannelSettings, Crown, Note, Participant, UserStats};
+use serde::{Deserialize, Serialize};
+
+/// Everything the server sends that regular MPP clients understand. Building these instead
+/// of `json!` objects turns a misspelled field into a compile error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "m")]
+pub enum Outgoing {
+    #[serde(rename = "hi")]
+    Hi {
+        u: Participant,
+        t: u64,
+        v: String,
+        motd: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<String>,
+        /// The client's capabilities this server supports, only when it listed some.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Vec<Capability>>,
+        /// Always `{}`, with `protocol = "mppclone"`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        permissions: Option<serde_json::Value>,
+    },
+    #[serde(rename = "ch")]
+    Ch {
+        ch: ChannelInfo,
+        ppl: Vec<Participant>,
+        /// The receiver's own participant id, only on their join.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        p: Option<String>,
+    },
+    #[serde(rename = "p")]
+    P(Participant),
+    #[serde(rename = "ls")]
+    Ls { c: bool, u: Vec<LsEntry> },
+    #[serde(rename = "n")]
+    N {
+        t: u64,
+        n: Vec<Note>,
+        p: String,
+    },
+    #[serde(rename = "m")]
+    M { id: String, x: f64, y: f64 },
+    #[serde(rename = "a")]
+    A {
+        a: String,
+        p: Participant,
+        t: u64,
+        /// Only with `protocol = "mppclone"`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    /// A direct message, sent to its recipient and echoed to its sender.
+    #[serde(rename = "dm")]
+    Dm {
+        a: String,
+        sender: Participant,
+        recipient: Participant,
+        t: u64,
+    },
+    #[serde(rename = "bye")]
+    Bye { p: String },
+    #[serde(rename = "notification")]
+    Notification(Notification),
+    #[serde(rename = "nq")]
+    Nq {
+        allowance: i32,
+        max: i32,
+        #[serde(rename = "maxHistLen")]
+        max_hist_len: usize,
+    },
+    #[serde(rename = "t")]
+    T { t: u64, e: serde_json::Value },
+    /// Active bans in the room, for its crown holder.
+    #[serde(rename = "bans")]
+    Bans { b: Vec<BanEntry> },
+    /// The recipient's own stats.
+    #[serde(rename = "stats")]
+    Stats { stats: UserStats },
+}
+
+impl Outgoing {
+    pub fn to_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("outgoing messages always serialize")
+    }
+}
+
+/// The array every frame is sent as.
+pub fn batch(messages: &[Outgoing]) -> serde_json::Value {
+    serde_json::to_value(messages).expect("outgoing messages always serialize")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub _id: String,
+    pub settings: ChannelSettings,
+    pub crown: Option<Crown>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LsEntry {
+    Channel {
+        _id: String,
+        count: usize,
+        crown: Option<Crown>,
+        settings: Box<ChannelSettings>,
+    },
+    /// Tells lists to drop a room that was deleted or hidden.
+    Removed { _id: String, count: usize, removed: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub _id: String,
+    /// Last name seen for the user, when there is one to go by.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub expiry: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub text: String,
+    pub class: String,
+    pub duration: u64,
+}
+
+impl Notification {
+    /// A "short" toast with an empty title, the kind most server notices use.
+    pub fn short(id: String, text: impl Into<String>, duration: u64) -> Outgoing {
+        Outgoing::Notification(Self {
+            id: Some(id),
+            title: Some(String::new()),
+            text: text.into(),
+            class: "short".to_string(),
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Position, Tag};
+    use serde_json::json;
+
+    fn participant() -> Participant {
+        Participant {
+            id: "p1".to_string(),
+            _id: "u1".to_string(),
+            name: "Anonymous".to_string(),
+            color: "#abcdef".to_string(),
+            x: 12.5,
+            y: 50.0,
+            tag: None,
+            afk: false,
+            typing: false,
+        }
+    }
+
+    fn settings() -> ChannelSettings {
+        ChannelSettings {
+            color: "#ecfaed".to_string(),
+            color2: None,
+            lobby: false,
+            visible: true,
+            chat: Some(true),
+            crownsolo: None,
+            note_quota: None,
+            limit: None,
+            noindex: None,
+            no_cussing: None,
+            min_online_time: None,
+            chat_history_length: None,
+            auto_pass_crown: None,
+            note_batch_ms: None,
+            cursor_throttle_ms: None,
+        }
+    }
+
+    fn crown() -> Crown {
+        Crown {
+            participant_id: Some("p1".to_string()),
+            user_id: Some("u1".to_string()),
+            time: 1000,
+            start_pos: Position { x: 0.0, y: 0.0 },
+            end_pos: Position { x: 1.0, y: 2.0 },
+        }
+    }
+
+    fn assert_wire(message: Outgoing, expected: serde_json::Value) {
+        assert_eq!(message.to_value(), expected);
+        assert_eq!(serde_json::from_value::<Outgoing>(expected).unwrap(), message);
+    }
+
+    #[test]
+    fn hi() {
+        assert_wire(
+            Outgoing::Hi {
+                u: participant(),
+                t: 1000,
+                v: "1.0.0".to_string(),
+                motd: "Welcome".to_string(),
+                token: None,
+                codec: None,
+                capabilities: None,
+                permissions: None,
+            },
+            json!({
+                "m": "hi",
+                "u": {"id": "p1", "_id": "u1", "name": "Anonymous", "color": "#abcdef", "x": 12.5, "y": 50.0},
+                "t": 1000,
+                "v": "1.0.0",
+                "motd": "Welcome"
+            }),
+        );
+    }
+
+    #[test]
+    fn hi_with_capabilities() {
+        assert_wire(
+            Outgoing::Hi {
+                u: participant(),
+                t: 1000,
+                v: "1.0.0".to_string(),
+                motd: "Welcome".to_string(),
+                token: None,
+                codec: Some("msgpack".to_string()),
+                capabilities: Some(vec![Capability::MsgPack, Capability::Typing]),
+                permissions: None,
+            },
+            json!({
+                "m": "hi",
+                "u": {"id": "p1", "_id": "u1", "name": "Anonymous", "color": "#abcdef", "x": 12.5, "y": 50.0},
+                "t": 1000,
+                "v": "1.0.0",
+                "motd": "Welcome",
+                "codec": "msgpack",
+                "capabilities": ["msgpack", "typing"]
+            }),
+        );
+    }
+
+    #[test]
+    fn ch() {
+        assert_wire(
+            Outgoing::Ch {
+                ch: ChannelInfo {
+                    _id: "room".to_string(),
+                    settings: settings(),
+                    crown: Some(crown()),
+                },
+                ppl: vec![participant()],
+                p: Some("p1".to_string()),
+            },
+            json!({
+                "m": "ch",
+                "ch": {
+                    "_id": "room",
+                    "settings": {"color": "#ecfaed", "lobby": false, "visible": true, "chat": true},
+                    "crown": {
+                        "participantId": "p1",
+                        "userId": "u1",
+                        "time": 1000,
+                        "startPos": {"x": 0.0, "y": 0.0},
+                        "endPos": {"x": 1.0, "y": 2.0}
+                    }
+                },
+                "ppl": [{"id": "p1", "_id": "u1", "name": "Anonymous", "color": "#abcdef", "x": 12.5, "y": 50.0}],
+                "p": "p1"
+            }),
+        );
+    }
+
+    #[test]
+    fn p() {
+        let mut p = participant();
+        p.tag = Some(Tag::Text("bot".to_string()));
+        p.afk = true;
+        assert_wire(
+            Outgoing::P(p),
+            json!({
+                "m": "p",
+                "id": "p1",
+                "_id": "u1",
+                "name": "Anonymous",
+                "color": "#abcdef",
+                "x": 12.5,
+                "y": 50.0,
+                "tag": "bot",
+                "afk": true
+            }),
+        );
+
+        let mut p = participant();
+        p.tag = Some(Tag::Styled {
+            text: "bot".to_string(),
+            color: "#55ff55".to_string(),
+        });
+        assert_wire(
+            Outgoing::P(p),
+            json!({
+                "m": "p",
+                "id": "p1",
+                "_id": "u1",
+                "name": "Anonymous",
+                "color": "#abcdef",
+                "x": 12.5,
+                "y": 50.0,
+                "tag": {"text": "bot", "color": "#55ff55"}
+            }),
+        );
+    }
+
+    #[test]
+    fn ls() {
+        assert_wire(
+            Outgoing::Ls {
+                c: false,
+                u: vec![
+                    LsEntry::Channel {
+                        _id: "lobby".to_string(),
+                        count: 3,
+                        crown: None,
+                        settings: Box::new(settings()),
+                    },
+                    LsEntry::Removed {
+                        _id: "gone".to_string(),
+                        count: 0,
+                        removed: true,
+                    },
+                ],
+            },
+            json!({
+                "m": "ls",
+                "c": false,
+                "u": [
+                    {
+                        "_id": "lobby",
+                        "count": 3,
+                        "crown": null,
+                        "settings": {"color": "#ecfaed", "lobby": false, "visible": true, "chat": true}
+                    },
+                    {"_id": "gone", "count": 0, "removed": true}
+                ]
+            }),
+        );
+    }
+
+    #[test]
+    fn n_and_m() {
+        assert_wire(
+            Outgoing::N {
+                t: 1000,
+                n: vec![Note {
+                    n: "a1".to_string(),
+                    v: Some(0.5),
+                    d: None,
+                    s: None,
+                }],
+                p: "p1".to_string(),
+            },
+            json!({"m": "n", "t": 1000, "n": [{"n": "a1", "v": 0.5}], "p": "p1"}),
+        );
+        assert_wire(
+            Outgoing::M {
+                id: "p1".to_string(),
+                x: 10.0,
+                y: 20.5,
+            },
+            json!({"m": "m", "id": "p1", "x": 10.0, "y": 20.5}),
+        );
+    }
+
+    #[test]
+    fn a_and_bye() {
+        assert_wire(
+            Outgoing::A {
+                a: "hello".to_string(),
+                p: participant(),
+                t: 1000,
+                id: None,
+            },
+            json!({
+                "m": "a",
+                "a": "hello",
+                "p": {"id": "p1", "_id": "u1", "name": "Anonymous", "color": "#abcdef", "x": 12.5, "y": 50.0},
+                "t": 1000
+            }),
+        );
+        assert_wire(Outgoing::Bye { p: "p1".to_string() }, json!({"m": "bye", "p": "p1"}));
+    }
+
+    #[test]
+    fn notification() {
+        assert_wire(
+            Notification::short("Notification-1".to_string(), "Hi there", 5000),
+            json!({
+                "m": "notification",
+                "id": "Notification-1",
+                "title": "",
+                "text": "Hi there",
+                "class": "short",
+                "duration": 5000
+            }),
+        );
+    }
+
+    #[test]
+    fn nq_and_t() {
+        assert_wire(
+            Outgoing::Nq {
+                allowance: 400,
+                max: 1200,
+                max_hist_len: 3,
+            },
+            json!({"m": "nq", "allowance": 400, "max": 1200, "maxHistLen": 3}),
+        );
+        assert_wire(
+            Outgoing::T { t: 1000, e: json!(999) },
+            json!({"m": "t", "t": 1000, "e": 999}),
+        );
+    }
+
+    #[test]
+    fn bans() {
+        assert_wire(
+            Outgoing::Bans {
+                b: vec![
+                    BanEntry { _id: "u1".to_string(), name: Some("Anonymous".to_string()), expiry: 5000 },
+                    BanEntry { _id: "u2".to_string(), name: None, expiry: 6000 },
+                ],
+            },
+            json!({
+                "m": "bans",
+                "b": [
+                    {"_id": "u1", "name": "Anonymous", "expiry": 5000},
+                    {"_id": "u2", "expiry": 6000}
+                ]
+            }),
+        );
+    }
+}
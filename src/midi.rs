@@ -0,0 +1,188 @@
+use crate::recording::note_name;
+use crate::server::Server;
+use crate::utils;
+use axum::{
+    extract::{ws::WebSocketUpgrade, ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// WebSocket endpoint at `/midi` for hardware bridges: a small program next to a keyboard
+/// connects with `?channel=..&name=..` and sends raw MIDI bytes as binary frames. The bot
+/// token goes in an `Authorization: Bearer ..` or `X-Bot-Token` header rather than the URL,
+/// so it doesn't end up in proxy and access logs. The bridge joins the room as a bot (the
+/// token has to be one of `[bots] tokens`) and every frame
+/// is played there as one `n` message. Everything else about the connection (quota, rate
+/// limits, kicks, text frames) works like a normal client's.
+pub fn router() -> Router<Arc<Server>> {
+    Router::new().route("/midi", get(upgrade))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MidiBridge {
+    pub channel: String,
+    /// From the request headers, never the query string
+    #[serde(skip)]
+    pub token: String,
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_name() -> String {
+    "MIDI keyboard".to_string()
+}
+
+impl MidiBridge {
+    /// What the bridge "sends" as soon as it's connected: a bot `hi`, its name and the join.
+    pub fn handshake(&self) -> Vec<serde_json::Value> {
+        vec![
+            json!({ "m": "hi", "botToken": self.token }),
+            json!({ "m": "userset", "set": { "name": self.name } }),
+            json!({ "m": "ch", "_id": self.channel }),
+        ]
+    }
+}
+
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    State(server): State<Arc<Server>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(mut bridge): Query<MidiBridge>,
+) -> Response {
+    if server.shutdown.is_cancelled() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let Some(token) = bearer_token(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if !server.config().bots.tokens.iter().any(|t| utils::secret_eq(t, &token)) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    bridge.token = token;
+
+    let max_bytes = server.config().max_message_bytes;
+    ws.max_message_size(max_bytes)
+        .max_frame_size(max_bytes)
+        .on_upgrade(move |socket| async move {
+            let ip = utils::normalize_ip(addr.ip(), server.config().ipv6_prefix_len);
+            if let Err(e) = server.handle_connection(socket, ip, Some(bridge), Default::default()).await {
+                tracing::error!("Error handling MIDI bridge connection: {}", e);
+            }
+        })
+        .into_response()
+}
+
+/// The bot token from `Authorization: Bearer <token>`, or `X-Bot-Token: <token>`.
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer
+        .or_else(|| headers.get("x-bot-token").and_then(|v| v.to_str().ok()))
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Turns a frame of raw MIDI into an `n` message. Note on is a note start with the velocity
+/// scaled to 0-1, note off (or note on with velocity 0) a stop. Running status is followed,
+/// everything that isn't a note is skipped, and keys off the piano are dropped.
+pub fn to_messages(data: &[u8], t: u64) -> Vec<serde_json::Value> {
+    // Real-time bytes (clock, active sensing) can show up anywhere, even in the middle of
+    // another message, and leave running status alone
+    let data: Vec<u8> = data.iter().copied().filter(|b| *b < 0xf8).collect();
+    let mut notes = Vec::new();
+    let mut status = 0u8;
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        if byte >= 0xf0 {
+            // The other system messages carry their own lengths and cancel running status
+            i += 1;
+            i += match byte {
+                0xf0 => data[i..].iter().position(|b| *b == 0xf7).map_or(data.len() - i, |end| end + 1),
+                0xf1 | 0xf3 => 1,
+                0xf2 => 2,
+                _ => 0,
+            };
+            status = 0;
+            continue;
+        }
+        if byte & 0x80 != 0 {
+            status = byte;
+            i += 1;
+        }
+
+        let len = match status & 0xf0 {
+            0x80 | 0x90 | 0xa0 | 0xb0 | 0xe0 => 2,
+            0xc0 | 0xd0 => 1,
+            // Data bytes with no status to go with them
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let Some(bytes) = data.get(i..i + len) else { break };
+        i += len;
+
+        let (key, velocity) = (bytes[0], bytes.get(1).copied().unwrap_or(0));
+        let Some(name) = note_name(key) else { continue };
+        match status & 0xf0 {
+            0x90 if velocity > 0 => notes.push(json!({ "n": name, "v": f64::from(velocity) / 127.0 })),
+            0x80 | 0x90 => notes.push(json!({ "n": name, "s": 1 })),
+            _ => {}
+        }
+    }
+
+    if notes.is_empty() {
+        return Vec::new();
+    }
+    vec![json!({ "m": "n", "t": t, "n": notes })]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_on_and_off_become_one_message() {
+        let msgs = to_messages(&[0x90, 60, 127, 0x80, 60, 0], 5);
+        assert_eq!(msgs, vec![json!({ "m": "n", "t": 5, "n": [{ "n": "c3", "v": 1.0 }, { "n": "c3", "s": 1 }] })]);
+    }
+
+    #[test]
+    fn running_status_and_zero_velocity() {
+        // One status byte for two notes, the second a note on with velocity 0
+        let msgs = to_messages(&[0x91, 69, 64, 69, 0], 0);
+        assert_eq!(msgs[0]["n"], json!([{ "n": "a3", "v": 64.0 / 127.0 }, { "n": "a3", "s": 1 }]));
+    }
+
+    #[test]
+    fn skips_everything_but_notes() {
+        // SysEx, a clock tick, a control change, a program change and a key off the piano
+        let data = [0xf0, 0x7e, 0x01, 0xf7, 0xf8, 0xb0, 64, 127, 0xc0, 3, 0x90, 10, 100];
+        assert!(to_messages(&data, 0).is_empty());
+        // Real-time bytes in the middle of a note keep running status
+        let msgs = to_messages(&[0x90, 0xf8, 60, 100, 62, 100], 0);
+        assert_eq!(msgs[0]["n"].as_array().unwrap().len(), 2);
+        // A cut-off message at the end is dropped
+        assert!(to_messages(&[0x90, 60], 0).is_empty());
+    }
+
+    #[test]
+    fn token_comes_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert("x-bot-token", "abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers).as_deref(), Some("abc"));
+        headers.insert(header::AUTHORIZATION, "Bearer xyz".parse().unwrap());
+        assert_eq!(bearer_token(&headers).as_deref(), Some("xyz"));
+    }
+}
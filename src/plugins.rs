@@ -0,0 +1,240 @@
+use crate::config::PluginConfig;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Points where plugins get to look at an event before it happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// `{"channel", "participant", "message"}`, `message` can be changed.
+    Chat,
+    /// `{"channel", "participant"}` when someone asks to join, `channel` can be changed to
+    /// send them somewhere else. Kicks and other forced moves don't go through it.
+    Join,
+    /// `{"channel", "participant", "set"}`, `set` can be changed.
+    Chset,
+}
+
+impl Hook {
+    pub fn export_name(self) -> &'static str {
+        match self {
+            Hook::Chat => "on_chat",
+            Hook::Join => "on_join",
+            Hook::Chset => "on_chset",
+        }
+    }
+}
+
+/// WebAssembly plugins from `[plugins] modules`, run with wasmtime when the server is built
+/// with the `plugins` feature.
+///
+/// A module exports its `memory`, `alloc(len: i32) -> i32` and any of `on_chat`, `on_join`
+/// and `on_chset` as `(ptr: i32, len: i32) -> i64`. The host allocates room for the event
+/// as JSON, writes it there and calls the hook, which returns 0 to let the event through
+/// unchanged, -1 to reject it, or the event to use instead as JSON at `ptr << 32 | len` in
+/// its memory. The host never frees anything, so plugins usually reset a bump allocator on
+/// each call. A plugin that traps or runs out of `fuel` is logged and treated as returning 0.
+pub struct PluginHost {
+    plugins: Arc<Vec<wasm::Plugin>>,
+}
+
+/// What a hook decided.
+#[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+enum Verdict {
+    Allow,
+    Replace(serde_json::Value),
+    Reject,
+}
+
+impl PluginHost {
+    pub fn load(config: &PluginConfig) -> Self {
+        let plugins = config
+            .modules
+            .iter()
+            .filter_map(|path| match wasm::Plugin::load(path, config.fuel) {
+                Ok(plugin) => {
+                    info!("Loaded plugin {}", path.display());
+                    Some(plugin)
+                }
+                Err(e) => {
+                    error!("Failed to load plugin {}: {:#}", path.display(), e);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            plugins: Arc::new(plugins),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Runs `event` through every plugin that has the hook, in the order they were listed,
+    /// each seeing the last one's changes. Returns the event to go ahead with, or `None` if a
+    /// plugin rejected it. Plugins can run for as long as their fuel lasts, so the calls go on
+    /// tokio's blocking pool rather than holding up a worker thread.
+    pub async fn run(&self, hook: Hook, event: serde_json::Value) -> Option<serde_json::Value> {
+        if self.plugins.is_empty() {
+            return Some(event);
+        }
+        let plugins = self.plugins.clone();
+        let unchanged = event.clone();
+        match tokio::task::spawn_blocking(move || Self::run_blocking(&plugins, hook, event)).await {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Plugins panicked in {}: {}", hook.export_name(), e);
+                Some(unchanged)
+            }
+        }
+    }
+
+    fn run_blocking(plugins: &[wasm::Plugin], hook: Hook, mut event: serde_json::Value) -> Option<serde_json::Value> {
+        for plugin in plugins {
+            match plugin.call(hook, &event) {
+                Ok(Verdict::Allow) => {}
+                Ok(Verdict::Replace(replacement)) => event = replacement,
+                Ok(Verdict::Reject) => return None,
+                Err(e) => warn!("Plugin {} failed in {}: {:#}", plugin.name(), hook.export_name(), e),
+            }
+        }
+        Some(event)
+    }
+}
+
+#[cfg(not(feature = "plugins"))]
+mod wasm {
+    use super::{Hook, Verdict};
+    use std::path::Path;
+
+    /// Nothing can be loaded without wasmtime, so there are never any of these.
+    pub enum Plugin {}
+
+    impl Plugin {
+        pub fn load(_path: &Path, _fuel: u64) -> anyhow::Result<Self> {
+            anyhow::bail!("this server was built without the plugins feature")
+        }
+
+        pub fn name(&self) -> &str {
+            match *self {}
+        }
+
+        pub fn call(&self, _hook: Hook, _event: &serde_json::Value) -> anyhow::Result<Verdict> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(feature = "plugins")]
+mod wasm {
+    use super::{Hook, Verdict};
+    use anyhow::Context;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use wasmtime::{Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+    pub struct Plugin {
+        name: String,
+        fuel: u64,
+        state: Mutex<State>,
+    }
+
+    struct State {
+        store: Store<()>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        hooks: HashMap<&'static str, TypedFunc<(i32, i32), i64>>,
+    }
+
+    impl Plugin {
+        pub fn load(path: &Path, fuel: u64) -> anyhow::Result<Self> {
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, path)?;
+            let mut store = Store::new(&engine, ());
+            let instance: Instance = Linker::new(&engine).instantiate(&mut store, &module)?;
+
+            let memory = instance.get_memory(&mut store, "memory").context("no exported memory")?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let mut hooks = HashMap::new();
+            for hook in [Hook::Chat, Hook::Join, Hook::Chset] {
+                if let Ok(func) = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook.export_name()) {
+                    hooks.insert(hook.export_name(), func);
+                }
+            }
+
+            Ok(Self {
+                name: path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned()),
+                fuel,
+                state: Mutex::new(State { store, memory, alloc, hooks }),
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        pub fn call(&self, hook: Hook, event: &serde_json::Value) -> anyhow::Result<Verdict> {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let State { store, memory, alloc, hooks } = &mut *state;
+            let Some(func) = hooks.get(hook.export_name()) else {
+                return Ok(Verdict::Allow);
+            };
+
+            let input = serde_json::to_vec(event)?;
+            store.set_fuel(self.fuel)?;
+            let ptr = alloc.call(&mut *store, i32::try_from(input.len())?)?;
+            memory.write(&mut *store, ptr as u32 as usize, &input)?;
+
+            let result = func.call(&mut *store, (ptr, input.len() as i32))?;
+            match result {
+                0 => Ok(Verdict::Allow),
+                -1 => Ok(Verdict::Reject),
+                packed => {
+                    let (ptr, len) = ((packed as u64 >> 32) as usize, (packed as u64 & 0xffff_ffff) as usize);
+                    let mut output = vec![0; len];
+                    memory.read(&*store, ptr, &mut output)?;
+                    Ok(Verdict::Replace(serde_json::from_slice(&output)?))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "plugins"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Rejects every chat, lets joins through and swaps any chset for `{"set":{}}`.
+    const MODULE: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 1024) "{\"set\":{}}")
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "on_chat") (param i32 i32) (result i64) (i64.const -1))
+            (func (export "on_join") (param i32 i32) (result i64) (i64.const 0))
+            (func (export "on_chset") (param i32 i32) (result i64)
+                (i64.or (i64.shl (i64.const 1024) (i64.const 32)) (i64.const 10))))
+    "#;
+
+    #[tokio::test]
+    async fn hooks_allow_reject_and_replace() {
+        let path = std::env::temp_dir().join(format!("mpp-plugin-test-{}.wat", std::process::id()));
+        std::fs::write(&path, MODULE).unwrap();
+        let host = PluginHost::load(&PluginConfig {
+            modules: vec![path.clone()],
+            ..Default::default()
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert!(!host.is_empty());
+
+        let event = json!({ "channel": "lobby", "message": "hi" });
+        assert_eq!(host.run(Hook::Chat, event.clone()).await, None);
+        assert_eq!(host.run(Hook::Join, event.clone()).await, Some(event));
+        let set = json!({ "channel": "lobby", "set": { "color": "#000000" } });
+        assert_eq!(host.run(Hook::Chset, set).await, Some(json!({ "set": {} })));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::types::Note;
+use std::path::{Path, PathBuf};
+
+// One tick per millisecond: 500 ticks per quarter at the default 120bpm tempo
+const TICKS_PER_QUARTER: u16 = 500;
+const TEMPO_US_PER_QUARTER: u32 = 500_000;
+const MAX_EVENTS: usize = 1_000_000;
+
+#[derive(Debug, Clone)]
+struct NoteEvent {
+    time: u64,
+    note: u8,
+    velocity: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub started_at: u64,
+    events: Vec<NoteEvent>,
+}
+
+impl Recording {
+    pub fn new(started_at: u64) -> Self {
+        Self {
+            started_at,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Records the notes of one `n` message, `t` being the message's base timestamp.
+    pub fn record(&mut self, t: u64, notes: &[Note]) {
+        for note in notes {
+            if self.events.len() >= MAX_EVENTS {
+                return;
+            }
+
+            let Some(number) = note_number(&note.n) else {
+                continue;
+            };
+
+            let time = t.saturating_add(note.d.unwrap_or(0)).saturating_sub(self.started_at);
+
+            let velocity = if note.is_stop() {
+                0
+            } else {
+                let v = note.v.unwrap_or(0.5);
+                (v * 127.0).round().clamp(1.0, 127.0) as u8
+            };
+
+            self.events.push(NoteEvent { time, note: number, velocity });
+        }
+    }
+
+    /// Encodes the recording as a format 0 Standard MIDI File.
+    pub fn to_midi(&self) -> Vec<u8> {
+        let mut events = self.events.clone();
+        events.sort_by_key(|e| e.time);
+
+        let mut track = Vec::new();
+        track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+        track.extend_from_slice(&TEMPO_US_PER_QUARTER.to_be_bytes()[1..]);
+
+        let mut last_time = 0;
+        for event in &events {
+            write_var_len(&mut track, (event.time - last_time).min(0x0FFF_FFFF) as u32);
+            last_time = event.time;
+            if event.velocity == 0 {
+                track.extend_from_slice(&[0x80, event.note, 0]);
+            } else {
+                track.extend_from_slice(&[0x90, event.note, event.velocity]);
+            }
+        }
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut file = Vec::with_capacity(track.len() + 22);
+        file.extend_from_slice(b"MThd");
+        file.extend_from_slice(&6u32.to_be_bytes());
+        file.extend_from_slice(&0u16.to_be_bytes());
+        file.extend_from_slice(&1u16.to_be_bytes());
+        file.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend_from_slice(&track);
+        file
+    }
+
+    pub async fn save(&self, dir: &Path, channel_id: &str) -> std::io::Result<PathBuf> {
+        tokio::fs::create_dir_all(dir).await?;
+
+        let safe_name: String = channel_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .take(64)
+            .collect();
+        let path = dir.join(format!("{}-{}.mid", safe_name, self.started_at));
+
+        tokio::fs::write(&path, self.to_midi()).await?;
+        Ok(path)
+    }
+}
+
+/// Maps MPP key names ("a-1" through "c7") to MIDI note numbers.
+pub fn note_number(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let base = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (sharp, octave) = match rest.strip_prefix('s') {
+        Some(octave) => (1, octave),
+        None => (0, rest),
+    };
+    let octave: i32 = octave.parse().ok()?;
+
+    let number = (octave + 2) * 12 + base + sharp;
+    u8::try_from(number).ok().filter(|n| *n <= 127)
+}
+
+/// The other way around: the MPP key name for a MIDI note number, for keys on the piano.
+pub fn note_name(number: u8) -> Option<String> {
+    const NAMES: [&str; 12] = ["c", "cs", "d", "ds", "e", "f", "fs", "g", "gs", "a", "as", "b"];
+    if !(21..=108).contains(&number) {
+        return None;
+    }
+    let octave = i32::from(number / 12) - 2;
+    Some(format!("{}{}", NAMES[usize::from(number % 12)], octave))
+}
+
+fn write_var_len(buf: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = [0u8; 4];
+    let mut i = 3;
+    bytes[i] = (value & 0x7F) as u8;
+    value >>= 7;
+    while value > 0 {
+        i -= 1;
+        bytes[i] = ((value & 0x7F) as u8) | 0x80;
+        value >>= 7;
+    }
+    buf.extend_from_slice(&bytes[i..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names_cover_the_piano() {
+        assert_eq!(note_name(21).as_deref(), Some("a-1"));
+        assert_eq!(note_name(60).as_deref(), Some("c3"));
+        assert_eq!(note_name(61).as_deref(), Some("cs3"));
+        assert_eq!(note_name(108).as_deref(), Some("c7"));
+        assert_eq!(note_name(20), None);
+        assert_eq!(note_name(109), None);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::types::ChatMessage;
+use crate::utils::{current_time_ms, generate_random_id};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Someone's `report` of another user, with what was said in the room around then.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub id: String,
+    pub t: u64,
+    pub reporter: String,
+    pub reporter_name: String,
+    /// User `_id` of whoever was reported.
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    pub reason: String,
+    /// The room's latest chat when the report came in, oldest first.
+    pub context: Vec<ChatMessage>,
+    /// Who marked it handled, from the admin API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_by: Option<String>,
+}
+
+impl Report {
+    pub fn new(reporter: String, reporter_name: String, target: String, reason: String) -> Self {
+        Self {
+            id: generate_random_id(),
+            t: current_time_ms(),
+            reporter,
+            reporter_name,
+            target,
+            target_name: None,
+            channel: None,
+            reason,
+            context: Vec::new(),
+            resolved_by: None,
+        }
+    }
+}
+
+/// Filters for `ReportQueue::list`, from the query string of `GET /api/admin/reports`.
+#[derive(Debug, Default, Deserialize)]
+pub struct ReportQuery {
+    /// Include reports already marked handled.
+    #[serde(default)]
+    pub all: bool,
+    pub target: Option<String>,
+}
+
+/// Reports waiting for a moderator, kept in memory. Past capacity the oldest go first,
+/// handled ones before open ones.
+pub struct ReportQueue {
+    reports: Mutex<VecDeque<Report>>,
+}
+
+impl ReportQueue {
+    pub fn new() -> Self {
+        Self {
+            reports: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn add(&self, report: Report, capacity: usize) {
+        let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        reports.push_back(report);
+        while reports.len() > capacity {
+            match reports.iter().position(|r| r.resolved_by.is_some()) {
+                Some(handled) => reports.remove(handled),
+                None => reports.pop_front(),
+            };
+        }
+    }
+
+    /// Matching reports, oldest first.
+    pub fn list(&self, query: &ReportQuery) -> Vec<Report> {
+        let reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        reports
+            .iter()
+            .filter(|r| query.all || r.resolved_by.is_none())
+            .filter(|r| query.target.as_ref().is_none_or(|t| &r.target == t))
+            .cloned()
+            .collect()
+    }
+
+    /// Marks a report handled. False when there's no open report with that id.
+    pub fn resolve(&self, id: &str, by: &str) -> bool {
+        let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        match reports.iter_mut().find(|r| r.id == id && r.resolved_by.is_none()) {
+            Some(report) => {
+                report.resolved_by = Some(by.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the reports `user_id` filed or was the target of, and takes their lines out of
+    /// the chat context of the rest. Returns how many reports went.
+    pub fn purge_user(&self, user_id: &str) -> usize {
+        let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        let before = reports.len();
+        reports.retain(|r| r.reporter != user_id && r.target != user_id);
+        for report in reports.iter_mut() {
+            report.context.retain(|m| m.p._id != user_id);
+        }
+        before - reports.len()
+    }
+
+    pub fn open_count(&self) -> usize {
+        let reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+        reports.iter().filter(|r| r.resolved_by.is_none()).count()
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::IpReputationConfig;
+use crate::utils::{current_time_ms, Cidr};
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Cached lookups past this many get pruned of the expired ones.
+const CACHE_PRUNE_AT: usize = 10_000;
+
+/// Decides whether a connecting address looks like a VPN, proxy or hosting provider: first
+/// against the configured CIDR ranges and list files, then, if that doesn't match and `api`
+/// is set, by asking the external service. Answers from the service are cached.
+pub struct IpReputation {
+    pub config: IpReputationConfig,
+    ranges: Vec<Cidr>,
+    cache: DashMap<IpAddr, (bool, u64)>,
+}
+
+impl IpReputation {
+    pub fn new(config: IpReputationConfig) -> Self {
+        let mut ranges = Vec::new();
+        for range in &config.ranges {
+            match range.parse::<Cidr>() {
+                Ok(cidr) => ranges.push(cidr),
+                Err(()) => warn!("Ignoring bad IP range {:?} in [ip_reputation]", range),
+            }
+        }
+        for path in &config.lists {
+            match std::fs::read_to_string(path) {
+                Ok(list) => {
+                    let before = ranges.len();
+                    ranges.extend(
+                        list.lines()
+                            .map(|line| line.split('#').next().unwrap_or_default().trim())
+                            .filter_map(|line| line.parse::<Cidr>().ok()),
+                    );
+                    info!("Loaded {} IP ranges from {}", ranges.len() - before, path.display());
+                }
+                Err(e) => error!("Failed to read IP list {}: {}", path.display(), e),
+            }
+        }
+
+        Self {
+            config,
+            ranges,
+            cache: DashMap::new(),
+        }
+    }
+
+    /// Whether `ip` is flagged. A lookup that fails counts as not flagged.
+    pub async fn is_flagged(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.ranges.iter().any(|range| range.contains(ip)) {
+            return true;
+        }
+        let Some(api) = &self.config.api else { return false };
+
+        let now = current_time_ms();
+        if let Some(cached) = self.cache.get(&ip).filter(|entry| entry.1 > now) {
+            return cached.0;
+        }
+        let flagged = match lookup(api, ip).await {
+            Ok(flagged) => flagged,
+            Err(e) => {
+                warn!("IP reputation lookup for {} failed: {:#}", ip, e);
+                return false;
+            }
+        };
+        debug!("IP reputation for {}: {}", ip, if flagged { "flagged" } else { "clean" });
+
+        if self.cache.len() >= CACHE_PRUNE_AT {
+            self.cache.retain(|_, entry| entry.1 > now);
+        }
+        self.cache.insert(ip, (flagged, now + api.cache_secs * 1000));
+        flagged
+    }
+}
+
+/// Fetches `url` with `{ip}` filled in and reads the value at `pointer` (which may contain
+/// `{ip}` too) from the JSON response. `true`, a non-zero number and "yes" or "true" (any
+/// case) mean flagged.
+async fn lookup(api: &crate::config::IpReputationApi, ip: IpAddr) -> anyhow::Result<bool> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let client = CLIENT.get_or_init(reqwest::Client::new);
+
+    let ip = ip.to_string();
+    let response: serde_json::Value = client
+        .get(api.url.replace("{ip}", &ip))
+        .timeout(Duration::from_millis(api.timeout_ms))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let flagged = match response.pointer(&api.pointer.replace("{ip}", &ip)) {
+        Some(serde_json::Value::Bool(b)) => *b,
+        Some(serde_json::Value::Number(n)) => n.as_f64().is_some_and(|n| n != 0.0),
+        Some(serde_json::Value::String(s)) => s.eq_ignore_ascii_case("yes") || s.eq_ignore_ascii_case("true"),
+        _ => false,
+    };
+    Ok(flagged)
+}
@@ -0,0 +1,88 @@
+use crate::server::Server;
+use crate::utils::{current_time_ms, normalize_ip};
+use axum::extract::Query;
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// How long a link's settings wait for the room to be created.
+const TEMPLATE_TTL_MS: u64 = 10 * 60 * 1000;
+/// Past this many waiting templates, links stop minting new ones until some expire.
+const MAX_TEMPLATES: usize = 10_000;
+
+/// Settings from a room link, waiting for whoever opened the link to create the room.
+pub struct RoomTemplate {
+    pub set: Value,
+    pub expires_at: u64,
+}
+
+/// Handles `/?room=<name>&<setting>=<value>...` when `room_links` is on: the other
+/// parameters become a template for the room, kept for the opener's address, and the
+/// browser is sent on to `/<name>`, where the client joins it. `None` for other requests.
+pub fn bootstrap(server: &Server, addr: SocketAddr, uri: &Uri) -> Option<Response> {
+    if !server.config().room_links {
+        return None;
+    }
+    let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    let room = params.remove("room")?;
+    if room.is_empty() || room.len() > 512 {
+        return Some(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    // Open rooms already have their settings, and lobbies can't be changed
+    if !params.is_empty() && !server.is_special_channel(&room) && !server.channels.contains_key(&room) {
+        let set = params.into_iter().map(|(key, value)| (key, query_value(&value))).collect();
+        let ip = normalize_ip(addr.ip(), server.config().ipv6_prefix_len);
+        mint(server, ip, room.clone(), Value::Object(set));
+    }
+    Some(Redirect::to(&format!("/{}", encode_path_segment(&room))).into_response())
+}
+
+fn mint(server: &Server, ip: String, room: String, set: Value) {
+    let now = current_time_ms();
+    if server.room_templates.len() >= MAX_TEMPLATES {
+        server.room_templates.retain(|_, template| template.expires_at > now);
+        if server.room_templates.len() >= MAX_TEMPLATES {
+            return;
+        }
+    }
+    server.room_templates.insert(
+        (ip, room),
+        RoomTemplate {
+            set,
+            expires_at: now + TEMPLATE_TTL_MS,
+        },
+    );
+}
+
+/// The settings a link from `ip` left for `room`, used up by taking them.
+pub fn take(server: &Server, ip: &str, room: &str) -> Option<Value> {
+    let (_, template) = server.room_templates.remove(&(ip.to_string(), room.to_string()))?;
+    (template.expires_at > current_time_ms()).then_some(template.set)
+}
+
+/// Query strings only carry text, so values that read as booleans, numbers or `null` are
+/// taken as those, the way `chset` expects them.
+fn query_value(value: &str) -> Value {
+    match value {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "null" => Value::Null,
+        _ => match value.parse::<u64>() {
+            Ok(n) => n.into(),
+            Err(_) => value.into(),
+        },
+    }
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::Deserialize;
+
+/// A cron-style `minute hour day-of-month month day-of-week` schedule, evaluated in UTC.
+/// Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and comma lists.
+/// Like cron, when both day fields are restricted either one matching is enough.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Schedule {:?} needs 5 fields", spec));
+        };
+
+        // 7 is Sunday too
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+            && day_matches
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        Self::parse(&spec)
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)),
+            None => (part, Some(1)),
+        };
+        let step = step.ok_or_else(|| format!("Bad step in {:?}", part))?;
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (parse_number(a)?, parse_number(b)?),
+                None => {
+                    let n = parse_number(range)?;
+                    // "5/10" means from 5 to the end in steps of 10
+                    (n, if part.contains('/') { max } else { n })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{:?} is outside {}-{}", part, min, max));
+        }
+
+        for n in (start..=end).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    s.parse().map_err(|_| format!("{:?} isn't a number", s))
+}
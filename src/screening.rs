@@ -0,0 +1,62 @@
+use crate::config::ScreeningConfig;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+
+#[derive(Default)]
+struct IpRecord {
+    connects: VecDeque<u64>,
+    strikes: VecDeque<u64>,
+}
+
+/// Scores new connections by what their IP has been doing lately. Everything is forgotten
+/// `window_secs` after it happened.
+pub struct Screener {
+    pub config: ScreeningConfig,
+    ips: DashMap<String, IpRecord>,
+}
+
+impl Screener {
+    pub fn new(config: ScreeningConfig) -> Self {
+        Self {
+            config,
+            ips: DashMap::new(),
+        }
+    }
+
+    /// Records a connection and returns its starting score: one point per connection past
+    /// `max_connects` in the window, plus one per recent strike against the IP.
+    pub fn on_connect(&self, ip: &str, now: u64) -> u32 {
+        let window_ms = self.config.window_secs * 1000;
+        let mut record = self.ips.entry(ip.to_string()).or_default();
+        prune(&mut record.connects, now, window_ms);
+        prune(&mut record.strikes, now, window_ms);
+        record.connects.push_back(now);
+
+        let excess = record.connects.len().saturating_sub(self.config.max_connects);
+        (excess + record.strikes.len()) as u32
+    }
+
+    /// Counts bad behaviour (note floods, never saying `hi`) against the IP's next connections.
+    pub fn strike(&self, ip: &str, now: u64) {
+        self.ips.entry(ip.to_string()).or_default().strikes.push_back(now);
+    }
+
+    pub fn is_flagged(&self, score: u32) -> bool {
+        score >= self.config.threshold
+    }
+
+    pub fn sweep(&self, now: u64) {
+        let window_ms = self.config.window_secs * 1000;
+        self.ips.retain(|_, record| {
+            prune(&mut record.connects, now, window_ms);
+            prune(&mut record.strikes, now, window_ms);
+            !record.connects.is_empty() || !record.strikes.is_empty()
+        });
+    }
+}
+
+fn prune(times: &mut VecDeque<u64>, now: u64, window_ms: u64) {
+    while times.front().is_some_and(|t| now.saturating_sub(*t) >= window_ms) {
+        times.pop_front();
+    }
+}
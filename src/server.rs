@@ -1,189 +1,2107 @@
+use crate::audit::{AuditEntry, AuditLog};
+use crate::capture::Capture;
+use crate::channel_task::{self, ChannelCommand};
+use crate::cluster::{Cluster, ClusterEvent};
+use crate::config::{
+    BlackMidiConfig, ChallengeConfig, Config, IpReputationAction, MessageRateAction, Protocol, ScreeningAction,
+};
+use crate::events::{EventBus, ServerEvent};
+use crate::geoip::GeoIp;
 use crate::handlers::MessageHandler;
-use crate::types::{BanInfo, Channel, ChannelSettings, ClientData, Crown, NoteQuota, Position};
-use crate::utils::{current_time_ms, generate_client_id, generate_random_id};
-use axum::extract::ws::{Message, WebSocket};
+use crate::messages::{self, LsEntry, Notification, Outgoing};
+use crate::midi::{self, MidiBridge};
+use crate::plugins::PluginHost;
+use crate::recording::Recording;
+use crate::reports::ReportQueue;
+use crate::reputation::IpReputation;
+use crate::room_links::RoomTemplate;
+use crate::screening::Screener;
+use crate::storage::{SavedChannel, Storage, StorageWrite, UserProfile};
+use crate::types::{
+    BanInfo, Channel, ChatMessage, ClientData, Crown, LeaderboardEntry, LeaderboardMetric, LeaderboardWindow,
+    MessageRate, NoteQuota, NoteQuotaParams, Position, Strikes, Tag, UserStats,
+};
+use crate::utils::{current_time_ms, generate_client_id, generate_random_id, is_lobby};
+use axum::extract::ws::{close_code, CloseFrame, Message, Utf8Bytes, WebSocket};
 use dashmap::DashMap;
 use futures::{SinkExt, StreamExt};
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Color of mppclone-style tags that `tag_colors` doesn't list.
+const DEFAULT_TAG_COLOR: &str = "#777777";
+
+/// Wire encoding for a connection. Everything defaults to JSON text frames; clients that
+/// negotiate MessagePack in `hi` get `n` and `m` broadcasts as binary frames instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Codec::Json),
+            "msgpack" => Some(Codec::MsgPack),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MsgPack => "msgpack",
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> anyhow::Result<Vec<serde_json::Value>> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(data)?),
+            Codec::MsgPack => Ok(rmp_serde::from_slice(data)?),
+        }
+    }
+
+    pub fn encode(&self, messages: &serde_json::Value) -> anyhow::Result<Message> {
+        match self {
+            Codec::Json => Ok(Message::Text(serde_json::to_string(messages)?.into())),
+            Codec::MsgPack => Ok(Message::Binary(rmp_serde::to_vec_named(messages)?.into())),
+        }
+    }
+
+    /// Only the high-volume note and cursor messages go out in binary.
+    fn is_binary_eligible(messages: &serde_json::Value) -> bool {
+        messages.as_array().is_some_and(|msgs| {
+            msgs.iter().all(|msg| matches!(msg.get("m").and_then(|m| m.as_str()), Some("n" | "m")))
+        })
+    }
+}
+
+/// One message fanned out to a whole channel. Each connection's sender task picks the
+/// encoding it needs, and the MessagePack one is only built if someone asks for it.
+#[derive(Debug)]
+pub struct ChannelMessage {
+    text: Utf8Bytes,
+    messages: serde_json::Value,
+    binary: OnceLock<Option<Message>>,
+    exclude: Option<String>,
+    /// User `_id` behind each chat, note and cursor message in `messages`, by position, so
+    /// block lists can leave them out.
+    authors: Vec<Option<String>>,
+}
+
+impl ChannelMessage {
+    pub fn messages(&self) -> &serde_json::Value {
+        &self.messages
+    }
+
+    pub fn is_excluded(&self, client_id: &str) -> bool {
+        self.exclude.as_deref() == Some(client_id)
+    }
+
+    pub fn new(messages: &serde_json::Value, exclude: Option<&str>) -> serde_json::Result<Self> {
+        Ok(Self {
+            text: serde_json::to_string(messages)?.into(),
+            messages: messages.clone(),
+            binary: OnceLock::new(),
+            exclude: exclude.map(str::to_string),
+            authors: Vec::new(),
+        })
+    }
+
+    /// This message as someone blocking `blocked` should get it: the same one when nothing
+    /// in it is from them, `None` when everything is. A `c` keeps its history minus their
+    /// lines, as a join's does.
+    fn without_blocked(message: &Arc<Self>, blocked: &HashSet<String>) -> Option<Arc<Self>> {
+        let is_blocked = |i: usize| {
+            let author = message.authors.get(i).and_then(Option::as_ref);
+            author.is_some_and(|author| blocked.contains(author))
+        };
+        let from_blocked = |line: &serde_json::Value| {
+            let author = line.pointer("/p/_id").and_then(|id| id.as_str());
+            author.is_some_and(|author| blocked.contains(author))
+        };
+        let Some(messages) = message.messages.as_array() else { return Some(message.clone()) };
+        let in_history = |m: &serde_json::Value| chat_history(m).is_some_and(|lines| lines.iter().any(from_blocked));
+        if !(0..messages.len()).any(is_blocked) && !messages.iter().any(in_history) {
+            return Some(message.clone());
+        }
+        let kept: Vec<_> = messages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !is_blocked(*i))
+            .map(|(_, kept)| {
+                let mut kept = kept.clone();
+                if chat_history(&kept).is_some() {
+                    if let Some(lines) = kept["c"].as_array_mut() {
+                        lines.retain(|line| !from_blocked(line));
+                    }
+                }
+                kept
+            })
+            .collect();
+        if kept.is_empty() {
+            return None;
+        }
+        Self::new(&serde_json::Value::Array(kept), None).ok().map(Arc::new)
+    }
+
+    /// The frame for one connection. Both encodings are reference-counted buffers, so every
+    /// connection gets the same one rather than a copy.
+    fn frame(&self, codec: Codec) -> Message {
+        if codec == Codec::MsgPack && Codec::is_binary_eligible(&self.messages) {
+            let binary = self.binary.get_or_init(|| match Codec::MsgPack.encode(&self.messages) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    error!("Failed to encode binary message: {}", e);
+                    None
+                }
+            });
+            if let Some(binary) = binary {
+                return binary.clone();
+            }
+        }
+        Message::Text(self.text.clone())
+    }
+}
+
+/// What was found out about a connection's address before the upgrade, see
+/// `IpReputation` and `GeoIp`.
+#[derive(Debug, Clone, Default)]
+pub struct AddressInfo {
+    pub ip_flagged: bool,
+    pub country: Option<String>,
+}
+
+/// Hands a connection's sender task the broadcast stream of the channel it just joined.
+pub struct ChannelSubscription {
+    pub channel_id: String,
+    pub rx: broadcast::Receiver<Arc<ChannelMessage>>,
+    pub codec: Codec,
+}
+
+pub struct WsSender {
+    pub tx: mpsc::Sender<Message>,
+    pub subscribe: mpsc::UnboundedSender<ChannelSubscription>,
+    pub codec: Codec,
+    pub last_pong: AtomicU64,
+    pub last_activity: AtomicU64,
+    pub afk: AtomicBool,
+    /// Cancelled to force this one connection closed (e.g. by the dead-connection reaper).
+    pub kill: CancellationToken,
+    /// User `_id`s whose chat, notes and cursor this connection doesn't get, set by `block`.
+    pub blocked: Arc<std::sync::RwLock<HashSet<String>>>,
+}
+
+impl WsSender {
+    /// Queues a message for the socket. A client that lets its queue fill up is too far
+    /// behind to be worth keeping, so it gets disconnected.
+    pub fn send(&self, msg: Message) {
+        match self.tx.try_send(msg) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !self.kill.is_cancelled() {
+                    warn!("Outgoing queue full, disconnecting slow client");
+                    self.kill.cancel();
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
 
 pub struct Server {
-    pub channels: DashMap<String, Arc<RwLock<Channel>>>,
+    /// Swapped out whole on reload, see `config()`.
+    config: std::sync::RwLock<Arc<Config>>,
+    /// Command queue of each open channel's task, which owns the room, see `channel_task`.
+    pub channels: DashMap<String, mpsc::UnboundedSender<ChannelCommand>>,
     pub clients: DashMap<String, Arc<RwLock<ClientData>>>,
     pub subscribed_to_ls: DashMap<String, bool>,
-    pub banned_users: DashMap<String, BanInfo>,
-    pub ws_senders: DashMap<String, mpsc::UnboundedSender<String>>,
+    pub subscribed_to_custom: DashMap<String, bool>,
+    /// User `_id`s that turned direct messages off with `userset`.
+    pub dms_off: DashMap<String, bool>,
+    /// Keyed by (user `_id`, channel id), so a user can be banned from several rooms at once.
+    pub banned_users: DashMap<(String, String), BanInfo>,
+    pub ws_senders: DashMap<String, WsSender>,
+    /// Every open connection id for a user `_id`, since one user can have several tabs open.
+    pub user_connections: DashMap<String, HashSet<String>>,
+    pub shutdown: CancellationToken,
+    pub cluster: OnceLock<Cluster>,
+    pub events: EventBus,
+    pub screener: Option<Screener>,
+    pub reputation: Option<IpReputation>,
+    pub geoip: Option<GeoIp>,
+    pub audit_log: AuditLog,
+    pub reports: ReportQueue,
+    pub plugins: PluginHost,
+    pub capture: Arc<Capture>,
+    pub storage: Arc<dyn Storage>,
+    /// Queue of the storage writer, None when storage doesn't keep anything.
+    writes: Option<std::sync::mpsc::Sender<StorageWrite>>,
+    /// Rooms from storage as last saved, put back in place when someone opens them again.
+    pub saved_channels: DashMap<String, SavedChannel>,
+    /// Chat of rooms in `chat_persistence.rooms` that aren't saved whole, by channel id.
+    pub saved_chats: DashMap<String, Vec<ChatMessage>>,
+    /// Settings from room links, keyed by (normalized IP, room), see `room_links`.
+    pub room_templates: DashMap<(String, String), RoomTemplate>,
+    ls_dirty: std::sync::Mutex<HashSet<String>>,
+    /// Rooms whose chat changed since it was last written out.
+    chat_dirty: std::sync::Mutex<HashSet<String>>,
+    motd: std::sync::RwLock<Vec<String>>,
+    motd_index: AtomicUsize,
+    /// When a restart countdown ends, 0 when none is running.
+    restart_at: AtomicU64,
+    /// Note quota ticks so far, see `NoteQuota::catch_up`.
+    quota_ticks: AtomicU64,
 }
 
-impl Server {
-    pub fn new() -> Self {
-        let server = Self {
-            channels: DashMap::new(),
-            clients: DashMap::new(),
-            subscribed_to_ls: DashMap::new(),
-            banned_users: DashMap::new(),
-            ws_senders: DashMap::new(),
-        };
+impl Server {
+    pub fn new(config: &Config) -> Arc<Self> {
+        Self::with_storage(config, crate::storage::from_config(config))
+    }
+
+    /// Like `new`, with persistence handled by `storage` instead of what `[storage]` says.
+    pub fn with_storage(config: &Config, storage: Arc<dyn Storage>) -> Arc<Self> {
+        let writes = storage.persistent().then(|| crate::storage::spawn_writer(storage.clone()));
+        let server = Arc::new(Self {
+            config: std::sync::RwLock::new(Arc::new(config.clone())),
+            channels: DashMap::new(),
+            clients: DashMap::new(),
+            subscribed_to_ls: DashMap::new(),
+            subscribed_to_custom: DashMap::new(),
+            dms_off: DashMap::new(),
+            banned_users: DashMap::new(),
+            ws_senders: DashMap::new(),
+            user_connections: DashMap::new(),
+            shutdown: CancellationToken::new(),
+            cluster: OnceLock::new(),
+            events: EventBus::new(),
+            screener: config.screening.clone().map(Screener::new),
+            reputation: config.ip_reputation.clone().map(IpReputation::new),
+            geoip: config.geoip.clone().and_then(|geoip| {
+                let path = geoip.database.clone();
+                GeoIp::open(geoip)
+                    .map_err(|e| error!("Failed to open GeoIP database {}, lookups are off: {}", path.display(), e))
+                    .ok()
+            }),
+            audit_log: AuditLog::new(config.audit_log.file.as_deref()),
+            reports: ReportQueue::new(),
+            plugins: PluginHost::load(&config.plugins),
+            capture: Arc::default(),
+            storage,
+            writes,
+            saved_channels: DashMap::new(),
+            saved_chats: DashMap::new(),
+            room_templates: DashMap::new(),
+            ls_dirty: std::sync::Mutex::new(HashSet::new()),
+            chat_dirty: std::sync::Mutex::new(HashSet::new()),
+            motd: std::sync::RwLock::new(config.motd.clone()),
+            motd_index: AtomicUsize::new(0),
+            restart_at: AtomicU64::new(0),
+            quota_ticks: AtomicU64::new(0),
+        });
+
+        server.load_saved_state();
+
+        if let Some(webhook_config) = &config.webhook {
+            crate::webhook::spawn(webhook_config.clone(), &server.events);
+        }
+
+        if let Some(chat_log_config) = &config.chat_log {
+            crate::chatlog::spawn(chat_log_config.clone(), server.events.subscribe());
+        }
+
+        crate::bots::spawn(&server, &config.bots.run);
+
+        if let Some(grpc_config) = &config.grpc {
+            crate::grpc::spawn(&server, grpc_config);
+        }
+
+        if let Some(snapshot_config) = &config.snapshot {
+            crate::snapshot::spawn(&server, snapshot_config.clone());
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &config.control_socket {
+            if let Err(e) = crate::control::spawn(&server, path) {
+                error!("Failed to open control socket {}: {}", path.display(), e);
+            }
+        }
+
+        if let Some(cluster_config) = &config.cluster {
+            match Cluster::start(&server, &cluster_config.redis_url, &cluster_config.topic) {
+                Ok(cluster) => {
+                    let _ = server.cluster.set(cluster);
+                }
+                Err(e) => error!("Failed to start cluster mode: {}", e),
+            }
+        }
+
+        let weak = Arc::downgrade(&server);
+        let reap_every = Duration::from_secs(config.ping_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reap_every);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.reap_dead_connections();
+                if let Some(screener) = &server.screener {
+                    screener.sweep(current_time_ms());
+                }
+            }
+        });
+
+        let weak = Arc::downgrade(&server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.check_idle_connections().await;
+            }
+        });
+
+        let weak = Arc::downgrade(&server);
+        let sweep_every = Duration::from_secs(config.ban_sweep_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sweep_every);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.sweep_expired_bans().await;
+            }
+        });
+
+        let weak = Arc::downgrade(&server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.release_held_crowns().await;
+            }
+        });
+
+        let weak = Arc::downgrade(&server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.expire_disconnected().await;
+            }
+        });
+
+        if server.storage.persistent() {
+            let weak = Arc::downgrade(&server);
+            let save_interval_secs = config.storage.clone().unwrap_or_default().save_interval_secs;
+            let save_every = Duration::from_secs(save_interval_secs.max(1));
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(save_every);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let Some(server) = weak.upgrade() else { break };
+                    server.save_channels().await;
+                    server.flush_user_stats().await;
+                }
+            });
+
+            let weak = Arc::downgrade(&server);
+            let chat_every = Duration::from_millis(config.chat_persistence.save_delay_ms.max(100));
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(chat_every);
+                loop {
+                    interval.tick().await;
+                    let Some(server) = weak.upgrade() else { break };
+                    server.save_changed_chats().await;
+                }
+            });
+        }
+
+        let weak = Arc::downgrade(&server);
+        let ls_every = Duration::from_millis(config.ls_interval_ms.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ls_every);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.flush_ls_updates().await;
+            }
+        });
+
+        // Wakes at the top of every minute and reads the list each time, so it follows
+        // whatever the config currently says
+        let weak = Arc::downgrade(&server);
+        tokio::spawn(async move {
+            let mut last_minute = current_time_ms() / 60_000;
+            loop {
+                let now = current_time_ms();
+                tokio::time::sleep(Duration::from_millis(60_000 - now % 60_000)).await;
+                let Some(server) = weak.upgrade() else { break };
+                let minute = current_time_ms() / 60_000;
+                if minute != last_minute {
+                    last_minute = minute;
+                    server.send_scheduled_announcements(minute * 60_000);
+                }
+            }
+        });
+
+        // There are better ways for loops, but I decided to use tokio::time :3
+        // Only counts; each quota catches up on the ticks it missed when it's next spent
+        let weak = Arc::downgrade(&server);
+        let tick_every = Duration::from_millis(config.note_quota.tick_ms.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tick_every);
+            loop {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { break };
+                server.quota_ticks.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        server
+    }
+
+    /// Fills `banned_users`, `saved_channels` and `saved_chats` from storage.
+    fn load_saved_state(&self) {
+        if !self.storage.persistent() {
+            return;
+        }
+        let storage = &self.storage;
+
+        match storage.load_bans(current_time_ms()) {
+            Ok(bans) => {
+                info!("Loaded {} bans from storage", bans.len());
+                for (user_id, channel_id, ban) in bans {
+                    self.banned_users.insert((user_id, channel_id), ban);
+                }
+            }
+            Err(e) => error!("Failed to load bans from storage: {}", e),
+        }
+
+        match storage.load_channels() {
+            Ok(channels) => {
+                info!("Loaded {} channels from storage", channels.len());
+                for channel in channels {
+                    self.saved_channels.insert(channel.id.clone(), channel);
+                }
+            }
+            Err(e) => error!("Failed to load channels from storage: {}", e),
+        }
+
+        match storage.load_chat_histories() {
+            Ok(histories) => {
+                info!("Loaded chat of {} rooms from storage", histories.len());
+                for (channel_id, messages) in histories {
+                    self.saved_chats.insert(channel_id, messages);
+                }
+            }
+            Err(e) => error!("Failed to load chat from storage: {}", e),
+        }
+    }
+
+    /// Queues a storage write behind the ones already queued, logging it if it fails.
+    /// Skipped when the storage doesn't keep anything.
+    pub fn persist<F>(&self, what: &'static str, write: F)
+    where
+        F: FnOnce(&dyn Storage) -> anyhow::Result<()> + Send + 'static,
+    {
+        self.queue_write(Box::new(move |storage| {
+            if let Err(e) = write(storage) {
+                error!("Failed to save {}: {}", what, e);
+            }
+        }));
+    }
+
+    /// Like `persist`, but waits for the write to run and returns what it did. None when
+    /// the storage doesn't keep anything or the writer is gone.
+    async fn persist_and_wait<T, F>(&self, write: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Storage) -> T + Send + 'static,
+    {
+        let (reply, done) = oneshot::channel();
+        self.queue_write(Box::new(move |storage| {
+            let _ = reply.send(write(storage));
+        }));
+        done.await.ok()
+    }
+
+    /// Waits until every storage write queued so far has run.
+    pub async fn writes_done(&self) {
+        self.persist_and_wait(|_| ()).await;
+    }
+
+    fn queue_write(&self, write: StorageWrite) {
+        if let Some(writes) = &self.writes {
+            if writes.send(write).is_err() {
+                error!("Storage writer has stopped, a write was lost");
+            }
+        }
+    }
+
+    /// Remembers a room's current state and writes it out, if it's one that gets kept.
+    pub fn save_channel(&self, channel: &Channel) {
+        if !self.storage.persistent() {
+            return;
+        }
+        let Some(saved) = SavedChannel::of(channel) else {
+            // Lost its owner, so it isn't kept anymore
+            if self.saved_channels.remove(&channel._id).is_some() {
+                let id = channel._id.clone();
+                self.persist("channel", move |storage| storage.delete_channel(&id));
+            }
+            return;
+        };
+        self.saved_channels.insert(saved.id.clone(), saved.clone());
+        self.persist("channel", move |storage| storage.save_channel(&saved));
+    }
+
+    /// Marks a room's chat as changed. It's written out with the next `save_changed_chats`.
+    pub fn chat_changed(&self, channel_id: &str) {
+        if self.storage.persistent() {
+            self.chat_dirty.lock().unwrap().insert(channel_id.to_string());
+        }
+    }
+
+    /// Writes out the rooms whose chat changed since the last time: owned rooms as a whole,
+    /// rooms in `chat_persistence.rooms` just their chat.
+    pub async fn save_changed_chats(&self) {
+        let dirty = std::mem::take(&mut *self.chat_dirty.lock().unwrap());
+        for channel_id in dirty {
+            self.with_channel(&channel_id, |server, channel| {
+                server.save_channel(channel);
+                server.save_chat(channel);
+            })
+            .await;
+        }
+    }
+
+    /// Remembers a room's chat and writes it out, if it's kept by name and not as part of
+    /// the saved room.
+    pub fn save_chat(&self, channel: &Channel) {
+        if !self.storage.persistent() {
+            return;
+        }
+        let Some(messages) = self.kept_chat(channel) else { return };
+        let channel_id = channel._id.clone();
+        self.saved_chats.insert(channel_id.clone(), messages.clone());
+        self.persist("chat", move |storage| storage.save_chat_history(&channel_id, &messages));
+    }
+
+    fn kept_chat(&self, channel: &Channel) -> Option<Vec<ChatMessage>> {
+        if SavedChannel::of(channel).is_some() || !self.config().chat_persistence.keeps(&channel._id) {
+            return None;
+        }
+        Some(channel.chat_history.iter().cloned().collect())
+    }
+
+    /// Writes the `[snapshot]` file now, when there is one.
+    pub async fn save_snapshot(&self) {
+        let Some(config) = self.config().snapshot.clone() else { return };
+        match crate::snapshot::write(self, &config.path).await {
+            Ok(()) => info!("Wrote snapshot to {}", config.path.display()),
+            Err(e) => error!("Failed to write snapshot to {}: {:#}", config.path.display(), e),
+        }
+    }
+
+    /// Reopens the rooms and brings back the bans from the `[snapshot]` file, see
+    /// `snapshot::restore`. Meant for startup, before connections come in.
+    pub async fn restore_snapshot(self: &Arc<Self>) -> anyhow::Result<usize> {
+        let config = self.config().snapshot.clone().ok_or_else(|| anyhow::anyhow!("[snapshot] isn't set"))?;
+        crate::snapshot::restore(self, &config.path).await
+    }
+
+    /// Writes out every open room that gets kept, waiting until it's done.
+    pub async fn save_channels(&self) {
+        if !self.storage.persistent() {
+            return;
+        }
+        let mut saved = Vec::new();
+        let mut chats = Vec::new();
+        let kept = self
+            .with_channels(|server, channel| match SavedChannel::of(channel) {
+                Some(saved_channel) => Some(Ok(saved_channel)),
+                None => server.kept_chat(channel).map(|messages| Err((channel._id.clone(), messages))),
+            })
+            .await;
+        for kept in kept.into_iter().flatten() {
+            match kept {
+                Ok(saved_channel) => {
+                    self.saved_channels.insert(saved_channel.id.clone(), saved_channel.clone());
+                    saved.push(saved_channel);
+                }
+                Err((channel_id, messages)) => {
+                    self.saved_chats.insert(channel_id.clone(), messages.clone());
+                    chats.push((channel_id, messages));
+                }
+            }
+        }
+
+        let result = self
+            .persist_and_wait(move |storage| {
+                for channel in &saved {
+                    storage.save_channel(channel)?;
+                }
+                for (channel_id, messages) in &chats {
+                    storage.save_chat_history(channel_id, messages)?;
+                }
+                anyhow::Ok(saved.len())
+            })
+            .await;
+        match result {
+            Some(Ok(count)) => debug!("Saved {} channels", count),
+            Some(Err(e)) => error!("Failed to save channels: {}", e),
+            None => error!("Storage writer has stopped, channels weren't saved"),
+        }
+    }
+
+    /// The name and color `user_id` had last time, if storage remembers it.
+    pub async fn load_profile(&self, user_id: &str) -> Option<UserProfile> {
+        if !self.storage.persistent() {
+            return None;
+        }
+        let storage = self.storage.clone();
+        let user_id = user_id.to_string();
+        match tokio::task::spawn_blocking(move || storage.load_profile(&user_id)).await {
+            Ok(Ok(profile)) => profile,
+            Ok(Err(e)) => {
+                error!("Failed to load profile: {}", e);
+                None
+            }
+            Err(e) => {
+                error!("Profile load task failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// `user_id`'s stats: what storage has plus what their open connections haven't
+    /// flushed yet.
+    pub async fn user_stats(&self, user_id: &str) -> UserStats {
+        let mut stats = UserStats::default();
+        if self.storage.persistent() {
+            let storage = self.storage.clone();
+            let id = user_id.to_string();
+            match tokio::task::spawn_blocking(move || storage.load_user_stats(&id)).await {
+                Ok(Ok(stored)) => stats = stored,
+                Ok(Err(e)) => error!("Failed to load user stats: {}", e),
+                Err(e) => error!("User stats load task failed: {}", e),
+            }
+        }
+
+        let now = current_time_ms();
+        let connections = self.user_connections.get(user_id).map(|c| c.clone()).unwrap_or_default();
+        for client_id in connections {
+            if let Some(client) = self.clients.get(&client_id).map(|c| c.value().clone()) {
+                stats.add(&client.read().await.pending_stats(now));
+            }
+        }
+        stats
+    }
+
+    /// Top `limit` users by `metric` over `window`, as of the last stats flush. Empty without
+    /// persistent storage.
+    pub async fn leaderboard(
+        &self,
+        metric: LeaderboardMetric,
+        window: LeaderboardWindow,
+        limit: usize,
+    ) -> Vec<LeaderboardEntry> {
+        if !self.storage.persistent() {
+            return Vec::new();
+        }
+        let storage = self.storage.clone();
+        let since_day = window.since_day(current_time_ms());
+        match tokio::task::spawn_blocking(move || storage.leaderboard(metric, since_day, limit)).await {
+            Ok(Ok(entries)) => entries,
+            Ok(Err(e)) => {
+                error!("Failed to load the leaderboard: {}", e);
+                Vec::new()
+            }
+            Err(e) => {
+                error!("Leaderboard task failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Writes out the stats every connection has gathered since the last flush.
+    pub async fn flush_user_stats(&self) {
+        if !self.storage.persistent() {
+            return;
+        }
+        let now = current_time_ms();
+        let mut pending: HashMap<String, UserStats> = HashMap::new();
+        let clients: Vec<_> = self.clients.iter().map(|c| c.value().clone()).collect();
+        for client in clients {
+            let mut client = client.write().await;
+            let stats = client.take_stats(now);
+            if !client.stats_purged {
+                pending.entry(client.user_id.clone()).or_default().add(&stats);
+            }
+        }
+        if pending.is_empty() {
+            return;
+        }
+
+        debug!("Flushing stats for {} users", pending.len());
+        self.persist("user stats", move |storage| {
+            for (user_id, stats) in &pending {
+                storage.add_user_stats(user_id, stats)?;
+            }
+            Ok(())
+        });
+    }
+
+    /// The config as of the last reload. Hold on to it for the duration of one handler rather
+    /// than calling this per item.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-reads the config file and swaps in everything that can change while running.
+    /// Returns the settings that changed but only take effect after a restart.
+    pub async fn reload_config(&self) -> anyhow::Result<Vec<&'static str>> {
+        let mut config = Config::load()?;
+        let old = self.config();
+        let needs_restart = config.keep_startup_settings(&old);
+        if config.motd != old.motd {
+            self.set_motd(config.motd.clone());
+        }
+        *self.config.write().unwrap() = Arc::new(config);
+
+        info!("Config reloaded");
+        for name in &needs_restart {
+            warn!("Config setting {} changed, it takes effect after a restart", name);
+        }
+
+        // Quota profiles may have changed under people who are already playing
+        let client_ids: Vec<String> = self.clients.iter().map(|c| c.key().clone()).collect();
+        for client_id in client_ids {
+            self.refresh_note_quota(&client_id).await;
+        }
+
+        Ok(needs_restart)
+    }
+
+    /// Rotates through the configured messages, one per call.
+    pub fn next_motd(&self) -> String {
+        let motd = self.motd.read().unwrap();
+        if motd.is_empty() {
+            return String::new();
+        }
+        let i = self.motd_index.fetch_add(1, Ordering::Relaxed) % motd.len();
+        motd[i].clone()
+    }
+
+    pub fn set_motd(&self, messages: Vec<String>) {
+        *self.motd.write().unwrap() = messages;
+        self.motd_index.store(0, Ordering::Relaxed);
+    }
+
+    /// Note quota ticks since startup.
+    pub fn quota_ticks(&self) -> u64 {
+        self.quota_ticks.load(Ordering::Relaxed)
+    }
+
+    /// Switches the client to the quota profile for its current channel and crown, sending
+    /// a fresh `nq` if that changed anything. Must not be called from the channel's task.
+    pub async fn refresh_note_quota(&self, client_id: &str) {
+        let Some(client_ref) = self.clients.get(client_id).map(|c| c.value().clone()) else {
+            return;
+        };
+
+        let (channel_id, is_bot) = {
+            let client = client_ref.read().await;
+            (client.channel_id.clone(), client.is_bot)
+        };
+
+        let params = if is_bot {
+            NoteQuotaParams {
+                allowance: self.config().bots.quota_allowance,
+                max: self.config().bots.quota_max,
+            }
+        } else {
+            let for_channel = match channel_id {
+                Some(channel_id) => {
+                    let client_id = client_id.to_string();
+                    self.with_channel(&channel_id, move |server, channel| {
+                        NoteQuotaParams::for_channel(channel, &client_id, &server.config().note_quota)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            for_channel.unwrap_or(self.config().note_quota.normal)
+        };
+
+        // mppclone clients reset their quota on every join and need it sent again
+        let always = self.config().protocol == Protocol::MppClone;
+        let nq = {
+            let mut client = client_ref.write().await;
+            if !client.note_quota.set_params(params) && !always {
+                return;
+            }
+            client.note_quota.get_params()
+        };
+
+        self.send_messages(client_id, &[nq]).await;
+    }
+
+    /// Lobbies (including `[geoip]` regional ones) and `test/` rooms: nobody owns them and
+    /// their settings are fixed.
+    pub fn is_special_channel(&self, channel_id: &str) -> bool {
+        is_lobby(channel_id)
+            || channel_id.starts_with("test/")
+            || self.geoip.as_ref().is_some_and(|geoip| geoip.is_lobby(channel_id))
+    }
+
+    /// The `[geoip]` lobby for the client's country, if there's one.
+    pub async fn regional_lobby(&self, client_id: &str) -> Option<String> {
+        let geoip = self.geoip.as_ref()?;
+        let client = self.clients.get(client_id).map(|c| c.value().clone())?;
+        let country = client.read().await.country.clone()?;
+        geoip.lobby(&country).map(str::to_string)
+    }
+
+    /// The first of "lobby", "lobby2", "lobby3"... that has room for the client (or already
+    /// has them in it).
+    pub async fn lobby_with_space(&self, client_id: &str) -> String {
+        let capacity = self.config().lobby_capacity;
+        let mut n = 1;
+        loop {
+            let id = if n == 1 { "lobby".to_string() } else { format!("lobby{}", n) };
+            if capacity == 0 {
+                return id;
+            }
+
+            let client_id = client_id.to_string();
+            let has_space = self
+                .with_channel(&id, move |_, channel| {
+                    channel.participants.len() < capacity || channel.participants.contains_key(&client_id)
+                })
+                .await;
+            // A room that isn't open (or just closed) has space too
+            if has_space != Some(false) {
+                return id;
+            }
+            n += 1;
+        }
+    }
+
+    pub async fn handle_connection(
+        self: Arc<Self>,
+        socket: WebSocket,
+        ip: String,
+        bridge: Option<MidiBridge>,
+        address: AddressInfo,
+    ) -> anyhow::Result<()> {
+        // Each socket is its own participant; the IP-derived id is shared as the user `_id`
+        let user_id = generate_client_id(&ip);
+        let client_id = generate_random_id();
+
+        match &address.country {
+            Some(country) => info!("New connection: user_id={}, client_id={}, country={}", user_id, client_id, country),
+            None => info!("New connection: user_id={}, client_id={}", user_id, client_id),
+        }
+
+        self.user_connections
+            .entry(user_id.clone())
+            .or_default()
+            .insert(client_id.clone());
+
+        let now = current_time_ms();
+        let screening_score = self.screener.as_ref().map_or(0, |s| s.on_connect(&ip, now));
+        // Bridges already showed a bot token to get in, and couldn't answer a challenge anyway
+        let mut challenge = match bridge {
+            Some(_) => None,
+            None => crate::challenge::issue(&self.config().challenge),
+        };
+        if let Some(screener) = self.screener.as_ref().filter(|_| bridge.is_none()) {
+            if screener.is_flagged(screening_score) {
+                info!("Connection {} from {} flagged by screening (score {})", client_id, ip, screening_score);
+                if challenge.is_none() && screener.config.action == ScreeningAction::Challenge {
+                    challenge = crate::challenge::issue(&ChallengeConfig::Script);
+                }
+            }
+        }
+
+        {
+            let client_data = ClientData {
+                challenge: challenge.as_ref().map(|(_, answer)| answer.clone()),
+                captcha_pending: bridge.is_none() && self.config().captcha.is_some(),
+                ip_flagged: address.ip_flagged,
+                country: address.country,
+                screening_score,
+                ..self.new_client_data(user_id, ip.clone(), now)
+            };
+            self.clients.insert(client_id.clone(), Arc::new(RwLock::new(client_data)));
+        }
+
+        let (mut ws_sender, mut ws_receiver) = socket.split();
+        let (tx, mut rx) = mpsc::channel::<Message>(self.config().send_queue_capacity.max(1));
+        let (subscribe, mut subscriptions) = mpsc::unbounded_channel::<ChannelSubscription>();
+
+        let kill = CancellationToken::new();
+        let blocked = Arc::<std::sync::RwLock<HashSet<String>>>::default();
+        self.ws_senders.insert(
+            client_id.clone(),
+            WsSender {
+                tx,
+                subscribe,
+                codec: Codec::Json,
+                last_pong: AtomicU64::new(current_time_ms()),
+                last_activity: AtomicU64::new(current_time_ms()),
+                afk: AtomicBool::new(false),
+                kill: kill.clone(),
+                blocked: blocked.clone(),
+            },
+        );
+        debug!("Stored WebSocket sender for client: {}", client_id);
+
+        let client_id_for_sender = client_id.clone();
+        let shutdown = self.shutdown.clone();
+        let kill_sender = kill.clone();
+        let ping_every = Duration::from_secs(self.config().ping_interval_secs.max(1));
+        let mut warmup_pings = self.config().time_sync.warmup_pings;
+        let backlog_limit = self.config().send_queue_capacity / 2;
+        let capture = self.capture.clone();
+
+        tokio::spawn(async move {
+            // The first few pings come quickly so time sync has round trips to go on
+            let next_ping_in = |warmup: u32| if warmup > 0 { Duration::from_secs(1) } else { ping_every };
+            let mut next_ping = tokio::time::Instant::now() + next_ping_in(warmup_pings);
+            let mut channel: Option<ChannelSubscription> = None;
+            loop {
+                tokio::select! {
+                    // Direct replies first, so a join's "ch" goes out before the room's traffic
+                    biased;
+                    msg = rx.recv() => {
+                        let Some(msg) = msg else { break };
+                        capture.outbound(&client_id_for_sender, channel.as_ref().map(|c| c.channel_id.as_str()), &msg);
+                        if let Err(e) = ws_sender.send(msg).await {
+                            error!("Failed to send WebSocket message: {}", e);
+                            break;
+                        }
+                    }
+                    _ = shutdown.cancelled() => {
+                        // Flush whatever is still queued (including the restart notice) before closing
+                        while let Ok(msg) = rx.try_recv() {
+                            if ws_sender.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                        let close = Message::Close(Some(CloseFrame {
+                            code: close_code::RESTART,
+                            reason: "Server restarting".into(),
+                        }));
+                        let _ = ws_sender.send(close).await;
+                        break;
+                    }
+                    _ = kill_sender.cancelled() => {
+                        let _ = ws_sender.send(Message::Close(None)).await;
+                        break;
+                    }
+                    Some(subscription) = subscriptions.recv() => channel = Some(subscription),
+                    msg = recv_channel(&mut channel) => match msg {
+                        Ok(msg) => {
+                            if msg.exclude.as_deref() == Some(client_id_for_sender.as_str()) {
+                                continue;
+                            }
+                            let msg = {
+                                let blocked = blocked.read().unwrap_or_else(|e| e.into_inner());
+                                if blocked.is_empty() { Some(msg) } else { ChannelMessage::without_blocked(&msg, &blocked) }
+                            };
+                            let Some(msg) = msg else { continue };
+                            // Falling behind: notes and cursors are the first thing to go
+                            let backlog = channel.as_ref().map_or(0, |c| c.rx.len());
+                            if backlog > backlog_limit && Codec::is_binary_eligible(&msg.messages) {
+                                continue;
+                            }
+                            let codec = channel.as_ref().map_or(Codec::Json, |c| c.codec);
+                            let frame = msg.frame(codec);
+                            capture.outbound(&client_id_for_sender, channel.as_ref().map(|c| c.channel_id.as_str()), &frame);
+                            if let Err(e) = ws_sender.send(frame).await {
+                                error!("Failed to send WebSocket message: {}", e);
+                                break;
+                            }
+                        }
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("{} fell {} channel messages behind, disconnecting", client_id_for_sender, n);
+                            let _ = ws_sender.send(Message::Close(Some(CloseFrame {
+                                code: close_code::AGAIN,
+                                reason: "Too slow to keep up".into(),
+                            }))).await;
+                            kill_sender.cancel();
+                            break;
+                        }
+                        Err(RecvError::Closed) => channel = None,
+                    },
+                    _ = tokio::time::sleep_until(next_ping) => {
+                        let sent_at = current_time_ms().to_be_bytes().to_vec().into();
+                        if ws_sender.send(Message::Ping(sent_at)).await.is_err() {
+                            break;
+                        }
+                        warmup_pings = warmup_pings.saturating_sub(1);
+                        next_ping = tokio::time::Instant::now() + next_ping_in(warmup_pings);
+                    }
+                }
+            }
+            debug!("Sender task ended for {}", client_id_for_sender);
+        });
+
+        // Screening can ask for a shorter deadline, and strikes the IP when it passes
+        let hi_timeout = [
+            self.config().handshake_timeout_secs,
+            self.screener.as_ref().map_or(0, |s| s.config.hi_timeout_secs),
+        ]
+        .into_iter()
+        .filter(|t| *t > 0)
+        .min();
+        if let Some(timeout) = hi_timeout {
+            let weak = Arc::downgrade(&self);
+            let client_id = client_id.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(timeout)).await;
+                let Some(server) = weak.upgrade() else { return };
+                server.check_hi_sent(&client_id).await;
+            });
+        }
+
+        if let Some((code, _)) = &challenge {
+            let b = serde_json::json!([{ "m": "b", "code": code }]);
+            self.send_to_client(&client_id, &b.to_string()).await;
+        }
+
+        let message_handler = MessageHandler::new(self.clone());
+        let client_id_clone = client_id.clone();
+        let self_clone = self.clone();
+        let mut malformed = Strikes::default();
+        let mut rate = MessageRate::default();
+        // Only a connection that dropped by itself gets its seat held; the server closing it
+        // for a timeout, bad messages or flooding doesn't
+        let mut keep_seat = false;
+
+        if let Some(bridge) = &bridge {
+            for msg in bridge.handshake() {
+                let Ok(msg) = serde_json::from_value(msg) else { continue };
+                if let Some(response) = message_handler.handle_message(&client_id, msg).await {
+                    self.send_to_client(&client_id, &serde_json::to_string(&response)?).await;
+                }
+            }
+        }
+
+        loop {
+            let read_timeout = Duration::from_secs(self_clone.config().read_timeout_secs);
+            let msg = tokio::select! {
+                msg = ws_receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => {
+                        keep_seat = true;
+                        break;
+                    }
+                },
+                _ = kill.cancelled() => break,
+                _ = tokio::time::sleep(read_timeout), if !read_timeout.is_zero() => {
+                    info!("Client {} sent nothing for {:?}, closing", client_id, read_timeout);
+                    break;
+                }
+            };
+
+            if self_clone.capture.is_active() {
+                if let Ok(frame) = &msg {
+                    let channel_id = match self_clone.clients.get(&client_id).map(|c| c.value().clone()) {
+                        Some(client) => client.read().await.channel_id.clone(),
+                        None => None,
+                    };
+                    self_clone.capture.inbound(&client_id, channel_id.as_deref(), frame);
+                }
+            }
+
+            let decoded = match msg {
+                Ok(Message::Text(text)) => Codec::Json.decode(text.as_bytes()),
+                Ok(Message::Binary(data)) => match &bridge {
+                    Some(_) => Ok(midi::to_messages(&data, current_time_ms())),
+                    None => Codec::MsgPack.decode(&data),
+                },
+                Ok(Message::Pong(data)) => {
+                    let now = current_time_ms();
+                    if let Some(sender) = self_clone.ws_senders.get(&client_id) {
+                        sender.last_pong.store(now, Ordering::Relaxed);
+                    }
+                    // Our pings carry their send time
+                    if let Ok(sent_at) = <[u8; 8]>::try_from(&data[..]) {
+                        let rtt = now.saturating_sub(u64::from_be_bytes(sent_at));
+                        self_clone.record_round_trip(&client_id, rtt).await;
+                    }
+                    continue;
+                }
+                Ok(Message::Close(_)) => {
+                    info!("Client {} closed connection", client_id);
+                    keep_seat = true;
+                    break;
+                }
+                Err(e) => {
+                    // Oversized messages end up here too
+                    error!("WebSocket error for client {}: {}", client_id, e);
+                    if let Some(sender) = self_clone.ws_senders.get(&client_id) {
+                        sender.send(Message::Close(Some(CloseFrame {
+                            code: close_code::PROTOCOL,
+                            reason: "Protocol error".into(),
+                        })));
+                    }
+                    keep_seat = true;
+                    break;
+                }
+                _ => continue,
+            };
+
+            let messages = match decoded {
+                Ok(messages) => messages,
+                Err(e) => {
+                    debug!("Failed to parse messages array from {}: {}", client_id, e);
+                    if self_clone.malformed_strike(&client_id, &mut malformed) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let limit = self_clone.config().message_rate;
+            let mut over_limit = false;
+            let mut too_malformed = false;
+            for msg_value in messages {
+                if limit.max_per_second > 0 {
+                    let count = rate.record(current_time_ms());
+                    if count > limit.max_per_second {
+                        if count == limit.max_per_second + 1 {
+                            warn!("Client {} sent more than {} messages in a second", client_id, limit.max_per_second);
+                        }
+                        over_limit = true;
+                        // Throttling drops the rest of this second's messages
+                        if limit.action == MessageRateAction::Disconnect {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                if let Ok(msg) = serde_json::from_value(msg_value) {
+                    if let Some(response) = message_handler
+                        .handle_message(&client_id, msg)
+                        .await
+                    {
+                        let response_str = serde_json::to_string(&response)?;
+                        self_clone.send_to_client(&client_id, &response_str).await;
+                    }
+                } else {
+                    debug!("Failed to parse message from {}", client_id);
+                    if self_clone.malformed_strike(&client_id, &mut malformed) {
+                        too_malformed = true;
+                        break;
+                    }
+                }
+            }
+            if too_malformed {
+                break;
+            }
+            if over_limit && limit.action == MessageRateAction::Disconnect {
+                if let Some(sender) = self_clone.ws_senders.get(&client_id) {
+                    sender.send(Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "Too many messages".into(),
+                    })));
+                }
+                break;
+            }
+        }
+
+        // Kicked, reaped and slow connections don't get their seat held either
+        self_clone.handle_disconnect(&client_id_clone, keep_seat && !kill.is_cancelled()).await;
+        self_clone.ws_senders.remove(&client_id_clone);
+
+        Ok(())
+    }
+
+    /// Adds a ping's round trip to the client's `ClockSync`, and pushes it a corrected `t`
+    /// when `push_interval_secs` have passed since the last one.
+    async fn record_round_trip(&self, client_id: &str, rtt: u64) {
+        let config = self.config();
+        let Some(client) = self.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let now = current_time_ms();
+        let push = {
+            let mut client = client.write().await;
+            client.clock.record_rtt(rtt, config.time_sync.samples);
+            let push_every = config.time_sync.push_interval_secs * 1000;
+            if push_every == 0 || client.participant.is_none() || now.saturating_sub(client.clock.pushed_at) < push_every {
+                return;
+            }
+            client.clock.pushed_at = now;
+            // `e` is our guess at the client's clock, as if it had asked
+            let e = client.clock.offset().map_or(serde_json::Value::Null, |offset| (now as i64 + offset).into());
+            Outgoing::T { t: client.clock.arrival_time(now), e }
+        };
+        self.send_messages(client_id, &[push]).await;
+    }
+
+    fn new_client_data(&self, user_id: String, ip: String, now: u64) -> ClientData {
+        ClientData {
+            user_id,
+            ip,
+            connected_at: now,
+            participant: None,
+            channel_id: None,
+            last_move_time: None,
+            last_join_time: None,
+            last_typing_time: None,
+            rate_limits: HashMap::new(),
+            clock: Default::default(),
+            client_version: None,
+            capabilities: None,
+            note_quota: NoteQuota::new(self.config().note_quota.normal, self.config().note_quota.max_hist_len),
+            is_bot: false,
+            chat_guard: Default::default(),
+            challenge: None,
+            captcha_pending: false,
+            ip_flagged: false,
+            country: None,
+            screening_score: 0,
+            early_notes: 0,
+            note_density: Default::default(),
+            quota_penalty: Default::default(),
+            spectating: None,
+            stats: Default::default(),
+            stats_since: now,
+            stats_purged: false,
+        }
+    }
+
+    /// `tag` for a client's participant: "bot" for bots, the `[ip_reputation]` tag for flagged
+    /// addresses when that's the action.
+    pub fn participant_tag(&self, client: &ClientData) -> Option<Tag> {
+        let text = if client.is_bot {
+            "bot".to_string()
+        } else {
+            let config = &self.reputation.as_ref()?.config;
+            if !client.ip_flagged || config.action != IpReputationAction::Tag {
+                return None;
+            }
+            config.tag.clone()
+        };
+        let config = self.config();
+        Some(match config.protocol {
+            Protocol::Mpp => Tag::Text(text),
+            Protocol::MppClone => Tag::Styled {
+                color: config.tag_colors.get(&text).cloned().unwrap_or_else(|| DEFAULT_TAG_COLOR.to_string()),
+                text,
+            },
+        })
+    }
+
+    /// Where a client asking for `channel_id` may go: there, unless it's restricted by
+    /// `[ip_reputation]`, in which case the first allowed room (`None` if there isn't one).
+    pub async fn allowed_channel<'a>(&'a self, client_id: &str, channel_id: &'a str) -> Option<&'a str> {
+        let Some(config) = self.reputation.as_ref().map(|r| &r.config) else { return Some(channel_id) };
+        if config.action != IpReputationAction::Restrict || config.allowed_channels.iter().any(|c| c == channel_id) {
+            return Some(channel_id);
+        }
+        let client = self.clients.get(client_id).map(|c| c.value().clone())?;
+        if !client.read().await.ip_flagged {
+            return Some(channel_id);
+        }
+        config.allowed_channels.first().map(String::as_str)
+    }
+
+    /// Adds a client with no socket behind it, for bots that run inside the server. Its
+    /// messages go through `MessageHandler` like anyone's, and whatever would be sent back to
+    /// it is dropped. Returns its participant id.
+    pub fn add_virtual_client(&self, user_id: String) -> String {
+        let client_id = generate_random_id();
+        let config = self.config();
+        let params = NoteQuotaParams {
+            allowance: config.bots.quota_allowance,
+            max: config.bots.quota_max,
+        };
+        let client_data = ClientData {
+            is_bot: true,
+            note_quota: NoteQuota::new(params, config.note_quota.max_hist_len),
+            ..self.new_client_data(user_id.clone(), "internal".to_string(), current_time_ms())
+        };
+        self.clients.insert(client_id.clone(), Arc::new(RwLock::new(client_data)));
+        self.user_connections.entry(user_id).or_default().insert(client_id.clone());
+        client_id
+    }
+
+    /// Tells every client the server is going away, then signals all sender tasks to
+    /// flush their queues and close their sockets with a restart close code.
+    pub async fn begin_shutdown(&self) {
+        if self.shutdown.is_cancelled() {
+            return;
+        }
+
+        info!("Shutting down, notifying {} clients", self.ws_senders.len());
+
+        let notification = messages::batch(&[Outgoing::Notification(Notification {
+            id: Some(format!("shutdown-{}", current_time_ms())),
+            title: Some("Server restarting".to_string()),
+            text: "The server is restarting. You will be reconnected shortly.".to_string(),
+            class: "short".to_string(),
+            duration: 10000,
+        })]);
+        let text = Utf8Bytes::from(notification.to_string());
+
+        for sender in self.ws_senders.iter() {
+            sender.send(Message::Text(text.clone()));
+        }
+
+        // While everyone is still in their rooms, which close as the connections do
+        self.save_snapshot().await;
+        self.shutdown.cancel();
+    }
+
+    /// Warns everyone that the server restarts in `secs` seconds, counting down, then shuts
+    /// down. Returns false if a countdown is already running.
+    pub fn schedule_restart(self: &Arc<Self>, secs: u64) -> bool {
+        let at = current_time_ms() + secs * 1000;
+        if self.restart_at.compare_exchange(0, at, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        info!("Restarting in {} seconds", secs);
+
+        let weak = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            for remaining in (1..=secs).rev() {
+                interval.tick().await;
+                let Some(server) = weak.upgrade() else { return };
+                if server.shutdown.is_cancelled() {
+                    return;
+                }
+                if remaining == secs || remaining % 30 == 0 || matches!(remaining, 15 | 10 | 5 | 3 | 2 | 1) {
+                    let unit = if remaining == 1 { "second" } else { "seconds" };
+                    server.notify_all(
+                        "Server restarting",
+                        &format!("The server is restarting in {} {}.", remaining, unit),
+                    );
+                }
+            }
+            interval.tick().await;
+            if let Some(server) = weak.upgrade() {
+                server.begin_shutdown().await;
+            }
+        });
+        true
+    }
+
+    /// Whether a restart countdown is running. No new channels are created meanwhile.
+    pub fn restart_pending(&self) -> bool {
+        self.restart_at.load(Ordering::Relaxed) != 0
+    }
+
+    /// Counts a bad message against a client and queues a policy-violation close once it has
+    /// sent `malformed_strikes` within `malformed_window_secs`.
+    fn malformed_strike(&self, client_id: &str, strikes: &mut Strikes) -> bool {
+        let config = self.config();
+        let malformed = strikes.record(current_time_ms(), config.malformed_window_secs * 1000);
+        if config.malformed_strikes == 0 || malformed < config.malformed_strikes {
+            return false;
+        }
+
+        warn!("Client {} sent {} malformed messages, closing", client_id, malformed);
+        if let Some(sender) = self.ws_senders.get(client_id) {
+            sender.send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "Too many malformed messages".into(),
+            })));
+        }
+        true
+    }
+
+    /// Closes a connection that never sent `hi`, counting it against its IP when screening is on.
+    async fn check_hi_sent(&self, client_id: &str) {
+        let Some(client) = self.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let client = client.read().await;
+        if client.participant.is_some() {
+            return;
+        }
+
+        info!("Client {} never sent hi, closing", client_id);
+        if let Some(screener) = &self.screener {
+            screener.strike(&client.ip, current_time_ms());
+        }
+        drop(client);
+        self.kick(client_id);
+    }
+
+    /// Counts a note message against a fresh connection. Flooding notes right after
+    /// connecting raises its screening score once and strikes its IP.
+    pub async fn screen_note(&self, client_id: &str) {
+        let Some(screener) = &self.screener else { return };
+        let Some(client) = self.clients.get(client_id).map(|c| c.value().clone()) else { return };
+        let mut client = client.write().await;
+
+        let now = current_time_ms();
+        if now.saturating_sub(client.connected_at) > screener.config.early_window_ms {
+            return;
+        }
+        client.early_notes += 1;
+        if client.early_notes == screener.config.early_note_limit + 1 {
+            info!("Client {} flooded notes right after connecting", client_id);
+            client.screening_score += 1;
+            screener.strike(&client.ip, now);
+        }
+    }
+
+    /// Black MIDI thresholds for a room, if detection is on there.
+    pub fn black_midi_limits(&self, channel_id: &str) -> Option<BlackMidiConfig> {
+        let config = self.config();
+        config.channel_black_midi.get(channel_id).or(config.black_midi.as_ref()).cloned()
+    }
+
+    /// How long a flagged client still has to wait before it may join a channel.
+    pub async fn join_delay(&self, client_id: &str) -> Option<Duration> {
+        let screener = self.screener.as_ref().filter(|s| s.config.action == ScreeningAction::Delay)?;
+        let client = self.clients.get(client_id).map(|c| c.value().clone())?;
+        let client = client.read().await;
+        if !screener.is_flagged(client.screening_score) {
+            return None;
+        }
+
+        let ready_at = client.connected_at + screener.config.join_delay_ms;
+        let now = current_time_ms();
+        (ready_at > now).then(|| Duration::from_millis(ready_at - now))
+    }
+
+    /// Moves a connection to a different user `_id`, e.g. once its token has been checked.
+    pub fn change_user_id(&self, client_id: &str, old_user_id: &str, new_user_id: &str) {
+        if let Some(mut connections) = self.user_connections.get_mut(old_user_id) {
+            connections.remove(client_id);
+        }
+        self.user_connections.remove_if(old_user_id, |_, connections| connections.is_empty());
+        self.user_connections
+            .entry(new_user_id.to_string())
+            .or_default()
+            .insert(client_id.to_string());
+    }
+
+    /// Closes a connection by participant id, or every connection of a user `_id`. Returns
+    /// how many were closed.
+    pub fn kick(&self, id: &str) -> usize {
+        let client_ids: Vec<String> = if self.ws_senders.contains_key(id) {
+            vec![id.to_string()]
+        } else {
+            self.user_connections
+                .get(id)
+                .map(|c| c.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+
+        for client_id in &client_ids {
+            if let Some(sender) = self.ws_senders.get(client_id) {
+                info!("Kicking client {}", client_id);
+                sender.kill.cancel();
+            }
+        }
+        client_ids.len()
+    }
+
+    pub fn notify_all(&self, title: &str, text: &str) {
+        self.announce(Notification {
+            id: Some(format!("broadcast-{}", current_time_ms())),
+            title: Some(title.to_string()),
+            text: text.to_string(),
+            class: "short".to_string(),
+            duration: 10000,
+        });
+    }
+
+    fn send_scheduled_announcements(&self, minute_ms: u64) {
+        let Some(now) = chrono::DateTime::from_timestamp_millis(minute_ms as i64) else { return };
+        for scheduled in self.config().announcements.iter().filter(|a| a.schedule.matches(&now)) {
+            if let Err(e) = crate::admin::announce(self, scheduled.announcement.clone()) {
+                warn!("Skipping scheduled announcement: {}", e);
+            }
+        }
+    }
+
+    /// Sends a notification to every open connection, whatever channel it's in, and returns
+    /// how many that was.
+    pub fn announce(&self, notification: Notification) -> usize {
+        let text = Utf8Bytes::from(messages::batch(&[Outgoing::Notification(notification)]).to_string());
+
+        let mut sent = 0;
+        for sender in self.ws_senders.iter() {
+            sender.send(Message::Text(text.clone()));
+            sent += 1;
+        }
+        sent
+    }
+
+    /// Closes connections that stopped answering pings, which would otherwise sit in
+    /// `ws_senders` forever when the other end vanished without a close frame.
+    pub fn reap_dead_connections(&self) {
+        let timeout_ms = self.config().ping_timeout_secs * 1000;
+        let now = current_time_ms();
 
-        // There are better ways for loops, but I decided to use tokio::time :3
-        let clients = server.clients.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
-            loop {
-                interval.tick().await;
-                for client_ref in clients.iter() {
-                    let mut client = client_ref.value().write().await;
-                    client.note_quota.tick();
+        for sender in self.ws_senders.iter() {
+            let last_pong = sender.last_pong.load(Ordering::Relaxed);
+            if now.saturating_sub(last_pong) > timeout_ms && !sender.kill.is_cancelled() {
+                info!("Client {} stopped answering pings, closing", sender.key());
+                sender.kill.cancel();
+            }
+        }
+    }
+
+    /// Marks connections idle past `afk.idle_secs` as AFK and closes ones idle past
+    /// `afk.disconnect_secs`.
+    pub async fn check_idle_connections(&self) {
+        let idle_ms = self.config().afk.idle_secs * 1000;
+        let disconnect_ms = self.config().afk.disconnect_secs * 1000;
+        let now = current_time_ms();
+
+        let mut went_afk = Vec::new();
+        for sender in self.ws_senders.iter() {
+            let idle = now.saturating_sub(sender.last_activity.load(Ordering::Relaxed));
+            if disconnect_ms > 0 && idle >= disconnect_ms {
+                if !sender.kill.is_cancelled() {
+                    info!("Client {} has been idle too long, closing", sender.key());
+                    sender.kill.cancel();
                 }
+            } else if idle_ms > 0 && idle >= idle_ms && !sender.afk.swap(true, Ordering::Relaxed) {
+                went_afk.push(sender.key().clone());
             }
-        });
+        }
 
-        server
+        for client_id in went_afk {
+            self.set_afk(&client_id, true).await;
+        }
     }
 
-    pub async fn handle_connection(
-        self: Arc<Self>,
-        socket: WebSocket,
-        ip: String,
-    ) -> anyhow::Result<()> {
-        let client_id = generate_client_id(&ip);
-        let connection_id = generate_random_id();
+    pub async fn mark_active(&self, client_id: &str) {
+        let was_afk = match self.ws_senders.get(client_id) {
+            Some(sender) => {
+                sender.last_activity.store(current_time_ms(), Ordering::Relaxed);
+                sender.afk.swap(false, Ordering::Relaxed)
+            }
+            None => return,
+        };
 
-        info!("New connection: client_id={}, connection_id={}", client_id, connection_id);
+        if was_afk {
+            self.set_afk(client_id, false).await;
+        }
+    }
 
-        if !self.clients.contains_key(&client_id) {
-            let client_data = ClientData {
-                user_id: client_id.clone(),
-                participant: None,
-                channel_id: None,
-                last_move_time: None,
-                note_quota: NoteQuota::new(),
-            };
-            self.clients.insert(client_id.clone(), Arc::new(RwLock::new(client_data)));
+    /// Flags the participant and tells their channel, so clients can grey out the cursor.
+    async fn set_afk(&self, client_id: &str, afk: bool) {
+        let Some(client_ref) = self.clients.get(client_id).map(|c| c.value().clone()) else {
+            return;
+        };
+
+        let mut client = client_ref.write().await;
+        let Some(participant) = client.participant.as_mut() else { return };
+        participant.afk = afk;
+        let participant = participant.clone();
+        let Some(channel_id) = client.channel_id.clone() else { return };
+        drop(client);
+
+        let id = client_id.to_string();
+        self.with_channel(&channel_id, move |_, channel| {
+            if let Some(p) = channel.participants.get_mut(&id) {
+                p.afk = afk;
+            }
+        })
+        .await;
+
+        // Participants leave out "afk" when it's false, but clients need to be told it cleared
+        let mut update = Outgoing::P(participant).to_value();
+        update["afk"] = serde_json::json!(afk);
+        self.broadcast_to_channel(&channel_id, &serde_json::json!([update]), None).await;
+    }
+
+    /// Relays a typing indicator change to the rest of the channel as a small `typing`
+    /// message rather than a full participant update.
+    pub async fn set_typing(&self, client_id: &str, typing: bool) {
+        let Some(client_ref) = self.clients.get(client_id).map(|c| c.value().clone()) else {
+            return;
+        };
+
+        let mut client = client_ref.write().await;
+        let Some(participant) = client.participant.as_mut() else { return };
+        if participant.typing == typing {
+            return;
         }
+        participant.typing = typing;
+        let Some(channel_id) = client.channel_id.clone() else { return };
+        drop(client);
 
-        let (mut ws_sender, mut ws_receiver) = socket.split();
-        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let id = client_id.to_string();
+        self.with_channel(&channel_id, move |_, channel| {
+            if let Some(p) = channel.participants.get_mut(&id) {
+                p.typing = typing;
+            }
+        })
+        .await;
 
-        self.ws_senders.insert(client_id.clone(), tx);
-        debug!("Stored WebSocket sender for client: {}", client_id);
+        let msg = serde_json::json!([{
+            "m": "typing",
+            "id": client_id,
+            "typing": typing
+        }]);
+        self.broadcast_to_channel(&channel_id, &msg, Some(client_id)).await;
+    }
 
-        let client_id_for_sender = client_id.clone();
+    /// Waits for connections to finish closing, giving up after `timeout`.
+    pub async fn wait_for_connections(&self, timeout: Duration) {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.ws_senders.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
 
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if let Err(e) = ws_sender.send(Message::Text(msg)).await {
-                    error!("Failed to send WebSocket message: {}", e);
-                    break;
+        if !self.ws_senders.is_empty() {
+            info!("{} connections did not close in time", self.ws_senders.len());
+        }
+    }
+
+    /// Cleans up after a connection. With `keep_seat` (the socket dropped rather than the
+    /// client leaving) the participant stays in its channel for `reconnect_grace_secs`, so the
+    /// same user can pick it up again, crown and all.
+    pub async fn handle_disconnect(&self, client_id: &str, keep_seat: bool) {
+        info!("Handling disconnect for client: {}", client_id);
+
+        if let Some(client_ref) = self.clients.get(client_id).map(|c| c.value().clone()) {
+            let (user_id, channel_id, spectating) = {
+                let client = client_ref.read().await;
+                (client.user_id.clone(), client.channel_id.clone(), client.spectating.clone())
+            };
+
+            if let Some(channel_id) = &channel_id {
+                let grace_ms = self.config().reconnect_grace_secs * 1000;
+                let held = keep_seat && grace_ms > 0 && self.hold_seat(channel_id, client_id, grace_ms).await;
+                if !held {
+                    self.remove_participant(channel_id, client_id, &user_id).await;
                 }
             }
-            debug!("Sender task ended for {}", client_id_for_sender);
+
+            if let Some(channel_id) = &spectating {
+                self.stop_spectating(client_id, channel_id).await;
+            }
+        }
+
+        self.subscribed_to_ls.remove(client_id);
+        self.subscribed_to_custom.remove(client_id);
+
+        if let Some((_, client_ref)) = self.clients.remove(client_id) {
+            let (user_id, stats) = {
+                let mut client = client_ref.write().await;
+                let stats = client.take_stats(current_time_ms());
+                (client.user_id.clone(), (!client.stats_purged).then_some(stats))
+            };
+            if let Some(mut connections) = self.user_connections.get_mut(&user_id) {
+                connections.remove(client_id);
+            }
+            self.user_connections.remove_if(&user_id, |_, connections| connections.is_empty());
+            if let Some(stats) = stats {
+                self.persist("user stats", move |storage| storage.add_user_stats(&user_id, &stats));
+            }
+        }
+    }
+
+    /// Marks a participant as disconnected instead of removing them. Returns false if they
+    /// weren't in the channel.
+    async fn hold_seat(&self, channel_id: &str, client_id: &str, grace_ms: u64) -> bool {
+        let client_id = client_id.to_string();
+        let held = self.with_channel(channel_id, move |_, channel| {
+            if !channel.participants.contains_key(&client_id) {
+                return false;
+            }
+            debug!("Holding {}'s seat in {} for {} ms", client_id, channel._id, grace_ms);
+            channel.disconnected.insert(client_id, current_time_ms() + grace_ms);
+            true
         });
+        held.await.unwrap_or(false)
+    }
 
-        let message_handler = MessageHandler::new(self.clone());
-        let client_id_clone = client_id.clone();
-        let self_clone = self.clone();
+    /// Removes disconnected participants nobody came back for.
+    pub async fn expire_disconnected(&self) {
+        let now = current_time_ms();
+        let rooms = self
+            .with_channels(move |_, channel| {
+                let expired: Vec<(String, String)> = channel
+                    .disconnected
+                    .iter()
+                    .filter(|(_, until)| **until <= now)
+                    .filter_map(|(id, _)| channel.participants.get(id).map(|p| (p.id.clone(), p._id.clone())))
+                    .collect();
+                (channel._id.clone(), expired)
+            })
+            .await;
+        for (channel_id, expired) in rooms {
+            for (client_id, user_id) in expired {
+                debug!("{} didn't reconnect to {} in time", client_id, channel_id);
+                self.remove_participant(&channel_id, &client_id, &user_id).await;
+            }
+        }
+    }
 
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(Message::Text(text)) => {
-                    match serde_json::from_str::<Vec<serde_json::Value>>(&text) {
-                        Ok(messages) => {
-                            for msg_value in messages {
-                                if let Ok(msg) = serde_json::from_value(msg_value) {
-                                    if let Some(response) = message_handler
-                                        .handle_message(&client_id, msg)
-                                        .await
-                                    {
-                                        let response_str = serde_json::to_string(&response)?;
-                                        self_clone.send_to_client(&client_id, &response_str).await;
-                                    }
-                                } else {
-                                    error!("Failed to parse message");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to parse messages array: {}", e);
-                        }
-                    }
-                }
-                Ok(Message::Close(_)) => {
-                    info!("Client {} closed connection", client_id);
-                    break;
-                }
-                Err(e) => {
-                    error!("WebSocket error for client {}: {}", client_id, e);
-                    break;
-                }
-                _ => {}
+    /// Takes a participant out of a channel, passing on the crown and telling the room.
+    async fn remove_participant(&self, channel_id: &str, client_id: &str, user_id: &str) {
+        let Some(new_crown_holder) = self
+            .channel_command(channel_id, |reply| ChannelCommand::Leave {
+                client_id: client_id.to_string(),
+                user_id: user_id.to_string(),
+                pass_crown: true,
+                reply,
+            })
+            .await
+        else {
+            return;
+        };
+
+        let bye_msg = messages::batch(&[Outgoing::Bye { p: client_id.to_string() }]);
+        self.broadcast_to_channel(channel_id, &bye_msg, Some(client_id))
+            .await;
+
+        if let Some(holder) = new_crown_holder {
+            self.broadcast_channel_info(channel_id).await;
+            self.refresh_note_quota(&holder).await;
+        }
+
+        self.remove_if_empty(channel_id).await;
+        self.queue_ls_update(channel_id);
+    }
+
+    /// Adds a newly created room and starts its task.
+    pub fn open_channel(self: &Arc<Self>, channel: Channel) {
+        let channel_id = channel._id.clone();
+        let commands = channel_task::spawn(Arc::downgrade(self), channel);
+        self.channels.insert(channel_id, commands);
+    }
+
+    /// Hands a command to a channel's task and waits for the answer. None if the channel
+    /// isn't open.
+    pub async fn channel_command<T>(
+        &self,
+        channel_id: &str,
+        command: impl FnOnce(oneshot::Sender<T>) -> ChannelCommand,
+    ) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.channels.get(channel_id)?.send(command(reply)).ok()?;
+        answer.await.ok()
+    }
+
+    /// Runs `f` against an open channel in its task's turn and hands back what it returns.
+    /// None if the channel isn't open. `f` must not wait on the same room.
+    pub async fn with_channel<T: Send + 'static>(
+        &self,
+        channel_id: &str,
+        f: impl FnOnce(&Server, &mut Channel) -> T + Send + 'static,
+    ) -> Option<T> {
+        self.channel_command(channel_id, |reply| {
+            ChannelCommand::With(Box::new(move |server, channel| {
+                let _ = reply.send(f(server, channel));
+            }))
+        })
+        .await
+    }
+
+    /// `with_channel` for every open channel. The rooms all get `f` queued before any answer
+    /// is waited on, so a busy one doesn't hold up the rest.
+    pub async fn with_channels<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Server, &mut Channel) -> T + Clone + Send + 'static,
+    ) -> Vec<T> {
+        let senders: Vec<_> = self.channels.iter().map(|c| c.value().clone()).collect();
+        let answers: Vec<_> = senders
+            .into_iter()
+            .filter_map(|commands| {
+                let (reply, answer) = oneshot::channel();
+                let f = f.clone();
+                let command = ChannelCommand::With(Box::new(move |server, channel| {
+                    let _ = reply.send(f(server, channel));
+                }));
+                commands.send(command).ok().map(|_| answer)
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(answers.len());
+        for answer in answers {
+            if let Ok(result) = answer.await {
+                results.push(result);
             }
         }
+        results
+    }
 
-        self_clone.handle_disconnect(&client_id_clone).await;
-        self_clone.ws_senders.remove(&client_id_clone);
+    /// Takes a spectator out of the room they're watching.
+    pub async fn stop_spectating(&self, client_id: &str, channel_id: &str) {
+        self.channel_command(channel_id, |reply| ChannelCommand::StopSpectating {
+            client_id: client_id.to_string(),
+            reply,
+        })
+        .await;
+        self.remove_if_empty(channel_id).await;
+        self.queue_ls_update(channel_id);
+    }
 
-        Ok(())
+    /// Deletes a room nobody is in or watching anymore, saving its recording if one was running.
+    pub async fn remove_if_empty(&self, channel_id: &str) {
+        if self.is_special_channel(channel_id) {
+            return;
+        }
+        let Some(mut channel) = self
+            .channel_command(channel_id, |reply| ChannelCommand::CloseIfEmpty { reply })
+            .await
+            .flatten()
+        else {
+            return;
+        };
+        self.channels.remove(channel_id);
+
+        let unsaved_recording = channel.recording.take();
+        self.save_channel(&channel);
+        self.save_chat(&channel);
+
+        if let Some(recording) = unsaved_recording {
+            match recording.save(&self.config().recordings_dir, channel_id).await {
+                Ok(path) => info!("Saved recording of {} to {}", channel_id, path.display()),
+                Err(e) => error!("Failed to save recording of {}: {}", channel_id, e),
+            }
+        }
     }
 
-    pub async fn handle_disconnect(&self, client_id: &str) {
-        info!("Handling disconnect for client: {}", client_id);
+    /// Starts or stops recording a channel's notes. Stopping writes the MIDI file and
+    /// returns how many note events it contained.
+    pub async fn set_recording(&self, channel_id: &str, on: bool) -> anyhow::Result<Option<usize>> {
+        // None when it's already the way it was asked to be, otherwise the stopped recording
+        let changed = self
+            .with_channel(channel_id, move |_, channel| match (on, &channel.recording) {
+                (true, None) => {
+                    channel.recording = Some(Recording::new(current_time_ms()));
+                    Some(None)
+                }
+                (false, Some(_)) => Some(channel.recording.take()),
+                _ => None,
+            })
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No such channel: {}", channel_id))?;
+        let Some(stopped) = changed else { return Ok(None) };
 
-        if let Some(client_ref) = self.clients.get(client_id) {
-            let client = client_ref.value().read().await;
+        let (text, saved) = match stopped {
+            None => ("This room is now being recorded.", None),
+            Some(recording) => {
+                let path = recording.save(&self.config().recordings_dir, channel_id).await?;
+                info!("Saved recording of {} to {}", channel_id, path.display());
+                ("Recording stopped.", Some(recording.len()))
+            }
+        };
 
-            if let Some(channel_id) = &client.channel_id {
-                if let Some(channel_ref) = self.channels.get(channel_id) {
-                    let mut channel = channel_ref.value().write().await;
-                    channel.participants.remove(client_id);
+        let notification = messages::batch(&[Notification::short(format!("recording-{}", current_time_ms()), text, 5000)]);
+        self.broadcast_to_channel(channel_id, &notification, None).await;
 
-                    if let Some(crown) = &mut channel.crown {
-                        if crown.participant_id.as_deref() == Some(client_id) {
-                            crown.participant_id = None;
-                            crown.user_id = None;
-                        }
-                    }
-                    
-                    let needs_crown_transfer = channel.crown.as_ref()
-                        .map(|c| c.participant_id.is_none())
-                        .unwrap_or(false);
-                    
-                    if needs_crown_transfer {
-                        let first_id = channel.participants.keys().next().cloned();
-                        if let Some(first_id) = first_id {
-                            if let Some(crown) = &mut channel.crown {
-                                crown.participant_id = Some(first_id.clone());
-                                if let Some(client_ref) = self.clients.get(&first_id) {
-                                    let client = client_ref.value().read().await;
-                                    crown.user_id = Some(client.user_id.clone());
-                                }
-                                crown.time = current_time_ms();
-                            }
-                        }
-                    }
+        Ok(saved)
+    }
 
-                    let bye_msg = serde_json::json!([{
-                        "m": "bye",
-                        "p": client_id
-                    }]);
-                    self.broadcast_to_channel(channel_id, &bye_msg, Some(client_id))
-                        .await;
+    pub fn emit(&self, event: ServerEvent) {
+        self.events.emit(event);
+    }
 
-                    if channel.participants.is_empty()
-                        && channel._id != "lobby"
-                        && !channel._id.starts_with("test/")
-                    {
-                        drop(channel);
-                        self.channels.remove(channel_id);
-                        self.broadcast_ls_update(channel_id, false).await;
-                    }
+    /// Records a moderation action in the audit log.
+    pub fn audit(&self, entry: AuditEntry) {
+        self.audit_log.record(entry.clone(), self.config().audit_log.capacity);
+        self.emit(ServerEvent::Moderation(entry));
+    }
+
+    fn publish(&self, event: ClusterEvent) {
+        if let Some(cluster) = self.cluster.get() {
+            cluster.publish(event);
+        }
+    }
+
+    pub fn add_ban(&self, user_id: &str, channel_id: &str, ban: BanInfo) {
+        self.publish(ClusterEvent::Ban {
+            user_id: user_id.to_string(),
+            channel_id: channel_id.to_string(),
+            expiry: ban.expiry,
+        });
+        let (user, channel) = (user_id.to_string(), channel_id.to_string());
+        let saved = ban.clone();
+        self.persist("ban", move |storage| storage.save_ban(&user, &channel, &saved));
+        self.banned_users.insert((user_id.to_string(), channel_id.to_string()), ban);
+    }
+
+    pub fn remove_ban(&self, user_id: &str, channel_id: &str) {
+        self.publish(ClusterEvent::Unban {
+            user_id: user_id.to_string(),
+            channel_id: channel_id.to_string(),
+        });
+        let (user, channel) = (user_id.to_string(), channel_id.to_string());
+        self.persist("unban", move |storage| storage.delete_ban(&user, &channel));
+        self.banned_users.remove(&(user_id.to_string(), channel_id.to_string()));
+    }
+
+    /// Hands an unheld crown to the owner if they're in the room, otherwise to the first
+    /// participant unless it's reserved for the owner and `force` isn't set. Returns the
+    /// new holder.
+    pub fn pass_unheld_crown(channel: &mut Channel, force: bool) -> Option<String> {
+        if channel.crown.as_ref().is_none_or(|c| c.participant_id.is_some()) {
+            return None;
+        }
+
+        // Participants waiting to reconnect can't take it
+        let mut present = channel
+            .participants
+            .values()
+            .filter(|p| !channel.disconnected.contains_key(&p.id));
+        let owner_present = present
+            .clone()
+            .find(|p| channel.owner_id.as_deref() == Some(p._id.as_str()));
+        let next = match owner_present {
+            Some(p) => p,
+            None if channel.crown_reserved() && !force => return None,
+            None => present.next()?,
+        };
+        let (participant_id, user_id) = (next.id.clone(), next._id.clone());
+        let to = Position { x: next.x, y: next.y };
+
+        let crown = channel.crown.as_mut()?;
+        let lying_at = crown.end_pos.clone();
+        crown.give(&participant_id, &user_id, &lying_at, &to, current_time_ms());
+        Some(participant_id)
+    }
+
+    /// Gives up on owners who haven't come back within `crown_hold_secs`.
+    pub async fn release_held_crowns(&self) {
+        let hold_ms = self.config().crown_hold_secs * 1000;
+        if hold_ms == 0 {
+            return;
+        }
+
+        let now = current_time_ms();
+        // The channel's task reports the crown changing hands
+        let passed = self
+            .with_channels(move |server, channel| {
+                let expired = channel.auto_passes_crown(server.config().auto_pass_crown)
+                    && channel.crown_reserved()
+                    && channel.crown.as_ref().is_some_and(|c| now.saturating_sub(c.time) >= hold_ms);
+                if !expired {
+                    return None;
                 }
+                let holder = Self::pass_unheld_crown(channel, true)?;
+                debug!("Owner of {} didn't come back, crown goes to {}", channel._id, holder);
+                server.broadcast_in(channel, &messages::batch(&[channel.update()]), None);
+                Some(holder)
+            })
+            .await;
+
+        for holder in passed.into_iter().flatten() {
+            self.refresh_note_quota(&holder).await;
+        }
+    }
+
+    pub async fn broadcast_channel_info(&self, channel_id: &str) {
+        self.with_channel(channel_id, |server, channel| {
+            server.broadcast_in(channel, &messages::batch(&[channel.update()]), None);
+        })
+        .await;
+    }
+
+    /// Estimated round trip (ms) of every participant that has one, with the participant id
+    /// and the room they're in.
+    pub async fn round_trips(&self) -> Vec<(String, Option<String>, u64)> {
+        let clients: Vec<_> = self.clients.iter().map(|c| c.value().clone()).collect();
+        let mut round_trips = Vec::with_capacity(clients.len());
+        for client in clients {
+            let client = client.read().await;
+            if let (Some(participant), Some(rtt)) = (&client.participant, client.clock.rtt()) {
+                round_trips.push((participant.id.clone(), client.channel_id.clone(), rtt));
             }
         }
+        round_trips
+    }
 
-        self.subscribed_to_ls.remove(client_id);
-        self.clients.remove(client_id);
+    /// Empties a room's chat history for everyone in it. False when there's no such room.
+    pub async fn clear_chat(&self, channel_id: &str) -> bool {
+        self.truncate_chat(channel_id, 0).await
+    }
+
+    /// Cuts a room's chat history down to its latest `keep` messages and sends what's left
+    /// as a `c`, so everyone's chat box shows the same. False when there's no such room.
+    pub async fn truncate_chat(&self, channel_id: &str, keep: usize) -> bool {
+        self.with_channel(channel_id, move |server, channel| {
+            let excess = channel.chat_history.len().saturating_sub(keep);
+            channel.chat_history.drain(..excess);
+            server.chat_changed(&channel._id);
+            let clear_msg = serde_json::json!([{ "m": "c", "c": channel.chat_history }]);
+            server.broadcast_in(channel, &clear_msg, None);
+        })
+        .await
+        .is_some()
+    }
+
+    /// Broadcasts the notes a room collected during its batching window.
+    pub async fn flush_note_batch(&self, channel_id: &str) {
+        self.with_channel(channel_id, |server, channel| {
+            let combined = channel.take_note_batch();
+            if !combined.is_empty() {
+                server.broadcast_in(channel, &messages::batch(&combined), None);
+            }
+        })
+        .await;
+    }
+
+    /// Drops bans that have run out, letting affected users who are online know.
+    pub async fn sweep_expired_bans(&self) {
+        let now = current_time_ms();
+        let mut expired = Vec::new();
+        self.banned_users.retain(|key, ban| {
+            if ban.expiry > now {
+                return true;
+            }
+            expired.push(key.clone());
+            false
+        });
+
+        if expired.is_empty() {
+            return;
+        }
+        debug!("Purged {} expired bans", expired.len());
+        self.persist("expired bans", move |storage| storage.delete_expired_bans(now).map(|_| ()));
+
+        if !self.config().notify_ban_expiry {
+            return;
+        }
+
+        for (user_id, channel_id) in expired {
+            let text = format!("Your ban from {} has expired.", channel_id);
+            let notification = Notification::short(format!("Notification-unban-{}", now), text, 5000);
+            self.send_messages_to_user(&user_id, &[notification]).await;
+        }
+    }
+
+    pub fn active_ban(&self, user_id: &str, channel_id: &str) -> Option<BanInfo> {
+        self.banned_users
+            .get(&(user_id.to_string(), channel_id.to_string()))
+            .filter(|ban| ban.expiry > current_time_ms())
+            .map(|ban| ban.clone())
+    }
+
+    /// Unexpired bans in a channel as (user `_id`, ban) pairs.
+    pub fn channel_bans(&self, channel_id: &str) -> Vec<(String, BanInfo)> {
+        let now = current_time_ms();
+        self.banned_users
+            .iter()
+            .filter(|entry| entry.key().1 == channel_id && entry.expiry > now)
+            .map(|entry| (entry.key().0.clone(), entry.value().clone()))
+            .collect()
     }
 
     pub async fn broadcast_to_channel(
@@ -192,92 +2110,162 @@ impl Server {
         messages: &serde_json::Value,
         exclude_client_id: Option<&str>,
     ) {
-        if let Some(channel_ref) = self.channels.get(channel_id) {
-            let channel = channel_ref.value().read().await;
+        self.deliver_to_channel(channel_id, messages, exclude_client_id).await;
+        self.publish(ClusterEvent::Broadcast {
+            channel_id: channel_id.to_string(),
+            messages: messages.clone(),
+            exclude: exclude_client_id.map(str::to_string),
+        });
+    }
 
-            let msg_str = match serde_json::to_string(messages) {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to serialize message: {}", e);
-                    return;
-                }
-            };
+    /// Sends to the participants connected to this instance only. The channel's task sends
+    /// it in its turn, after whatever was queued before.
+    pub async fn deliver_to_channel(
+        &self,
+        channel_id: &str,
+        messages: &serde_json::Value,
+        exclude_client_id: Option<&str>,
+    ) {
+        let Some(commands) = self.channels.get(channel_id) else {
+            debug!("Tried to broadcast to non-existent channel: {}", channel_id);
+            return;
+        };
+        let _ = commands.send(ChannelCommand::Broadcast {
+            messages: messages.clone(),
+            exclude: exclude_client_id.map(str::to_string),
+        });
+    }
 
-            for (participant_id, _) in channel.participants.iter() {
-                if Some(participant_id.as_str()) != exclude_client_id {
-                    self.send_to_client(participant_id, &msg_str).await;
-                }
+    /// `broadcast_to_channel` from inside the channel's task.
+    pub fn broadcast_in(&self, channel: &Channel, messages: &serde_json::Value, exclude_client_id: Option<&str>) {
+        self.deliver_in(channel, messages, exclude_client_id);
+        self.publish(ClusterEvent::Broadcast {
+            channel_id: channel._id.clone(),
+            messages: messages.clone(),
+            exclude: exclude_client_id.map(str::to_string),
+        });
+    }
+
+    /// `deliver_to_channel` from inside the channel's task.
+    pub fn deliver_in(&self, channel: &Channel, messages: &serde_json::Value, exclude_client_id: Option<&str>) {
+        let mut message = match ChannelMessage::new(messages, exclude_client_id) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Failed to serialize message: {}", e);
+                return;
             }
-        } else {
-            debug!("Tried to broadcast to non-existent channel: {}", channel_id);
+        };
+        message.authors = message_authors(channel, messages);
+
+        // Only fails when nobody is subscribed, which is fine
+        let _ = channel.tx.send(Arc::new(message));
+    }
+
+    /// Switches a connection over to a channel's broadcasts. Called from the channel's task
+    /// while the participant is added, so nothing sent after they're in the room is missed.
+    pub fn subscribe_to_channel(&self, client_id: &str, channel: &Channel) {
+        let Some(sender) = self.ws_senders.get(client_id) else { return };
+        let subscription = ChannelSubscription {
+            channel_id: channel._id.clone(),
+            rx: channel.tx.subscribe(),
+            codec: sender.codec,
+        };
+        let _ = sender.subscribe.send(subscription);
+    }
+
+    /// Sends to every socket the user currently has open.
+    pub async fn send_to_user(&self, user_id: &str, message: &str) {
+        let connections: Vec<String> = match self.user_connections.get(user_id) {
+            Some(connections) => connections.iter().cloned().collect(),
+            None => return,
+        };
+
+        let text = Utf8Bytes::from(message);
+        for client_id in connections {
+            self.send_shared(&client_id, &text);
         }
     }
 
+    /// Whether a connection has `user_id` on its block list.
+    pub fn is_blocking(&self, client_id: &str, user_id: &str) -> bool {
+        self.ws_senders.get(client_id).is_some_and(|sender| {
+            sender.blocked.read().unwrap_or_else(|e| e.into_inner()).contains(user_id)
+        })
+    }
+
+    pub async fn send_messages(&self, client_id: &str, messages: &[Outgoing]) {
+        self.send_to_client(client_id, &messages::batch(messages).to_string()).await;
+    }
+
+    pub async fn send_messages_to_user(&self, user_id: &str, messages: &[Outgoing]) {
+        self.send_to_user(user_id, &messages::batch(messages).to_string()).await;
+    }
+
     pub async fn send_to_client(&self, client_id: &str, message: &str) {
         if let Some(sender) = self.ws_senders.get(client_id) {
-            if let Err(e) = sender.send(message.to_string()) {
-                error!("Failed to send message to client {}: {}", client_id, e);
-            }
+            sender.send(Message::Text(message.into()));
         } else {
             debug!("No WebSocket sender found for client: {}", client_id);
         }
     }
 
-    pub async fn broadcast_ls_update(&self, channel_id: &str, is_bulk: bool) {
-        if let Some(channel_ref) = self.channels.get(channel_id) {
-            let channel = channel_ref.value().read().await;
+    /// Queues text that's going to several connections. `Utf8Bytes` is reference-counted, so
+    /// they all share one buffer all the way to the socket.
+    pub fn send_shared(&self, client_id: &str, text: &Utf8Bytes) {
+        if let Some(sender) = self.ws_senders.get(client_id) {
+            sender.send(Message::Text(text.clone()));
+        }
+    }
+
+    /// Marks a channel's `ls` entry as changed. It goes out with the next batch.
+    pub fn queue_ls_update(&self, channel_id: &str) {
+        self.ls_dirty.lock().unwrap().insert(channel_id.to_string());
+    }
 
-            if !channel.settings.visible {
-                return;
-            }
+    /// Sends every channel changed since the last flush to `+ls` subscribers as one `ls` message.
+    pub async fn flush_ls_updates(&self) {
+        let dirty = std::mem::take(&mut *self.ls_dirty.lock().unwrap());
+        if dirty.is_empty() {
+            return;
+        }
 
-            let message = serde_json::json!([{
-                "m": "ls",
-                "c": is_bulk,
-                "u": [{
-                    "_id": channel._id,
-                    "count": channel.participants.len(),
-                    "crown": if channel.settings.lobby { None } else { channel.crown.as_ref() },
-                    "settings": &channel.settings
-                }]
-            }]);
+        let mut entries = Vec::with_capacity(dirty.len());
+        for channel_id in dirty {
+            let entry = self
+                .with_channel(&channel_id, |_, channel| channel.settings.visible.then(|| channel.ls_entry()))
+                .await
+                .flatten();
+            // Deleted and hidden rooms have to be taken out of lists that still show them
+            entries.push(entry.unwrap_or(LsEntry::Removed {
+                _id: channel_id,
+                count: 0,
+                removed: true,
+            }));
+        }
 
-            let msg_str = match serde_json::to_string(&message) {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to serialize ls update: {}", e);
-                    return;
-                }
-            };
+        let message = messages::batch(&[Outgoing::Ls { c: false, u: entries }]);
+        self.deliver_ls_update(&message).await;
+        self.publish(ClusterEvent::Ls { messages: message });
+    }
 
-            for subscriber in self.subscribed_to_ls.iter() {
-                self.send_to_client(subscriber.key(), &msg_str).await;
+    pub async fn deliver_ls_update(&self, message: &serde_json::Value) {
+        let text = match serde_json::to_string(message).map(Utf8Bytes::from) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("Failed to serialize ls update: {}", e);
+                return;
             }
+        };
+
+        for subscriber in self.subscribed_to_ls.iter() {
+            self.send_shared(subscriber.key(), &text);
         }
     }
 
     pub fn create_default_channel(&self, channel_id: &str) -> Channel {
-        let is_special = channel_id == "lobby" || channel_id.starts_with("test/");
+        let is_special = self.is_special_channel(channel_id);
 
-        let settings = if is_special {
-            ChannelSettings {
-                color: "#73b3cc".to_string(),
-                color2: Some("#273546".to_string()),
-                lobby: true,
-                visible: true,
-                chat: Some(true),
-                crownsolo: None,
-            }
-        } else {
-            ChannelSettings {
-                color: "#ecfaed".to_string(),
-                color2: None,
-                lobby: false,
-                visible: true,
-                chat: None,
-                crownsolo: None,
-            }
-        };
+        let settings = self.config().channel_preset(channel_id, is_special);
 
         let crown = if is_special {
             None
@@ -291,12 +2279,72 @@ impl Server {
             })
         };
 
-        Channel {
+        let mut channel = Channel {
             _id: channel_id.to_string(),
             settings,
             crown,
             participants: Default::default(),
-            chat_history: Vec::new(),
+            chat_history: VecDeque::new(),
+            tx: broadcast::channel(self.config().send_queue_capacity.max(1)).0,
+            recording: None,
+            owner_id: None,
+            note_mutes: HashMap::new(),
+            spectators: HashSet::new(),
+            disconnected: HashMap::new(),
+            note_batch: Vec::new(),
+            activity: Default::default(),
+        };
+
+        if let Some(saved) = self.saved_channels.get(channel_id).filter(|_| !is_special) {
+            channel.settings = saved.settings.clone();
+            channel.owner_id = saved.owner_id.clone();
+            channel.chat_history = saved.chat_history.iter().cloned().collect();
+            channel.trim_chat_history(self.config().chat_history_len);
+            // Held for the owner like it would be if they had just stepped out
+            if let Some(crown) = channel.crown.as_mut() {
+                crown.user_id = saved.owner_id.clone();
+            }
+        } else if let Some(chat) = self.saved_chats.get(channel_id) {
+            if self.config().chat_persistence.keeps(channel_id) {
+                channel.chat_history = chat.iter().cloned().collect();
+                channel.trim_chat_history(self.config().chat_history_len);
+            }
         }
+
+        channel
+    }
+}
+
+/// The user `_id` of whoever sent each chat, note and cursor message in a frame, going by
+/// the participant ids in them.
+fn message_authors(channel: &Channel, messages: &serde_json::Value) -> Vec<Option<String>> {
+    let Some(messages) = messages.as_array() else { return Vec::new() };
+    messages
+        .iter()
+        .map(|message| {
+            let participant_id = match message.get("m").and_then(|m| m.as_str()) {
+                Some("a") => message.pointer("/p/id"),
+                Some("n") => message.get("p"),
+                Some("m") => message.get("id"),
+                _ => None,
+            }?;
+            let participant = channel.participants.get(participant_id.as_str()?)?;
+            Some(participant._id.clone())
+        })
+        .collect()
+}
+
+/// The lines of a `c` message.
+fn chat_history(message: &serde_json::Value) -> Option<&Vec<serde_json::Value>> {
+    if message.get("m")?.as_str()? != "c" {
+        return None;
     }
-}
\ No newline at end of file
+    message.get("c")?.as_array()
+}
+
+async fn recv_channel(channel: &mut Option<ChannelSubscription>) -> Result<Arc<ChannelMessage>, RecvError> {
+    match channel {
+        Some(channel) => channel.rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
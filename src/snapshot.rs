@@ -0,0 +1,148 @@
+use crate::config::SnapshotConfig;
+use crate::server::Server;
+use crate::types::{BanInfo, ChannelSettings, ChatMessage, Crown};
+use crate::utils::current_time_ms;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// How long restored rooms wait for someone to come back before they close like any
+/// other empty room.
+const RESTORED_ROOM_HOLD: Duration = Duration::from_secs(120);
+
+/// Everything about the open rooms and bans that a restart would otherwise lose.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub t: u64,
+    pub channels: Vec<ChannelSnapshot>,
+    pub bans: Vec<BanSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub id: String,
+    pub settings: ChannelSettings,
+    pub owner_id: Option<String>,
+    pub crown: Option<Crown>,
+    pub chat_history: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BanSnapshot {
+    pub user_id: String,
+    pub channel_id: String,
+    pub expiry: u64,
+}
+
+/// Writes a snapshot every `interval_secs`.
+pub fn spawn(server: &Arc<Server>, config: SnapshotConfig) {
+    let weak = Arc::downgrade(server);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(server) = weak.upgrade() else { break };
+            if let Err(e) = write(&server, &config.path).await {
+                error!("Failed to write snapshot to {}: {:#}", config.path.display(), e);
+            }
+        }
+    });
+}
+
+pub async fn take(server: &Server) -> Snapshot {
+    let channels = server
+        .with_channels(|_, channel| ChannelSnapshot {
+            id: channel._id.clone(),
+            settings: channel.settings.clone(),
+            owner_id: channel.owner_id.clone(),
+            crown: channel.crown.clone(),
+            chat_history: channel.chat_history.iter().cloned().collect(),
+        })
+        .await;
+
+    let now = current_time_ms();
+    let bans = server
+        .banned_users
+        .iter()
+        .filter(|ban| ban.expiry > now)
+        .map(|ban| BanSnapshot {
+            user_id: ban.key().0.clone(),
+            channel_id: ban.key().1.clone(),
+            expiry: ban.expiry,
+        })
+        .collect();
+
+    Snapshot { t: now, channels, bans }
+}
+
+/// Replaces the snapshot at `path` with the current state. Written next to it first and
+/// renamed over it, so a crash mid-write leaves the last one intact.
+pub async fn write(server: &Server, path: &Path) -> anyhow::Result<()> {
+    let snapshot = take(server).await;
+    let json = serde_json::to_vec(&snapshot)?;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    tokio::fs::write(&partial, json).await?;
+    tokio::fs::rename(&partial, path).await?;
+    debug!("Wrote snapshot of {} channels and {} bans", snapshot.channels.len(), snapshot.bans.len());
+    Ok(())
+}
+
+/// Reopens the rooms in the snapshot at `path` and brings back its bans that haven't run
+/// out. Crowns are held for whoever had them, like for someone who just stepped out.
+/// Answers with how many rooms were reopened.
+pub async fn restore(server: &Arc<Server>, path: &Path) -> anyhow::Result<usize> {
+    let snapshot: Snapshot = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+    let now = current_time_ms();
+
+    // Through add_ban, so they're in storage too and outlast the next restart
+    for ban in snapshot.bans.into_iter().filter(|ban| ban.expiry > now) {
+        server.add_ban(&ban.user_id, &ban.channel_id, BanInfo { expiry: ban.expiry });
+    }
+
+    let mut restored = Vec::new();
+    for saved in snapshot.channels {
+        if server.channels.contains_key(&saved.id) {
+            continue;
+        }
+        let mut channel = server.create_default_channel(&saved.id);
+        channel.settings = saved.settings;
+        channel.owner_id = saved.owner_id;
+        channel.chat_history = saved.chat_history.into_iter().collect();
+        channel.trim_chat_history(server.config().chat_history_len);
+        // Lobbies never get one, whatever the snapshot says
+        if channel.crown.is_some() {
+            // The hold starts over now, not from when the crown last moved
+            channel.crown = saved.crown.map(|mut crown| {
+                crown.participant_id = None;
+                crown.time = now;
+                crown
+            });
+        }
+        server.open_channel(channel);
+        server.queue_ls_update(&saved.id);
+        restored.push(saved.id);
+    }
+    info!(
+        "Restored {} channels from the snapshot taken {}s ago",
+        restored.len(),
+        now.saturating_sub(snapshot.t) / 1000
+    );
+
+    let count = restored.len();
+    let weak = Arc::downgrade(server);
+    tokio::spawn(async move {
+        tokio::time::sleep(RESTORED_ROOM_HOLD).await;
+        let Some(server) = weak.upgrade() else { return };
+        for channel_id in restored {
+            server.remove_if_empty(&channel_id).await;
+        }
+    });
+    Ok(count)
+}
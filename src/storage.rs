@@ -0,0 +1,541 @@
+use crate::config::Config;
+use crate::types::{
+    BanInfo, Channel, ChannelSettings, ChatMessage, LeaderboardEntry, LeaderboardMetric, UserStats, DAY_MS,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use tracing::{error, info, warn};
+
+/// Where rooms, bans and user profiles are kept between restarts. Calls block, so the server
+/// makes writes on a thread of their own (see `Server::persist`) and reads on the blocking
+/// pool. Forks can pass their own to `Server::with_storage`.
+pub trait Storage: Send + Sync {
+    /// False when nothing written here can be read back. The server then skips writing and
+    /// doesn't bring closed rooms back or remember names.
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    fn load_channels(&self) -> anyhow::Result<Vec<SavedChannel>>;
+    fn save_channel(&self, channel: &SavedChannel) -> anyhow::Result<()>;
+    fn delete_channel(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Chat of rooms kept by name rather than saved whole, as (channel id, messages).
+    fn load_chat_histories(&self) -> anyhow::Result<Vec<(String, Vec<ChatMessage>)>>;
+    fn save_chat_history(&self, channel_id: &str, messages: &[ChatMessage]) -> anyhow::Result<()>;
+
+    /// Bans that haven't run out yet, as (user `_id`, channel id, ban).
+    fn load_bans(&self, now: u64) -> anyhow::Result<Vec<(String, String, BanInfo)>>;
+    fn save_ban(&self, user_id: &str, channel_id: &str, ban: &BanInfo) -> anyhow::Result<()>;
+    fn delete_ban(&self, user_id: &str, channel_id: &str) -> anyhow::Result<()>;
+    fn delete_expired_bans(&self, now: u64) -> anyhow::Result<usize>;
+
+    fn load_profile(&self, user_id: &str) -> anyhow::Result<Option<UserProfile>>;
+    fn save_profile(&self, profile: &UserProfile) -> anyhow::Result<()>;
+    fn delete_profile(&self, user_id: &str) -> anyhow::Result<()>;
+
+    /// Everything added for `user_id` so far, all zero for someone new.
+    fn load_user_stats(&self, user_id: &str) -> anyhow::Result<UserStats>;
+    /// Adds `stats` to what's kept for `user_id`, both in total and for the current day.
+    fn add_user_stats(&self, user_id: &str, stats: &UserStats) -> anyhow::Result<()>;
+    fn delete_user_stats(&self, user_id: &str) -> anyhow::Result<()>;
+    /// The `limit` users with the most of `metric` since `since_day` (days since the epoch,
+    /// `None` for all time), most first. Only the last week of days has to be kept.
+    fn leaderboard(
+        &self,
+        metric: LeaderboardMetric,
+        since_day: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<LeaderboardEntry>>;
+}
+
+/// A write queued with `Server::persist`.
+pub type StorageWrite = Box<dyn FnOnce(&dyn Storage) + Send>;
+
+/// Starts the thread that runs storage writes one at a time, in the order they're sent, so
+/// a later write to the same rows always lands last. It ends once the sender is dropped.
+pub fn spawn_writer(storage: Arc<dyn Storage>) -> mpsc::Sender<StorageWrite> {
+    let (tx, rx) = mpsc::channel::<StorageWrite>();
+    let spawned = std::thread::Builder::new().name("storage-writer".to_string()).spawn(move || {
+        for write in rx {
+            write(&*storage);
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Failed to start the storage writer, nothing will be saved: {}", e);
+    }
+    tx
+}
+
+/// SQLite when `[storage]` is set, memory otherwise.
+pub fn from_config(config: &Config) -> Arc<dyn Storage> {
+    let Some(storage_config) = &config.storage else {
+        return Arc::new(MemoryStorage);
+    };
+    match SqliteStorage::open(&storage_config.path) {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            error!("Failed to open storage {}, nothing will be kept: {}", storage_config.path.display(), e);
+            Arc::new(MemoryStorage)
+        }
+    }
+}
+
+/// What's kept of a room between restarts.
+#[derive(Debug, Clone)]
+pub struct SavedChannel {
+    pub id: String,
+    pub settings: ChannelSettings,
+    pub owner_id: Option<String>,
+    pub chat_history: Vec<ChatMessage>,
+}
+
+impl SavedChannel {
+    /// Only rooms somebody owns are worth keeping; lobbies and ownerless rooms start fresh.
+    pub fn of(channel: &Channel) -> Option<Self> {
+        if channel.settings.lobby {
+            return None;
+        }
+        Some(Self {
+            id: channel._id.clone(),
+            settings: channel.settings.clone(),
+            owner_id: Some(channel.owner_id.clone()?),
+            chat_history: channel.chat_history.iter().cloned().collect(),
+        })
+    }
+}
+
+/// The name and color a user last set, given back to them on `hi`.
+#[derive(Debug, Clone)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub name: String,
+    pub color: String,
+}
+
+/// Keeps nothing beyond what the server already holds in memory: rooms are gone once they
+/// close and bans once the process exits. The default without `[storage]`.
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    fn load_channels(&self) -> anyhow::Result<Vec<SavedChannel>> {
+        Ok(Vec::new())
+    }
+
+    fn save_channel(&self, _channel: &SavedChannel) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_channel(&self, _id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_chat_histories(&self) -> anyhow::Result<Vec<(String, Vec<ChatMessage>)>> {
+        Ok(Vec::new())
+    }
+
+    fn save_chat_history(&self, _channel_id: &str, _messages: &[ChatMessage]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_bans(&self, _now: u64) -> anyhow::Result<Vec<(String, String, BanInfo)>> {
+        Ok(Vec::new())
+    }
+
+    fn save_ban(&self, _user_id: &str, _channel_id: &str, _ban: &BanInfo) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_ban(&self, _user_id: &str, _channel_id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_expired_bans(&self, _now: u64) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn load_profile(&self, _user_id: &str) -> anyhow::Result<Option<UserProfile>> {
+        Ok(None)
+    }
+
+    fn save_profile(&self, _profile: &UserProfile) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_profile(&self, _user_id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_user_stats(&self, _user_id: &str) -> anyhow::Result<UserStats> {
+        Ok(UserStats::default())
+    }
+
+    fn add_user_stats(&self, _user_id: &str, _stats: &UserStats) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_user_stats(&self, _user_id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn leaderboard(
+        &self,
+        _metric: LeaderboardMetric,
+        _since_day: Option<u64>,
+        _limit: usize,
+    ) -> anyhow::Result<Vec<LeaderboardEntry>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Schema changes in order. `PRAGMA user_version` counts how many have been applied, so
+/// new ones only ever go on the end.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE channels (
+        id TEXT PRIMARY KEY,
+        settings TEXT NOT NULL,
+        owner_id TEXT,
+        chat_history TEXT NOT NULL,
+        saved_at INTEGER NOT NULL
+    );
+    CREATE TABLE bans (
+        user_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        expiry INTEGER NOT NULL,
+        PRIMARY KEY (user_id, channel_id)
+    );
+    ",
+    "
+    CREATE TABLE profiles (
+        user_id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        color TEXT NOT NULL
+    );
+    ",
+    "
+    CREATE TABLE user_stats (
+        user_id TEXT PRIMARY KEY,
+        notes INTEGER NOT NULL,
+        messages INTEGER NOT NULL,
+        online_ms INTEGER NOT NULL,
+        rooms INTEGER NOT NULL
+    );
+    ",
+    "
+    CREATE TABLE user_stats_daily (
+        user_id TEXT NOT NULL,
+        day INTEGER NOT NULL,
+        notes INTEGER NOT NULL,
+        online_ms INTEGER NOT NULL,
+        PRIMARY KEY (user_id, day)
+    );
+    CREATE INDEX user_stats_daily_day ON user_stats_daily (day);
+    ",
+    "
+    CREATE TABLE chat_histories (
+        channel_id TEXT PRIMARY KEY,
+        messages TEXT NOT NULL,
+        saved_at INTEGER NOT NULL
+    );
+    ",
+];
+
+/// A SQLite database file.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut conn = Connection::open(path)?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        migrate(&mut conn)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_channels(&self) -> anyhow::Result<Vec<SavedChannel>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT id, settings, owner_id, chat_history FROM channels")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+
+        let mut channels = Vec::new();
+        for row in rows {
+            let (id, settings, owner_id, chat_history) = row?;
+            match parse_channel(&settings, &chat_history) {
+                Ok((settings, chat_history)) => channels.push(SavedChannel {
+                    id,
+                    settings,
+                    owner_id,
+                    chat_history,
+                }),
+                Err(e) => warn!("Skipping saved channel {}, it doesn't parse: {}", id, e),
+            }
+        }
+        Ok(channels)
+    }
+
+    fn save_channel(&self, channel: &SavedChannel) -> anyhow::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO channels (id, settings, owner_id, chat_history, saved_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                channel.id,
+                serde_json::to_string(&channel.settings)?,
+                channel.owner_id,
+                serde_json::to_string(&channel.chat_history)?,
+                to_sql_time(crate::utils::current_time_ms()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_channel(&self, id: &str) -> anyhow::Result<()> {
+        self.conn().execute("DELETE FROM channels WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn load_chat_histories(&self) -> anyhow::Result<Vec<(String, Vec<ChatMessage>)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT channel_id, messages FROM chat_histories")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut histories = Vec::new();
+        for row in rows {
+            let (channel_id, messages) = row?;
+            match serde_json::from_str(&messages) {
+                Ok(messages) => histories.push((channel_id, messages)),
+                Err(e) => warn!("Skipping saved chat of {}, it doesn't parse: {}", channel_id, e),
+            }
+        }
+        Ok(histories)
+    }
+
+    fn save_chat_history(&self, channel_id: &str, messages: &[ChatMessage]) -> anyhow::Result<()> {
+        if messages.is_empty() {
+            self.conn().execute("DELETE FROM chat_histories WHERE channel_id = ?1", [channel_id])?;
+            return Ok(());
+        }
+        self.conn().execute(
+            "INSERT OR REPLACE INTO chat_histories (channel_id, messages, saved_at) VALUES (?1, ?2, ?3)",
+            params![
+                channel_id,
+                serde_json::to_string(messages)?,
+                to_sql_time(crate::utils::current_time_ms()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_bans(&self, now: u64) -> anyhow::Result<Vec<(String, String, BanInfo)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT user_id, channel_id, expiry FROM bans WHERE expiry > ?1")?;
+        let bans = stmt
+            .query_map([to_sql_time(now)], |row| {
+                let expiry: i64 = row.get(2)?;
+                Ok((row.get(0)?, row.get(1)?, BanInfo { expiry: expiry.max(0) as u64 }))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(bans)
+    }
+
+    fn save_ban(&self, user_id: &str, channel_id: &str, ban: &BanInfo) -> anyhow::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO bans (user_id, channel_id, expiry) VALUES (?1, ?2, ?3)",
+            params![user_id, channel_id, to_sql_time(ban.expiry)],
+        )?;
+        Ok(())
+    }
+
+    fn delete_ban(&self, user_id: &str, channel_id: &str) -> anyhow::Result<()> {
+        self.conn().execute(
+            "DELETE FROM bans WHERE user_id = ?1 AND channel_id = ?2",
+            params![user_id, channel_id],
+        )?;
+        Ok(())
+    }
+
+    fn delete_expired_bans(&self, now: u64) -> anyhow::Result<usize> {
+        Ok(self.conn().execute("DELETE FROM bans WHERE expiry <= ?1", [to_sql_time(now)])?)
+    }
+
+    fn load_profile(&self, user_id: &str) -> anyhow::Result<Option<UserProfile>> {
+        let profile = self
+            .conn()
+            .query_row("SELECT name, color FROM profiles WHERE user_id = ?1", [user_id], |row| {
+                Ok(UserProfile {
+                    user_id: user_id.to_string(),
+                    name: row.get(0)?,
+                    color: row.get(1)?,
+                })
+            })
+            .optional()?;
+        Ok(profile)
+    }
+
+    fn save_profile(&self, profile: &UserProfile) -> anyhow::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO profiles (user_id, name, color) VALUES (?1, ?2, ?3)",
+            params![profile.user_id, profile.name, profile.color],
+        )?;
+        Ok(())
+    }
+
+    fn delete_profile(&self, user_id: &str) -> anyhow::Result<()> {
+        self.conn().execute("DELETE FROM profiles WHERE user_id = ?1", [user_id])?;
+        Ok(())
+    }
+
+    fn load_user_stats(&self, user_id: &str) -> anyhow::Result<UserStats> {
+        let stats = self
+            .conn()
+            .query_row(
+                "SELECT notes, messages, online_ms, rooms FROM user_stats WHERE user_id = ?1",
+                [user_id],
+                |row| {
+                    Ok(UserStats {
+                        notes: from_sql_count(row.get(0)?),
+                        messages: from_sql_count(row.get(1)?),
+                        online_ms: from_sql_count(row.get(2)?),
+                        rooms: from_sql_count(row.get(3)?),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(stats.unwrap_or_default())
+    }
+
+    fn add_user_stats(&self, user_id: &str, stats: &UserStats) -> anyhow::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO user_stats (user_id, notes, messages, online_ms, rooms) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user_id) DO UPDATE SET
+                notes = notes + excluded.notes,
+                messages = messages + excluded.messages,
+                online_ms = online_ms + excluded.online_ms,
+                rooms = rooms + excluded.rooms",
+            params![
+                user_id,
+                to_sql_count(stats.notes),
+                to_sql_count(stats.messages),
+                to_sql_count(stats.online_ms),
+                to_sql_count(stats.rooms),
+            ],
+        )?;
+
+        let today = crate::utils::current_time_ms() / DAY_MS;
+        tx.execute(
+            "INSERT INTO user_stats_daily (user_id, day, notes, online_ms) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (user_id, day) DO UPDATE SET
+                notes = notes + excluded.notes,
+                online_ms = online_ms + excluded.online_ms",
+            params![user_id, to_sql_count(today), to_sql_count(stats.notes), to_sql_count(stats.online_ms)],
+        )?;
+        // Nothing ranks further back than a week
+        tx.execute(
+            "DELETE FROM user_stats_daily WHERE user_id = ?1 AND day < ?2",
+            params![user_id, to_sql_count(today.saturating_sub(6))],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_user_stats(&self, user_id: &str) -> anyhow::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM user_stats WHERE user_id = ?1", [user_id])?;
+        conn.execute("DELETE FROM user_stats_daily WHERE user_id = ?1", [user_id])?;
+        Ok(())
+    }
+
+    fn leaderboard(
+        &self,
+        metric: LeaderboardMetric,
+        since_day: Option<u64>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<LeaderboardEntry>> {
+        let column = match metric {
+            LeaderboardMetric::Notes => "notes",
+            LeaderboardMetric::Online => "online_ms",
+        };
+        let entry = |row: &rusqlite::Row<'_>| -> rusqlite::Result<LeaderboardEntry> {
+            Ok(LeaderboardEntry {
+                user_id: row.get(0)?,
+                name: row.get(1)?,
+                value: from_sql_count(row.get(2)?),
+            })
+        };
+        let limit = to_sql_count(limit as u64);
+
+        let conn = self.conn();
+        let entries = match since_day {
+            Some(day) => conn
+                .prepare(&format!(
+                    "SELECT s.user_id, p.name, SUM(s.{column}) AS value FROM user_stats_daily s
+                     LEFT JOIN profiles p ON p.user_id = s.user_id
+                     WHERE s.day >= ?1 GROUP BY s.user_id HAVING value > 0 ORDER BY value DESC LIMIT ?2"
+                ))?
+                .query_map(params![to_sql_count(day), limit], entry)?
+                .collect::<Result<_, _>>()?,
+            None => conn
+                .prepare(&format!(
+                    "SELECT s.user_id, p.name, s.{column} FROM user_stats s
+                     LEFT JOIN profiles p ON p.user_id = s.user_id
+                     WHERE s.{column} > 0 ORDER BY s.{column} DESC LIMIT ?1"
+                ))?
+                .query_map([limit], entry)?
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(entries)
+    }
+}
+
+fn parse_channel(settings: &str, chat_history: &str) -> serde_json::Result<(ChannelSettings, Vec<ChatMessage>)> {
+    Ok((serde_json::from_str(settings)?, serde_json::from_str(chat_history)?))
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (version, sql) in MIGRATIONS.iter().enumerate().skip(applied.max(0) as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        tx.commit()?;
+        info!("Applied storage migration {}", version + 1);
+    }
+    Ok(())
+}
+
+/// SQLite integers are signed; permanent bans use times past what fits.
+fn to_sql_time(ms: u64) -> i64 {
+    i64::try_from(ms).unwrap_or(i64::MAX)
+}
+
+fn to_sql_count(count: u64) -> i64 {
+    i64::try_from(count).unwrap_or(i64::MAX)
+}
+
+fn from_sql_count(count: i64) -> u64 {
+    count.max(0) as u64
+}
@@ -1,7 +1,13 @@
+use crate::config::{BlackMidiConfig, ChatFloodConfig, NoteQuotaConfig, QuotaPenaltyConfig, RateConfig};
+use crate::messages::{ChannelInfo, LsEntry, Outgoing};
+use crate::recording::{note_number, Recording};
+use crate::server::ChannelMessage;
+use crate::utils::current_time_ms;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Participant {
     pub id: String,
     pub _id: String,
@@ -9,9 +15,80 @@ pub struct Participant {
     pub color: String,
     pub x: f64,
     pub y: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<Tag>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub afk: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub typing: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A participant's tag: plain text for MPP clients, text and color for mppclone ones (see
+/// `Protocol`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Tag {
+    Text(String),
+    Styled { text: String, color: String },
+}
+
+/// One entry of an `n` message: a key starting (`v` is the velocity) or, with `s: 1`, stopping.
+/// `d` delays it relative to the message's `t`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub n: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub d: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s: Option<u8>,
+}
+
+impl Note {
+    /// Rebuilds a note from client input, keeping only the fields MPP knows about. Returns
+    /// `None` for anything that isn't a playable note.
+    pub fn parse(value: &serde_json::Value, max_delay_ms: u64) -> Option<Self> {
+        let n = value.get("n")?.as_str()?;
+        note_number(n)?;
+
+        let v = match value.get("v") {
+            None => None,
+            Some(v) => Some(v.as_f64().filter(|v| (0.0..=1.0).contains(v))?),
+        };
+        let d = match value.get("d") {
+            None => None,
+            Some(d) => Some(d.as_f64().filter(|d| *d >= 0.0 && *d <= max_delay_ms as f64)?.round() as u64),
+        };
+        let s = match value.get("s") {
+            None => None,
+            Some(s) if s.as_u64() == Some(1) || s.as_bool() == Some(true) => Some(1),
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            n: n.to_string(),
+            v,
+            d,
+            s,
+        })
+    }
+
+    pub fn is_stop(&self) -> bool {
+        self.s == Some(1)
+    }
+}
+
+/// How long a dropped crown is left alone before anyone can pick it up.
+const CROWN_DROP_MS: u64 = 15_000;
+/// How close (in cursor coordinates, 0-100) someone's cursor has to be to pick up the crown.
+const CROWN_PICKUP_DISTANCE: f64 = 10.0;
+/// How far a dropped crown falls below where it was let go, and the lowest it lands, so it
+/// stays on screen.
+const CROWN_FALL: f64 = 30.0;
+const CROWN_FLOOR: f64 = 90.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Crown {
     #[serde(rename = "participantId")]
     pub participant_id: Option<String>,
@@ -24,13 +101,56 @@ pub struct Crown {
     pub end_pos: Position,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Crown {
+    /// Whether someone with their cursor at `at` can pick the crown up off the floor. Like
+    /// MPP, whoever dropped it doesn't have to wait for it to land.
+    pub fn can_claim(&self, user_id: &str, at: &Position, now: u64) -> bool {
+        if self.participant_id.is_some() {
+            return false;
+        }
+        let landed = now.saturating_sub(self.time) >= CROWN_DROP_MS;
+        let dropped_it = self.user_id.as_deref() == Some(user_id);
+        let distance = (at.x - self.end_pos.x).hypot(at.y - self.end_pos.y);
+        (landed || dropped_it) && distance <= CROWN_PICKUP_DISTANCE
+    }
+
+    /// Hands the crown to a participant. MPP clients only animate a crown nobody holds, but
+    /// `startPos`/`endPos` still say where it came from and went.
+    pub fn give(&mut self, participant_id: &str, user_id: &str, from: &Position, to: &Position, now: u64) {
+        self.participant_id = Some(participant_id.to_string());
+        self.user_id = Some(user_id.to_string());
+        self.time = now;
+        self.start_pos = from.on_screen();
+        self.end_pos = to.on_screen();
+    }
+
+    /// Lets go of the crown at `at`. Clients animate it falling from `startPos` to `endPos`
+    /// over the first 2 seconds after `time`, and it can be picked up where it lands.
+    pub fn drop_at(&mut self, at: &Position, now: u64) {
+        self.participant_id = None;
+        self.time = now;
+        self.start_pos = at.on_screen();
+        self.end_pos = Position { x: at.x, y: (at.y + CROWN_FALL).min(CROWN_FLOOR) }.on_screen();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Position {
     pub x: f64,
     pub y: f64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Position {
+    /// Clamped to the 0-100 (percent of the screen) range clients draw cursors and the
+    /// crown in.
+    pub fn on_screen(&self) -> Self {
+        let clamp = |v: f64| if v.is_finite() { v.clamp(0.0, 100.0) } else { 50.0 };
+        Position { x: clamp(self.x), y: clamp(self.y) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChannelSettings {
     pub color: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -41,6 +161,111 @@ pub struct ChannelSettings {
     pub chat: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub crownsolo: Option<bool>,
+    #[serde(rename = "noteQuota", default, skip_serializing_if = "Option::is_none")]
+    pub note_quota: Option<NoteQuotaParams>,
+    /// Most participants allowed in at once. The owner and crown holder can always get in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noindex: Option<bool>,
+    /// Masks `filter_words` in chat.
+    #[serde(rename = "no cussing", default, skip_serializing_if = "Option::is_none")]
+    pub no_cussing: Option<bool>,
+    /// How long (ms) someone has to have been connected before they can join.
+    #[serde(rename = "minOnlineTime", default, skip_serializing_if = "Option::is_none")]
+    pub min_online_time: Option<u64>,
+    /// Chat messages kept for people joining, up to the server's `chat_history_len`.
+    #[serde(rename = "chatHistoryLength", default, skip_serializing_if = "Option::is_none")]
+    pub chat_history_length: Option<u32>,
+    /// Whether the crown goes to someone else when its holder leaves, instead of staying
+    /// dropped for them. Unset uses the server's `auto_pass_crown`.
+    #[serde(rename = "autoPassCrown", default, skip_serializing_if = "Option::is_none")]
+    pub auto_pass_crown: Option<bool>,
+    /// Notes are held this long (ms) and sent as one frame, up to `MAX_NOTE_BATCH_MS`.
+    /// Unset uses the server's `note_batch_ms`, 0 sends each note message right away.
+    #[serde(rename = "noteBatchMs", default, skip_serializing_if = "Option::is_none")]
+    pub note_batch_ms: Option<u64>,
+    /// Least time (ms) between one participant's cursor updates. Only raises the server's
+    /// `cursor_throttle_ms`, up to `MAX_CURSOR_THROTTLE_MS`.
+    #[serde(rename = "cursorThrottleMs", default, skip_serializing_if = "Option::is_none")]
+    pub cursor_throttle_ms: Option<u64>,
+}
+
+/// What a normal room starts with, the "default" preset unless the config changes it.
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            color: "#ecfaed".to_string(),
+            color2: None,
+            lobby: false,
+            visible: true,
+            chat: None,
+            crownsolo: None,
+            note_quota: None,
+            limit: None,
+            noindex: None,
+            no_cussing: None,
+            min_online_time: None,
+            chat_history_length: None,
+            auto_pass_crown: None,
+            note_batch_ms: None,
+            cursor_throttle_ms: None,
+        }
+    }
+}
+
+impl ChannelSettings {
+    /// What lobbies start with, the "lobby" preset unless the config changes it.
+    pub fn lobby() -> Self {
+        Self {
+            color: "#73b3cc".to_string(),
+            color2: Some("#273546".to_string()),
+            lobby: true,
+            chat: Some(true),
+            ..Self::default()
+        }
+    }
+}
+
+/// Longest note batching window a room can ask for.
+pub const MAX_NOTE_BATCH_MS: u64 = 100;
+
+/// Slowest cursor throttle a room can ask for.
+pub const MAX_CURSOR_THROTTLE_MS: u64 = 1000;
+
+/// Turns batched note messages, as (participant id, `t`, notes), into one `n` per
+/// participant. Each keeps the earliest `t` it sent, and notes from its later messages get
+/// that much more delay so they still play when they were meant to.
+pub fn combine_notes(batch: Vec<(String, u64, Vec<Note>)>) -> Vec<Outgoing> {
+    // (participant id, earliest `t`, notes with the `t` each came in)
+    type Combined = (String, u64, Vec<(u64, Note)>);
+    let mut combined: Vec<Combined> = Vec::new();
+    for (participant_id, t, notes) in batch {
+        let timed = notes.into_iter().map(|note| (t, note));
+        match combined.iter_mut().find(|(id, _, _)| *id == participant_id) {
+            Some((_, first_t, all)) => {
+                *first_t = (*first_t).min(t);
+                all.extend(timed);
+            }
+            None => combined.push((participant_id, t, timed.collect())),
+        }
+    }
+
+    combined
+        .into_iter()
+        .map(|(p, t, notes)| Outgoing::N {
+            t,
+            n: notes
+                .into_iter()
+                .map(|(note_t, mut note)| {
+                    let delay = note.d.unwrap_or(0) + (note_t - t);
+                    note.d = (delay > 0).then_some(delay);
+                    note
+                })
+                .collect(),
+            p,
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone)]
@@ -49,7 +274,134 @@ pub struct Channel {
     pub settings: ChannelSettings,
     pub crown: Option<Crown>,
     pub participants: HashMap<String, Participant>,
-    pub chat_history: Vec<ChatMessage>,
+    pub chat_history: VecDeque<ChatMessage>,
+    /// Fan-out to everyone in the channel; each connection's sender task subscribes on join.
+    pub tx: tokio::sync::broadcast::Sender<Arc<ChannelMessage>>,
+    pub recording: Option<Recording>,
+    /// `_id` of whoever created the room. Their crown is held for them while they're away.
+    pub owner_id: Option<String>,
+    /// Users whose notes aren't relayed here until the given time, see `BlackMidiConfig`.
+    pub note_mutes: HashMap<String, u64>,
+    /// Connections watching the room without being in `participants`.
+    pub spectators: HashSet<String>,
+    /// Participants whose socket dropped, kept in `participants` until the given time in case
+    /// they reconnect.
+    pub disconnected: HashMap<String, u64>,
+    /// Note messages waiting for the batching window to end, as (participant id, `t`, notes).
+    pub note_batch: Vec<(String, u64, Vec<Note>)>,
+    pub activity: ChannelActivity,
+}
+
+impl Channel {
+    /// This channel's entry in an `ls` list.
+    pub fn ls_entry(&self) -> LsEntry {
+        LsEntry::Channel {
+            _id: self._id.clone(),
+            count: self.participants.len(),
+            crown: if self.settings.lobby { None } else { self.crown.clone() },
+            settings: Box::new(self.settings.clone()),
+        }
+    }
+
+    /// How many chat messages this room keeps, given the server-wide limit.
+    pub fn chat_history_limit(&self, server_limit: usize) -> usize {
+        self.settings
+            .chat_history_length
+            .map_or(server_limit, |len| (len as usize).min(server_limit))
+    }
+
+    /// Adds to the history sent on join, dropping the oldest messages past the limit.
+    pub fn push_chat(&mut self, message: ChatMessage, server_limit: usize) {
+        self.chat_history.push_back(message);
+        self.trim_chat_history(server_limit);
+    }
+
+    pub fn trim_chat_history(&mut self, server_limit: usize) {
+        let limit = self.chat_history_limit(server_limit);
+        while self.chat_history.len() > limit {
+            self.chat_history.pop_front();
+        }
+    }
+
+    /// The `ch` part of a channel update.
+    pub fn info(&self) -> ChannelInfo {
+        ChannelInfo {
+            _id: self._id.clone(),
+            settings: self.settings.clone(),
+            crown: self.crown.clone(),
+        }
+    }
+
+    /// A full `ch` update for everyone in the room.
+    pub fn update(&self) -> Outgoing {
+        Outgoing::Ch {
+            ch: self.info(),
+            ppl: self.participants.values().cloned().collect(),
+            p: None,
+        }
+    }
+
+    /// How long notes are held before going out, given the server default.
+    pub fn note_batch_ms(&self, server_default: u64) -> u64 {
+        self.settings.note_batch_ms.unwrap_or(server_default).min(MAX_NOTE_BATCH_MS)
+    }
+
+    /// Least time between cursor updates here, given the server's throttle.
+    pub fn cursor_throttle_ms(&self, server_default: u64) -> u64 {
+        self.settings.cursor_throttle_ms.map_or(server_default, |ms| ms.max(server_default))
+    }
+
+    /// Empties `note_batch` into the messages to broadcast, see `combine_notes`.
+    pub fn take_note_batch(&mut self) -> Vec<Outgoing> {
+        combine_notes(std::mem::take(&mut self.note_batch))
+    }
+
+    /// Current activity figures for metrics and the admin API.
+    pub fn stats(&self, now: u64) -> ChannelStats {
+        ChannelStats {
+            participants: self.participants.len(),
+            peak_participants: self.activity.peak_participants,
+            notes_per_second: self.activity.notes.per_second(now),
+            chat_per_second: self.activity.chat.per_second(now),
+            notes_total: self.activity.notes.total(),
+            chat_total: self.activity.chat.total(),
+        }
+    }
+
+    /// Whether a crown nobody holds goes to someone else in the room on its own.
+    pub fn auto_passes_crown(&self, server_default: bool) -> bool {
+        self.settings.auto_pass_crown.unwrap_or(server_default)
+    }
+
+    /// Takes the crown off a participant who is leaving. The owner's crown stays reserved
+    /// for them, and so does anyone's when it doesn't pass on by itself (`auto_pass` off);
+    /// otherwise it's left free for the next person.
+    pub fn release_crown(&mut self, client_id: &str, user_id: &str, auto_pass: bool) {
+        let is_owner = self.owner_id.as_deref() == Some(user_id);
+        let at = self.participants.get(client_id).map(|p| Position { x: p.x, y: p.y });
+        if let Some(crown) = &mut self.crown {
+            if crown.participant_id.as_deref() == Some(client_id) {
+                let at = at.unwrap_or_else(|| crown.end_pos.clone());
+                crown.drop_at(&at, current_time_ms());
+                crown.user_id = (is_owner || !auto_pass).then(|| user_id.to_string());
+            }
+        }
+    }
+
+    /// `id` of a participant of this user that is waiting to be reconnected to.
+    pub fn disconnected_participant(&self, user_id: &str) -> Option<String> {
+        self.disconnected
+            .keys()
+            .find(|id| self.participants.get(*id).is_some_and(|p| p._id == user_id))
+            .cloned()
+    }
+
+    /// Whether an unheld crown is being kept for the absent owner.
+    pub fn crown_reserved(&self) -> bool {
+        self.crown.as_ref().is_some_and(|c| {
+            c.participant_id.is_none() && c.user_id.is_some() && c.user_id == self.owner_id
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -58,85 +410,621 @@ pub struct ChatMessage {
     pub a: String,
     pub p: Participant,
     pub t: u64,
+    /// Only with `protocol = "mppclone"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientData {
     pub user_id: String,
+    pub ip: String,
+    pub connected_at: u64,
     pub participant: Option<Participant>,
     pub channel_id: Option<String>,
     pub last_move_time: Option<u64>,
+    pub last_join_time: Option<u64>,
+    pub last_typing_time: Option<u64>,
+    pub clock: ClockSync,
+    /// `hi.version`, whatever the client calls itself.
+    pub client_version: Option<String>,
+    /// `hi.capabilities`, the ones this server knows. `None` for clients that didn't send
+    /// any, which are treated as they were before capabilities existed.
+    pub capabilities: Option<Vec<Capability>>,
+    /// One bucket per message type in `rate_limits`, made on first use.
+    pub rate_limits: HashMap<String, TokenBucket>,
     pub note_quota: NoteQuota,
+    pub is_bot: bool,
+    pub chat_guard: ChatGuard,
+    /// Answer expected in `hi.code` while the `b` challenge is still outstanding.
+    pub challenge: Option<String>,
+    /// Set until a `hi` carries a good CAPTCHA token, see `CaptchaConfig`.
+    pub captcha_pending: bool,
+    /// Connected from an address `IpReputation` flagged.
+    pub ip_flagged: bool,
+    /// ISO country code from `[geoip]`.
+    pub country: Option<String>,
+    /// Connect screening score, see `ScreeningConfig`.
+    pub screening_score: u32,
+    pub early_notes: u32,
+    pub note_density: NoteDensity,
+    pub quota_penalty: QuotaPenalty,
+    /// Channel watched as a spectator. `channel_id` stays empty meanwhile, so nothing the
+    /// client sends reaches the room.
+    pub spectating: Option<String>,
+    /// Stats not written to storage yet, and when `online_ms` was last counted up to.
+    pub stats: UserStats,
+    pub stats_since: u64,
+    /// Set when the user's data was purged, so the connection's stats are never written back.
+    pub stats_purged: bool,
+}
+
+impl ClientData {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.as_ref().is_none_or(|caps| caps.contains(&capability))
+    }
+
+    /// `stats` with the time online since `stats_since` added.
+    pub fn pending_stats(&self, now: u64) -> UserStats {
+        UserStats {
+            online_ms: self.stats.online_ms + now.saturating_sub(self.stats_since),
+            ..self.stats.clone()
+        }
+    }
+
+    /// `pending_stats`, starting over from nothing.
+    pub fn take_stats(&mut self, now: u64) -> UserStats {
+        let stats = self.pending_stats(now);
+        self.stats = UserStats::default();
+        self.stats_since = now;
+        stats
+    }
 }
 
+/// Optional protocol features a client can list in `hi.capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// MessagePack frames, picked with `hi.codec`.
+    MsgPack,
+    /// `+custom`, `-custom` and `custom`.
+    Custom,
+    /// Sending `typing`.
+    Typing,
+}
+
+impl Capability {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "msgpack" => Some(Capability::MsgPack),
+            "custom" => Some(Capability::Custom),
+            "typing" => Some(Capability::Typing),
+            _ => None,
+        }
+    }
+}
+
+/// What a user has done on the server, added up across connections and restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStats {
+    /// Note starts.
+    pub notes: u64,
+    /// Chat messages that went out.
+    pub messages: u64,
+    pub online_ms: u64,
+    /// Rooms joined, not counting a rejoin of the room they were already in.
+    pub rooms: u64,
+}
+
+impl UserStats {
+    pub fn add(&mut self, other: &UserStats) {
+        self.notes = self.notes.saturating_add(other.notes);
+        self.messages = self.messages.saturating_add(other.messages);
+        self.online_ms = self.online_ms.saturating_add(other.online_ms);
+        self.rooms = self.rooms.saturating_add(other.rooms);
+    }
+}
+
+/// What the leaderboard ranks by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardMetric {
+    #[default]
+    Notes,
+    Online,
+}
+
+impl LeaderboardMetric {
+    /// A value of this metric the way `/top` shows it.
+    pub fn format(self, value: u64) -> String {
+        match self {
+            LeaderboardMetric::Notes => format!("{} notes", value),
+            LeaderboardMetric::Online => format!("{:.1} h", value as f64 / 3_600_000.0),
+        }
+    }
+}
+
+/// How far back the leaderboard counts, in UTC days.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardWindow {
+    /// Today so far.
+    Day,
+    /// Today and the six days before.
+    Week,
+    #[default]
+    All,
+}
+
+impl LeaderboardWindow {
+    /// First day (days since the epoch) counted at `now`, `None` for all time.
+    pub fn since_day(self, now: u64) -> Option<u64> {
+        let today = now / DAY_MS;
+        match self {
+            LeaderboardWindow::Day => Some(today),
+            LeaderboardWindow::Week => Some(today.saturating_sub(6)),
+            LeaderboardWindow::All => None,
+        }
+    }
+}
+
+pub const DAY_MS: u64 = 86_400_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LeaderboardEntry {
+    #[serde(rename = "_id")]
+    pub user_id: String,
+    /// The name they last set, if storage remembers it.
+    pub name: Option<String>,
+    pub value: u64,
+}
+
+/// Limits something to a `RateConfig`. Starts out full.
+#[derive(Debug, Clone, Default)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    /// Uses up a token if there is one.
+    pub fn take(&mut self, now: u64, rate: &RateConfig) -> bool {
+        if rate.per_second <= 0.0 {
+            return true;
+        }
+        let burst = f64::from(rate.burst.max(1));
+        let refill = now.saturating_sub(self.updated_at) as f64 / 1000.0 * rate.per_second;
+        self.tokens = (self.tokens + refill).min(burst);
+        self.updated_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Round trip and clock offset estimates for one connection, from timestamped pings and the
+/// client's own `t` messages. Each keeps the last few samples and averages the ones that
+/// aren't outliers, so one slow pong doesn't throw it off.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    rtts: VecDeque<u64>,
+    offsets: VecDeque<i64>,
+    /// When a corrected `t` was last pushed.
+    pub pushed_at: u64,
+}
+
+impl ClockSync {
+    pub fn record_rtt(&mut self, rtt: u64, samples: usize) {
+        push_sample(&mut self.rtts, rtt, samples);
+    }
+
+    /// Takes a `t` the client sent at `client_time` on its clock and that arrived at `now`.
+    /// Needs a round trip estimate first to know when it was sent.
+    pub fn record_client_time(&mut self, client_time: i64, now: u64, samples: usize) {
+        let Some(rtt) = self.rtt() else { return };
+        let sent_at = now.saturating_sub(rtt / 2) as i64;
+        push_sample(&mut self.offsets, client_time - sent_at, samples);
+    }
+
+    pub fn rtt(&self) -> Option<u64> {
+        robust_mean(self.rtts.iter().map(|rtt| *rtt as i64)).map(|rtt| rtt as u64)
+    }
+
+    /// How far (ms) the client's clock is ahead of the server's.
+    pub fn offset(&self) -> Option<i64> {
+        robust_mean(self.offsets.iter().copied())
+    }
+
+    /// Server time as it will be when something sent at `now` reaches the client.
+    pub fn arrival_time(&self, now: u64) -> u64 {
+        now + self.rtt().unwrap_or(0) / 2
+    }
+}
+
+fn push_sample<T>(samples: &mut VecDeque<T>, sample: T, keep: usize) {
+    while samples.len() >= keep.max(1) {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Mean of the samples within three median absolute deviations of the median.
+fn robust_mean(samples: impl Iterator<Item = i64>) -> Option<i64> {
+    let mut sorted: Vec<i64> = samples.collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+    let mut deviations: Vec<i64> = sorted.iter().map(|s| (s - median).abs()).collect();
+    deviations.sort_unstable();
+    let limit = 3 * deviations[deviations.len() / 2].max(1);
+    let kept: Vec<i64> = sorted.into_iter().filter(|s| (s - median).abs() <= limit).collect();
+    Some(kept.iter().sum::<i64>() / kept.len() as i64)
+}
+
+/// Note starts per wall-clock second, for spotting black MIDI that fits in a big quota.
+#[derive(Debug, Clone, Default)]
+pub struct NoteDensity {
+    second: u64,
+    count: u32,
+    busy_secs: u32,
+}
+
+impl NoteDensity {
+    /// Counts `notes` played at `now` and returns true once the player has kept up
+    /// `notes_per_second` for `sustain_secs` seconds in a row.
+    pub fn record(&mut self, now: u64, notes: u32, config: &BlackMidiConfig) -> bool {
+        let second = now / 1000;
+        if second != self.second {
+            let busy = self.count >= config.notes_per_second && second == self.second + 1;
+            self.busy_secs = if busy { self.busy_secs + 1 } else { 0 };
+            self.second = second;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(notes);
+
+        let current = u32::from(self.count >= config.notes_per_second);
+        if self.busy_secs + current >= config.sustain_secs.max(1) {
+            self.busy_secs = 0;
+            self.count = 0;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Seconds `ActivityRate` averages over.
+pub const ACTIVITY_WINDOW_SECS: u64 = 10;
+
+/// Something counted per wall-clock second over the last `ACTIVITY_WINDOW_SECS`, plus a
+/// running total.
+#[derive(Debug, Clone, Default)]
+pub struct ActivityRate {
+    second: u64,
+    buckets: [u32; ACTIVITY_WINDOW_SECS as usize],
+    total: u64,
+}
+
+impl ActivityRate {
+    pub fn record(&mut self, now: u64, count: u32) {
+        let second = now / 1000;
+        // A clock going backwards just lands in the current second
+        if second > self.second {
+            let stale = (second - self.second).min(ACTIVITY_WINDOW_SECS);
+            for s in 1..=stale {
+                self.buckets[((self.second + s) % ACTIVITY_WINDOW_SECS) as usize] = 0;
+            }
+            self.second = second;
+        }
+        let bucket = &mut self.buckets[(self.second % ACTIVITY_WINDOW_SECS) as usize];
+        *bucket = bucket.saturating_add(count);
+        self.total = self.total.saturating_add(u64::from(count));
+    }
+
+    /// Average per second over the window ending at `now`.
+    pub fn per_second(&self, now: u64) -> f64 {
+        let age = (now / 1000).saturating_sub(self.second);
+        if age >= ACTIVITY_WINDOW_SECS {
+            return 0.0;
+        }
+        let sum: u64 = (0..ACTIVITY_WINDOW_SECS - age)
+            .filter_map(|back| self.second.checked_sub(back))
+            .map(|s| u64::from(self.buckets[(s % ACTIVITY_WINDOW_SECS) as usize]))
+            .sum();
+        sum as f64 / ACTIVITY_WINDOW_SECS as f64
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// How busy a room has been since it was opened.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelActivity {
+    /// Entries of relayed `n` messages, starts and stops alike.
+    pub notes: ActivityRate,
+    pub chat: ActivityRate,
+    pub peak_participants: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub participants: usize,
+    pub peak_participants: usize,
+    pub notes_per_second: f64,
+    pub chat_per_second: f64,
+    pub notes_total: u64,
+    pub chat_total: u64,
+}
+
+/// Note quota violations and the mutes they've earned, see `QuotaPenaltyConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaPenalty {
+    violations: VecDeque<u64>,
+    /// Mutes given since the last reset, picks the next `mute_secs` entry.
+    level: usize,
+    last_violation: u64,
+    last_notice: u64,
+    pub muted_until: Option<u64>,
+}
+
+impl QuotaPenalty {
+    pub fn is_muted(&self, now: u64) -> bool {
+        self.muted_until.is_some_and(|until| until > now)
+    }
+
+    /// Counts a failed quota spend and returns how long (ms) the player is now muted for, if
+    /// this one earned a mute.
+    pub fn record(&mut self, now: u64, config: &QuotaPenaltyConfig) -> Option<u64> {
+        if now.saturating_sub(self.last_violation) >= config.reset_after_secs * 1000 {
+            self.level = 0;
+        }
+        self.last_violation = now;
+        self.violations.push_back(now);
+        while self.violations.front().is_some_and(|t| now.saturating_sub(*t) >= config.window_ms) {
+            self.violations.pop_front();
+        }
+        if self.violations.len() < config.violations.max(1) as usize {
+            return None;
+        }
+
+        let secs = *config.mute_secs.get(self.level).or(config.mute_secs.last())?;
+        let ms = secs * 1000;
+        self.violations.clear();
+        self.level += 1;
+        self.muted_until = Some(now + ms);
+        // Good behavior is counted from when the mute ends
+        self.last_violation = now + ms;
+        Some(ms)
+    }
+
+    /// Whether a muted player should be reminded how long is left, at most once a second.
+    pub fn should_remind(&mut self, now: u64) -> bool {
+        if now.saturating_sub(self.last_notice) < 1000 {
+            return false;
+        }
+        self.last_notice = now;
+        true
+    }
+}
+
+/// Messages per wall-clock second from one connection, whatever their type.
+#[derive(Debug, Clone, Default)]
+pub struct MessageRate {
+    second: u64,
+    count: u32,
+}
+
+impl MessageRate {
+    /// Counts a message received at `now` and returns how many came in this second so far.
+    pub fn record(&mut self, now: u64) -> u32 {
+        let second = now / 1000;
+        if second != self.second {
+            self.second = second;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        self.count
+    }
+}
+
+/// Strikes against a connection, each forgotten once it's older than the window.
+#[derive(Debug, Clone, Default)]
+pub struct Strikes {
+    recent: VecDeque<u64>,
+}
+
+impl Strikes {
+    /// Records a strike at `now` and returns how many are within the last `window_ms`.
+    pub fn record(&mut self, now: u64, window_ms: u64) -> u32 {
+        while self.recent.front().is_some_and(|&t| now.saturating_sub(t) >= window_ms) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        self.recent.len() as u32
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatGuard {
+    recent: VecDeque<u64>,
+    last_message: Option<String>,
+    last_time: u64,
+    duplicates: u32,
+    pub muted_until: Option<u64>,
+}
+
+impl ChatGuard {
+    pub fn is_muted(&self, now: u64) -> bool {
+        self.muted_until.is_some_and(|until| until > now)
+    }
+
+    /// Counts a chat message and returns true if it earned the sender a mute.
+    pub fn record(&mut self, message: &str, now: u64, config: &ChatFloodConfig) -> bool {
+        let is_repeat = self.last_message.as_deref() == Some(message)
+            && now.saturating_sub(self.last_time) < config.duplicate_window_ms;
+        self.duplicates = if is_repeat { self.duplicates + 1 } else { 1 };
+        self.last_message = Some(message.to_string());
+        self.last_time = now;
+
+        while self.recent.front().is_some_and(|t| now.saturating_sub(*t) >= config.window_ms) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+
+        if self.duplicates >= config.max_duplicates || self.recent.len() > config.max_messages {
+            self.muted_until = Some(now + config.mute_ms);
+            self.recent.clear();
+            self.duplicates = 0;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The quota profiles MPP hands out: lobbies are the strictest and the crown holder of a
+/// normal room gets the most room to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteQuotaParams {
+    pub allowance: i32,
+    pub max: i32,
+}
+
+impl NoteQuotaParams {
+    pub const LOBBY: Self = Self { allowance: 200, max: 600 };
+    pub const NORMAL: Self = Self { allowance: 400, max: 1200 };
+    pub const CROWN: Self = Self { allowance: 600, max: 1800 };
+
+    /// A room's own `noteQuota` setting wins over the profiles.
+    pub fn for_channel(channel: &Channel, client_id: &str, profiles: &NoteQuotaConfig) -> Self {
+        if let Some(params) = channel.settings.note_quota {
+            return params;
+        }
+
+        let has_crown = channel
+            .crown
+            .as_ref()
+            .is_some_and(|c| c.participant_id.as_deref() == Some(client_id));
+
+        if channel.settings.lobby {
+            profiles.lobby
+        } else if has_crown {
+            profiles.crown
+        } else {
+            profiles.normal
+        }
+    }
+}
+
+/// Server side of MPP's `NoteQuota`, kept in step with the reference client so both agree on
+/// when notes get dropped. Points refill by `allowance` every tick up to `max`; each note
+/// start costs one. If the client has been flat broke for the whole history window, every
+/// note costs `allowance` instead, which is how the reference cuts off sustained spam.
 #[derive(Debug, Clone)]
 pub struct NoteQuota {
     pub points: i32,
     pub allowance: i32,
     pub max: i32,
     pub max_hist_len: usize,
-    pub history: Vec<i32>,
+    pub history: VecDeque<i32>,
+    /// Server tick count this quota was last brought up to, see `catch_up`.
+    ticked: u64,
 }
 
 impl NoteQuota {
-    pub fn new() -> Self {
-        let max = 24000;
-        let max_hist_len = 3;
-        let mut history = Vec::new();
-        for _ in 0..max_hist_len {
-            history.push(max);
-        }
-        
-        Self {
-            points: max,
-            allowance: 8000,
-            max,
-            max_hist_len,
-            history,
+    pub fn new(params: NoteQuotaParams, max_hist_len: usize) -> Self {
+        let mut quota = Self {
+            points: 0,
+            allowance: params.allowance,
+            max: params.max,
+            max_hist_len: max_hist_len.max(1),
+            history: VecDeque::new(),
+            ticked: 0,
+        };
+        quota.reset_points();
+        quota
+    }
+
+    pub fn params(&self) -> NoteQuotaParams {
+        NoteQuotaParams {
+            allowance: self.allowance,
+            max: self.max,
         }
     }
 
+    /// Swaps in new parameters and refills the quota, like MPP's `setParams`. Returns false
+    /// if nothing changed.
+    pub fn set_params(&mut self, params: NoteQuotaParams) -> bool {
+        if self.params() == params {
+            return false;
+        }
+
+        self.allowance = params.allowance;
+        self.max = params.max;
+        self.reset_points();
+        true
+    }
+
+    fn reset_points(&mut self) {
+        self.points = self.max;
+        self.history = std::iter::repeat_n(self.points, self.max_hist_len).collect();
+    }
+
     pub fn tick(&mut self) {
-        self.history.insert(0, self.points);
+        self.history.push_front(self.points);
         self.history.truncate(self.max_hist_len);
 
         if self.points < self.max {
-            self.points += self.allowance;
-            if self.points > self.max {
-                self.points = self.max;
-            }
+            self.points = (self.points + self.allowance).min(self.max);
         }
     }
 
-    pub fn spend(&mut self, needed: i32) -> bool {
-        let sum: i32 = self.history.iter().sum();
-        let mut num_needed = needed;
+    /// Applies the ticks that went by since the last call, given the server's tick count.
+    /// Quotas are only brought up to date when they're used, so idle clients cost nothing
+    /// per tick. Past the point where the quota is full again with a full history, more
+    /// ticks change nothing and are skipped.
+    pub fn catch_up(&mut self, tick: u64) {
+        let behind = tick.saturating_sub(self.ticked);
+        self.ticked = self.ticked.max(tick);
+        let settled_after = (self.max / self.allowance.max(1)) as u64 + self.max_hist_len as u64 + 1;
+        for _ in 0..behind.min(settled_after) {
+            self.tick();
+        }
+    }
 
-        if sum <= 0 {
-            num_needed *= self.allowance;
+    /// Takes `needed` note starts' worth of points, or nothing if there aren't enough.
+    pub fn spend(&mut self, needed: i32) -> bool {
+        let mut needed = needed;
+        if self.history.iter().sum::<i32>() <= 0 {
+            needed *= self.allowance;
         }
 
-        if self.points < num_needed {
+        if self.points < needed {
             return false;
         }
 
-        self.points -= num_needed;
+        self.points -= needed;
         true
     }
 
-    pub fn get_params(&self) -> serde_json::Value {
-        serde_json::json!({
-            "m": "nq",
-            "allowance": self.allowance,
-            "max": self.max,
-            "maxHistLen": self.max_hist_len
-        })
+    pub fn get_params(&self) -> Outgoing {
+        Outgoing::Nq {
+            allowance: self.allowance,
+            max: self.max,
+            max_hist_len: self.max_hist_len,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct BanInfo {
-    pub channel_id: String,
     pub expiry: u64,
 }
 
@@ -145,4 +1033,242 @@ pub struct IncomingMessage {
     pub m: String,
     #[serde(flatten)]
     pub data: serde_json::Value,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: NoteQuotaParams = NoteQuotaParams { allowance: 10, max: 30 };
+
+    #[test]
+    fn starts_full_and_refills_by_allowance() {
+        let mut quota = NoteQuota::new(PARAMS, 3);
+        assert_eq!(quota.points, 30);
+
+        assert!(quota.spend(25));
+        assert!(!quota.spend(6));
+        assert_eq!(quota.points, 5);
+
+        quota.tick();
+        assert_eq!(quota.points, 15);
+        quota.tick();
+        quota.tick();
+        assert_eq!(quota.points, 30);
+    }
+
+    #[test]
+    fn broke_for_the_whole_history_costs_allowance_per_note() {
+        let mut quota = NoteQuota::new(PARAMS, 3);
+        for _ in 0..3 {
+            assert!(quota.spend(quota.points));
+            quota.tick();
+        }
+        assert_eq!(quota.history, [0, 0, 0]);
+        assert_eq!(quota.points, 10);
+        assert!(!quota.spend(2));
+        assert!(quota.spend(1));
+        assert_eq!(quota.points, 0);
+    }
+
+    #[test]
+    fn a_pause_clears_the_penalty() {
+        let mut quota = NoteQuota::new(PARAMS, 3);
+        assert!(quota.spend(30));
+        for _ in 0..3 {
+            quota.tick();
+        }
+        assert!(quota.spend(2));
+        assert_eq!(quota.points, 28);
+    }
+
+    #[test]
+    fn catching_up_matches_ticking() {
+        let mut ticked = NoteQuota::new(PARAMS, 3);
+        let mut lazy = NoteQuota::new(PARAMS, 3);
+        assert!(ticked.spend(30));
+        assert!(lazy.spend(30));
+
+        ticked.tick();
+        ticked.tick();
+        lazy.catch_up(2);
+        assert_eq!(lazy.points, ticked.points);
+        assert_eq!(lazy.history, ticked.history);
+
+        lazy.catch_up(1_000_000);
+        assert_eq!(lazy.points, 30);
+        assert_eq!(lazy.history, [30, 30, 30]);
+    }
+
+    #[test]
+    fn batched_notes_keep_their_timing() {
+        let note = |n: &str, d: Option<u64>| Note {
+            n: n.to_string(),
+            v: Some(0.5),
+            d,
+            s: None,
+        };
+        let batch = combine_notes(vec![
+            ("p1".to_string(), 1000, vec![note("a1", None)]),
+            ("p2".to_string(), 1005, vec![note("c2", None)]),
+            ("p1".to_string(), 1020, vec![note("b1", Some(10))]),
+        ]);
+        assert_eq!(
+            batch,
+            [
+                Outgoing::N {
+                    t: 1000,
+                    n: vec![note("a1", None), note("b1", Some(30))],
+                    p: "p1".to_string(),
+                },
+                Outgoing::N {
+                    t: 1005,
+                    n: vec![note("c2", None)],
+                    p: "p2".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_crown_can_be_claimed_nearby_once_it_lands() {
+        let crown = Crown {
+            participant_id: None,
+            user_id: Some("dropper".to_string()),
+            time: 1000,
+            start_pos: Position { x: 50.0, y: 50.0 },
+            end_pos: Position { x: 50.0, y: 80.0 },
+        };
+        let near = Position { x: 55.0, y: 85.0 };
+        let far = Position { x: 10.0, y: 10.0 };
+
+        assert!(!crown.can_claim("someone", &near, 1000 + CROWN_DROP_MS - 1));
+        assert!(crown.can_claim("dropper", &near, 1000));
+        assert!(crown.can_claim("someone", &near, 1000 + CROWN_DROP_MS));
+        assert!(!crown.can_claim("someone", &far, 1000 + CROWN_DROP_MS));
+
+        let held = Crown { participant_id: Some("holder".to_string()), ..crown };
+        assert!(!held.can_claim("someone", &near, 1000 + CROWN_DROP_MS));
+    }
+
+    #[test]
+    fn dropped_crown_falls_and_stays_on_screen() {
+        let mut crown = Crown {
+            participant_id: Some("holder".to_string()),
+            user_id: Some("u1".to_string()),
+            time: 0,
+            start_pos: Position { x: 0.0, y: 0.0 },
+            end_pos: Position { x: 0.0, y: 0.0 },
+        };
+        crown.drop_at(&Position { x: 40.0, y: 20.0 }, 1000);
+        assert_eq!(crown.participant_id, None);
+        assert_eq!(crown.start_pos, Position { x: 40.0, y: 20.0 });
+        assert_eq!(crown.end_pos, Position { x: 40.0, y: 50.0 });
+
+        crown.drop_at(&Position { x: 250.0, y: 120.0 }, 2000);
+        assert_eq!(crown.start_pos, Position { x: 100.0, y: 100.0 });
+        assert_eq!(crown.end_pos, Position { x: 100.0, y: CROWN_FLOOR });
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_the_rate() {
+        let rate = RateConfig { per_second: 1.0, burst: 3 };
+        let mut bucket = TokenBucket::default();
+        let start = 1_000_000;
+        for _ in 0..3 {
+            assert!(bucket.take(start, &rate));
+        }
+        assert!(!bucket.take(start, &rate));
+        assert!(!bucket.take(start + 999, &rate));
+        assert!(bucket.take(start + 1000, &rate));
+        assert!(!bucket.take(start + 1000, &rate));
+    }
+
+    #[test]
+    fn strikes_fall_out_of_the_window() {
+        let mut strikes = Strikes::default();
+        let start = 1_000_000;
+        assert_eq!(strikes.record(start, 60_000), 1);
+        assert_eq!(strikes.record(start + 30_000, 60_000), 2);
+        assert_eq!(strikes.record(start + 59_999, 60_000), 3);
+        assert_eq!(strikes.record(start + 60_000, 60_000), 3);
+        assert_eq!(strikes.record(start + 200_000, 60_000), 1);
+    }
+
+    #[test]
+    fn clock_sync_ignores_outliers() {
+        let mut clock = ClockSync::default();
+        for rtt in [40, 42, 38, 900, 40] {
+            clock.record_rtt(rtt, 8);
+        }
+        assert_eq!(clock.rtt(), Some(40));
+        assert_eq!(clock.arrival_time(1000), 1020);
+
+        // The client's clock is 5 seconds ahead; its `t` took half the round trip to arrive
+        clock.record_client_time(6000 - 20, 1000, 8);
+        assert_eq!(clock.offset(), Some(5000));
+    }
+
+    #[test]
+    fn set_params_only_resets_on_change() {
+        let mut quota = NoteQuota::new(PARAMS, 3);
+        assert!(quota.spend(20));
+        assert!(!quota.set_params(PARAMS));
+        assert_eq!(quota.points, 10);
+
+        assert!(quota.set_params(NoteQuotaParams { allowance: 20, max: 60 }));
+        assert_eq!(quota.points, 60);
+        assert_eq!(quota.history, [60, 60, 60]);
+    }
+
+    #[test]
+    fn activity_rate_averages_over_the_window() {
+        let mut rate = ActivityRate::default();
+        let start = 1_000_000;
+        rate.record(start, 30);
+        rate.record(start + 1500, 20);
+        assert_eq!(rate.per_second(start + 1500), 5.0);
+        assert_eq!(rate.per_second(start + 10_500), 2.0);
+        assert_eq!(rate.per_second(start + 11_000), 0.0);
+
+        rate.record(start + 20_000, 10);
+        assert_eq!(rate.per_second(start + 20_000), 1.0);
+        assert_eq!(rate.total(), 60);
+    }
+
+    #[test]
+    fn quota_penalties_escalate_and_reset() {
+        let config = QuotaPenaltyConfig {
+            violations: 3,
+            window_ms: 1000,
+            mute_secs: vec![30, 120],
+            reset_after_secs: 600,
+        };
+        let mut penalty = QuotaPenalty::default();
+        let start = 1_000_000;
+        assert_eq!(penalty.record(start, &config), None);
+        assert_eq!(penalty.record(start + 1500, &config), None);
+        assert_eq!(penalty.record(start + 1600, &config), None);
+        assert_eq!(penalty.record(start + 1700, &config), Some(30_000));
+        assert!(penalty.is_muted(start + 31_000));
+        assert!(!penalty.is_muted(start + 31_700));
+
+        let later = start + 40_000;
+        for t in 0..2 {
+            assert_eq!(penalty.record(later + t, &config), None);
+        }
+        assert_eq!(penalty.record(later + 2, &config), Some(120_000));
+        let later = later + 200_000;
+        for t in 0..2 {
+            penalty.record(later + t, &config);
+        }
+        assert_eq!(penalty.record(later + 2, &config), Some(120_000));
+
+        // Ten quiet minutes after that mute ended
+        let much_later = later + 2 + 120_000 + 600_000;
+        for t in 0..2 {
+            penalty.record(much_later + t, &config);
+        }
+        assert_eq!(penalty.record(much_later + 2, &config), Some(30_000));
+    }
+}
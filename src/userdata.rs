@@ -0,0 +1,222 @@
+use crate::reports::ReportQuery;
+use crate::server::Server;
+use crate::utils::current_time_ms;
+use serde_json::json;
+
+/// Everything the server holds about a user `_id`: live connections, bans, owned rooms,
+/// chat history entries, reports, whether they take DMs and stats.
+pub async fn export(server: &Server, user_id: &str) -> serde_json::Value {
+    let mut connections = Vec::new();
+    for client_id in connection_ids(server, user_id) {
+        let Some(client) = server.clients.get(&client_id).map(|c| c.value().clone()) else {
+            continue;
+        };
+        let client = client.read().await;
+        connections.push(json!({
+            "id": client_id,
+            "channel": client.channel_id,
+            "participant": client.participant,
+            "bot": client.is_bot,
+            "ip_flagged": client.ip_flagged,
+            "country": client.country,
+            "version": client.client_version,
+            "capabilities": client.capabilities,
+            "latency_ms": client.clock.rtt(),
+            "clock_offset_ms": client.clock.offset(),
+        }));
+    }
+
+    let bans: Vec<_> = server
+        .banned_users
+        .iter()
+        .filter(|entry| entry.key().0 == user_id)
+        .map(|entry| json!({ "channel": entry.key().1, "expiry": entry.expiry }))
+        .collect();
+
+    let mut owned_channels = Vec::new();
+    let mut chat = Vec::new();
+    let user = user_id.to_string();
+    let rooms = server
+        .with_channels(move |_, channel| {
+            let owned = channel.owner_id.as_deref() == Some(user.as_str());
+            let chat: Vec<_> = channel
+                .chat_history
+                .iter()
+                .filter(|m| m.p._id == user)
+                .map(|msg| json!({ "channel": channel._id, "message": msg.a, "t": msg.t }))
+                .collect();
+            (owned.then(|| channel._id.clone()), chat)
+        })
+        .await;
+    for (owned, messages) in rooms {
+        owned_channels.extend(owned);
+        chat.extend(messages);
+    }
+
+    for saved in server.saved_channels.iter().filter(|s| !server.channels.contains_key(&s.id)) {
+        if saved.owner_id.as_deref() == Some(user_id) {
+            owned_channels.push(saved.id.clone());
+        }
+        for msg in saved.chat_history.iter().filter(|m| m.p._id == user_id) {
+            chat.push(json!({ "channel": saved.id, "message": msg.a, "t": msg.t }));
+        }
+    }
+    for saved in server.saved_chats.iter().filter(|s| !server.channels.contains_key(s.key())) {
+        for msg in saved.value().iter().filter(|m| m.p._id == user_id) {
+            chat.push(json!({ "channel": saved.key(), "message": msg.a, "t": msg.t }));
+        }
+    }
+
+    // Reports about them leave out who filed them
+    let mut reports_filed = Vec::new();
+    let mut reports_about = Vec::new();
+    for report in server.reports.list(&ReportQuery { all: true, target: None }) {
+        for msg in report.context.iter().filter(|m| m.p._id == user_id) {
+            chat.push(json!({ "report": report.id, "channel": report.channel, "message": msg.a, "t": msg.t }));
+        }
+        if report.reporter == user_id {
+            reports_filed.push(json!(report));
+        } else if report.target == user_id {
+            reports_about.push(json!({
+                "id": report.id,
+                "t": report.t,
+                "channel": report.channel,
+                "reason": report.reason,
+                "resolved": report.resolved_by.is_some(),
+            }));
+        }
+    }
+
+    json!({
+        "_id": user_id,
+        "connections": connections,
+        "bans": bans,
+        "owned_channels": owned_channels,
+        "chat": chat,
+        "reports_filed": reports_filed,
+        "reports_about": reports_about,
+        "dms_off": server.dms_off.contains_key(user_id),
+        "stats": server.user_stats(user_id).await,
+    })
+}
+
+/// Removes everything `export` would return and disconnects the user.
+pub async fn purge(server: &Server, user_id: &str) -> serde_json::Value {
+    let ban_channels: Vec<String> = server
+        .banned_users
+        .iter()
+        .filter(|entry| entry.key().0 == user_id)
+        .map(|entry| entry.key().1.clone())
+        .collect();
+    for channel_id in &ban_channels {
+        server.remove_ban(user_id, channel_id);
+    }
+
+    let user = user_id.to_string();
+    let rooms = server
+        .with_channels(move |server, channel| {
+            let before = channel.chat_history.len();
+            channel.chat_history.retain(|m| m.p._id != user);
+            let removed = before - channel.chat_history.len();
+            if removed > 0 {
+                server.chat_changed(&channel._id);
+            }
+
+            let released = channel.owner_id.as_deref() == Some(user.as_str());
+            if released {
+                channel.owner_id = None;
+                // No longer kept without an owner, so this drops it from storage
+                server.save_channel(channel);
+            }
+            if let Some(crown) = channel.crown.as_mut() {
+                if crown.participant_id.is_none() && crown.user_id.as_deref() == Some(user.as_str()) {
+                    crown.user_id = None;
+                }
+            }
+            (removed, released)
+        })
+        .await;
+    let mut chat_removed: usize = rooms.iter().map(|(removed, _)| removed).sum();
+    let mut channels_released = rooms.iter().filter(|(_, released)| *released).count();
+
+    // Closed rooms only live in storage. Theirs are deleted, since rooms without an owner
+    // aren't kept
+    let mut saved_changed = Vec::new();
+    let mut saved_released = Vec::new();
+    for mut saved in server.saved_channels.iter_mut() {
+        if server.channels.contains_key(&saved.id) {
+            continue;
+        }
+        let before = saved.chat_history.len();
+        saved.chat_history.retain(|m| m.p._id != user_id);
+        let removed = before - saved.chat_history.len();
+        chat_removed += removed;
+        if saved.owner_id.as_deref() == Some(user_id) {
+            saved_released.push(saved.id.clone());
+        } else if removed > 0 {
+            saved_changed.push(saved.clone());
+        }
+    }
+    for saved in saved_changed {
+        server.persist("channel", move |storage| storage.save_channel(&saved));
+    }
+    channels_released += saved_released.len();
+    for id in saved_released {
+        server.saved_channels.remove(&id);
+        server.persist("channel", move |storage| storage.delete_channel(&id));
+    }
+    let mut chats_changed = Vec::new();
+    for mut saved in server.saved_chats.iter_mut() {
+        if server.channels.contains_key(saved.key()) {
+            continue;
+        }
+        let before = saved.len();
+        saved.retain(|m| m.p._id != user_id);
+        if saved.len() < before {
+            chat_removed += before - saved.len();
+            chats_changed.push((saved.key().clone(), saved.value().clone()));
+        }
+    }
+    for (channel_id, messages) in chats_changed {
+        server.persist("chat", move |storage| storage.save_chat_history(&channel_id, &messages));
+    }
+
+    let reports_removed = server.reports.purge_user(user_id);
+    server.dms_off.remove(user_id);
+
+    let profile_user_id = user_id.to_string();
+    server.persist("profile", move |storage| storage.delete_profile(&profile_user_id));
+
+    let connection_ids = connection_ids(server, user_id);
+    let now = current_time_ms();
+    for client_id in &connection_ids {
+        // Dropped rather than flushed when the connection closes
+        if let Some(client) = server.clients.get(client_id).map(|c| c.value().clone()) {
+            let mut client = client.write().await;
+            client.take_stats(now);
+            client.stats_purged = true;
+        }
+        if let Some(sender) = server.ws_senders.get(client_id) {
+            sender.kill.cancel();
+        }
+    }
+    let stats_user_id = user_id.to_string();
+    server.persist("user stats", move |storage| storage.delete_user_stats(&stats_user_id));
+
+    json!({
+        "_id": user_id,
+        "bans_removed": ban_channels.len(),
+        "chat_messages_removed": chat_removed,
+        "channels_released": channels_released,
+        "reports_removed": reports_removed,
+        "connections_closed": connection_ids.len(),
+    })
+}
+
+fn connection_ids(server: &Server, user_id: &str) -> Vec<String> {
+    server
+        .user_connections
+        .get(user_id)
+        .map(|c| c.iter().cloned().collect())
+        .unwrap_or_default()
+}
@@ -1,5 +1,15 @@
 use sha2::{Sha256, Digest};
 use rand::Rng;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use std::net::{IpAddr, Ipv6Addr};
+use subtle::ConstantTimeEq;
+
+const MAX_NAME_GRAPHEMES: usize = 40;
+const MAX_CHAT_GRAPHEMES: usize = 256;
+// Enough for accents and keycap emoji, not for zalgo text
+const MAX_COMBINING_MARKS: usize = 3;
 
 pub fn generate_client_id(ip: &str) -> String {
     if let Ok(env) = std::env::var("NODE_ENV") {
@@ -19,6 +29,68 @@ pub fn generate_client_id(ip: &str) -> String {
     generate_random_id()
 }
 
+/// Turns IPv4-mapped IPv6 addresses back into plain IPv4 and cuts other IPv6 addresses down
+/// to their first `v6_prefix_len` bits, since one household usually gets a whole /64 and
+/// rotates through it. Used for the user `_id` hash and per-IP screening.
+pub fn normalize_ip(ip: IpAddr, v6_prefix_len: u8) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => {
+                let bits = u32::from(v6_prefix_len.min(128));
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(v6) & mask), bits)
+            }
+        },
+    }
+}
+
+/// An IP range like `10.0.0.0/8` or `2001:db8::/32`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let bits = u32::from(self.prefix);
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.trim().parse().map_err(|_| ())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| ())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(());
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
 pub fn generate_random_id() -> String {
     let bytes: Vec<u8> = (0..12)
         .map(|_| rand::thread_rng().gen())
@@ -26,9 +98,302 @@ pub fn generate_random_id() -> String {
     hex::encode(bytes)
 }
 
+/// NFC-normalizes a display name, drops control, zero-width and bidi override characters,
+/// collapses whitespace and cuts it to 40 graphemes. Returns `None` if nothing is left.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let chars: Vec<char> = name.nfc().filter(|c| !is_invisible(*c)).collect();
+
+    // Joiners are kept only between two visible characters, where emoji sequences and some
+    // scripts need them
+    let cleaned: String = chars
+        .iter()
+        .enumerate()
+        .filter(|(i, c)| {
+            !is_joiner(**c)
+                || (*i > 0
+                    && chars.get(i + 1).is_some_and(|n| !n.is_whitespace() && !is_joiner(*n))
+                    && !chars[i - 1].is_whitespace()
+                    && !is_joiner(chars[i - 1]))
+        })
+        .map(|(_, c)| *c)
+        .collect();
+    let collapsed = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let truncated: String = collapsed.graphemes(true).take(MAX_NAME_GRAPHEMES).collect();
+    let truncated = truncated.trim_end();
+
+    if truncated.is_empty() {
+        None
+    } else {
+        Some(truncated.to_string())
+    }
+}
+
+/// NFC-normalizes a chat message, turns newlines and tabs into spaces, drops other control and
+/// invisible characters, keeps at most a few combining marks per character and cuts it to 256
+/// graphemes. Returns `None` if nothing is left.
+pub fn sanitize_chat(message: &str) -> Option<String> {
+    let cleaned: String = message
+        .nfc()
+        .filter_map(|c| match c {
+            c if c.is_control() && c.is_whitespace() => Some(' '),
+            c if is_invisible(c) => None,
+            c => Some(c),
+        })
+        .collect();
+
+    let mut truncated = String::with_capacity(cleaned.len());
+    for grapheme in cleaned.graphemes(true).take(MAX_CHAT_GRAPHEMES) {
+        let mut marks = 0;
+        for c in grapheme.chars() {
+            if is_combining_mark(c) {
+                marks += 1;
+                if marks > MAX_COMBINING_MARKS {
+                    continue;
+                }
+            }
+            truncated.push(c);
+        }
+    }
+    let truncated = truncated.trim();
+
+    if truncated.is_empty() {
+        None
+    } else {
+        Some(truncated.to_string())
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    // Whitespace controls like tabs and newlines are left for split_whitespace to collapse
+    (c.is_control() && !c.is_whitespace())
+        || matches!(
+            c,
+            '\u{200B}'
+                | '\u{200E}'
+                | '\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{061C}'
+                | '\u{FEFF}'
+        )
+}
+
+fn is_joiner(c: char) -> bool {
+    matches!(c, '\u{200C}' | '\u{200D}')
+}
+
+/// "lobby" and its numbered overflow rooms ("lobby2", "lobby3", ...).
+pub fn is_lobby(channel_id: &str) -> bool {
+    channel_id.strip_prefix("lobby").is_some_and(|n| {
+        n.is_empty() || (!n.starts_with('0') && n.parse::<u32>().is_ok_and(|n| n >= 2))
+    })
+}
+
+/// Accepts `#RRGGBB` (any case) and returns it lowercased. When `palette` isn't empty the
+/// color also has to be one of its entries.
+pub fn validate_color(color: &str, palette: &[String]) -> Option<String> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let normalized = color.to_ascii_lowercase();
+    if !palette.is_empty() && !palette.iter().any(|p| p.eq_ignore_ascii_case(&normalized)) {
+        return None;
+    }
+
+    Some(normalized)
+}
+
+/// A darker, duller `color` for a room's `color2`, the bottom of its background gradient:
+/// same hue, 60% of the saturation and 35% of the lightness (how the lobby's two colors
+/// relate). Takes a color `validate_color` accepted.
+pub fn derive_color2(color: &str) -> String {
+    let channel = |i: usize| u8::from_str_radix(&color[1 + 2 * i..3 + 2 * i], 16).unwrap_or(0) as f64 / 255.0;
+    let (r, g, b) = (channel(0), channel(1), channel(2));
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+    let s = if d == 0.0 { 0.0 } else { d / (1.0 - (2.0 * l - 1.0).abs()) };
+    let h = if d == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / d).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / d + 2.0)
+    } else {
+        60.0 * ((r - g) / d + 4.0)
+    };
+
+    let (s, l) = (s * 0.6, l * 0.35);
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    let byte = |v: f64| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    format!("#{:02x}{:02x}{:02x}", byte(r), byte(g), byte(b))
+}
+
 pub fn current_time_ms() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_millis() as u64
-}
\ No newline at end of file
+}
+
+/// Compares a password or token without letting the time taken hint at how much of it matched.
+pub fn secret_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Nearest-rank percentile (`q` from 0 to 1) of already sorted values.
+pub fn percentile(sorted: &[u64], q: f64) -> Option<u64> {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+}
+
+/// Replaces every case-insensitive occurrence of `words` with asterisks.
+pub fn mask_words(message: &str, words: &[String]) -> String {
+    let mut masked = message.to_string();
+    for word in words.iter().filter(|w| !w.is_empty()) {
+        let word = word.to_ascii_lowercase();
+        let mut from = 0;
+        while let Some(pos) = masked[from..].to_ascii_lowercase().find(&word) {
+            let start = from + pos;
+            let end = start + word.len();
+            let stars = "*".repeat(masked[start..end].chars().count());
+            masked.replace_range(start..end, &stars);
+            from = start + stars.len();
+        }
+    }
+    masked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_ipv4_matches_plain_ipv4() {
+        let plain: IpAddr = "203.0.113.7".parse().unwrap();
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(normalize_ip(plain, 64), "203.0.113.7");
+        assert_eq!(normalize_ip(mapped, 64), "203.0.113.7");
+    }
+
+    #[test]
+    fn ipv6_collapses_to_prefix() {
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::9".parse().unwrap();
+        assert_eq!(normalize_ip(a, 64), "2001:db8:1:2::/64");
+        assert_eq!(normalize_ip(a, 64), normalize_ip(b, 64));
+        assert_ne!(normalize_ip(a, 128), normalize_ip(b, 128));
+        assert_eq!(normalize_ip(a, 0), "::/0");
+    }
+
+    #[test]
+    fn secrets_compare_by_content() {
+        assert!(secret_eq("hunter2", "hunter2"));
+        assert!(!secret_eq("hunter2", "hunter3"));
+        assert!(!secret_eq("hunter2", "hunter22"));
+        assert!(!secret_eq("", "hunter2"));
+    }
+
+    #[test]
+    fn color2_is_a_darker_shade() {
+        assert_eq!(derive_color2("#73b3cc"), "#283f47");
+        assert_eq!(derive_color2("#ffffff"), "#595959");
+        assert_eq!(derive_color2("#000000"), "#000000");
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let values: Vec<u64> = (1..=10).map(|v| v * 10).collect();
+        assert_eq!(percentile(&values, 0.5), Some(50));
+        assert_eq!(percentile(&values, 0.9), Some(90));
+        assert_eq!(percentile(&values, 0.99), Some(100));
+        assert_eq!(percentile(&values, 0.0), Some(10));
+        assert_eq!(percentile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn cidr_matches_its_range() {
+        let v4: Cidr = "198.51.100.0/24".parse().unwrap();
+        assert!(v4.contains("198.51.100.200".parse().unwrap()));
+        assert!(!v4.contains("198.51.101.1".parse().unwrap()));
+        assert!(!v4.contains("::1".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        let single: Cidr = "203.0.113.7".parse().unwrap();
+        assert!(single.contains("203.0.113.7".parse().unwrap()));
+        assert!(!single.contains("203.0.113.8".parse().unwrap()));
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("not an ip".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn names_lose_invisible_characters() {
+        // Joiners stay inside emoji sequences, but not at the ends or next to spaces
+        let family = "\u{1F468}\u{200D}\u{1F469}";
+        assert_eq!(sanitize_name(family).as_deref(), Some(family));
+        assert_eq!(sanitize_name("\u{200D}ab\u{200C} c").as_deref(), Some("ab c"));
+        // Bidi overrides and isolates can't flip the text around it
+        assert_eq!(sanitize_name("a\u{202E}b\u{2066}c\u{2069}").as_deref(), Some("abc"));
+        assert_eq!(sanitize_name("  tab\there   ").as_deref(), Some("tab here"));
+    }
+
+    #[test]
+    fn names_are_normalized_and_capped() {
+        assert_eq!(sanitize_name("Jose\u{0301}").as_deref(), Some("Jos\u{00E9}"));
+        let long = "x".repeat(MAX_NAME_GRAPHEMES + 10);
+        assert_eq!(sanitize_name(&long).map(|n| n.graphemes(true).count()), Some(MAX_NAME_GRAPHEMES));
+        // Whatever is cut off, trailing space isn't left behind
+        let spaced = format!("{} y", "x".repeat(MAX_NAME_GRAPHEMES - 1));
+        assert_eq!(sanitize_name(&spaced), Some("x".repeat(MAX_NAME_GRAPHEMES - 1)));
+    }
+
+    #[test]
+    fn empty_names_are_refused() {
+        // userset keeps the current name (Anonymous, to begin with) when this is None
+        assert_eq!(sanitize_name(""), None);
+        assert_eq!(sanitize_name(" \t\n "), None);
+        assert_eq!(sanitize_name("\u{200B}\u{200D}\u{FEFF}"), None);
+    }
+
+    #[test]
+    fn chat_is_cut_between_graphemes() {
+        // The 256th grapheme is a family emoji made of five code points
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let message = format!("{}{}b", "a".repeat(MAX_CHAT_GRAPHEMES - 1), family);
+        let sanitized = sanitize_chat(&message).unwrap();
+        assert_eq!(sanitized.graphemes(true).count(), MAX_CHAT_GRAPHEMES);
+        assert!(sanitized.ends_with(family));
+
+        let accents = format!("x{}", "\u{0301}".repeat(50));
+        assert_eq!(sanitize_chat(&accents).unwrap(), format!("x{}", "\u{0301}".repeat(MAX_COMBINING_MARKS)));
+    }
+
+    #[test]
+    fn chat_newlines_become_spaces() {
+        assert_eq!(sanitize_chat("hi\r\nthere\tyou").as_deref(), Some("hi  there you"));
+        assert_eq!(sanitize_chat("\r\n\n"), None);
+        assert_eq!(sanitize_chat("a\u{0007}b").as_deref(), Some("ab"));
+    }
+}
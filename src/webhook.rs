@@ -0,0 +1,82 @@
+use crate::config::WebhookConfig;
+use crate::events::{EventBus, ServerEvent};
+use std::time::Duration;
+use tracing::error;
+
+/// Discord rejects messages with more characters than this.
+const MAX_CONTENT_CHARS: usize = 2000;
+
+pub fn spawn(config: WebhookConfig, events: &EventBus) {
+    let client = reqwest::Client::new();
+    events.subscribe_with("Webhook notifier", move |event| {
+        let content = format_event(&config, &event);
+        let (client, url) = (client.clone(), config.url.clone());
+        async move {
+            if let Some(content) = content {
+                post(&client, &url, &content).await;
+            }
+        }
+    });
+}
+
+fn format_event(config: &WebhookConfig, event: &ServerEvent) -> Option<String> {
+    match event {
+        ServerEvent::ChannelCreated { channel_id } if config.channel_created => {
+            Some(format!("Channel created: **{}**", channel_id))
+        }
+        ServerEvent::UserBanned { channel_id, user_id, name, banned_by, duration_ms } if config.user_banned => {
+            Some(format!(
+                "**{}** banned **{}** (`{}`) from **{}** for {} seconds",
+                banned_by,
+                name,
+                user_id,
+                channel_id,
+                duration_ms / 1000
+            ))
+        }
+        ServerEvent::ChatFiltered { channel_id, user_id, name, message, word } if config.filtered_chat => {
+            Some(format!(
+                "Filtered word `{}` from **{}** (`{}`) in **{}**: {}",
+                word, name, user_id, channel_id, message
+            ))
+        }
+        _ => None,
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, content: &str) {
+    let content = match content.char_indices().nth(MAX_CONTENT_CHARS) {
+        // One character short of the limit to make room for the ellipsis
+        Some(_) => format!("{}…", content.chars().take(MAX_CONTENT_CHARS - 1).collect::<String>()),
+        None => content.to_string(),
+    };
+    // Names and chat are user controlled, so never let them ping anyone
+    let body = serde_json::json!({
+        "content": content,
+        "allowed_mentions": { "parse": [] }
+    });
+
+    for _ in 0..2 {
+        match client.post(url).json(&body).send().await {
+            Ok(res) if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = res
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|v| v.get("retry_after").and_then(|r| r.as_f64()))
+                    .filter(|r| r.is_finite())
+                    .unwrap_or(1.0);
+                tokio::time::sleep(Duration::from_secs_f64(retry_after.clamp(0.0, 30.0))).await;
+            }
+            Ok(res) if !res.status().is_success() => {
+                error!("Webhook returned {}", res.status());
+                return;
+            }
+            Ok(_) => return,
+            Err(e) => {
+                error!("Failed to post webhook: {}", e);
+                return;
+            }
+        }
+    }
+}